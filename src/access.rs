@@ -0,0 +1,148 @@
+// Access bits of a sector trailer (bytes 6-9): the access condition C1 C2 C3 of each block
+// of the sector, stored once plainly and once inverted, and the general purpose byte.
+// Conditions read and parse as `keyA: read+write, keyB: none`, with the operations
+//
+//   data blocks : read, write, increment, decrement (decrement, transfer and restore)
+//   trailer     : read_access, write_access, write_keys (key A and key B), read_key_b
+use crate::error::ReaderError;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+const DATA_OPERATIONS: [&str; 4] = ["read", "write", "increment", "decrement"];
+const TRAILER_OPERATIONS: [&str; 4] = ["read_access", "write_access", "write_keys", "read_key_b"];
+
+// Operations (bit n is OPERATIONS[n]) key A / key B may do under condition 0b_c1_c2_c3
+const DATA: [(u8, u8); 8] = [(15, 15), (9, 9), (1, 1), (0, 3), (1, 3), (0, 1), (9, 15), (0, 0)];
+const TRAILER: [(u8, u8); 8] = [(13, 0), (15, 0), (9, 0), (1, 7), (1, 5), (1, 3), (1, 1), (1, 1)];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccessBits {
+    // 0b_c1_c2_c3 of blocks 0-2 and of the trailer (3), blocks 0-4 / 5-9 / 10-14 of a 16
+    // block sector share one
+    pub conditions: [u8; 4],
+    pub user_byte: u8,
+}
+
+impl AccessBits {
+    // FF078069 of factory cards: data blocks open to both keys, key A may do everything
+    pub const TRANSPORT: AccessBits = AccessBits {
+        conditions: [0, 0, 0, 1],
+        user_byte: 0x69,
+    };
+
+    pub fn encode(&self) -> [u8; 4] {
+        let bit = |block: usize, shift: u8| (self.conditions[block] >> shift) & 1;
+        let nibble = |shift: u8| (0..4).fold(0, |nibble, block| nibble | bit(block, shift) << block);
+        let (c1, c2, c3) = (nibble(2), nibble(1), nibble(0));
+        [(!c2 & 0x0f) << 4 | (!c1 & 0x0f), c1 << 4 | (!c3 & 0x0f), c3 << 4 | c2, self.user_byte]
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<AccessBits, ReaderError> {
+        let invalid = || ReaderError::InvalidInput("the access bits don't match their inverted copy".to_string());
+        let bytes: [u8; 4] = bytes.try_into().map_err(|_| ReaderError::InvalidInput("access bits are 4 bytes".to_string()))?;
+        let (c1, c2, c3) = (bytes[1] >> 4, bytes[2] & 0x0f, bytes[2] >> 4);
+        if bytes[0] & 0x0f != !c1 & 0x0f || bytes[0] >> 4 != !c2 & 0x0f || bytes[1] & 0x0f != !c3 & 0x0f {
+            return Err(invalid());
+        }
+        let condition = |block: u8| (c1 >> block & 1) << 2 | (c2 >> block & 1) << 1 | (c3 >> block & 1);
+        Ok(AccessBits {
+            conditions: [condition(0), condition(1), condition(2), condition(3)],
+            user_byte: bytes[3],
+        })
+    }
+
+    // `keyA: ..., keyB: ...` of block 0-3
+    pub fn describe(&self, block: usize) -> String {
+        match block {
+            3 => describe(TRAILER[self.conditions[3] as usize], &TRAILER_OPERATIONS),
+            _ => describe(DATA[self.conditions[block] as usize], &DATA_OPERATIONS),
+        }
+    }
+}
+
+// Condition of the data blocks granting exactly `text`
+pub fn data_condition(text: &str) -> Result<u8, ReaderError> {
+    condition(text, &DATA, &DATA_OPERATIONS)
+}
+
+// Condition of the trailer granting exactly `text`
+pub fn trailer_condition(text: &str) -> Result<u8, ReaderError> {
+    condition(text, &TRAILER, &TRAILER_OPERATIONS)
+}
+
+fn condition(text: &str, table: &[(u8, u8); 8], operations: &[&str; 4]) -> Result<u8, ReaderError> {
+    let invalid = |reason: String| ReaderError::InvalidInput(reason);
+    let (mut key_a, mut key_b) = (0, 0);
+    for part in text.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+        let (key, granted) = part.split_once(':').ok_or_else(|| invalid(alloc::format!("`{}` isn't `key: operations`", part)))?;
+        let mut mask = 0;
+        for operation in granted.split('+').map(str::trim).filter(|operation| *operation != "none") {
+            let index = operations
+                .iter()
+                .position(|known| *known == operation)
+                .ok_or_else(|| invalid(alloc::format!("unknown operation {}, one of {}", operation, operations.join(", "))))?;
+            mask |= 1 << index;
+        }
+        match key.trim().to_ascii_lowercase().as_str() {
+            "keya" => key_a = mask,
+            "keyb" => key_b = mask,
+            other => return Err(invalid(alloc::format!("unknown key {}, keyA or keyB", other))),
+        }
+    }
+    table
+        .iter()
+        .position(|&granted| granted == (key_a, key_b))
+        .map(|condition| condition as u8)
+        .ok_or_else(|| invalid(alloc::format!("no access condition grants exactly `{}`", text.trim())))
+}
+
+fn describe((key_a, key_b): (u8, u8), operations: &[&str; 4]) -> String {
+    let granted = |mask: u8| {
+        let names: Vec<&str> = (0..4).filter(|bit| mask >> bit & 1 == 1).map(|bit| operations[bit]).collect();
+        match names.is_empty() {
+            true => "none".to_string(),
+            false => names.join("+"),
+        }
+    };
+    alloc::format!("keyA: {}, keyB: {}", granted(key_a), granted(key_b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transport_bits() {
+        assert_eq!(AccessBits::TRANSPORT.encode(), [0xff, 0x07, 0x80, 0x69]);
+        assert_eq!(AccessBits::decode(&[0xff, 0x07, 0x80, 0x69]), Ok(AccessBits::TRANSPORT));
+        assert_eq!(AccessBits::TRANSPORT.describe(0), "keyA: read+write+increment+decrement, keyB: read+write+increment+decrement");
+        assert_eq!(AccessBits::TRANSPORT.describe(3), "keyA: read_access+write_access+write_keys+read_key_b, keyB: none");
+    }
+
+    #[test]
+    fn round_trip() {
+        // value blocks decremented with key A and topped up with key B, trailer managed by key B
+        let bits = AccessBits {
+            conditions: [6, 6, 0, 3],
+            user_byte: 0,
+        };
+        assert_eq!(bits.encode(), [0x4c, 0x37, 0x8b, 0x00]);
+        assert_eq!(AccessBits::decode(&bits.encode()), Ok(bits));
+        assert!(AccessBits::decode(&[0xff, 0x07, 0x81, 0x69]).is_err());
+    }
+
+    #[test]
+    fn parses_permissions() {
+        assert_eq!(data_condition("keyA: read+decrement, keyB: read+write+increment+decrement"), Ok(6));
+        assert_eq!(data_condition("keyA: none, keyB: none"), Ok(7));
+        assert_eq!(data_condition("keyB: read"), Ok(5));
+        assert_eq!(trailer_condition("keyA: read_access, keyB: read_access+write_access+write_keys"), Ok(3));
+        assert!(data_condition("keyA: read+write, keyB: none").is_err());
+        assert!(data_condition("keyA: fly").is_err());
+        assert!(data_condition("keyC: read").is_err());
+        for condition in 0..8 {
+            let bits = AccessBits { conditions: [condition; 4], user_byte: 0 };
+            assert_eq!(data_condition(&bits.describe(0)), Ok(condition));
+        }
+    }
+}
@@ -0,0 +1,87 @@
+// Command-line parsing shared by the server and er302-cli: `--name value` and `--name=value`
+// for the flags that take a value, bare switches, and what doesn't start with `--` as the
+// command's own arguments. Only splits the line, each binary checks the values itself.
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Arg {
+    // one of the switches
+    Switch(String),
+    // one of the flags with a value, and the value
+    Value(String, String),
+    // anything else that doesn't start with `--`
+    Positional(String),
+}
+
+// The flags of a command: those without a value and those with one
+pub struct Spec<'a> {
+    pub switches: &'a [&'a str],
+    pub values: &'a [&'a str],
+}
+
+impl Spec<'_> {
+    // The arguments in their order, or the first unknown flag or missing value
+    pub fn parse(&self, args: impl IntoIterator<Item = String>) -> Result<Vec<Arg>, String> {
+        let mut args = args.into_iter();
+        let mut parsed = Vec::new();
+        while let Some(arg) = args.next() {
+            let (name, inline) = match arg.split_once('=') {
+                Some((name, value)) if name.starts_with("--") => (name, Some(value.to_string())),
+                _ => (arg.as_str(), None),
+            };
+            if self.values.contains(&name) {
+                let value = inline.or_else(|| args.next()).ok_or(format!("missing value for {}", name))?;
+                parsed.push(Arg::Value(name.to_string(), value));
+            } else if self.switches.contains(&name) && inline.is_none() {
+                parsed.push(Arg::Switch(arg));
+            } else if arg.starts_with("--") {
+                return Err(format!("unknown flag: {}", arg));
+            } else {
+                parsed.push(Arg::Positional(arg));
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: Spec = Spec {
+        switches: &["--confirm", "-h"],
+        values: &["--port", "--set"],
+    };
+
+    fn parse(args: &[&str]) -> Result<Vec<Arg>, String> {
+        SPEC.parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn splits_flags_and_arguments() {
+        let parsed = parse(&["--port", "COM3", "increase", "--set=card.sector=3", "-5", "--confirm", "-h"]).unwrap();
+        assert_eq!(
+            parsed,
+            [
+                Arg::Value("--port".into(), "COM3".into()),
+                Arg::Positional("increase".into()),
+                Arg::Value("--set".into(), "card.sector=3".into()),
+                Arg::Positional("-5".into()),
+                Arg::Switch("--confirm".into()),
+                Arg::Switch("-h".into()),
+            ]
+        );
+        // the value is the next argument, whatever it looks like
+        assert_eq!(parse(&["--port", "--confirm"]).unwrap(), [Arg::Value("--port".into(), "--confirm".into())]);
+    }
+
+    #[test]
+    fn refuses_unknown_flags_and_missing_values() {
+        assert_eq!(parse(&["--bogus"]), Err("unknown flag: --bogus".to_string()));
+        assert_eq!(parse(&["--confirm=yes"]), Err("unknown flag: --confirm=yes".to_string()));
+        assert_eq!(parse(&["increase", "--port"]), Err("missing value for --port".to_string()));
+        assert_eq!(parse(&["--port="]).unwrap(), [Arg::Value("--port".into(), String::new())]);
+    }
+}
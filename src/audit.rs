@@ -0,0 +1,460 @@
+// Append-only records: the audit log of every command sent to a reader (timestamp, route,
+// reader, UID, amount, result, caller) as rows of the `audit` table of the SQLite database
+// `audit.file`, and the journal of the balance changes with their transaction IDs and the
+// balances before and after, one JSON line per entry of `journal.file`. GET /audit and
+// GET /journal search them. Triggers of the table refuse updates and deletes. Each entry
+// carries the `hash` of the chain, the SHA-256 of the one before's and of the entry without
+// it, so an entry changed, removed or put in between after it was written (with the
+// triggers dropped, or the file edited) breaks the chain from there on; GET /audit/verify
+// walks it.
+use crate::calls::Origin;
+use crate::sqlite::{Connection, Sql};
+use er302::{codec, hmac, Counters};
+use rocket::serde::json::{json, serde_json, Value};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Entries answered by one GET /audit at most
+pub const MAX_ENTRIES: usize = 1000;
+// Read at once from the end of a log by search
+const CHUNK: u64 = 64 * 1024;
+
+// The entry as written and its hash, the columns searched are taken from the entry
+const AUDIT_TABLE: &str = "CREATE TABLE IF NOT EXISTS audit (
+        id INTEGER PRIMARY KEY,
+        entry TEXT NOT NULL,
+        hash TEXT NOT NULL,
+        timestamp REAL GENERATED ALWAYS AS (json_extract(entry, '$.timestamp')),
+        request_id TEXT GENERATED ALWAYS AS (json_extract(entry, '$.request_id')),
+        transaction_id TEXT GENERATED ALWAYS AS (json_extract(entry, '$.transaction_id')),
+        method TEXT GENERATED ALWAYS AS (json_extract(entry, '$.method')),
+        endpoint TEXT GENERATED ALWAYS AS (json_extract(entry, '$.endpoint')),
+        route TEXT GENERATED ALWAYS AS (json_extract(entry, '$.route')),
+        reader TEXT GENERATED ALWAYS AS (json_extract(entry, '$.reader')),
+        command TEXT GENERATED ALWAYS AS (json_extract(entry, '$.command')),
+        uid TEXT GENERATED ALWAYS AS (json_extract(entry, '$.uid')),
+        amount INTEGER GENERATED ALWAYS AS (json_extract(entry, '$.amount')),
+        status INTEGER GENERATED ALWAYS AS (json_extract(entry, '$.status')),
+        code TEXT GENERATED ALWAYS AS (json_extract(entry, '$.code')),
+        reason TEXT GENERATED ALWAYS AS (json_extract(entry, '$.reason')),
+        caller TEXT GENERATED ALWAYS AS (json_extract(entry, '$.caller')),
+        client TEXT GENERATED ALWAYS AS (json_extract(entry, '$.client'))
+    );
+    CREATE INDEX IF NOT EXISTS audit_uid ON audit (uid, timestamp);
+    CREATE INDEX IF NOT EXISTS audit_timestamp ON audit (timestamp);
+    CREATE TRIGGER IF NOT EXISTS audit_no_update BEFORE UPDATE ON audit
+        BEGIN SELECT RAISE(ABORT, 'audit is append-only'); END;
+    CREATE TRIGGER IF NOT EXISTS audit_no_delete BEFORE DELETE ON audit
+        BEGIN SELECT RAISE(ABORT, 'audit is append-only'); END;";
+
+// Card operation of a request, recorded by the route and written with its response
+pub struct Operation {
+    pub reader: String,
+    pub command: &'static str,
+    pub uid: Option<String>,
+    pub amount: Option<u32>,
+    pub transaction: Option<Transaction>,
+}
+
+// An increase / decrease, None where the balance couldn't be read
+pub struct Transaction {
+    pub id: String,
+    pub before: Option<u32>,
+    pub after: Option<u32>,
+}
+
+// Request-local slot for the operation, empty for routes that don't talk to a reader
+#[derive(Default)]
+pub struct Pending(Mutex<Option<Operation>>);
+
+impl Pending {
+    pub fn set(&self, operation: Operation) {
+        *self.0.lock().unwrap() = Some(operation);
+    }
+
+    pub fn take(&self) -> Option<Operation> {
+        self.0.lock().unwrap().take()
+    }
+
+    // {uid, previous_balance, amount, new_balance, tx_id} of a completed increase / decrease
+    pub fn receipt(&self) -> Option<Value> {
+        let operation = self.0.lock().unwrap();
+        let operation = operation.as_ref()?;
+        let transaction = operation.transaction.as_ref()?;
+        Some(json!({
+            "uid": operation.uid,
+            "previous_balance": transaction.before?,
+            "amount": operation.amount,
+            "new_balance": transaction.after?,
+            "tx_id": transaction.id,
+        }))
+    }
+}
+
+// Highest card counters of the journal
+pub fn seed_counters(journal: &JsonLines, counters: &Counters) {
+    let entries = match journal.search(&Filter::default(), usize::MAX) {
+        Ok(entries) => entries,
+        Err(e) => {
+            tracing::error!(error = %e, "can't read the card counters from the journal");
+            return;
+        }
+    };
+    for entry in entries {
+        let uid = entry["uid"].as_str().and_then(|uid| codec::from_hex(uid).ok());
+        let counter = entry["counter"].as_u64().and_then(|counter| u32::try_from(counter).ok());
+        if let (Some(uid), Some(counter)) = (uid, counter) {
+            counters.record(&uid, counter);
+        }
+    }
+}
+
+pub struct JsonLines {
+    // off while None
+    path: Option<PathBuf>,
+    file: Mutex<Option<Appender>>,
+}
+
+// The open file and the hash of its last entry
+struct Appender {
+    file: File,
+    hash: String,
+}
+
+// Clones append to the same table
+#[derive(Clone)]
+pub struct AuditLog(pub Arc<AuditTable>);
+
+impl AuditLog {
+    pub fn record(&self, mut entry: Value) {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        entry["timestamp"] = json!(timestamp.as_millis() as f64 / 1000.0);
+        self.0.append(entry);
+    }
+
+    // A call refused with 403 before it got to a reader, with the reason
+    pub fn refused(&self, origin: &Origin, reason: &str) {
+        self.record(json!({
+            "request_id": origin.request_id,
+            "method": origin.method,
+            "endpoint": origin.endpoint,
+            "route": origin.route,
+            "status": false,
+            "code": "FORBIDDEN",
+            "reason": reason,
+            "caller": origin.caller,
+            "client": origin.client.map(|client| client.to_string()),
+        }));
+    }
+}
+
+#[derive(Clone)]
+pub struct Journal(pub Arc<JsonLines>);
+
+// Fields of GET /audit, entries must match all the given ones
+#[derive(Default)]
+pub struct Filter<'a> {
+    pub uid: Option<&'a str>,
+    pub transaction_id: Option<&'a str>,
+    pub route: Option<&'a str>,
+    pub caller: Option<&'a str>,
+    pub code: Option<&'a str>,
+    pub status: Option<bool>,
+    // unix seconds, inclusive
+    pub since: Option<f64>,
+    pub until: Option<f64>,
+}
+
+impl Filter<'_> {
+    pub fn matches(&self, entry: &Value) -> bool {
+        let text = |name: &str, wanted: Option<&str>| wanted.is_none_or(|wanted| entry[name].as_str() == Some(wanted));
+        let timestamp = entry["timestamp"].as_f64().unwrap_or_default();
+        text("uid", self.uid)
+            && text("transaction_id", self.transaction_id)
+            && text("route", self.route)
+            && text("caller", self.caller)
+            && text("code", self.code)
+            && self.status.is_none_or(|status| entry["status"].as_bool() == Some(status))
+            && self.since.is_none_or(|since| timestamp >= since)
+            && self.until.is_none_or(|until| timestamp <= until)
+    }
+}
+
+impl JsonLines {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        JsonLines {
+            path,
+            file: Mutex::new(None),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    // Append one entry, the timestamp is added here unless it has one
+    pub fn append(&self, mut entry: Value) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if entry["timestamp"].is_null() {
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            entry["timestamp"] = json!(timestamp.as_millis() as f64 / 1000.0);
+        }
+        let mut appender = self.file.lock().unwrap();
+        if appender.is_none() {
+            let opened = OpenOptions::new().create(true).append(true).read(true).open(path).and_then(|mut file| {
+                let hash = last_hash(&mut file)?;
+                Ok(Appender { file, hash })
+            });
+            match opened {
+                Ok(opened) => *appender = Some(opened),
+                Err(e) => {
+                    tracing::error!(file = %path.display(), error = %e, "can't open the log file");
+                    return;
+                }
+            }
+        }
+        let Appender { file, hash } = appender.as_mut().expect("audit log was just opened");
+        let next = chain(hash, &entry);
+        entry["hash"] = json!(next);
+        let line = format!("{}\n", entry);
+        // the card operation already happened, a lost entry is logged and not retried
+        match file.write_all(line.as_bytes()) {
+            Ok(()) => *hash = next,
+            Err(e) => {
+                tracing::error!(file = %path.display(), error = %e, entry = %entry, "can't write the log file");
+                *appender = None;
+            }
+        }
+    }
+
+    // Down to the disk, at shutdown
+    pub fn sync(&self) {
+        let appender = self.file.lock().unwrap();
+        if let (Some(path), Some(Appender { file, .. })) = (&self.path, appender.as_ref()) {
+            if let Err(e) = file.sync_all() {
+                tracing::error!(file = %path.display(), error = %e, "can't flush the log file");
+            }
+        }
+    }
+
+    // The file to read from the start, None while it's off or nothing was written yet
+    pub fn reader(&self) -> io::Result<Option<File>> {
+        let Some(path) = &self.path else {
+            return Ok(None);
+        };
+        match File::open(path) {
+            Ok(file) => Ok(Some(file)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // The last `limit` entries matching `filter`, oldest first, read from the end of the file
+    // until there are enough
+    pub fn search(&self, filter: &Filter, limit: usize) -> io::Result<Vec<Value>> {
+        let Some(mut file) = self.reader()? else {
+            return Ok(Vec::new());
+        };
+        let mut entries = Vec::new();
+        let mut end = file.seek(SeekFrom::End(0))?;
+        // the start of the line cut by the chunk before
+        let mut rest = Vec::new();
+        while end > 0 && entries.len() < limit {
+            let start = end.saturating_sub(CHUNK);
+            let mut chunk = vec![0; (end - start) as usize];
+            file.seek(SeekFrom::Start(start))?;
+            file.read_exact(&mut chunk)?;
+            chunk.extend_from_slice(&rest);
+            let mut lines = chunk.split(|&byte| byte == b'\n');
+            // unless at the start of the file, the first line goes on in the chunk before
+            rest = match start {
+                0 => Vec::new(),
+                _ => lines.next().unwrap_or_default().to_vec(),
+            };
+            for line in lines.rev() {
+                if entries.len() == limit {
+                    break;
+                }
+                if let Ok(entry) = serde_json::from_slice::<Value>(line) {
+                    if filter.matches(&entry) {
+                        entries.push(entry);
+                    }
+                }
+            }
+            end = start;
+        }
+        entries.reverse();
+        Ok(entries)
+    }
+}
+
+// The audit table of a SQLite database
+pub struct AuditTable {
+    // off while None
+    path: Option<PathBuf>,
+    database: Mutex<Option<Writer>>,
+}
+
+// The open database and the hash of its last entry
+struct Writer {
+    connection: Connection,
+    hash: String,
+}
+
+impl AuditTable {
+    pub fn new(path: Option<PathBuf>) -> Self {
+        AuditTable {
+            path,
+            database: Mutex::new(None),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    // Insert one entry, the table is created at the first one
+    pub fn append(&self, mut entry: Value) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let mut writer = self.database.lock().unwrap();
+        if writer.is_none() {
+            let opened = Connection::open(path).and_then(|connection| {
+                connection.execute(AUDIT_TABLE)?;
+                let last = connection.query("SELECT hash FROM audit ORDER BY id DESC LIMIT 1", &[])?;
+                let hash = last.first().and_then(|row| row[0].text()).unwrap_or_default().to_string();
+                Ok(Writer { connection, hash })
+            });
+            match opened {
+                Ok(opened) => *writer = Some(opened),
+                Err(e) => {
+                    tracing::error!(file = %path.display(), error = %e, "can't open the audit database");
+                    return;
+                }
+            }
+        }
+        let Writer { connection, hash } = writer.as_mut().expect("audit database was just opened");
+        if let Some(entry) = entry.as_object_mut() {
+            entry.remove("hash");
+        }
+        let text = entry.to_string();
+        let next = chain(hash, &text);
+        let inserted = connection.query("INSERT INTO audit (entry, hash) VALUES (?, ?)", &[Sql::Text(text), Sql::Text(next.clone())]);
+        // the card operation already happened, a lost entry is logged and not retried
+        match inserted {
+            Ok(_) => *hash = next,
+            Err(e) => {
+                tracing::error!(file = %path.display(), error = %e, entry = %entry, "can't write the audit entry");
+                *writer = None;
+            }
+        }
+    }
+
+    // The last `limit` entries matching `filter`, oldest first, with their hash
+    pub fn search(&self, filter: &Filter, limit: usize) -> io::Result<Vec<Value>> {
+        let Some(connection) = self.reader()? else {
+            return Ok(Vec::new());
+        };
+        let mut conditions = vec!["1"];
+        let mut parameters = Vec::new();
+        let texts = [
+            ("uid = ?", filter.uid),
+            ("transaction_id = ?", filter.transaction_id),
+            ("route = ?", filter.route),
+            ("caller = ?", filter.caller),
+            ("code = ?", filter.code),
+        ];
+        for (condition, wanted) in texts {
+            if let Some(wanted) = wanted {
+                conditions.push(condition);
+                parameters.push(Sql::from(Some(wanted)));
+            }
+        }
+        let numbers = [
+            ("status = ?", filter.status.map(|status| Sql::Integer(status as i64))),
+            ("timestamp >= ?", filter.since.map(Sql::Real)),
+            ("timestamp <= ?", filter.until.map(Sql::Real)),
+        ];
+        for (condition, wanted) in numbers {
+            if let Some(wanted) = wanted {
+                conditions.push(condition);
+                parameters.push(wanted);
+            }
+        }
+        parameters.push(Sql::Integer(limit.min(i64::MAX as usize) as i64));
+        let statement = format!(
+            "SELECT entry, hash FROM audit WHERE {} ORDER BY id DESC LIMIT ?",
+            conditions.join(" AND ")
+        );
+        let mut entries: Vec<Value> = connection
+            .query(&statement, &parameters)?
+            .iter()
+            .filter_map(|row| {
+                let mut entry = serde_json::from_str::<Value>(row[0].text()?).ok()?;
+                entry["hash"] = json!(row[1].text()?);
+                Some(entry)
+            })
+            .collect();
+        entries.reverse();
+        Ok(entries)
+    }
+
+    // Walks the hash chain: the number of entries when it's whole, otherwise the ID of the
+    // entry where it breaks
+    pub fn verify(&self) -> io::Result<Result<usize, i64>> {
+        let Some(connection) = self.reader()? else {
+            return Ok(Ok(0));
+        };
+        let (mut hash, mut count, mut broken) = (String::new(), 0, None);
+        connection.each("SELECT id, entry, hash FROM audit ORDER BY id", &[], |row| {
+            let (Some(id), Some(entry), Some(written)) = (row[0].integer(), row[1].text(), row[2].text()) else {
+                broken = row[0].integer();
+                return false;
+            };
+            if chain(&hash, &entry) != written {
+                broken = Some(id);
+                return false;
+            }
+            (hash, count) = (written.to_string(), count + 1);
+            true
+        })?;
+        Ok(broken.map_or(Ok(count), Err))
+    }
+
+    // A connection to read with, None while it's off or nothing was written yet
+    fn reader(&self) -> io::Result<Option<Connection>> {
+        let Some(path) = &self.path else {
+            return Ok(None);
+        };
+        match Connection::open_read_only(path) {
+            Ok(connection) => Ok(Some(connection)),
+            Err(e) => match io::Error::from(e) {
+                e if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                e => Err(e),
+            },
+        }
+    }
+}
+
+// The hash of `entry` after the one of `previous`
+fn chain(previous: &str, entry: &impl Display) -> String {
+    codec::to_hex(&hmac::sha256(format!("{}{}", previous, entry).as_bytes()))
+}
+
+// The hash of the last entry of the file, empty when it has none
+fn last_hash(file: &mut File) -> io::Result<String> {
+    let end = file.seek(SeekFrom::End(0))?;
+    let start = end.saturating_sub(CHUNK);
+    let mut tail = vec![0; (end - start) as usize];
+    file.seek(SeekFrom::Start(start))?;
+    file.read_exact(&mut tail)?;
+    let last = tail.split(|&byte| byte == b'\n').rev().find_map(|line| serde_json::from_slice::<Value>(line).ok());
+    Ok(last.and_then(|entry| entry["hash"].as_str().map(str::to_string)).unwrap_or_default())
+}
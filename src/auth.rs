@@ -0,0 +1,343 @@
+// API keys and JWTs: `Authorization: Bearer <key or token>` on every versioned route once a key
+// or a JWT secret / public key is configured. API keys are named (one per kiosk) in
+// `[auth.keys]` or in the `auth.file` key file, which is re-read when it changes so a key
+// can be revoked without a restart. API keys may do everything, JWTs what their roles allow.
+// `auth.mutations_from` limits the routes that change something to client networks.
+use crate::audit::AuditLog;
+use crate::calls::Origin;
+use crate::jwt::{constant_time_eq, JwtKeys};
+use crate::logging;
+use rocket::serde::json::Value;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+#[derive(Clone, Default)]
+pub struct AuthConfig {
+    // name -> key
+    pub keys: BTreeMap<String, String>,
+    // one `name key` per line, `#` starts a comment
+    pub file: Option<PathBuf>,
+    // [auth.jwt] secret (HS256) / public_key_file (RS256), issuer, audience
+    pub jwt_secret: Option<String>,
+    pub jwt_public_key: Option<PathBuf>,
+    pub jwt_issuer: Option<String>,
+    pub jwt_audience: Option<String>,
+    // [auth.jwt] require_exp, tokens without exp are refused
+    pub jwt_require_exp: bool,
+    // client networks allowed to call the routes above the read role, any when empty
+    pub mutations_from: Vec<Cidr>,
+}
+
+impl AuthConfig {
+    // Without any key configured the API stays open, as before
+    pub fn enabled(&self) -> bool {
+        !self.keys.is_empty() || self.file.is_some() || self.jwt_secret.is_some() || self.jwt_public_key.is_some()
+    }
+}
+
+// `10.20.0.0/16`, `fd00::/8` or a single address
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid network: {}", text);
+        let (address, prefix) = match text.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (text, None),
+        };
+        let network: IpAddr = address.trim().parse().map_err(|_| invalid())?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse().ok().filter(|&prefix| prefix <= bits).ok_or_else(invalid)?,
+            None => bits,
+        };
+        Ok(Cidr { network, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        // IPv4 clients of a dual stack listener show up as ::ffff:a.b.c.d
+        let address = match address {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(address),
+            v4 => v4,
+        };
+        let (network, address, bits) = match (self.network, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => (u32::from(network) as u128, u32::from(address) as u128, 32),
+            (IpAddr::V6(network), IpAddr::V6(address)) => (u128::from(network), u128::from(address), 128),
+            _ => return false,
+        };
+        let shift = bits - u32::from(self.prefix);
+        shift >= bits || network >> shift == address >> shift
+    }
+}
+
+// What a caller may do, each role includes the ones before it
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Role {
+    // card and reader information
+    Read,
+    // payments and beeps
+    Cashier,
+    // card setup, raw writes, keys and reader settings
+    Admin,
+}
+
+impl Role {
+    fn parse(name: &str) -> Option<Role> {
+        match name {
+            "read" => Some(Role::Read),
+            "cashier" => Some(Role::Cashier),
+            "admin" => Some(Role::Admin),
+            _ => None,
+        }
+    }
+
+    // Highest role of the `roles` (array) or `role` (string) claim
+    fn of_claims(claims: &Value) -> Option<Role> {
+        let roles = match (&claims["roles"], &claims["role"]) {
+            (Value::Array(roles), _) => roles.iter().filter_map(Value::as_str).collect(),
+            (_, Value::String(role)) => vec![role.as_str()],
+            _ => Vec::new(),
+        };
+        roles.into_iter().filter_map(Role::parse).max()
+    }
+}
+
+// Role a route needs, by route (function) name. Anything not listed needs admin.
+fn required_role(route: &str) -> Role {
+    match route {
+        "ports" | "list_readers" | "id" | "cardtype" | "reader_info" | "reader_status" | "read_balance"
+        | "read_block" | "read_sector" | "read_ndef" | "read_page" | "read_cardholder" | "card_events" | "present"
+        | "last_card" | "wait" | "cards_in_field" | "wallet_balance" | "transactions" => Role::Read,
+        "increase" | "decrease" | "post_increase" | "post_decrease" | "wallet_increase" | "wallet_decrease"
+        | "post_wallet_increase" | "post_wallet_decrease" | "halt" | "beep" | "websocket" => Role::Cashier,
+        _ => Role::Admin,
+    }
+}
+
+// Keys of `auth.file` and the modification time they were read at
+#[derive(Default)]
+struct FileKeys {
+    modified: Option<SystemTime>,
+    keys: Vec<(String, String)>,
+}
+
+// Clones share the keys read from `auth.file`
+#[derive(Clone)]
+pub struct ApiKeys {
+    config: AuthConfig,
+    file: Arc<Mutex<FileKeys>>,
+    jwt: Arc<JwtKeys>,
+}
+
+impl ApiKeys {
+    pub fn new(config: AuthConfig) -> Self {
+        let public_key = config.jwt_public_key.as_ref().and_then(|path| {
+            let key = fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|pem| crate::jwt::RsaPublicKey::from_pem(&pem).map_err(str::to_string));
+            match key {
+                Ok(key) => Some(key),
+                // tokens can't be checked, so none is accepted
+                Err(e) => {
+                    tracing::error!(file = %path.display(), error = %e, "can't load the JWT public key");
+                    None
+                }
+            }
+        });
+        let jwt = JwtKeys {
+            secret: config.jwt_secret.as_ref().map(|secret| secret.as_bytes().to_vec()),
+            public_key,
+            issuer: config.jwt_issuer.clone(),
+            audience: config.jwt_audience.clone(),
+            require_exp: config.jwt_require_exp,
+        };
+        ApiKeys {
+            config,
+            file: Arc::default(),
+            jwt: Arc::new(jwt),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled()
+    }
+
+    // (caller name, role) of a bearer credential
+    fn authenticate(&self, credential: &str) -> Result<(String, Role), &'static str> {
+        if let Some(name) = self.lookup(credential) {
+            return Ok((name, Role::Admin));
+        }
+        if !self.jwt.enabled() || credential.matches('.').count() != 2 {
+            return Err("unknown API key");
+        }
+        let claims = self.jwt.validate(credential)?;
+        let role = Role::of_claims(&claims).ok_or("token without a known role")?;
+        let subject = claims["sub"].as_str().unwrap_or("jwt").to_string();
+        Ok((subject, role))
+    }
+
+    // Mutations only from the `auth.mutations_from` networks, an unknown address is refused
+    fn client_allowed(&self, client: Option<IpAddr>, route: &str) -> bool {
+        let networks = &self.config.mutations_from;
+        networks.is_empty()
+            || required_role(route) == Role::Read
+            || client.is_some_and(|client| networks.iter().any(|network| network.contains(client)))
+    }
+
+    // The caller of a call that doesn't go through Rocket's routes (gRPC) with its
+    // `authorization` header, allowed to call what `route` is; None while auth is off
+    pub fn check(&self, client: Option<IpAddr>, authorization: Option<&str>, route: &str) -> Result<Option<String>, (Status, &'static str)> {
+        if !self.client_allowed(client, route) {
+            let client = client.map(|client| client.to_string()).unwrap_or_default();
+            tracing::warn!(target: "er302::audit", client, route, "client address not allowed");
+            return Err((Status::Forbidden, "the client's address may not call this route"));
+        }
+        if !self.enabled() {
+            return Ok(None);
+        }
+        let credential = authorization
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or((Status::Unauthorized, "missing API key or token"))?;
+        let (name, role) = self.authenticate(credential.trim()).map_err(|reason| {
+            tracing::warn!(reason, "credential refused");
+            (Status::Unauthorized, reason)
+        })?;
+        if role < required_role(route) {
+            tracing::warn!(caller = name, route, role = ?role, "role not allowed");
+            return Err((Status::Forbidden, "the caller's role doesn't allow this route"));
+        }
+        Ok(Some(name))
+    }
+
+    // Name of the key, None when it isn't (or no longer) configured
+    pub fn lookup(&self, key: &str) -> Option<String> {
+        let mut found = None;
+        // compare with every key so the time doesn't tell how close a guess was
+        for (name, candidate) in self.config.keys.iter() {
+            if constant_time_eq(candidate.as_bytes(), key.as_bytes()) {
+                found = Some(name.clone());
+            }
+        }
+        for (name, candidate) in self.file_keys() {
+            if constant_time_eq(candidate.as_bytes(), key.as_bytes()) {
+                found = Some(name);
+            }
+        }
+        found
+    }
+
+    fn file_keys(&self) -> Vec<(String, String)> {
+        let Some(path) = &self.config.file else {
+            return Vec::new();
+        };
+        let mut file = self.file.lock().unwrap();
+        let modified = fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+        if modified.is_none() || modified != file.modified {
+            // a missing or unreadable file revokes its keys
+            let keys = match fs::read_to_string(path) {
+                Ok(text) => parse_key_file(&text),
+                Err(e) => {
+                    tracing::error!(file = %path.display(), error = %e, "can't read the API key file");
+                    Vec::new()
+                }
+            };
+            *file = FileKeys { modified, keys };
+        }
+        file.keys.clone()
+    }
+}
+
+fn parse_key_file(text: &str) -> Vec<(String, String)> {
+    text.lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter_map(|line| {
+            let (name, key) = line.split_once(char::is_whitespace)?;
+            Some((name.to_string(), key.trim().to_string()))
+        })
+        .collect()
+}
+
+// An authenticated caller (anyone while no key is configured), the key name is logged
+// with the request
+pub struct Caller;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Caller {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let keys = request.rocket().state::<ApiKeys>().expect("API keys are managed");
+        let route = request.route().and_then(|route| route.name.as_deref()).unwrap_or_default();
+        // the peer address, X-Real-IP could be set by anyone
+        let client = request.remote().map(|remote| remote.ip());
+        if !keys.client_allowed(client, route) {
+            let client = client.map(|client| client.to_string()).unwrap_or_default();
+            tracing::warn!(target: "er302::audit", client, route, "client address not allowed");
+            refuse(request, None, "the client's address may not call this route");
+            return Outcome::Error((Status::Forbidden, "client address not allowed"));
+        }
+        if !keys.enabled() {
+            return Outcome::Success(Caller);
+        }
+        let bearer = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let Some(credential) = bearer else {
+            return Outcome::Error((Status::Unauthorized, "missing API key or token"));
+        };
+        let (name, role) = match keys.authenticate(credential.trim()) {
+            Ok(caller) => caller,
+            Err(reason) => {
+                tracing::warn!(reason, "credential refused");
+                return Outcome::Error((Status::Unauthorized, reason));
+            }
+        };
+        logging::request_span(request).span.record("caller", name.as_str());
+        request.local_cache(|| Identity(Some(name.clone())));
+        request.local_cache(|| Granted(Some(role)));
+        if role < required_role(route) {
+            tracing::warn!(caller = name, route, role = ?role, "role not allowed");
+            refuse(request, Some(name), "the caller's role doesn't allow this route");
+            return Outcome::Error((Status::Forbidden, "role not allowed"));
+        }
+        Outcome::Success(Caller)
+    }
+}
+
+// Why a 403 was given, for the catcher's message
+pub struct Refusal(pub &'static str);
+
+// The 403's reason for the catcher, and the refusal in the audit log
+pub fn refuse(request: &Request<'_>, caller: Option<String>, reason: &'static str) {
+    request.local_cache(|| Refusal(reason));
+    let audit = request.rocket().state::<AuditLog>().expect("audit log is managed");
+    audit.refused(&Origin::of(request, caller), reason);
+}
+
+// Name of the authenticated caller, None while auth is off
+pub struct Identity(pub Option<String>);
+
+// Role of the authenticated caller, None while auth is off
+struct Granted(Option<Role>);
+
+// Whether the caller may do what the admin role allows, anyone while auth is off. Only once
+// the Caller guard ran.
+pub fn is_admin(request: &Request<'_>) -> bool {
+    let keys = request.rocket().state::<ApiKeys>().expect("API keys are managed");
+    !keys.enabled() || request.local_cache(|| Granted(None)).0 == Some(Role::Admin)
+}
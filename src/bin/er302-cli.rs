@@ -0,0 +1,356 @@
+// Command line companion of the API server: card operations straight on the serial port, for
+// scripts and troubleshooting without the server, and a load generator for a running one
+use er302::access::AccessBits;
+use er302::args::{Arg, Spec};
+use er302::codec::{self, BlockAddress, DEFAULT_VALUE_BLOCK};
+use er302::tcp::{self, TcpPort};
+use er302::{Reader, ReaderError, DEFAULTKEY};
+use serialport::SerialPort;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::exit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "usage: er302-cli <command> [--port COM3 | tcp://host:port] [--baud 115200] [--sector 13] [--block 1] [--key <hex>]
+
+  id                                 UID of the card in the field
+  balance                            balance of the value block (--sector, --block)
+  increase <value>, decrease <value> change the balance, answers the new one
+  init                               give the sector of --sector the application key
+  dump [--sectors 16]                every block as hex, with --key or the application / factory key
+  write-block <sector> <block> <hex> write 16 bytes, --confirm for trailers and block 0
+  bench [--url http://127.0.0.1:8888] [--clients 20] [--ops 1000] [--path /id]
+
+The port defaults to ER302_SERIAL__PORTNAME, the baud rate to ER302_SERIAL__BAUDRATE.";
+
+// Flags of the card commands
+struct CardOptions {
+    port: String,
+    baud: u32,
+    sector: u8,
+    block: u8,
+    key: Option<Vec<u8>>,
+    sectors: u8,
+    confirm: bool,
+    // the command's own arguments
+    args: Vec<String>,
+}
+
+impl CardOptions {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = CardOptions {
+            port: std::env::var("ER302_SERIAL__PORTNAME").unwrap_or_else(|_| "COM3".to_string()),
+            baud: std::env::var("ER302_SERIAL__BAUDRATE").ok().and_then(|baud| baud.parse().ok()).unwrap_or(115200),
+            sector: DEFAULT_VALUE_BLOCK.sector,
+            block: DEFAULT_VALUE_BLOCK.block,
+            key: None,
+            sectors: 16,
+            confirm: false,
+            args: Vec::new(),
+        };
+        let spec = Spec {
+            switches: &["--confirm"],
+            values: &["--port", "--baud", "--sector", "--block", "--sectors", "--key"],
+        };
+        for arg in spec.parse(args.iter().cloned())? {
+            let (flag, value) = match arg {
+                Arg::Switch(_) => {
+                    options.confirm = true;
+                    continue;
+                }
+                Arg::Positional(arg) => {
+                    options.args.push(arg);
+                    continue;
+                }
+                Arg::Value(flag, value) => (flag, value),
+            };
+            match flag.as_str() {
+                "--port" => options.port = value,
+                "--baud" => options.baud = number(&value, "baud rate")?,
+                "--sector" => options.sector = number(&value, "sector")?,
+                "--block" => options.block = number(&value, "block")?,
+                "--sectors" => options.sectors = number(&value, "sector count")?,
+                _ => match codec::from_hex(&value) {
+                    Ok(key) if key.len() == 6 => options.key = Some(key),
+                    _ => return Err(format!("invalid key: {}, 6 bytes as hex", value)),
+                },
+            }
+        }
+        Ok(options)
+    }
+
+    // The n-th argument of the command
+    fn arg(&self, index: usize, name: &str) -> Result<&str, String> {
+        self.args.get(index).map(String::as_str).ok_or(format!("missing {}", name))
+    }
+
+    fn value(&self) -> Result<u32, String> {
+        number(self.arg(0, "value")?, "value")
+    }
+}
+
+fn number<T: std::str::FromStr>(value: &str, name: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid {}: {}", name, value))
+}
+
+fn open(options: &CardOptions) -> Result<Reader, String> {
+    let failed = |e: &dyn std::fmt::Display| format!("can't open {}: {}", options.port, e);
+    let port: Box<dyn SerialPort> = match tcp::parse_url(&options.port) {
+        Some((address, telnet)) => {
+            Box::new(TcpPort::connect(address, options.baud, telnet, tcp::CONNECT_TIMEOUT, Duration::from_secs(2)).map_err(|e| failed(&e))?)
+        }
+        None => serialport::new(&options.port, options.baud)
+            .timeout(Duration::from_secs(2))
+            .open()
+            .map_err(|e| failed(&e))?,
+    };
+    Ok(Reader::new(port))
+}
+
+// Output of a card command, or `CODE: message` of the reader error
+fn card_command(command: &str, options: &CardOptions) -> Result<String, String> {
+    let failed = |e: ReaderError| format!("{}: {}", e.code(), e);
+    let mut reader = open(options)?;
+    let value_block = || BlockAddress::data(options.sector, options.block).map_err(failed);
+    match command {
+        "id" => reader.read_id().map_err(failed),
+        "balance" => reader.read_balance(value_block()?).map_err(failed),
+        "increase" => reader.increase(value_block()?, options.value()?).map_err(failed),
+        "decrease" => reader.decrease(value_block()?, options.value()?).map_err(failed),
+        "init" => reader.init_card(BlockAddress::data(options.sector, 1).map_err(failed)?, None).map_err(failed),
+        "dump" => Ok(dump(&mut reader, options)),
+        "write-block" => {
+            let sector = number(options.arg(0, "sector")?, "sector")?;
+            let block = BlockAddress::new(sector, number(options.arg(1, "block")?, "block")?).map_err(failed)?;
+            let data = codec::from_hex(options.arg(2, "data")?).map_err(failed)?;
+            if data.len() != 16 {
+                return Err("a block is 16 bytes".to_string());
+            }
+            // the same interlock as POST /block
+            if block.is_trailer() {
+                AccessBits::decode(&data[6..10]).map_err(failed)?;
+            }
+            if (block.is_trailer() || block.absolute() == 0) && !options.confirm {
+                return Err("a trailer or block 0 write can lock the card for good, add --confirm".to_string());
+            }
+            reader.write_block(block, options.key.as_deref(), &data).map_err(failed)?;
+            Ok("Block written".to_string())
+        }
+        _ => Err(format!("unknown command: {}", command)),
+    }
+}
+
+// Every block of the first `--sectors` sectors, a sector no key opens shows the error
+fn dump(reader: &mut Reader, options: &CardOptions) -> String {
+    let mut lines = Vec::new();
+    for sector in 0..options.sectors {
+        let keys: Vec<Option<&[u8]>> = match &options.key {
+            Some(key) => vec![Some(key)],
+            None => vec![None, Some(DEFAULTKEY)],
+        };
+        let blocks = (0..BlockAddress::blocks_in_sector(sector))
+            .map(|block| BlockAddress::new(sector, block))
+            .collect::<Result<Vec<_>, _>>();
+        let read = blocks.and_then(|blocks| {
+            let mut result = Err(ReaderError::AuthFailed);
+            for key in &keys {
+                result = blocks.iter().map(|&block| reader.read_block(block, *key)).collect::<Result<Vec<_>, _>>();
+                if !matches!(result, Err(ReaderError::AuthFailed)) {
+                    break;
+                }
+            }
+            result
+        });
+        match read {
+            Ok(blocks) => {
+                for (block, data) in blocks.iter().enumerate() {
+                    lines.push(format!("{:2}/{:<2} {}", sector, block, codec::to_hex(data)));
+                }
+            }
+            Err(e) => lines.push(format!("{:2}/.. {}", sector, e.code())),
+        }
+    }
+    lines.join("\n")
+}
+
+struct BenchOptions {
+    host: String,
+    port: u16,
+    path: String,
+    clients: usize,
+    ops: usize,
+}
+
+impl BenchOptions {
+    fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = BenchOptions {
+            host: "127.0.0.1".to_string(),
+            port: 8888,
+            path: "/id".to_string(),
+            clients: 20,
+            ops: 1000,
+        };
+        let spec = Spec {
+            switches: &[],
+            values: &["--url", "--clients", "--ops", "--path"],
+        };
+        for arg in spec.parse(args.iter().cloned())? {
+            let (flag, value) = match arg {
+                Arg::Value(flag, value) => (flag, value),
+                Arg::Switch(arg) | Arg::Positional(arg) => return Err(format!("unexpected argument: {}", arg)),
+            };
+            match flag.as_str() {
+                "--url" => {
+                    let address = value
+                        .strip_prefix("http://")
+                        .ok_or("only http:// urls are supported")?;
+                    let (host, port) = address.trim_end_matches('/').rsplit_once(':').unwrap_or((address, "80"));
+                    options.host = host.to_string();
+                    options.port = port.parse().map_err(|_| format!("invalid port: {}", port))?;
+                }
+                "--clients" => options.clients = value.parse().map_err(|_| format!("invalid clients: {}", value))?,
+                "--ops" => options.ops = value.parse().map_err(|_| format!("invalid ops: {}", value))?,
+                _ => options.path = value,
+            }
+        }
+        if options.clients == 0 {
+            return Err("--clients must be at least 1".to_string());
+        }
+        Ok(options)
+    }
+}
+
+struct Sample {
+    ok: bool,
+    // Server side queue wait from `X-Queue-Wait-Ms`
+    queue_wait: Option<Duration>,
+}
+
+// One GET over a fresh connection, ok when the API reported `"status":true`
+fn request(options: &BenchOptions) -> std::io::Result<Sample> {
+    let mut stream = TcpStream::connect((options.host.as_str(), options.port))?;
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        options.path, options.host
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let queue_wait = response
+        .lines()
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("x-queue-wait-ms"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .map(Duration::from_millis);
+    Ok(Sample {
+        ok: response.starts_with("HTTP/1.1 200") && response.contains("\"status\":true"),
+        queue_wait,
+    })
+}
+
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    sorted[(sorted.len() * percent / 100).min(sorted.len() - 1)]
+}
+
+// Hammer one endpoint from several clients and report latency / queue wait percentiles
+fn bench(options: BenchOptions) {
+    let options = Arc::new(options);
+    let next = Arc::new(AtomicUsize::new(0));
+    let started = Instant::now();
+
+    let workers: Vec<_> = (0..options.clients)
+        .map(|_| {
+            let options = options.clone();
+            let next = next.clone();
+            thread::spawn(move || {
+                let mut latencies = Vec::new();
+                let mut waits = Vec::new();
+                let mut failures = 0;
+                while next.fetch_add(1, Ordering::Relaxed) < options.ops {
+                    let sent = Instant::now();
+                    match request(&options) {
+                        Ok(sample) => {
+                            waits.extend(sample.queue_wait);
+                            match sample.ok {
+                                true => latencies.push(sent.elapsed()),
+                                false => failures += 1,
+                            }
+                        }
+                        Err(_) => failures += 1,
+                    }
+                }
+                (latencies, waits, failures)
+            })
+        })
+        .collect();
+
+    let mut latencies = Vec::new();
+    let mut waits = Vec::new();
+    let mut failures = 0;
+    for worker in workers {
+        let (worker_latencies, worker_waits, worker_failures) = worker.join().expect("bench client panicked");
+        latencies.extend(worker_latencies);
+        waits.extend(worker_waits);
+        failures += worker_failures;
+    }
+    let elapsed = started.elapsed();
+    latencies.sort();
+    waits.sort();
+
+    println!("target      : http://{}:{}{}", options.host, options.port, options.path);
+    println!("clients     : {}", options.clients);
+    println!("operations  : {} ok, {} failed in {:.2?}", latencies.len(), failures, elapsed);
+    println!("throughput  : {:.1} ops/s", latencies.len() as f64 / elapsed.as_secs_f64());
+    println!(
+        "latency     : p50 {:.2?} | p90 {:.2?} | p99 {:.2?} | max {:.2?}",
+        percentile(&latencies, 50),
+        percentile(&latencies, 90),
+        percentile(&latencies, 99),
+        latencies.last().copied().unwrap_or_default()
+    );
+    println!(
+        "queue wait  : p50 {:.2?} | p90 {:.2?} | p99 {:.2?} | max {:.2?}",
+        percentile(&waits, 50),
+        percentile(&waits, 90),
+        percentile(&waits, 99),
+        waits.last().copied().unwrap_or_default()
+    );
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("bench") => match BenchOptions::parse(&args[1..]) {
+            Ok(options) => bench(options),
+            Err(e) => {
+                eprintln!("error : {}\n{}", e, USAGE);
+                exit(2);
+            }
+        },
+        Some(command @ ("id" | "balance" | "increase" | "decrease" | "init" | "dump" | "write-block")) => {
+            let options = CardOptions::parse(&args[1..]).unwrap_or_else(|e| {
+                eprintln!("error : {}\n{}", e, USAGE);
+                exit(2);
+            });
+            match card_command(command, &options) {
+                Ok(output) => println!("{}", output),
+                Err(e) => {
+                    eprintln!("error : {}", e);
+                    exit(1);
+                }
+            }
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(2);
+        }
+    }
+}
@@ -0,0 +1,200 @@
+// Card commands of the API beside the REST routes, gRPC and the WebSocket's messages: what
+// the guards and the responder of a route do around its one command (the journal, the
+// transaction event and the audit entry) done per command, as they answer outside of a route.
+use crate::audit::{AuditLog, Journal, Operation, Transaction};
+use crate::auth::Identity;
+use crate::events::Events;
+use crate::idempotency::{Idempotency, Refused};
+use crate::readers::{Readers, DEFAULT_READER};
+use crate::worker::{Options, ReaderCommand, Worker};
+use crate::{failure, journal_entry, logging, reply, ApiResponse, Reply, Units, ValueBlock};
+use er302::codec::{BlockAddress, UidFormat};
+use er302::currency::Currency;
+use er302::ReaderError;
+use rocket::http::Header;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::json::{json, Json};
+use rocket::{Orbit, Rocket};
+use std::net::IpAddr;
+use std::time::Duration;
+
+// Who asked for a command, for its audit entry
+#[derive(Clone)]
+pub struct Origin {
+    pub request_id: String,
+    // POST for gRPC, GET for the WebSocket
+    pub method: &'static str,
+    // e.g. /er302.v1.CardService/Adjust
+    pub endpoint: String,
+    // e.g. grpc.Adjust
+    pub route: String,
+    pub client: Option<IpAddr>,
+    pub caller: Option<String>,
+}
+
+impl Origin {
+    // The request's, with the caller the Caller guard found
+    pub fn of(request: &Request<'_>, caller: Option<String>) -> Self {
+        Origin {
+            request_id: logging::request_span(request).id.clone(),
+            method: request.method().as_str(),
+            endpoint: request.uri().path().to_string(),
+            route: request.route().and_then(|route| route.name.as_deref()).unwrap_or_default().to_string(),
+            client: request.remote().map(|remote| remote.ip()),
+            caller,
+        }
+    }
+}
+
+// After the Caller guard
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Origin {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let Identity(caller) = request.local_cache(|| Identity(None));
+        Outcome::Success(Origin::of(request, caller.clone()))
+    }
+}
+
+// What the commands need of the server, cloned from its managed state
+pub struct Backend {
+    workers: Vec<(String, Worker)>,
+    pub events: Events,
+    value_block: BlockAddress,
+    pub format: UidFormat,
+    pub currency: Option<Currency>,
+    pub audit: AuditLog,
+    pub journal: Journal,
+    idempotency: Idempotency,
+}
+
+impl Backend {
+    pub fn new(rocket: &Rocket<Orbit>) -> Self {
+        let readers = rocket.state::<Readers>().expect("readers are managed");
+        Backend {
+            workers: readers.iter().map(|(name, slot)| (name.to_string(), slot.worker.clone())).collect(),
+            events: rocket.state::<Events>().expect("events are managed").clone(),
+            value_block: rocket.state::<ValueBlock>().expect("value block is managed").0,
+            format: *rocket.state::<UidFormat>().expect("UID format is managed"),
+            currency: rocket.state::<Units>().expect("units are managed").0.clone(),
+            audit: rocket.state::<AuditLog>().expect("audit log is managed").clone(),
+            journal: rocket.state::<Journal>().expect("journal is managed").clone(),
+            idempotency: rocket.state::<Idempotency>().expect("idempotency keys are managed").clone(),
+        }
+    }
+
+    // The reader named, the [serial] one when empty
+    pub fn worker<'a>(&'a self, reader: &'a str) -> Result<(&'a str, &'a Worker), ReaderError> {
+        let reader = if reader.is_empty() { DEFAULT_READER } else { reader };
+        self.workers
+            .iter()
+            .find(|(name, _)| name == reader)
+            .map(|(name, worker)| (name.as_str(), worker))
+            .ok_or_else(|| ReaderError::InvalidInput(format!("unknown reader: {}", reader)))
+    }
+
+    pub fn value_block(&self, sector: Option<u8>, block: Option<u8>) -> Result<BlockAddress, ReaderError> {
+        ValueBlock(self.value_block).resolve(sector, block)
+    }
+
+    // The command's answer in the body of the REST routes, with its journal and audit entries
+    pub async fn send(&self, origin: &Origin, reader: &str, command: ReaderCommand, options: Options) -> ApiResponse {
+        let (reader, worker) = match self.worker(reader) {
+            Ok(found) => found,
+            Err(e) => return reply(Err(e), Duration::ZERO).body.into_inner(),
+        };
+        let (name, amount) = (command.name(), command.amount());
+        let changes_balance = matches!(command, ReaderCommand::Increase(..) | ReaderCommand::Decrease(..));
+        let response = worker.send_with(command, options).await;
+        let transaction = changes_balance.then(|| Transaction {
+            id: logging::new_uuid(),
+            before: response.before,
+            after: response.result.as_ref().ok().and_then(|data| data.as_str()?.parse().ok()),
+        });
+        let mut body = reply(response.result, response.queue_wait).body.into_inner();
+        body.request_id = Some(origin.request_id.clone());
+        body.transaction_id = transaction.as_ref().map(|transaction| transaction.id.clone());
+        body.counter = response.counter;
+        body.key_profile = response.key_profile;
+        body.uid = response.uid.as_deref().map(|uid| self.format.render(uid));
+        body.command = Some(name);
+        let operation = Operation {
+            reader: reader.to_string(),
+            command: name,
+            uid: body.uid.clone(),
+            amount,
+            transaction,
+        };
+        if let Some(transaction) = &operation.transaction {
+            journal_entry(&self.journal, &self.events, &operation, transaction, &body, &origin.caller);
+            // the receipt of POST /increase, also what a retry with the same key gets
+            if let (true, Some(before), Some(after)) = (body.status, transaction.before, transaction.after) {
+                body.data = json!({
+                    "uid": body.uid,
+                    "previous_balance": before,
+                    "amount": amount,
+                    "new_balance": after,
+                    "tx_id": transaction.id,
+                });
+            }
+        }
+        self.audit.record(json!({
+            "request_id": body.request_id,
+            "transaction_id": body.transaction_id,
+            "method": origin.method,
+            "endpoint": origin.endpoint,
+            "route": origin.route,
+            "reader": operation.reader,
+            "command": operation.command,
+            "uid": operation.uid,
+            "amount": operation.amount,
+            "status": body.status,
+            "code": body.code,
+            "caller": origin.caller,
+            "client": origin.client.map(|client| client.to_string()),
+        }));
+        body
+    }
+
+    // Increase (amount > 0) or decrease the balance, once per idempotency key with the
+    // fingerprint of the REST routes, so a key can't be reused across the APIs
+    pub async fn adjust(&self, origin: &Origin, reader: &str, sector: Option<u8>, block: Option<u8>, amount: i64, key: Option<&str>) -> ApiResponse {
+        let Some(value) = u32::try_from(amount.unsigned_abs()).ok().filter(|&value| value > 0) else {
+            let error = ReaderError::InvalidInput(format!("invalid amount {}", amount));
+            return reply(Err(error), Duration::ZERO).body.into_inner();
+        };
+        let (change, command): (_, fn(BlockAddress, u32) -> ReaderCommand) = match amount > 0 {
+            true => ("increase", ReaderCommand::Increase),
+            false => ("decrease", ReaderCommand::Decrease),
+        };
+        let address = match self.value_block(sector, block) {
+            Ok(address) => address,
+            Err(e) => return reply(Err(e), Duration::ZERO).body.into_inner(),
+        };
+        let reader = if reader.is_empty() { DEFAULT_READER } else { reader };
+        let fingerprint = format!("{} {} {:?} {:?} {}", change, value, sector, block, reader);
+        let run = async {
+            Reply {
+                body: Json(self.send(origin, reader, command(address, value), Options::default()).await),
+                queue_wait: Header::new("X-Queue-Wait-Ms", "0"),
+            }
+        };
+        let reply = match self.idempotency.run(key, fingerprint, run).await {
+            Ok(reply) => reply,
+            Err(Refused::InvalidKey) => failure("INVALID_INPUT", "the idempotency key must be 1 to 255 characters"),
+            Err(Refused::InProgress) => failure("IN_PROGRESS", "a call with this idempotency key is still running"),
+            Err(Refused::Mismatch) => failure("IDEMPOTENCY_MISMATCH", "this idempotency key was used for another call"),
+        };
+        reply.body.into_inner()
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Backend {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(Backend::new(request.rocket()))
+    }
+}
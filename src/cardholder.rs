@@ -0,0 +1,167 @@
+// Cardholder record in the data blocks 0-2 of its own sector, for offline devices that show
+// who a card belongs to
+//
+// 0      : version (1), 0 when there's no record
+// 1      : length of the name
+// 2..26  : owner name, UTF-8 padded with zeros
+// 26..42 : card number, ASCII padded with zeros
+// 42..46 : expiry date, year (u16 LE) | month | day
+// 46..48 : CRC-16/CCITT-FALSE of bytes 0..46, big endian
+use crate::error::ReaderError;
+use alloc::string::{String, ToString};
+use core::fmt;
+use core::str::FromStr;
+
+pub const RECORD_LENGTH: usize = 48;
+const VERSION: u8 = 1;
+const NAME: core::ops::Range<usize> = 2..26;
+const NUMBER: core::ops::Range<usize> = 26..42;
+const EXPIRY: usize = 42;
+const CRC: usize = 46;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Date {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+}
+
+// YYYY-MM-DD
+impl FromStr for Date {
+    type Err = ReaderError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || ReaderError::InvalidInput("the expiry date is YYYY-MM-DD".to_string());
+        let mut parts = text.splitn(3, '-');
+        let mut part = || parts.next().ok_or_else(invalid);
+        let (year, month, day) = (part()?, part()?, part()?);
+        if year.len() != 4 || month.len() != 2 || day.len() != 2 {
+            return Err(invalid());
+        }
+        let date = Date {
+            year: year.parse().map_err(|_| invalid())?,
+            month: month.parse().map_err(|_| invalid())?,
+            day: day.parse().map_err(|_| invalid())?,
+        };
+        match (1..=12).contains(&date.month) && (1..=31).contains(&date.day) {
+            true => Ok(date),
+            false => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cardholder {
+    pub name: String,
+    pub number: String,
+    pub expiry: Date,
+}
+
+impl Cardholder {
+    pub fn encode(&self) -> Result<[u8; RECORD_LENGTH], ReaderError> {
+        if self.name.len() > NAME.len() {
+            return Err(ReaderError::InvalidInput("the name is at most 24 bytes".to_string()));
+        }
+        if self.number.len() > NUMBER.len() || !self.number.bytes().all(|byte| byte.is_ascii_graphic()) {
+            return Err(ReaderError::InvalidInput("the card number is at most 16 ASCII characters".to_string()));
+        }
+        let mut record = [0u8; RECORD_LENGTH];
+        record[0] = VERSION;
+        record[1] = self.name.len() as u8;
+        record[NAME.start..NAME.start + self.name.len()].copy_from_slice(self.name.as_bytes());
+        record[NUMBER.start..NUMBER.start + self.number.len()].copy_from_slice(self.number.as_bytes());
+        record[EXPIRY..EXPIRY + 2].copy_from_slice(&self.expiry.year.to_le_bytes());
+        record[EXPIRY + 2] = self.expiry.month;
+        record[EXPIRY + 3] = self.expiry.day;
+        let crc = crc16(&record[..CRC]);
+        record[CRC..].copy_from_slice(&crc.to_be_bytes());
+        Ok(record)
+    }
+
+    pub fn decode(record: &[u8]) -> Result<Cardholder, ReaderError> {
+        let record = record.get(..RECORD_LENGTH).ok_or(ReaderError::InvalidCardholder("record is too short"))?;
+        match record[0] {
+            0 => return Err(ReaderError::InvalidCardholder("no record on the card")),
+            VERSION => (),
+            _ => return Err(ReaderError::InvalidCardholder("unknown version")),
+        }
+        if crc16(&record[..CRC]).to_be_bytes() != record[CRC..] {
+            return Err(ReaderError::InvalidCardholder("checksum mismatch"));
+        }
+        let name = record[NAME].get(..record[1] as usize).ok_or(ReaderError::InvalidCardholder("name is too long"))?;
+        let name = core::str::from_utf8(name).map_err(|_| ReaderError::InvalidCardholder("name isn't UTF-8"))?;
+        let number = &record[NUMBER];
+        let number = &number[..number.iter().position(|&byte| byte == 0).unwrap_or(number.len())];
+        let number = core::str::from_utf8(number).map_err(|_| ReaderError::InvalidCardholder("card number isn't ASCII"))?;
+        Ok(Cardholder {
+            name: name.to_string(),
+            number: number.to_string(),
+            expiry: Date {
+                year: u16::from_le_bytes([record[EXPIRY], record[EXPIRY + 1]]),
+                month: record[EXPIRY + 2],
+                day: record[EXPIRY + 3],
+            },
+        })
+    }
+}
+
+// CRC-16/CCITT-FALSE: polynomial 0x1021, initial value 0xFFFF
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xffff, |crc, &byte| {
+        (0..8).fold(crc ^ (u16::from(byte) << 8), |crc, _| match crc & 0x8000 {
+            0 => crc << 1,
+            _ => (crc << 1) ^ 0x1021,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cardholder() -> Cardholder {
+        Cardholder {
+            name: "Sara Ahmadi".to_string(),
+            number: "6037991234567890".to_string(),
+            expiry: "2027-03-31".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29b1);
+    }
+
+    #[test]
+    fn round_trip() {
+        let record = cardholder().encode().unwrap();
+        assert_eq!(&record[..3], &[1, 11, b'S']);
+        assert_eq!(Cardholder::decode(&record).unwrap(), cardholder());
+        assert_eq!(cardholder().expiry.to_string(), "2027-03-31");
+    }
+
+    #[test]
+    fn refuses_damaged_records() {
+        let mut record = cardholder().encode().unwrap();
+        record[5] ^= 1;
+        assert_eq!(Cardholder::decode(&record), Err(ReaderError::InvalidCardholder("checksum mismatch")));
+        assert_eq!(Cardholder::decode(&[0; 48]), Err(ReaderError::InvalidCardholder("no record on the card")));
+    }
+
+    #[test]
+    fn refuses_invalid_input() {
+        let long = Cardholder { name: "x".repeat(25), ..cardholder() };
+        assert!(matches!(long.encode(), Err(ReaderError::InvalidInput(_))));
+        let number = Cardholder { number: "1234 5678".to_string(), ..cardholder() };
+        assert!(matches!(number.encode(), Err(ReaderError::InvalidInput(_))));
+        for date in ["2027-3-31", "2027-13-01", "27-03-31", "2027-03"] {
+            assert!(date.parse::<Date>().is_err(), "{}", date);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+// Lists of cards by UID: the blacklist of lost or cloned cards, whose balance operations
+// are refused with CARD_BLOCKED, and the registry of the cards issued here with their label
+// and issue date. With `registry.required` only registered cards may be used, so foreign
+// MIFARE cards can't be topped up. `blacklist.file` / `registry.file` keep them across
+// restarts, they're rewritten on every change.
+use er302::{codec, CardCheck, ReaderError};
+use rocket::serde::json::{json, serde_json, Value};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// UID (hex) -> JSON object of the card, in memory only while `path` is None
+pub struct CardFile {
+    path: Option<PathBuf>,
+    cards: Mutex<BTreeMap<String, Value>>,
+}
+
+impl CardFile {
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let cards = match &path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(text) => serde_json::from_str(&text).unwrap_or_else(|e| {
+                    tracing::error!(file = %path.display(), error = %e, "can't parse the card file");
+                    BTreeMap::new()
+                }),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+                Err(e) => {
+                    tracing::error!(file = %path.display(), error = %e, "can't read the card file");
+                    BTreeMap::new()
+                }
+            },
+            None => BTreeMap::new(),
+        };
+        CardFile {
+            path,
+            cards: Mutex::new(cards),
+        }
+    }
+
+    pub fn contains(&self, uid: &[u8]) -> bool {
+        self.cards.lock().unwrap().contains_key(&codec::to_hex(uid))
+    }
+
+    // {uid, ...} of one card
+    pub fn get(&self, uid: &str) -> Option<Value> {
+        let mut card = self.cards.lock().unwrap().get(uid)?.clone();
+        card["uid"] = json!(uid);
+        Some(card)
+    }
+
+    // {uid, ...} of every card
+    pub fn list(&self) -> Vec<Value> {
+        let cards = self.cards.lock().unwrap();
+        cards
+            .iter()
+            .map(|(uid, card)| {
+                let mut card = card.clone();
+                card["uid"] = json!(uid);
+                card
+            })
+            .collect()
+    }
+
+    // Add or replace a card, `added` is set to now
+    pub fn insert(&self, uid: &str, mut card: Value) -> std::io::Result<()> {
+        let added = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        card["added"] = json!(added.as_millis() as f64 / 1000.0);
+        let mut cards = self.cards.lock().unwrap();
+        let previous = cards.insert(uid.to_string(), card);
+        // the list stays as it's on disk
+        if let Err(e) = self.save(&cards) {
+            match previous {
+                Some(previous) => cards.insert(uid.to_string(), previous),
+                None => cards.remove(uid),
+            };
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // false when the card wasn't listed
+    pub fn remove(&self, uid: &str) -> std::io::Result<bool> {
+        let mut cards = self.cards.lock().unwrap();
+        let Some(previous) = cards.remove(uid) else {
+            return Ok(false);
+        };
+        if let Err(e) = self.save(&cards) {
+            cards.insert(uid.to_string(), previous);
+            return Err(e);
+        }
+        Ok(true)
+    }
+
+    // Written next to the file and renamed, so a crash never leaves half a list
+    fn save(&self, cards: &BTreeMap<String, Value>) -> std::io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, serde_json::to_string_pretty(cards)?)?;
+        fs::rename(&temporary, path)
+    }
+}
+
+pub struct Blacklist(pub CardFile);
+
+pub struct Registry {
+    pub cards: CardFile,
+    // refuse the cards that aren't registered
+    pub required: bool,
+}
+
+// UID of a route as the lists key it, uppercase hex
+pub fn normalize_uid(uid: &str) -> Result<String, ReaderError> {
+    let bytes = codec::from_hex(uid)?;
+    match bytes.len() {
+        4 | 7 | 10 => Ok(codec::to_hex(&bytes)),
+        _ => Err(ReaderError::InvalidInput("a UID is 4, 7 or 10 bytes".to_string())),
+    }
+}
+
+// Check the readers run before balance operations
+pub fn card_check(blacklist: Arc<Blacklist>, registry: Arc<Registry>) -> CardCheck {
+    Arc::new(move |uid: &[u8]| {
+        if blacklist.0.contains(uid) {
+            tracing::warn!(target: "er302::audit", uid = %codec::to_hex(uid), "blacklisted card refused");
+            return Err(ReaderError::CardBlocked);
+        }
+        if registry.required && !registry.cards.contains(uid) {
+            tracing::warn!(target: "er302::audit", uid = %codec::to_hex(uid), "unregistered card refused");
+            return Err(ReaderError::CardNotRegistered);
+        }
+        Ok(())
+    })
+}
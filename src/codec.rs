@@ -0,0 +1,722 @@
+// ER302 frame codec, no std / serialport / rocket needed so it also builds for wasm
+//
+// Request  : AA BB | size (LE) | node (2) | command (LE) | data | xor
+// Response : AA BB | size (LE) | node (2) | command (LE) | status | data | xor
+use crate::error::ReaderError;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub const HEADER: &[u8] = &[0xaa, 0xbb];
+
+// Command codes
+pub const READ_VERSION: u16 = 0x0104;
+pub const READ_SERIAL: u16 = 0x0105;
+pub const BEEP: u16 = 0x0106;
+pub const ANTENNA: u16 = 0x010C;
+pub const MIFARE_REQUEST: u16 = 0x0201;
+pub const ANTICOLLISION: u16 = 0x0202;
+pub const SELECT: u16 = 0x0203;
+pub const AUTHENTICATE: u16 = 0x0207;
+pub const READ_BLOCK: u16 = 0x0208;
+pub const WRITE_BLOCK: u16 = 0x0209;
+pub const INIT_VALUE: u16 = 0x020A;
+pub const READ_VALUE: u16 = 0x020B;
+pub const DECREMENT: u16 = 0x020C;
+pub const INCREMENT: u16 = 0x020D;
+// The card's transfer buffer: restore loads a value block into it, transfer writes it to a
+// value block of the authenticated sector
+pub const RESTORE: u16 = 0x020E;
+pub const TRANSFER: u16 = 0x020F;
+pub const HALT: u16 = 0x0204;
+pub const ULTRALIGHT_WRITE: u16 = 0x0213;
+// ISO 14443-4 (T=CL) of CPU cards: RATS answers the ATS, the reader wraps APDUs in I-blocks
+pub const RATS: u16 = 0x0216;
+pub const APDU: u16 = 0x0217;
+
+// Name of a command code for logs and metrics, "other" for the ones the driver doesn't send
+pub fn command_name(code: u16) -> &'static str {
+    match code {
+        READ_VERSION => "read_version",
+        READ_SERIAL => "read_serial",
+        BEEP => "beep",
+        ANTENNA => "antenna",
+        MIFARE_REQUEST => "request",
+        ANTICOLLISION => "anticollision",
+        SELECT => "select",
+        AUTHENTICATE => "authenticate",
+        READ_BLOCK => "read_block",
+        WRITE_BLOCK => "write_block",
+        INIT_VALUE => "init_value",
+        READ_VALUE => "read_value",
+        DECREMENT => "decrement",
+        INCREMENT => "increment",
+        RESTORE => "restore",
+        TRANSFER => "transfer",
+        HALT => "halt",
+        ULTRALIGHT_WRITE => "ultralight_write",
+        RATS => "rats",
+        APDU => "apdu",
+        _ => "other",
+    }
+}
+
+// Authentication modes
+pub const KEY_A: u8 = 0x60;
+
+// ISO 14443-3 SEL codes of cascade level 1, 2 and 3
+pub const CASCADE_LEVELS: [u8; 3] = [0x93, 0x95, 0x97];
+// First UID byte of a level that isn't the last one
+pub const CASCADE_TAG: u8 = 0x88;
+
+pub fn calculate_size(data: &[u8]) -> Vec<u8> {
+    // Calculate the length and add 1
+    let length = data.len() + 1;
+
+    // Convert length to a 2-byte number in little-endian format
+    let low_byte = (length & 0xFF) as u8; // Low byte
+    let high_byte = ((length >> 8) & 0xFF) as u8; // High byte
+    Vec::from([low_byte, high_byte])
+}
+
+// Function to calculate XOR over a slice of data
+pub fn calculate_xor(data: Vec<u8>) -> Vec<u8> {
+    if data.len() < 4 {
+        panic!("Data must have at least 4 elements to calculate XOR");
+    }
+
+    // Calculate XOR from index 3 to the end
+    let xor = data[3..].iter().fold(0, |acc, &x| acc ^ x);
+
+    // Append the XOR result to the data and return as a new vector
+    let mut extended_data = data;
+    extended_data.push(xor);
+    extended_data
+}
+
+// Wrap node + command + data with header, size and checksum
+pub fn encode_frame(input: &[u8]) -> Vec<u8> {
+    let mut data: Vec<u8> = input.to_vec();
+    let size = calculate_size(input);
+
+    data.splice(0..0, size.iter().copied());
+    data.splice(0..0, HEADER.iter().copied());
+
+    calculate_xor(data)
+}
+
+// Node 0x0000 + command + data, ready for `encode_frame`
+pub fn command(code: u16, data: &[u8]) -> Vec<u8> {
+    let mut command = Vec::from([0x00, 0x00]);
+    command.extend_from_slice(&code.to_le_bytes());
+    command.extend_from_slice(data);
+    command
+}
+
+// Model and firmware of the reader as ASCII, e.g. "ER302 V2.1"
+pub fn read_version() -> Vec<u8> {
+    command(READ_VERSION, &[])
+}
+
+pub fn read_serial() -> Vec<u8> {
+    command(READ_SERIAL, &[])
+}
+
+// Switch the RF field, cards lose power (and their state) while it's off
+pub fn antenna(on: bool) -> Vec<u8> {
+    command(ANTENNA, &[on as u8])
+}
+
+pub fn beep(time: u8) -> Vec<u8> {
+    command(BEEP, &[time])
+}
+
+// Request modes: every card in the field (halted ones included) or only idle cards
+pub const REQUEST_ALL: u8 = 0x52;
+pub const REQUEST_IDLE: u8 = 0x26;
+
+// Request all cards in the field
+pub fn mifare_request() -> Vec<u8> {
+    mifare_request_mode(REQUEST_ALL)
+}
+
+pub fn mifare_request_mode(mode: u8) -> Vec<u8> {
+    command(MIFARE_REQUEST, &[mode])
+}
+
+// Put the selected card to sleep until it leaves the field (or a REQUEST_ALL)
+pub fn halt() -> Vec<u8> {
+    command(HALT, &[])
+}
+
+pub fn anticollision() -> Vec<u8> {
+    command(ANTICOLLISION, &[])
+}
+
+// Level 1 keeps the plain frame, level 2 and 3 carry their SEL code
+pub fn anticollision_level(level: u8) -> Vec<u8> {
+    match level {
+        0x93 => anticollision(),
+        level => command(ANTICOLLISION, &[level]),
+    }
+}
+
+pub fn select(uid: &[u8]) -> Vec<u8> {
+    command(SELECT, uid)
+}
+
+pub fn select_level(level: u8, part: &[u8]) -> Vec<u8> {
+    match level {
+        0x93 => select(part),
+        level => {
+            let mut data = Vec::from([level]);
+            data.extend_from_slice(part);
+            command(SELECT, &data)
+        }
+    }
+}
+
+pub fn authenticate(block: u8, key: &[u8]) -> Vec<u8> {
+    let mut data = Vec::from([KEY_A, block]);
+    data.extend_from_slice(key);
+    command(AUTHENTICATE, &data)
+}
+
+pub fn read_block(block: u8) -> Vec<u8> {
+    command(READ_BLOCK, &[block])
+}
+
+pub fn write_block(block: u8, data: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::from([block]);
+    payload.extend_from_slice(data);
+    command(WRITE_BLOCK, &payload)
+}
+
+pub fn read_value(block: u8) -> Vec<u8> {
+    command(READ_VALUE, &[block])
+}
+
+pub fn restore(block: u8) -> Vec<u8> {
+    command(RESTORE, &[block])
+}
+
+pub fn transfer(block: u8) -> Vec<u8> {
+    command(TRANSFER, &[block])
+}
+
+// Ultralight pages are read with `read_block` (4 pages per read) but written one at a time
+pub fn ultralight_write(page: u8, data: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::from([page]);
+    payload.extend_from_slice(data);
+    command(ULTRALIGHT_WRITE, &payload)
+}
+
+// RATS parameter byte: FSDI 5 (the reader takes 64 byte frames), CID 0
+pub const RATS_PARAMETER: u8 = 0x50;
+
+pub fn rats() -> Vec<u8> {
+    command(RATS, &[RATS_PARAMETER])
+}
+
+// A command APDU to the activated card, answered with the response APDU (data, SW1, SW2)
+pub fn apdu(apdu: &[u8]) -> Vec<u8> {
+    command(APDU, apdu)
+}
+
+// Init / Decrement / Increment carry the block and a little-endian u32
+pub fn value_operation(code: u16, block: u8, value: u32) -> Vec<u8> {
+    let mut data = Vec::from([block]);
+    data.extend_from_slice(&value.to_le_bytes());
+    command(code, &data)
+}
+
+// Uppercase hex without separators, the format of UIDs and keys in the API
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+// How UIDs are shown to clients, access-control backends expect all kinds of them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UidFormat {
+    // DEADBEEF, as the card sends it
+    #[default]
+    Hex,
+    // EFBEADDE, least significant byte first
+    Reversed,
+    // the bytes as one big-endian number, 3735928559
+    Decimal,
+    // facility (8 bits) and card number (16 bits) of the last 3 bytes, "173,48879"
+    Wiegand26,
+    // facility (16 bits) and card number (16 bits) of the last 4 bytes, "57005,48879"
+    Wiegand34,
+}
+
+impl core::str::FromStr for UidFormat {
+    type Err = ReaderError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "hex" => Ok(UidFormat::Hex),
+            "reversed" => Ok(UidFormat::Reversed),
+            "decimal" => Ok(UidFormat::Decimal),
+            "wiegand26" => Ok(UidFormat::Wiegand26),
+            "wiegand34" => Ok(UidFormat::Wiegand34),
+            _ => Err(ReaderError::InvalidInput(format!(
+                "unknown UID format {}, one of hex, reversed, decimal, wiegand26, wiegand34",
+                name
+            ))),
+        }
+    }
+}
+
+impl UidFormat {
+    pub fn render(&self, uid: &[u8]) -> String {
+        // 10 bytes at most, u128 holds them
+        let number = uid.iter().fold(0u128, |number, &byte| number << 8 | u128::from(byte));
+        match self {
+            UidFormat::Hex => to_hex(uid),
+            UidFormat::Reversed => uid.iter().rev().map(|byte| format!("{:02X}", byte)).collect(),
+            UidFormat::Decimal => format!("{}", number),
+            UidFormat::Wiegand26 => format!("{:03},{:05}", number >> 16 & 0xff, number & 0xffff),
+            UidFormat::Wiegand34 => format!("{:05},{:05}", number >> 16 & 0xffff, number & 0xffff),
+        }
+    }
+}
+
+pub fn from_hex(hex: &str) -> Result<Vec<u8>, ReaderError> {
+    let invalid = || ReaderError::InvalidInput(format!("invalid hex: {}", hex));
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid()))
+        .collect()
+}
+
+// Sector / block of a MIFARE Classic 1K or 4K card
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockAddress {
+    pub sector: u8,
+    pub block: u8,
+}
+
+// Block 53 (sector 13, block 1), where the balance lived before it was configurable
+pub const DEFAULT_VALUE_BLOCK: BlockAddress = BlockAddress { sector: 13, block: 1 };
+
+impl BlockAddress {
+    // Any block that exists on a 4K card, trailers included
+    pub fn new(sector: u8, block: u8) -> Result<Self, ReaderError> {
+        if sector >= 40 || block >= Self::blocks_in_sector(sector) {
+            return Err(ReaderError::InvalidBlock { sector, block });
+        }
+        Ok(BlockAddress { sector, block })
+    }
+
+    // Only data blocks: no sector trailer and no manufacturer block
+    pub fn data(sector: u8, block: u8) -> Result<Self, ReaderError> {
+        let address = Self::new(sector, block)?;
+        if address.is_trailer() || (sector == 0 && block == 0) {
+            return Err(ReaderError::InvalidBlock { sector, block });
+        }
+        Ok(address)
+    }
+
+    // Sectors 32..40 of a 4K card have 16 blocks, every other one 4
+    pub fn blocks_in_sector(sector: u8) -> u8 {
+        if sector < 32 {
+            4
+        } else {
+            16
+        }
+    }
+
+    pub fn is_trailer(&self) -> bool {
+        self.block == Self::blocks_in_sector(self.sector) - 1
+    }
+
+    // Block number as sent to the reader
+    pub fn absolute(&self) -> u8 {
+        if self.sector < 32 {
+            self.sector * 4 + self.block
+        } else {
+            128 + (self.sector - 32) * 16 + self.block
+        }
+    }
+
+    // Absolute number of this sector's trailer
+    pub fn trailer(&self) -> u8 {
+        BlockAddress {
+            sector: self.sector,
+            block: Self::blocks_in_sector(self.sector) - 1,
+        }
+        .absolute()
+    }
+}
+
+// Access bits (bytes 6-8 of a trailer) are stored plain and inverted, a card refuses the
+// sector for good once they don't match
+pub fn access_bits_valid(bits: &[u8]) -> bool {
+    let [b6, b7, b8, ..] = bits else {
+        return false;
+    };
+    b6 & 0x0f == !(b7 >> 4) & 0x0f && b6 >> 4 == !b8 & 0x0f && b7 & 0x0f == !(b8 >> 4) & 0x0f
+}
+
+// Answers of READ_VERSION / READ_SERIAL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReaderInfo {
+    pub model: String,
+    pub firmware: String,
+    // hex
+    pub serial: String,
+}
+
+impl ReaderInfo {
+    // "<model> <firmware>", NUL padding of the version is dropped
+    pub fn parse(version: &[u8], serial: &[u8]) -> Self {
+        let text: String = version
+            .iter()
+            .take_while(|byte| **byte != 0)
+            .map(|byte| *byte as char)
+            .collect();
+        let (model, firmware) = text.trim().split_once(' ').unwrap_or((text.trim(), ""));
+        ReaderInfo {
+            model: String::from(model),
+            firmware: String::from(firmware.trim()),
+            serial: to_hex(serial),
+        }
+    }
+}
+
+// Card family from the ATQA of the request and the SAK of the last select (NXP AN10833).
+// Ultralight and NTAG answer alike, telling them apart needs GET_VERSION.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CardType {
+    ClassicMini,
+    Classic1K,
+    Classic4K,
+    Plus,
+    Ultralight,
+    Desfire,
+    // Other ISO 14443-4 (T=CL) cards, e.g. Plus SL3 or smart cards
+    Iso14443_4,
+    Unknown,
+}
+
+impl CardType {
+    pub fn detect(atqa: u16, sak: u8) -> Self {
+        match (atqa, sak) {
+            (_, 0x09) => CardType::ClassicMini,
+            // 0x28 / 0x38: Classic emulated by a SmartMX
+            (_, 0x08 | 0x88 | 0x28) => CardType::Classic1K,
+            (_, 0x18 | 0x38) => CardType::Classic4K,
+            (_, 0x10 | 0x11) => CardType::Plus,
+            // 7 byte UID, no MIFARE Classic crypto
+            (0x0044, 0x00) => CardType::Ultralight,
+            (0x0344, 0x20) => CardType::Desfire,
+            (_, sak) if sak & 0x20 != 0 => CardType::Iso14443_4,
+            _ => CardType::Unknown,
+        }
+    }
+
+    // Sector / key based commands (authenticate, blocks, values) work
+    pub fn is_classic(&self) -> bool {
+        matches!(self, CardType::ClassicMini | CardType::Classic1K | CardType::Classic4K)
+    }
+
+    // Stable name for API clients
+    pub fn name(&self) -> &'static str {
+        match self {
+            CardType::ClassicMini => "CLASSIC_MINI",
+            CardType::Classic1K => "CLASSIC_1K",
+            CardType::Classic4K => "CLASSIC_4K",
+            CardType::Plus => "PLUS",
+            CardType::Ultralight => "ULTRALIGHT",
+            CardType::Desfire => "DESFIRE",
+            CardType::Iso14443_4 => "ISO14443_4",
+            CardType::Unknown => "UNKNOWN",
+        }
+    }
+}
+
+// What the activation (request + cascade) of a card tells about it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CardInfo {
+    pub atqa: u16,
+    pub sak: u8,
+    // 4, 7 or 10 bytes
+    pub uid: Vec<u8>,
+}
+
+impl CardInfo {
+    pub fn card_type(&self) -> CardType {
+        CardType::detect(self.atqa, self.sak)
+    }
+}
+
+// Total length of the frame at the start of `buffer`, once header and size have arrived
+pub fn frame_length(buffer: &[u8]) -> Option<usize> {
+    match buffer {
+        [0xaa, 0xbb, low, high, ..] => Some(u16::from_le_bytes([*low, *high]) as usize + 4),
+        _ => None,
+    }
+}
+
+// Drop any noise in front of the next header, keeps a trailing 0xAA that may start one
+pub fn skip_to_header(buffer: &mut Vec<u8>) {
+    let start = buffer
+        .windows(2)
+        .position(|window| window == HEADER)
+        .unwrap_or(buffer.len() - usize::from(buffer.last() == Some(&0xaa)));
+    buffer.drain(..start);
+}
+
+// Decoded response frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub node: u16,
+    pub command: u16,
+    pub status: u8,
+    pub data: Vec<u8>,
+}
+
+impl Frame {
+    // Validate header, size field and checksum of a complete response
+    pub fn parse(frame: &[u8]) -> Result<Self, ReaderError> {
+        if frame.len() < 10 {
+            return Err(ReaderError::InvalidFrame("frame too short"));
+        }
+        if &frame[..2] != HEADER {
+            return Err(ReaderError::InvalidFrame("bad header"));
+        }
+        // size counts node, command, status, data and the checksum
+        let size = u16::from_le_bytes([frame[2], frame[3]]) as usize;
+        if size + 4 != frame.len() {
+            return Err(ReaderError::InvalidFrame("size field doesn't match frame length"));
+        }
+        let (body, xor) = frame[4..].split_at(frame.len() - 5);
+        if body.iter().fold(0, |acc, &x| acc ^ x) != xor[0] {
+            return Err(ReaderError::ChecksumMismatch);
+        }
+        Ok(Frame {
+            node: u16::from_le_bytes([body[0], body[1]]),
+            command: u16::from_le_bytes([body[2], body[3]]),
+            status: body[4],
+            data: body[5..].to_vec(),
+        })
+    }
+
+    // Err(ProtocolError) unless the reader reported success
+    pub fn check_status(self) -> Result<Self, ReaderError> {
+        match self.status {
+            0x00 => Ok(self),
+            code => Err(ReaderError::ProtocolError { code }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_access_bits() {
+        // transport configuration and the one of initcard
+        assert!(access_bits_valid(&[0xff, 0x07, 0x80, 0x69]));
+        assert!(access_bits_valid(&[0x78, 0x77, 0x88, 0xc1]));
+        assert!(!access_bits_valid(&[0xff, 0x07, 0x81, 0x69]));
+        assert!(!access_bits_valid(&[0xff, 0x07]));
+    }
+
+    #[test]
+    fn encodes_mifare_request() {
+        assert_eq!(
+            encode_frame(&mifare_request()),
+            [0xaa, 0xbb, 0x06, 0x00, 0x00, 0x00, 0x01, 0x02, 0x52, 0x51]
+        );
+    }
+
+    #[test]
+    fn names_commands() {
+        assert_eq!(command_name(AUTHENTICATE), "authenticate");
+        assert_eq!(command_name(u16::from_le_bytes([mifare_request()[2], mifare_request()[3]])), "request");
+        // raw commands of any code share one name
+        assert_eq!(command_name(0x0299), "other");
+    }
+
+    #[test]
+    fn encodes_apdus() {
+        assert_eq!(encode_frame(&rats()), [0xaa, 0xbb, 0x06, 0x00, 0x00, 0x00, 0x16, 0x02, 0x50, 0x44]);
+        let select = [0x00, 0xa4, 0x04, 0x00, 0x00];
+        assert_eq!(apdu(&select)[..4], [0x00, 0x00, 0x17, 0x02]);
+        assert_eq!(apdu(&select)[4..], select);
+    }
+
+    #[test]
+    fn renders_uids() {
+        let uid = [0xde, 0xad, 0xbe, 0xef];
+        assert_eq!(UidFormat::Hex.render(&uid), "DEADBEEF");
+        assert_eq!(UidFormat::Reversed.render(&uid), "EFBEADDE");
+        assert_eq!(UidFormat::Decimal.render(&uid), "3735928559");
+        assert_eq!(UidFormat::Wiegand26.render(&uid), "173,48879");
+        assert_eq!(UidFormat::Wiegand34.render(&uid), "57005,48879");
+        // only the last bytes of a long UID fit a Wiegand frame
+        let long = [0x04, 0x11, 0x22, 0x33, 0x01, 0x00, 0x2a];
+        assert_eq!(UidFormat::Wiegand26.render(&long), "001,00042");
+        assert_eq!(UidFormat::Decimal.render(&long), "1144738489106474");
+        assert_eq!("wiegand34".parse(), Ok(UidFormat::Wiegand34));
+        assert!("octal".parse::<UidFormat>().is_err());
+    }
+
+    #[test]
+    fn calculates_size() {
+        // node + command + data, plus the xor byte
+        assert_eq!(calculate_size(&[0x00, 0x00, 0x04, 0x01]), [0x05, 0x00]);
+        assert_eq!(calculate_size(&[]), [0x01, 0x00]);
+        assert_eq!(calculate_size(&[0; 300]), [0x2d, 0x01]);
+    }
+
+    #[test]
+    fn calculates_xor() {
+        // from the high size byte on, the header and low size byte aren't covered
+        assert_eq!(
+            calculate_xor(Vec::from([0xaa, 0xbb, 0x05, 0x00, 0x00, 0x00, 0x04, 0x01])),
+            [0xaa, 0xbb, 0x05, 0x00, 0x00, 0x00, 0x04, 0x01, 0x05]
+        );
+        assert_eq!(calculate_xor(Vec::from([0xaa, 0xbb, 0xff, 0x00])), [0xaa, 0xbb, 0xff, 0x00, 0x00]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn xor_needs_a_header() {
+        calculate_xor(Vec::from([0xaa, 0xbb, 0x05]));
+    }
+
+    // Exact request frames of every command builder
+    #[test]
+    fn encodes_golden_frames() {
+        let data: Vec<u8> = (0..16).collect();
+        let golden: [(Vec<u8>, &str); 19] = [
+            (read_version(), "AABB05000000040105"),
+            (read_serial(), "AABB05000000050104"),
+            (beep(10), "AABB0600000006010A0D"),
+            (antenna(true), "AABB060000000C01010C"),
+            (antenna(false), "AABB060000000C01000D"),
+            (mifare_request_mode(REQUEST_IDLE), "AABB0600000001022625"),
+            (anticollision(), "AABB05000000020200"),
+            (select(&[0xde, 0xad, 0xbe, 0xef]), "AABB090000000302DEADBEEF23"),
+            (halt(), "AABB05000000040206"),
+            (authenticate(0x34, &[0xff; 6]), "AABB0D00000007026034FFFFFFFFFFFF51"),
+            (read_block(0x35), "AABB060000000802353F"),
+            (write_block(0x35, &data), "AABB16000000090235000102030405060708090A0B0C0D0E0F3E"),
+            (read_value(0x35), "AABB060000000B02353C"),
+            (value_operation(INIT_VALUE, 0x35, 1000), "AABB0A0000000A0235E8030000D6"),
+            (value_operation(DECREMENT, 0x35, 5), "AABB0A0000000C0235050000003E"),
+            (value_operation(INCREMENT, 0x35, 0x01020304), "AABB0A0000000D0235040302013E"),
+            (restore(0x35), "AABB060000000E023539"),
+            (transfer(0x35), "AABB060000000F023538"),
+            (ultralight_write(4, &[1, 2, 3, 4]), "AABB0A0000001302040102030411"),
+        ];
+        for (command, frame) in golden {
+            assert_eq!(to_hex(&encode_frame(&command)), frame);
+        }
+    }
+
+    // anticollision answer carrying UID DE AD BE EF
+    const ANTICOLLISION_FRAME: [u8; 14] = [
+        0xaa, 0xbb, 0x0a, 0x00, 0x00, 0x00, 0x02, 0x02, 0x00, 0xde, 0xad, 0xbe, 0xef, 0x22,
+    ];
+
+    #[test]
+    fn parses_response() {
+        let frame = Frame::parse(&ANTICOLLISION_FRAME).unwrap();
+        assert_eq!(frame.command, ANTICOLLISION);
+        assert_eq!(frame.status, 0x00);
+        assert_eq!(frame.data, [0xde, 0xad, 0xbe, 0xef]);
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        assert!(matches!(Frame::parse(&ANTICOLLISION_FRAME[..9]), Err(ReaderError::InvalidFrame(_))));
+
+        let mut bad_header = ANTICOLLISION_FRAME;
+        bad_header[1] = 0xaa;
+        assert!(matches!(Frame::parse(&bad_header), Err(ReaderError::InvalidFrame(_))));
+
+        let mut bad_size = ANTICOLLISION_FRAME;
+        bad_size[2] = 0x0b;
+        assert!(matches!(Frame::parse(&bad_size), Err(ReaderError::InvalidFrame(_))));
+
+        let mut bad_xor = ANTICOLLISION_FRAME;
+        bad_xor[13] = 0x23;
+        assert_eq!(Frame::parse(&bad_xor), Err(ReaderError::ChecksumMismatch));
+    }
+
+    #[test]
+    fn finds_frame_length_after_noise() {
+        let mut buffer = Vec::from([0x00, 0x13, 0xaa]);
+        skip_to_header(&mut buffer);
+        assert_eq!(buffer, [0xaa]);
+        assert_eq!(frame_length(&buffer), None);
+
+        buffer.extend_from_slice(&ANTICOLLISION_FRAME[1..4]);
+        skip_to_header(&mut buffer);
+        assert_eq!(frame_length(&buffer), Some(ANTICOLLISION_FRAME.len()));
+    }
+
+    #[test]
+    fn addresses_blocks() {
+        assert_eq!(DEFAULT_VALUE_BLOCK.absolute(), 0x35);
+        assert_eq!(DEFAULT_VALUE_BLOCK.trailer(), 0x37);
+        assert_eq!(BlockAddress::new(39, 15).unwrap().absolute(), 255);
+        assert_eq!(BlockAddress::data(32, 3).unwrap().trailer(), 143);
+
+        assert!(BlockAddress::new(13, 3).unwrap().is_trailer());
+        assert_eq!(BlockAddress::data(13, 3), Err(ReaderError::InvalidBlock { sector: 13, block: 3 }));
+        assert_eq!(BlockAddress::data(0, 0), Err(ReaderError::InvalidBlock { sector: 0, block: 0 }));
+        assert_eq!(BlockAddress::new(13, 4), Err(ReaderError::InvalidBlock { sector: 13, block: 4 }));
+        assert_eq!(BlockAddress::new(40, 0), Err(ReaderError::InvalidBlock { sector: 40, block: 0 }));
+    }
+
+    #[test]
+    fn converts_hex() {
+        assert_eq!(to_hex(&[0xde, 0xad, 0x0b]), "DEAD0B");
+        assert_eq!(from_hex("dead0B").unwrap(), [0xde, 0xad, 0x0b]);
+        assert!(from_hex("abc").is_err());
+        assert!(from_hex("zz").is_err());
+        assert!(from_hex("é0").is_err());
+    }
+
+    #[test]
+    fn encodes_cascade_levels() {
+        assert_eq!(anticollision_level(0x93), anticollision());
+        assert_eq!(anticollision_level(0x95), [0x00, 0x00, 0x02, 0x02, 0x95]);
+        assert_eq!(select_level(0x97, &[1, 2, 3, 4]), [0x00, 0x00, 0x03, 0x02, 0x97, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn parses_reader_info() {
+        let info = ReaderInfo::parse(b"ER302 V2.1\0\0", &[0x12, 0x34]);
+        assert_eq!((info.model.as_str(), info.firmware.as_str(), info.serial.as_str()), ("ER302", "V2.1", "1234"));
+        assert_eq!(ReaderInfo::parse(b"YHY523U", &[]).firmware, "");
+    }
+
+    #[test]
+    fn detects_card_type() {
+        assert_eq!(CardType::detect(0x0004, 0x08), CardType::Classic1K);
+        assert_eq!(CardType::detect(0x0002, 0x18), CardType::Classic4K);
+        assert_eq!(CardType::detect(0x0044, 0x00), CardType::Ultralight);
+        assert_eq!(CardType::detect(0x0344, 0x20), CardType::Desfire);
+        assert_eq!(CardType::detect(0x0004, 0x20), CardType::Iso14443_4);
+        assert_eq!(CardType::detect(0x0004, 0x01), CardType::Unknown);
+        assert!(CardType::Classic4K.is_classic() && !CardType::Plus.is_classic());
+    }
+
+    #[test]
+    fn checks_status() {
+        let frame = [0xaa, 0xbb, 0x06, 0x00, 0x00, 0x00, 0x07, 0x02, 0x01, 0x04];
+        let frame = Frame::parse(&frame).unwrap();
+        assert_eq!(frame.check_status(), Err(ReaderError::ProtocolError { code: 0x01 }));
+    }
+}
@@ -0,0 +1,69 @@
+// CORS headers for browser kiosk frontends calling the API from another origin,
+// `[api.cors]` origins and methods. Preflight OPTIONS requests are answered here.
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Method, Status};
+use rocket::{Request, Response};
+use std::io::Cursor;
+
+// Requested by the frontends, X-Request-Id lets them correlate with the server log
+const ALLOWED_HEADERS: &str = "Authorization, Content-Type, X-Request-Id";
+const EXPOSED_HEADERS: &str = "X-Request-Id, X-Queue-Wait-Ms";
+// How long a browser may cache a preflight answer
+const MAX_AGE_SECS: u32 = 600;
+
+#[derive(Clone)]
+pub struct CorsConfig {
+    // `https://kiosk.example:8443` or `*` for any, CORS is off while empty
+    pub origins: Vec<String>,
+    pub methods: Vec<String>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        CorsConfig {
+            origins: Vec::new(),
+            methods: vec!["GET".to_string(), "POST".to_string()],
+        }
+    }
+}
+
+impl CorsConfig {
+    pub fn allows(&self, origin: &str) -> bool {
+        self.origins.iter().any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+    }
+}
+
+pub struct Cors(pub CorsConfig);
+
+#[rocket::async_trait]
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let Some(origin) = request.headers().get_one("Origin") else {
+            return;
+        };
+        // an origin that isn't allowed gets no headers, the browser blocks the call
+        if !self.0.allows(origin) {
+            return;
+        }
+        // the origin itself rather than `*`, so credentials (Authorization) are allowed
+        response.set_header(Header::new("Access-Control-Allow-Origin", origin.to_string()));
+        response.set_header(Header::new("Vary", "Origin"));
+        response.set_header(Header::new("Access-Control-Expose-Headers", EXPOSED_HEADERS));
+        let preflight = request.method() == Method::Options && request.headers().contains("Access-Control-Request-Method");
+        if preflight && response.status() == Status::NotFound {
+            response.set_status(Status::NoContent);
+            response.set_sized_body(0, Cursor::new(""));
+            response.remove_header("Content-Type");
+            response.set_header(Header::new("Access-Control-Allow-Methods", self.0.methods.join(", ")));
+            response.set_header(Header::new("Access-Control-Allow-Headers", ALLOWED_HEADERS));
+            response.set_header(Header::new("Access-Control-Max-Age", MAX_AGE_SECS.to_string()));
+        }
+    }
+}
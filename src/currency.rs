@@ -0,0 +1,99 @@
+// Balances are stored in minor units (cents), shown and typed in as decimals of the
+// currency: 1550 is "15.50" EUR with 2 minor units, 1550 IRR with none
+use crate::error::ReaderError;
+use alloc::format;
+use alloc::string::{String, ToString};
+
+// 10^9 still fits a u32
+pub const MAX_MINOR_UNITS: u8 = 9;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Currency {
+    // ISO 4217 code, e.g. "EUR"
+    pub code: String,
+    // digits after the decimal point
+    pub minor_units: u8,
+}
+
+impl Currency {
+    pub fn new(code: &str, minor_units: u8) -> Result<Self, ReaderError> {
+        if code.len() != 3 || !code.bytes().all(|byte| byte.is_ascii_uppercase()) {
+            return Err(ReaderError::InvalidInput(format!("currency code {} isn't 3 capital letters", code)));
+        }
+        if minor_units > MAX_MINOR_UNITS {
+            return Err(ReaderError::InvalidInput(format!("at most {} minor units", MAX_MINOR_UNITS)));
+        }
+        Ok(Currency { code: code.to_string(), minor_units })
+    }
+
+    fn scale(&self) -> u32 {
+        10u32.pow(u32::from(self.minor_units))
+    }
+
+    // 1550 -> "15.50"
+    pub fn format(&self, raw: u32) -> String {
+        match self.minor_units {
+            0 => format!("{}", raw),
+            digits => format!("{}.{:0width$}", raw / self.scale(), raw % self.scale(), width = usize::from(digits)),
+        }
+    }
+
+    // "15.50", "15.5" or "15" -> 1550, more decimals than the currency has are refused
+    // rather than rounded
+    pub fn parse(&self, amount: &str) -> Result<u32, ReaderError> {
+        let invalid = || ReaderError::InvalidInput(format!("invalid amount {} for {}", amount, self.code));
+        let digits = |text: &str| !text.is_empty() && text.bytes().all(|byte| byte.is_ascii_digit());
+        let (units, fraction) = match amount.trim().split_once('.') {
+            Some((units, fraction)) if digits(fraction) => (units, fraction),
+            Some(_) => return Err(invalid()),
+            None => (amount.trim(), ""),
+        };
+        if !digits(units) || fraction.len() > usize::from(self.minor_units) {
+            return Err(invalid());
+        }
+        let padding = 10u32.pow((usize::from(self.minor_units) - fraction.len()) as u32);
+        let fraction: u32 = match fraction {
+            "" => 0,
+            fraction => fraction.parse().map_err(|_| invalid())?,
+        };
+        let too_large = || ReaderError::InvalidInput(format!("amount {} is too large", amount));
+        units
+            .parse::<u32>()
+            .map_err(|_| too_large())?
+            .checked_mul(self.scale())
+            .and_then(|raw| raw.checked_add(fraction * padding))
+            .ok_or_else(too_large)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_minor_units() {
+        let eur = Currency::new("EUR", 2).unwrap();
+        assert_eq!(eur.format(1550), "15.50");
+        assert_eq!(eur.format(5), "0.05");
+        assert_eq!(eur.format(0), "0.00");
+        assert_eq!(Currency::new("IRR", 0).unwrap().format(1550), "1550");
+        assert_eq!(Currency::new("BHD", 3).unwrap().format(1550), "1.550");
+    }
+
+    #[test]
+    fn parses_decimals() {
+        let eur = Currency::new("EUR", 2).unwrap();
+        assert_eq!(eur.parse("15.50"), Ok(1550));
+        assert_eq!(eur.parse("15.5"), Ok(1550));
+        assert_eq!(eur.parse("15"), Ok(1500));
+        assert_eq!(eur.parse("0.05"), Ok(5));
+        assert_eq!(eur.parse("42949672.95"), Ok(u32::MAX));
+        for amount in ["15.505", "-1", "1,50", "", ".5", "15.", "abc", "42949672.96"] {
+            assert_eq!(eur.parse(amount).map_err(|e| e.code()), Err("INVALID_INPUT"), "{}", amount);
+        }
+        assert_eq!(Currency::new("IRR", 0).unwrap().parse("1550"), Ok(1550));
+        assert!(Currency::new("IRR", 0).unwrap().parse("1.5").is_err());
+        assert!(Currency::new("eur", 2).is_err());
+        assert!(Currency::new("EUR", 10).is_err());
+    }
+}
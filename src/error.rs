@@ -0,0 +1,87 @@
+// Errors of the ER302 driver, each one has a stable code for API clients
+use alloc::string::String;
+use thiserror::Error;
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum ReaderError {
+    #[error("Error in Connection: {0}")]
+    PortError(String),
+    #[error("Card not found")]
+    NoCard,
+    #[error("Authentication failed")]
+    AuthFailed,
+    #[error("Invalid frame from reader: {0}")]
+    InvalidFrame(&'static str),
+    #[error("Checksum mismatch in reader response")]
+    ChecksumMismatch,
+    #[error("Reader timeout")]
+    Timeout,
+    #[error("The reader didn't connect within {ms} ms")]
+    OpenTimeout { ms: u64 },
+    #[error("The command didn't finish within {ms} ms")]
+    DeadlineExceeded { ms: u64 },
+    #[error("Reader busy")]
+    Busy,
+    #[error("Reader returned status {code:02X}")]
+    ProtocolError { code: u8 },
+    #[error("Balance has wrote to card but can't retrive balance")]
+    ReadBackFailed,
+    #[error("Sector {sector} block {block} can't be used here")]
+    InvalidBlock { sector: u8, block: u8 },
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+    #[error("Card is not NDEF formatted")]
+    NotNdef,
+    #[error("Invalid NDEF data: {0}")]
+    InvalidNdef(&'static str),
+    #[error("Operation not supported by this card")]
+    UnsupportedCard,
+    #[error("Insufficient funds: the balance is {balance}, {amount} requested")]
+    InsufficientFunds { balance: u32, amount: u32 },
+    #[error("The balance can't exceed {max}")]
+    BalanceLimit { max: u32 },
+    #[error("The balance's MAC doesn't match, it wasn't written by this API")]
+    ValueTampered,
+    #[error("The card's transaction counter is {counter} but was {seen} already, an older copy of the card was written back")]
+    CardRollback { counter: u32, seen: u32 },
+    #[error("Card is blacklisted")]
+    CardBlocked,
+    #[error("Card is not registered")]
+    CardNotRegistered,
+    #[error("Invalid cardholder record: {0}")]
+    InvalidCardholder(&'static str),
+    #[error("The new key was written but doesn't authenticate")]
+    KeyNotVerified,
+}
+
+impl ReaderError {
+    // Machine readable code, part of the API so never rename these
+    pub fn code(&self) -> &'static str {
+        match self {
+            ReaderError::PortError(_) => "PORT_ERROR",
+            ReaderError::NoCard => "NO_CARD",
+            ReaderError::AuthFailed => "AUTH_FAILED",
+            ReaderError::InvalidFrame(_) => "INVALID_FRAME",
+            ReaderError::ChecksumMismatch => "CHECKSUM_MISMATCH",
+            ReaderError::Timeout => "TIMEOUT",
+            ReaderError::OpenTimeout { .. } => "OPEN_TIMEOUT",
+            ReaderError::DeadlineExceeded { .. } => "DEADLINE_EXCEEDED",
+            ReaderError::Busy => "BUSY",
+            ReaderError::ProtocolError { .. } => "PROTOCOL_ERROR",
+            ReaderError::ReadBackFailed => "READ_BACK_FAILED",
+            ReaderError::InvalidBlock { .. } => "INVALID_BLOCK",
+            ReaderError::InvalidInput(_) => "INVALID_INPUT",
+            ReaderError::NotNdef => "NOT_NDEF",
+            ReaderError::InvalidNdef(_) => "INVALID_NDEF",
+            ReaderError::UnsupportedCard => "UNSUPPORTED_CARD",
+            ReaderError::InsufficientFunds { .. } => "INSUFFICIENT_FUNDS",
+            ReaderError::BalanceLimit { .. } => "BALANCE_LIMIT",
+            ReaderError::ValueTampered => "VALUE_TAMPERED",
+            ReaderError::CardRollback { .. } => "CARD_ROLLBACK",
+            ReaderError::CardBlocked => "CARD_BLOCKED",
+            ReaderError::CardNotRegistered => "CARD_NOT_REGISTERED",
+            ReaderError::InvalidCardholder(_) => "INVALID_CARDHOLDER",
+            ReaderError::KeyNotVerified => "KEY_NOT_VERIFIED",
+        }
+    }
+}
@@ -0,0 +1,80 @@
+// Card events of all the readers: the workers publish the taps, the API the completed
+// transactions, and GET /events streams them to frontends as server-sent events, so they
+// see taps without polling /id. While anyone listens the workers poll their reader for
+// cards between commands.
+use rocket::serde::json::serde_json::Map;
+use rocket::serde::json::Value;
+use rocket::serde::Serialize;
+use rocket::tokio::sync::broadcast;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Events a slow subscriber may fall behind before it misses some
+const CAPACITY: usize = 256;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Event {
+    // card_detected, card_removed, transaction
+    pub kind: &'static str,
+    pub uid: String,
+    pub reader: String,
+    // unix seconds
+    pub timestamp: f64,
+    // of a transaction: transaction_id, command, amount, before, after, counter
+    #[serde(flatten)]
+    pub details: Map<String, Value>,
+}
+
+impl Event {
+    pub fn new(kind: &'static str, uid: String, reader: &str) -> Self {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        Event {
+            kind,
+            uid,
+            reader: reader.to_string(),
+            timestamp: timestamp.as_millis() as f64 / 1000.0,
+            details: Map::new(),
+        }
+    }
+}
+
+// Clones share the subscribers
+#[derive(Clone)]
+pub struct Events {
+    sender: broadcast::Sender<Event>,
+    // card_detected of the card seen last, by any reader
+    last_card: Arc<Mutex<Option<Event>>>,
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Events {
+            sender: broadcast::channel(CAPACITY).0,
+            last_card: Arc::default(),
+        }
+    }
+}
+
+impl Events {
+    pub fn publish(&self, event: Event) {
+        tracing::debug!(kind = event.kind, uid = event.uid, reader = event.reader, "card event");
+        if event.kind == "card_detected" {
+            *self.last_card.lock().unwrap() = Some(event.clone());
+        }
+        // nobody listening
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    pub fn last_card(&self) -> Option<Event> {
+        self.last_card.lock().unwrap().clone()
+    }
+
+    // Whether polling for cards is worth it
+    pub fn listening(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+}
@@ -0,0 +1,171 @@
+// Export of the journal for accounting imports, GET /transactions: the entries matching the
+// filters in the journal's order, as CSV or as the JSON of GET /journal, streamed line by
+// line instead of read into memory. A page ends after `limit` entries, the next one starts
+// at ?after= the last transaction_id of the page; the order never changes as the journal is
+// only appended to.
+use crate::audit::Filter;
+use crate::logging;
+use rocket::futures::{Stream, StreamExt};
+use rocket::http::ContentType;
+use rocket::request::Request;
+use rocket::response::stream::{stream, ReaderStream};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::{json, serde_json, Value};
+use rocket::tokio::fs;
+use rocket::tokio::io::{AsyncBufReadExt, BufReader};
+use std::fs::File;
+use std::io::Cursor;
+
+// Entries of a page by default, and at most
+pub const PAGE: usize = 1000;
+pub const MAX_PAGE: usize = 10_000;
+
+const COLUMNS: [&str; 13] =
+    ["timestamp", "transaction_id", "request_id", "reader", "uid", "command", "amount", "before", "after", "counter", "status", "code", "caller"];
+
+#[derive(Clone, Copy)]
+pub enum Format {
+    Csv,
+    Json,
+}
+
+impl Format {
+    // JSON when not given
+    pub fn parse(format: Option<&str>) -> Option<Self> {
+        match format {
+            Some("csv") => Some(Format::Csv),
+            Some("json") | None => Some(Format::Json),
+            Some(_) => None,
+        }
+    }
+}
+
+pub struct Export {
+    // None while the journal is empty
+    pub file: Option<File>,
+    pub format: Format,
+    pub uid: Option<String>,
+    pub status: Option<bool>,
+    // unix seconds, inclusive
+    pub from: Option<f64>,
+    pub to: Option<f64>,
+    // transaction_id the page starts after
+    pub after: Option<String>,
+    pub limit: usize,
+}
+
+impl Export {
+    // The page as CSV lines, or as {status, data: {entries, next}, request_id} with the
+    // transaction_id to continue after in `next` (null on the last page)
+    fn rows(self, request_id: String) -> impl Stream<Item = Vec<u8>> {
+        stream! {
+            let Export { file, format, uid, status, from, to, after, limit } = self;
+            match format {
+                Format::Csv => yield format!("{}\r\n", COLUMNS.join(",")).into_bytes(),
+                Format::Json => yield br#"{"status":true,"data":{"entries":["#.to_vec(),
+            }
+            let filter = Filter { uid: uid.as_deref(), status, since: from, until: to, ..Filter::default() };
+            let (mut sent, mut more, mut last) = (0, false, None);
+            if let Some(file) = file {
+                let mut lines = BufReader::new(fs::File::from_std(file)).lines();
+                let mut skipping = after.is_some();
+                loop {
+                    let line = match lines.next_line().await {
+                        Ok(Some(line)) => line,
+                        Ok(None) => break,
+                        Err(e) => {
+                            // the status is sent already, the page ends short
+                            tracing::error!(error = %e, "can't read the journal");
+                            break;
+                        }
+                    };
+                    let Ok(entry) = serde_json::from_str::<Value>(&line) else {
+                        continue;
+                    };
+                    if skipping {
+                        skipping = entry["transaction_id"].as_str() != after.as_deref();
+                        continue;
+                    }
+                    if !filter.matches(&entry) {
+                        continue;
+                    }
+                    if sent == limit {
+                        more = true;
+                        break;
+                    }
+                    yield match format {
+                        Format::Csv => csv_line(&entry).into_bytes(),
+                        Format::Json if sent == 0 => entry.to_string().into_bytes(),
+                        Format::Json => format!(",{}", entry).into_bytes(),
+                    };
+                    sent += 1;
+                    last = entry["transaction_id"].as_str().map(str::to_string);
+                }
+            }
+            if let Format::Json = format {
+                yield format!(r#"],"next":{}}},"request_id":{}}}"#, json!(last.filter(|_| more)), json!(request_id)).into_bytes();
+            }
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Export {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let request_id = logging::request_span(request).id.clone();
+        let format = self.format;
+        let content_type = match format {
+            Format::Csv => ContentType::CSV,
+            Format::Json => ContentType::JSON,
+        };
+        let mut response = Response::build();
+        response.header(content_type).streamed_body(ReaderStream::from(self.rows(request_id).map(Cursor::new)));
+        if let Format::Csv = format {
+            response.raw_header("Content-Disposition", "attachment; filename=\"transactions.csv\"");
+        }
+        response.ok()
+    }
+}
+
+// One entry as a CSV line (RFC 4180), the timestamp in UTC as RFC 3339
+fn csv_line(entry: &Value) -> String {
+    let fields: Vec<String> = COLUMNS
+        .iter()
+        .map(|&column| match (column, &entry[column]) {
+            ("timestamp", Value::Number(timestamp)) => utc(timestamp.as_f64().unwrap_or_default()),
+            (_, Value::Null) => String::new(),
+            (_, Value::String(text)) => text.clone(),
+            (_, other) => other.to_string(),
+        })
+        .map(|field| match field.contains([',', '"', '\r', '\n']) {
+            true => format!("\"{}\"", field.replace('"', "\"\"")),
+            false => field,
+        })
+        .collect();
+    format!("{}\r\n", fields.join(","))
+}
+
+// e.g. 2026-10-14T09:30:12.345Z
+fn utc(timestamp: f64) -> String {
+    let millis = (timestamp * 1000.0).round() as i64;
+    let (days, millis) = (millis.div_euclid(86_400_000), millis.rem_euclid(86_400_000));
+    // the civil date of the days since 1970-01-01, in 400 year eras from 0000-03-01
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000
+    )
+}
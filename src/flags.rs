@@ -0,0 +1,84 @@
+// Command-line flags of the server, for containers and systemd units that would rather not
+// ship an app.toml or .env: each flag is the last configuration layer, above the ER302_*
+// variables. The flags are parsed once at launch, the test-suite runs without them.
+use er302::args::{Arg, Spec};
+use std::sync::OnceLock;
+
+pub const USAGE: &str = "usage: ER302-API-Bartarandishan [flags]
+
+  --config <file>        configuration file instead of app.toml (ER302_CONFIG)
+  --serial <portname>    serial.portname, e.g. /dev/ttyUSB0, auto or tcp://host:port
+  --baud <rate>          serial.baudrate
+  --port <port>          api.port the API listens on
+  --log-level <level>    error, warn, info, debug or trace (ER302_LOG_LEVEL)
+  --mock                 simulated readers, like READER_MODE=mock
+  --tui                  terminal dashboard instead of the log
+  --set <key>=<value>    any other setting, e.g. --set card.sector=3";
+
+const SPEC: Spec = Spec {
+    switches: &["--mock", "--tui", "--help", "-h"],
+    values: &["--config", "--serial", "--baud", "--port", "--log-level", "--set"],
+};
+
+static FLAGS: OnceLock<Flags> = OnceLock::new();
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Flags {
+    pub config: Option<String>,
+    // (key, value) of --serial, --baud, --port and --set, in their order
+    pub overrides: Vec<(String, String)>,
+    pub log_level: Option<tracing::Level>,
+    pub mock: bool,
+    pub tui: bool,
+    pub help: bool,
+}
+
+impl Flags {
+    pub fn parse(args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut flags = Flags::default();
+        for arg in SPEC.parse(args)? {
+            match arg {
+                Arg::Switch(name) => match name.as_str() {
+                    "--mock" => flags.mock = true,
+                    "--tui" => flags.tui = true,
+                    _ => flags.help = true,
+                },
+                Arg::Value(name, value) => flags.set(&name, value)?,
+                Arg::Positional(arg) => return Err(format!("unknown flag: {}", arg)),
+            }
+        }
+        Ok(flags)
+    }
+
+    fn set(&mut self, name: &str, value: String) -> Result<(), String> {
+        let number = |key: &str| match value.parse::<u32>() {
+            Ok(_) => Ok((key.to_string(), value.clone())),
+            Err(_) => Err(format!("{}: not a number: {}", name, value)),
+        };
+        match name {
+            "--config" => self.config = Some(value),
+            "--serial" => self.overrides.push(("serial.portname".to_string(), value)),
+            "--baud" => self.overrides.push(number("serial.baudrate")?),
+            "--port" => self.overrides.push(number("api.port")?),
+            "--log-level" => {
+                self.log_level = Some(value.parse().map_err(|_| format!("{}: error, warn, info, debug or trace", name))?)
+            }
+            _ => match value.split_once('=') {
+                Some((key, setting)) if !key.trim().is_empty() => {
+                    self.overrides.push((key.trim().to_string(), setting.to_string()))
+                }
+                _ => return Err(format!("--set {}: expected key=value", value)),
+            },
+        }
+        Ok(())
+    }
+
+    // Those of the launch, none before it or under the test-suite
+    pub fn get() -> Flags {
+        FLAGS.get().cloned().unwrap_or_default()
+    }
+
+    pub fn install(self) {
+        let _ = FLAGS.set(self);
+    }
+}
@@ -0,0 +1,524 @@
+// gRPC beside the REST API for POS software that would rather have typed calls and a stream
+// of the card events: the CardService of proto/er302.proto on `[grpc] port`, plaintext
+// HTTP/2. The calls are authorized, journaled and audited like the routes they mirror (id,
+// read_balance, increase / decrease, initcard, events). The protobuf messages are encoded
+// here by hand, the few fields they have don't need a code generator.
+use crate::auth::ApiKeys;
+use crate::calls::{Backend, Origin};
+use crate::events::Event;
+use crate::worker::{Options, ReaderCommand};
+use crate::{logging, v2, ApiResponse};
+use er302::ReaderError;
+use hyper::body::{Bytes, HttpBody};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, HeaderMap, Request, Response, Server};
+use rocket::fairing::AdHoc;
+use rocket::http::Status;
+use rocket::serde::json::{self, Value};
+use rocket::serde::Deserialize;
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::{self, error::RecvError};
+use rocket::Shutdown;
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::field::Empty;
+use tracing::Instrument;
+
+const SERVICE: &str = "/er302.v1.CardService/";
+// Longest request message taken, the requests are a few bytes
+const MAX_MESSAGE: usize = 64 * 1024;
+
+// gRPC status codes
+const OK: u16 = 0;
+const INVALID_ARGUMENT: u16 = 3;
+const RESOURCE_EXHAUSTED: u16 = 8;
+const UNIMPLEMENTED: u16 = 12;
+const INTERNAL: u16 = 13;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct GrpcConfig {
+    // on api.host
+    pub port: u16,
+}
+
+// What the calls need of the server, cloned from its managed state once it's up
+struct Service {
+    backend: Backend,
+    keys: ApiKeys,
+}
+
+// Why a call failed, with the error code of the REST API when there is one
+struct Failure {
+    status: u16,
+    message: String,
+    code: Option<&'static str>,
+}
+
+impl Failure {
+    fn new(status: u16, message: impl Into<String>) -> Self {
+        Failure { status, message: message.into(), code: None }
+    }
+
+    fn reader(error: ReaderError) -> Self {
+        Failure {
+            status: grpc_status(v2::status(error.code())),
+            message: error.to_string(),
+            code: Some(error.code()),
+        }
+    }
+}
+
+// A unary call answers one message, StreamEvents the events of a reader (all when None)
+enum Answer {
+    Message(Vec<u8>),
+    Events(broadcast::Receiver<Event>, Option<String>),
+}
+
+// Listens once the server is up, until it shuts down
+pub fn serve(config: GrpcConfig, host: IpAddr) -> AdHoc {
+    AdHoc::on_liftoff("gRPC", move |rocket| {
+        Box::pin(async move {
+            let address = SocketAddr::new(host, config.port);
+            let builder = match Server::try_bind(&address) {
+                Ok(builder) => builder,
+                Err(e) => {
+                    tracing::error!(address = %address, error = %e, "can't listen for gRPC");
+                    return;
+                }
+            };
+            let service = Arc::new(Service {
+                backend: Backend::new(rocket),
+                keys: rocket.state::<ApiKeys>().expect("API keys are managed").clone(),
+            });
+            let shutdown = rocket.shutdown();
+            let connections = make_service_fn(move |connection: &AddrStream| {
+                let (service, client, shutdown) = (service.clone(), connection.remote_addr().ip(), shutdown.clone());
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request| call(service.clone(), client, shutdown.clone(), request)))
+                }
+            });
+            tracing::info!(address = %address, "gRPC listening");
+            let server = builder.http2_only(true).serve(connections).with_graceful_shutdown(rocket.shutdown());
+            rocket::tokio::spawn(async move {
+                if let Err(e) = server.await {
+                    tracing::error!(error = %e, "gRPC server failed");
+                }
+            });
+        })
+    })
+}
+
+async fn call(service: Arc<Service>, client: IpAddr, shutdown: Shutdown, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let grpc = request
+        .headers()
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/grpc"));
+    if !grpc {
+        let mut response = Response::new(Body::from("not a gRPC call"));
+        *response.status_mut() = hyper::StatusCode::UNSUPPORTED_MEDIA_TYPE;
+        return Ok(response);
+    }
+    let header = |name: &str| request.headers().get(name).and_then(|value| value.to_str().ok()).map(str::to_string);
+    let (authorization, idempotency_key, traceparent) = (header("authorization"), header("idempotency-key"), header("traceparent"));
+    let method = request.uri().path().strip_prefix(SERVICE).unwrap_or_default().to_string();
+    let mut origin = Origin {
+        request_id: logging::new_uuid(),
+        method: "POST",
+        endpoint: request.uri().path().to_string(),
+        route: format!("grpc.{}", method),
+        client: Some(client),
+        caller: None,
+    };
+    let span = tracing::info_span!(
+        "grpc",
+        request_id = %origin.request_id,
+        method,
+        traceparent,
+        caller = Empty,
+        status = Empty
+    );
+    let start = Instant::now();
+    let answer = async {
+        let route = route(&method).ok_or_else(|| Failure::new(UNIMPLEMENTED, format!("unknown method {}", method)))?;
+        origin.caller = service.keys.check(Some(client), authorization.as_deref(), route).map_err(|(status, reason)| {
+            if status == Status::Forbidden {
+                service.backend.audit.refused(&origin, reason);
+            }
+            Failure {
+                status: grpc_status(status),
+                message: reason.to_string(),
+                code: Some(if status == Status::Unauthorized { "UNAUTHORIZED" } else { "FORBIDDEN" }),
+            }
+        })?;
+        if let Some(caller) = &origin.caller {
+            tracing::Span::current().record("caller", caller.as_str());
+        }
+        let message = read_message(request.into_body()).await?;
+        service.answer(&origin, &method, decode(&message)?, idempotency_key.as_deref()).await
+    }
+    .instrument(span.clone())
+    .await;
+    let status = answer.as_ref().err().map_or(OK, |failure| failure.status);
+    tracing::info!(parent: &span, status, elapsed_ms = start.elapsed().as_millis() as u64, "call done");
+    span.record("status", status);
+    Ok(respond(answer, shutdown))
+}
+
+// REST route a method is authorized as
+fn route(method: &str) -> Option<&'static str> {
+    match method {
+        "ReadId" => Some("id"),
+        "ReadBalance" => Some("read_balance"),
+        // decreases need the same role
+        "Adjust" => Some("increase"),
+        "InitCard" => Some("initcard"),
+        "StreamEvents" => Some("card_events"),
+        _ => None,
+    }
+}
+
+impl Service {
+    async fn answer(&self, origin: &Origin, method: &str, request: Fields, idempotency_key: Option<&str>) -> Result<Answer, Failure> {
+        let backend = &self.backend;
+        let reader = request.text(1)?;
+        let (sector, block) = (request.byte(2)?, request.byte(3)?);
+        let send = |command: ReaderCommand| async {
+            let body = backend.send(origin, &reader, command, Options::default()).await;
+            succeeded(&body).map(|_| body)
+        };
+        match method {
+            "ReadId" => {
+                let body = send(ReaderCommand::ReadId).await?;
+                Ok(Answer::Message(Message::default().text(1, &uid(&body)).0))
+            }
+            "ReadBalance" => {
+                let block = backend.value_block(sector, block).map_err(Failure::reader)?;
+                let body = send(ReaderCommand::ReadBalance(block)).await?;
+                Ok(Answer::Message(self.balance(&body)))
+            }
+            "Adjust" => {
+                let amount = request.number(4).unwrap_or_default() as i64;
+                let body = backend.adjust(origin, &reader, sector, block, amount, idempotency_key).await;
+                succeeded(&body)?;
+                Ok(Answer::Message(self.balance(&body)))
+            }
+            "InitCard" => {
+                let block = backend.value_block(sector, None).map_err(Failure::reader)?;
+                let body = send(ReaderCommand::InitCard(block, None)).await?;
+                let message = body.data.as_str().unwrap_or_default();
+                Ok(Answer::Message(Message::default().text(1, &uid(&body)).text(2, message).0))
+            }
+            "StreamEvents" => {
+                // an empty reader is every reader here, like GET /events without ?reader
+                if !reader.is_empty() {
+                    backend.worker(&reader).map_err(Failure::reader)?;
+                }
+                let filter = Some(reader).filter(|reader| !reader.is_empty());
+                Ok(Answer::Events(backend.events.subscribe(), filter))
+            }
+            _ => Err(Failure::new(UNIMPLEMENTED, format!("unknown method {}", method))),
+        }
+    }
+
+    // Balance of a balance read or of an increase / decrease receipt
+    fn balance(&self, body: &ApiResponse) -> Vec<u8> {
+        let (balance, previous) = match &body.data {
+            Value::String(text) => (text.parse().unwrap_or_default(), None),
+            receipt => (receipt["new_balance"].as_u64().unwrap_or_default(), receipt["previous_balance"].as_u64()),
+        };
+        let formatted = self.backend.currency.as_ref().map(|currency| currency.format(balance as u32)).unwrap_or_default();
+        Message::default()
+            .text(1, &uid(body))
+            .number(2, balance)
+            .text(3, &formatted)
+            .text(4, body.transaction_id.as_deref().unwrap_or_default())
+            .optional(5, previous)
+            .optional(6, body.counter.map(u64::from))
+            .0
+    }
+}
+
+fn uid(body: &ApiResponse) -> String {
+    body.uid.clone().or_else(|| body.data.as_str().map(str::to_string)).unwrap_or_default()
+}
+
+fn succeeded(body: &ApiResponse) -> Result<(), Failure> {
+    match body.code {
+        Some(code) => Err(Failure {
+            status: grpc_status(v2::status(code)),
+            message: body.data.as_str().unwrap_or_default().to_string(),
+            code: Some(code),
+        }),
+        None => Ok(()),
+    }
+}
+
+// The gRPC status of the HTTP status /v2 answers an error with
+fn grpc_status(status: Status) -> u16 {
+    match status.code {
+        400 => INVALID_ARGUMENT,
+        // UNAUTHENTICATED, PERMISSION_DENIED, NOT_FOUND
+        401 => 16,
+        403 => 7,
+        404 => 5,
+        // FAILED_PRECONDITION: no funds, the wrong key, a call with the key still running
+        409 | 422 | 428 => 9,
+        // UNAVAILABLE: the reader is gone, busy or answered garbage
+        502 | 503 => 14,
+        // DEADLINE_EXCEEDED
+        504 => 4,
+        _ => INTERNAL,
+    }
+}
+
+// The message of a request's one length-prefixed frame
+async fn read_message(mut body: Body) -> Result<Vec<u8>, Failure> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| Failure::new(INTERNAL, e.to_string()))?;
+        if bytes.len() + chunk.len() > MAX_MESSAGE + 5 {
+            return Err(Failure::new(RESOURCE_EXHAUSTED, "request message too large"));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    match bytes.as_slice() {
+        [] => Ok(Vec::new()),
+        [0, rest @ ..] if rest.len() >= 4 => {
+            let (length, message) = rest.split_at(4);
+            match u32::from_be_bytes(length.try_into().unwrap_or_default()) as usize == message.len() {
+                true => Ok(message.to_vec()),
+                false => Err(Failure::new(INTERNAL, "request frame length doesn't match")),
+            }
+        }
+        [1, ..] => Err(Failure::new(UNIMPLEMENTED, "compressed messages aren't supported")),
+        _ => Err(Failure::new(INTERNAL, "not a gRPC frame")),
+    }
+}
+
+fn respond(answer: Result<Answer, Failure>, mut shutdown: Shutdown) -> Response<Body> {
+    let answer = match answer {
+        Ok(answer) => answer,
+        // trailers-only, the status in the headers and no message
+        Err(failure) => {
+            let mut response = Response::new(Body::empty());
+            response.headers_mut().extend(grpc_headers());
+            response.headers_mut().extend(trailers(Some(&failure)));
+            return response;
+        }
+    };
+    let (mut sender, body) = Body::channel();
+    rocket::tokio::spawn(async move {
+        match answer {
+            Answer::Message(message) => {
+                if sender.send_data(frame(&message)).await.is_err() {
+                    return;
+                }
+            }
+            Answer::Events(mut events, reader) => loop {
+                select! {
+                    event = events.recv() => match event {
+                        Ok(event) if reader.as_ref().is_none_or(|reader| *reader == event.reader) => {
+                            // the call was cancelled
+                            if sender.send_data(frame(&event_message(&event))).await.is_err() {
+                                return;
+                            }
+                        }
+                        Ok(_) | Err(RecvError::Lagged(_)) => (),
+                        Err(RecvError::Closed) => break,
+                    },
+                    _ = &mut shutdown => break,
+                }
+            },
+        }
+        let _ = sender.send_trailers(trailers(None)).await;
+    });
+    let mut response = Response::new(body);
+    response.headers_mut().extend(grpc_headers());
+    response
+}
+
+fn grpc_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", "application/grpc".parse().unwrap());
+    headers
+}
+
+// grpc-status and grpc-message, plus er302-code with the REST API's error code
+fn trailers(failure: Option<&Failure>) -> HeaderMap {
+    let mut trailers = HeaderMap::new();
+    let status = failure.map_or(OK, |failure| failure.status);
+    trailers.insert("grpc-status", status.into());
+    if let Some(failure) = failure {
+        if let Ok(message) = percent_encode(&failure.message).parse() {
+            trailers.insert("grpc-message", message);
+        }
+        if let Some(code) = failure.code {
+            trailers.insert("er302-code", code.parse().unwrap());
+        }
+    }
+    trailers
+}
+
+// grpc-message is percent-encoded UTF-8
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|byte| match byte {
+            b' '..=b'~' if byte != b'%' => char::from(byte).to_string(),
+            byte => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+fn frame(message: &[u8]) -> Bytes {
+    let mut frame = vec![0];
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(message);
+    Bytes::from(frame)
+}
+
+fn event_message(event: &Event) -> Vec<u8> {
+    let details = match event.details.is_empty() {
+        true => String::new(),
+        false => json::to_string(&event.details).unwrap_or_default(),
+    };
+    Message::default()
+        .text(1, event.kind)
+        .text(2, &event.uid)
+        .text(3, &event.reader)
+        .double(4, event.timestamp)
+        .text(5, &details)
+        .0
+}
+
+// Fields of a protobuf message by number, the last one of a number counts: varints,
+// fixed32 and fixed64 as numbers, length-delimited ones as bytes
+#[derive(Default)]
+struct Fields {
+    numbers: BTreeMap<u32, u64>,
+    bytes: BTreeMap<u32, Vec<u8>>,
+}
+
+impl Fields {
+    fn number(&self, field: u32) -> Option<u64> {
+        self.numbers.get(&field).copied()
+    }
+
+    fn byte(&self, field: u32) -> Result<Option<u8>, Failure> {
+        self.number(field)
+            .map(|number| u8::try_from(number).map_err(|_| Failure::reader(ReaderError::InvalidInput(format!("field {} is out of range", field)))))
+            .transpose()
+    }
+
+    fn text(&self, field: u32) -> Result<String, Failure> {
+        let bytes = self.bytes.get(&field).cloned().unwrap_or_default();
+        String::from_utf8(bytes).map_err(|_| Failure::new(INVALID_ARGUMENT, format!("field {} isn't UTF-8", field)))
+    }
+}
+
+fn decode(mut message: &[u8]) -> Result<Fields, Failure> {
+    let invalid = || Failure::new(INTERNAL, "invalid protobuf message");
+    let mut fields = Fields::default();
+    while !message.is_empty() {
+        let key = varint(&mut message).ok_or_else(invalid)?;
+        let field = u32::try_from(key >> 3).map_err(|_| invalid())?;
+        match key & 7 {
+            0 => {
+                let value = varint(&mut message).ok_or_else(invalid)?;
+                fields.numbers.insert(field, value);
+            }
+            1 => {
+                let value = take(&mut message, 8).ok_or_else(invalid)?;
+                fields.numbers.insert(field, u64::from_le_bytes(value.try_into().unwrap_or_default()));
+            }
+            2 => {
+                let length = varint(&mut message).ok_or_else(invalid)?;
+                let value = take(&mut message, usize::try_from(length).map_err(|_| invalid())?).ok_or_else(invalid)?;
+                fields.bytes.insert(field, value.to_vec());
+            }
+            5 => {
+                let value = take(&mut message, 4).ok_or_else(invalid)?;
+                fields.numbers.insert(field, u64::from(u32::from_le_bytes(value.try_into().unwrap_or_default())));
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(fields)
+}
+
+fn take<'a>(message: &mut &'a [u8], length: usize) -> Option<&'a [u8]> {
+    let (taken, rest) = message.split_at_checked(length)?;
+    *message = rest;
+    Some(taken)
+}
+
+// Base 128, least significant group first
+fn varint(message: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = message.split_first()?;
+        *message = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+// A protobuf message, proto3 leaves out the fields with their default value
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn key(&mut self, field: u32, wire_type: u8) {
+        self.varint(u64::from(field) << 3 | u64::from(wire_type));
+    }
+
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn text(mut self, field: u32, text: &str) -> Self {
+        if !text.is_empty() {
+            self.key(field, 2);
+            self.varint(text.len() as u64);
+            self.0.extend_from_slice(text.as_bytes());
+        }
+        self
+    }
+
+    fn number(mut self, field: u32, value: u64) -> Self {
+        if value != 0 {
+            self.key(field, 0);
+            self.varint(value);
+        }
+        self
+    }
+
+    // an `optional` field, present even when 0
+    fn optional(mut self, field: u32, value: Option<u64>) -> Self {
+        if let Some(value) = value {
+            self.key(field, 0);
+            self.varint(value);
+        }
+        self
+    }
+
+    fn double(mut self, field: u32, value: f64) -> Self {
+        if value != 0.0 {
+            self.key(field, 1);
+            self.0.extend_from_slice(&value.to_le_bytes());
+        }
+        self
+    }
+}
@@ -0,0 +1,137 @@
+// `Idempotency-Key` of the increases / decreases: a kiosk that timed out and sends the same
+// request again gets the answer of the first one instead of charging the card twice.
+use crate::{ApiResponse, Reply};
+use rocket::http::Header;
+use rocket::serde::json::Json;
+use rocket::request::{FromRequest, Outcome, Request};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// How long a key is remembered, and how many at most (the oldest are forgotten first)
+const KEEP: Duration = Duration::from_secs(24 * 60 * 60);
+const MAX_KEYS: usize = 10_000;
+const MAX_KEY_LENGTH: usize = 255;
+
+// Failures that certainly didn't change the card, the key may be used again after them
+const RETRYABLE: [&str; 13] = [
+    "NO_CARD",
+    "BUSY",
+    // the command never started
+    "DEADLINE_EXCEEDED",
+    "OPEN_TIMEOUT",
+    "AUTH_FAILED",
+    "INVALID_INPUT",
+    "INVALID_BLOCK",
+    "INSUFFICIENT_FUNDS",
+    "BALANCE_LIMIT",
+    "VALUE_TAMPERED",
+    "CARD_ROLLBACK",
+    "CARD_BLOCKED",
+    "CARD_NOT_REGISTERED",
+];
+
+// Failures that leave it unknown whether the card changed, not kept either: a replayed error
+// would tell the retry the change failed when it may have gone through
+const UNKNOWN: [&str; 6] = ["TIMEOUT", "PORT_ERROR", "INVALID_FRAME", "CHECKSUM_MISMATCH", "PROTOCOL_ERROR", "READ_BACK_FAILED"];
+
+struct Entry {
+    // what the request asked for, a key can't be reused for another amount or card block
+    fingerprint: String,
+    created: Instant,
+    // None while the first request is still running
+    answer: Option<ApiResponse>,
+}
+
+// Clones share the keys
+#[derive(Clone, Default)]
+pub struct Idempotency {
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+// Why a request with a key wasn't run
+pub enum Refused {
+    InvalidKey,
+    // same key, still running
+    InProgress,
+    // same key, another request
+    Mismatch,
+}
+
+impl Idempotency {
+    // Run `request` once per key, retries get the answer of the first one
+    pub async fn run<F>(&self, key: Option<&str>, fingerprint: String, request: F) -> Result<Reply, Refused>
+    where
+        F: Future<Output = Reply>,
+    {
+        let Some(key) = key else {
+            return Ok(request.await);
+        };
+        if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+            return Err(Refused::InvalidKey);
+        }
+        {
+            let mut entries = self.entries.lock().unwrap();
+            entries.retain(|_, entry| entry.created.elapsed() < KEEP);
+            if let Some(entry) = entries.get(key) {
+                return match (&entry.answer, entry.fingerprint == fingerprint) {
+                    (_, false) => Err(Refused::Mismatch),
+                    (None, true) => Err(Refused::InProgress),
+                    (Some(answer), true) => Ok(Reply {
+                        body: Json(answer.clone()),
+                        queue_wait: Header::new("X-Queue-Wait-Ms", "0"),
+                    }),
+                };
+            }
+            if entries.len() >= MAX_KEYS {
+                let oldest = entries.iter().min_by_key(|(_, entry)| entry.created).map(|(key, _)| key.clone());
+                entries.remove(&oldest.unwrap_or_default());
+            }
+            let entry = Entry {
+                fingerprint,
+                created: Instant::now(),
+                answer: None,
+            };
+            entries.insert(key.to_string(), entry);
+        }
+        let mut running = Running { idempotency: self, key, done: false };
+        let reply = request.await;
+        let kept = !reply.body.code.is_some_and(|code| RETRYABLE.contains(&code) || UNKNOWN.contains(&code));
+        if kept {
+            if let Some(entry) = self.entries.lock().unwrap().get_mut(key) {
+                entry.answer = Some(reply.body.0.clone());
+            }
+            running.done = true;
+        }
+        Ok(reply)
+    }
+}
+
+// Forgets the key unless the request finished with an answer to keep, also when the
+// handler is dropped halfway
+struct Running<'a> {
+    idempotency: &'a Idempotency,
+    key: &'a str,
+    done: bool,
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.idempotency.entries.lock().unwrap().remove(self.key);
+        }
+    }
+}
+
+// The `Idempotency-Key` header, None without one
+pub struct IdempotencyKey<'r>(pub Option<&'r str>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IdempotencyKey<'r> {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(IdempotencyKey(request.headers().get_one("Idempotency-Key")))
+    }
+}
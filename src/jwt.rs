@@ -0,0 +1,288 @@
+// JWT validation for HS256 (shared secret) and RS256 (RSA public key), with just the
+// RSA verification it needs
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use rocket::serde::json::{serde_json, Value};
+use std::cmp::Ordering;
+
+pub use er302::hmac::{constant_time_eq, hmac_sha256, sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Clock skew accepted on exp / nbf, in seconds
+const LEEWAY: f64 = 30.0;
+
+// Big unsigned integer, little endian 32 bit limbs without leading zeros
+#[derive(Clone, Debug, PartialEq)]
+struct Natural(Vec<u32>);
+
+impl Natural {
+    fn from_be_bytes(bytes: &[u8]) -> Self {
+        let mut limbs: Vec<u32> = bytes
+            .rchunks(4)
+            .map(|chunk| chunk.iter().fold(0, |limb, &byte| limb << 8 | u32::from(byte)))
+            .collect();
+        while limbs.last() == Some(&0) {
+            limbs.pop();
+        }
+        Natural(limbs)
+    }
+
+    // `length` bytes, None when the number doesn't fit
+    fn to_be_bytes(&self, length: usize) -> Option<Vec<u8>> {
+        let mut bytes: Vec<u8> = self.0.iter().rev().flat_map(|limb| limb.to_be_bytes()).collect();
+        let zeros = bytes.iter().take_while(|&&byte| byte == 0).count();
+        bytes.drain(..zeros);
+        if bytes.len() > length {
+            return None;
+        }
+        let mut padded = vec![0; length - bytes.len()];
+        padded.extend_from_slice(&bytes);
+        Some(padded)
+    }
+
+    fn bits(&self) -> usize {
+        match self.0.last() {
+            Some(last) => self.0.len() * 32 - last.leading_zeros() as usize,
+            None => 0,
+        }
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        self.0.get(index / 32).is_some_and(|limb| limb >> (index % 32) & 1 == 1)
+    }
+
+    fn compare(&self, other: &Natural) -> Ordering {
+        self.0.len().cmp(&other.0.len()).then_with(|| self.0.iter().rev().cmp(other.0.iter().rev()))
+    }
+
+    fn multiply(&self, other: &Natural) -> Natural {
+        let mut product = vec![0u32; self.0.len() + other.0.len()];
+        for (i, &a) in self.0.iter().enumerate() {
+            let mut carry = 0u64;
+            for (j, &b) in other.0.iter().enumerate() {
+                let sum = u64::from(product[i + j]) + u64::from(a) * u64::from(b) + carry;
+                product[i + j] = sum as u32;
+                carry = sum >> 32;
+            }
+            product[i + other.0.len()] = carry as u32;
+        }
+        let mut product = Natural(product);
+        product.trim();
+        product
+    }
+
+    fn trim(&mut self) {
+        while self.0.last() == Some(&0) {
+            self.0.pop();
+        }
+    }
+
+    // self = self * 2 + bit
+    fn shift_in(&mut self, bit: bool) {
+        let mut carry = u32::from(bit);
+        for limb in self.0.iter_mut() {
+            let next = *limb >> 31;
+            *limb = *limb << 1 | carry;
+            carry = next;
+        }
+        if carry != 0 {
+            self.0.push(carry);
+        }
+    }
+
+    // self -= other, other <= self
+    fn subtract(&mut self, other: &Natural) {
+        let mut borrow = 0i64;
+        for (i, limb) in self.0.iter_mut().enumerate() {
+            let difference = i64::from(*limb) - i64::from(other.0.get(i).copied().unwrap_or(0)) - borrow;
+            borrow = i64::from(difference < 0);
+            *limb = difference.rem_euclid(1 << 32) as u32;
+        }
+        self.trim();
+    }
+
+    // Bit by bit, slow but plenty for one signature per request
+    fn remainder(&self, modulus: &Natural) -> Natural {
+        let mut remainder = Natural(Vec::new());
+        for index in (0..self.bits()).rev() {
+            remainder.shift_in(self.bit(index));
+            if remainder.compare(modulus) != Ordering::Less {
+                remainder.subtract(modulus);
+            }
+        }
+        remainder
+    }
+
+    fn modpow(&self, exponent: &Natural, modulus: &Natural) -> Natural {
+        let base = self.remainder(modulus);
+        let mut result = Natural(vec![1]).remainder(modulus);
+        for index in (0..exponent.bits()).rev() {
+            result = result.multiply(&result).remainder(modulus);
+            if exponent.bit(index) {
+                result = result.multiply(&base).remainder(modulus);
+            }
+        }
+        result
+    }
+}
+
+pub struct RsaPublicKey {
+    modulus: Natural,
+    exponent: Natural,
+    // modulus length in bytes
+    size: usize,
+}
+
+// One DER element: (tag, content, rest)
+fn der(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first, mut input) = input.split_first()?;
+    let length = match first {
+        0..=0x7f => usize::from(first),
+        0x81..=0x84 => {
+            let count = usize::from(first & 0x7f);
+            let (bytes, rest) = input.split_at_checked(count)?;
+            input = rest;
+            bytes.iter().fold(0, |length, &byte| length << 8 | usize::from(byte))
+        }
+        _ => return None,
+    };
+    let (content, rest) = input.split_at_checked(length)?;
+    Some((tag, content, rest))
+}
+
+const SEQUENCE: u8 = 0x30;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+
+impl RsaPublicKey {
+    // PEM of a SubjectPublicKeyInfo ("PUBLIC KEY") or a PKCS#1 "RSA PUBLIC KEY"
+    pub fn from_pem(pem: &str) -> Result<Self, &'static str> {
+        let body: String = pem.lines().filter(|line| !line.starts_with("-----")).collect();
+        let der = STANDARD.decode(body.trim()).map_err(|_| "public key isn't base64")?;
+        Self::from_der(&der).ok_or("not an RSA public key")
+    }
+
+    fn from_der(input: &[u8]) -> Option<Self> {
+        let (tag, content, _) = der(input)?;
+        if tag != SEQUENCE {
+            return None;
+        }
+        let (tag, first, rest) = der(content)?;
+        let (n, e) = match tag {
+            // RSAPublicKey: n, e
+            INTEGER => (first, der(rest)?.1),
+            // SubjectPublicKeyInfo: algorithm, BIT STRING wrapping an RSAPublicKey
+            SEQUENCE => {
+                let (tag, key, _) = der(rest)?;
+                if tag != BIT_STRING {
+                    return None;
+                }
+                return Self::from_der(key.get(1..)?);
+            }
+            _ => return None,
+        };
+        let modulus = Natural::from_be_bytes(n);
+        let size = modulus.bits().div_ceil(8);
+        Some(RsaPublicKey {
+            modulus,
+            exponent: Natural::from_be_bytes(e),
+            size,
+        })
+    }
+
+    // RSASSA-PKCS1-v1_5 with SHA-256
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        const DIGEST_INFO: [u8; 19] = [
+            0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05, 0x00,
+            0x04, 0x20,
+        ];
+        let signature = Natural::from_be_bytes(signature);
+        if self.size < DIGEST_INFO.len() + 32 + 11 || signature.compare(&self.modulus) != Ordering::Less {
+            return false;
+        }
+        let Some(decoded) = signature.modpow(&self.exponent, &self.modulus).to_be_bytes(self.size) else {
+            return false;
+        };
+        let mut expected = vec![0x00, 0x01];
+        expected.resize(self.size - DIGEST_INFO.len() - 32 - 1, 0xff);
+        expected.push(0x00);
+        expected.extend_from_slice(&DIGEST_INFO);
+        expected.extend_from_slice(&sha256(message));
+        decoded == expected
+    }
+}
+
+// What tokens are checked against, one of both keys is enough
+#[derive(Default)]
+pub struct JwtKeys {
+    pub secret: Option<Vec<u8>>,
+    pub public_key: Option<RsaPublicKey>,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    // tokens without exp are refused, they'd be valid forever
+    pub require_exp: bool,
+}
+
+impl JwtKeys {
+    pub fn enabled(&self) -> bool {
+        self.secret.is_some() || self.public_key.is_some()
+    }
+
+    // Claims of a valid token, or why it was refused
+    pub fn validate(&self, token: &str) -> Result<Value, &'static str> {
+        let mut parts = token.split('.');
+        let (Some(header), Some(payload), Some(signature), None) = (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err("not a JWT");
+        };
+        let decode = |part: &str| URL_SAFE_NO_PAD.decode(part).map_err(|_| "bad base64url");
+        let header: Value = serde_json::from_slice(&decode(header)?).map_err(|_| "bad header")?;
+        let claims: Value = serde_json::from_slice(&decode(payload)?).map_err(|_| "bad payload")?;
+        let signature = decode(signature)?;
+        let signed = &token[..token.rfind('.').unwrap_or_default()];
+        let valid = match (header["alg"].as_str(), &self.secret, &self.public_key) {
+            (Some("HS256"), Some(secret), _) => constant_time_eq(&hmac_sha256(secret, signed.as_bytes()), &signature),
+            (Some("RS256"), _, Some(key)) => key.verify(signed.as_bytes(), &signature),
+            _ => return Err("unsupported alg"),
+        };
+        if !valid {
+            return Err("bad signature");
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64();
+        match time(&claims, "exp")? {
+            Some(exp) if now > exp + LEEWAY => return Err("expired"),
+            None if self.require_exp => return Err("no exp"),
+            _ => {}
+        }
+        if time(&claims, "nbf")?.is_some_and(|nbf| now + LEEWAY < nbf) {
+            return Err("not valid yet");
+        }
+        if let Some(issuer) = &self.issuer {
+            if claims["iss"].as_str() != Some(issuer) {
+                return Err("wrong issuer");
+            }
+        }
+        if let Some(audience) = &self.audience {
+            let matches = match &claims["aud"] {
+                Value::String(aud) => aud == audience,
+                Value::Array(auds) => auds.iter().any(|aud| aud.as_str() == Some(audience)),
+                _ => false,
+            };
+            if !matches {
+                return Err("wrong audience");
+            }
+        }
+        Ok(claims)
+    }
+}
+
+// A NumericDate claim (seconds since the epoch, fractions allowed), None when it's missing;
+// any other type refuses the token rather than skipping the check
+fn time(claims: &Value, name: &str) -> Result<Option<f64>, &'static str> {
+    match &claims[name] {
+        Value::Null => Ok(None),
+        Value::Number(number) => number.as_f64().map(Some).ok_or("bad date claim"),
+        _ => Err("bad date claim"),
+    }
+}
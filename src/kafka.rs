@@ -0,0 +1,417 @@
+// Kafka sink of the completed transactions for settlement and analytics: with `[kafka]` set
+// every transaction event is produced to `topic` as one record, keyed by the card UID with
+// the JSON of the /events data as its value and the reader in an `er302-reader` header.
+// The partition is the one Kafka's default partitioner picks for the key (murmur2), so the
+// records of a card stay in order. Plain TCP to the brokers, the leader is found with a
+// Metadata request. The records go through the spool, so one the brokers don't take is tried
+// again until they do, across restarts, and one they refuse for good is kept in the spool's
+// rejected file.
+use crate::events::Event;
+use crate::spool::{Delivery, Spool};
+use crate::webhooks;
+use rocket::serde::json::{self, Value};
+use rocket::serde::Deserialize;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const PRODUCE: i16 = 0;
+const METADATA: i16 = 3;
+// Error codes worth another try once the metadata is read again: unknown topic or partition,
+// leader not available, not the leader, request timed out, network exception, not enough
+// replicas (before / after the append)
+const RETRIABLE: &[i16] = &[3, 5, 6, 7, 13, 19, 20];
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct KafkaConfig {
+    // host:port of the bootstrap brokers
+    pub brokers: Vec<String>,
+    #[serde(default = "default_topic")]
+    pub topic: String,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    // -1 waits for all the in-sync replicas, 1 for the leader only
+    #[serde(default = "default_acks")]
+    pub acks: i16,
+    // the records not produced yet
+    #[serde(default = "default_spool")]
+    pub spool: PathBuf,
+    // plaintext to brokers on other hosts, off by default
+    #[serde(default)]
+    pub insecure: bool,
+}
+
+fn default_topic() -> String {
+    "er302.transactions".to_string()
+}
+
+fn default_client_id() -> String {
+    "er302-api".to_string()
+}
+
+fn default_acks() -> i16 {
+    -1
+}
+
+fn default_spool() -> PathBuf {
+    PathBuf::from("er302-kafka.spool")
+}
+
+static SPOOL: OnceLock<Arc<Spool>> = OnceLock::new();
+
+pub fn validate(config: &KafkaConfig) -> Result<(), String> {
+    if config.brokers.is_empty() {
+        return Err("brokers is empty".to_string());
+    }
+    if let Some(broker) = config.brokers.iter().find(|broker| broker.rsplit_once(':').is_none_or(|(_, port)| port.parse::<u16>().is_err())) {
+        return Err(format!("{}: not a host:port", broker));
+    }
+    if !matches!(config.acks, -1 | 1) {
+        return Err(format!("acks = {}: -1 or 1", config.acks));
+    }
+    for broker in &config.brokers {
+        webhooks::plaintext(broker, broker, config.insecure)?;
+    }
+    Ok(())
+}
+
+// The transaction event of a completed increase / decrease, nothing without [kafka]
+pub fn record(event: &Event) {
+    if let Some(spool) = SPOOL.get() {
+        if let Err(e) = spool.push(&json::to_value(event).unwrap_or_default()) {
+            tracing::error!(error = %e, uid = event.uid, details = ?event.details, "can't spool the Kafka record");
+        }
+    }
+}
+
+pub fn spawn(config: KafkaConfig) {
+    let spool = match Spool::open(&config.spool) {
+        Ok(spool) => spool,
+        Err(e) => {
+            tracing::error!(file = %config.spool.display(), error = %e, "can't open the Kafka spool, nothing is produced");
+            return;
+        }
+    };
+    if SPOOL.set(spool.clone()).is_err() {
+        return;
+    }
+    thread::Builder::new()
+        .name("er302-kafka".to_string())
+        .spawn(move || {
+            let mut producer = Producer::new(config);
+            spool.deliver(|record| producer.send(record));
+        })
+        .expect("failed to spawn Kafka thread");
+}
+
+// Leaders of the topic's partitions, by partition index
+struct Metadata {
+    leaders: Vec<i32>,
+    // node id -> host:port
+    brokers: HashMap<i32, String>,
+}
+
+struct Producer {
+    config: KafkaConfig,
+    metadata: Option<Metadata>,
+    // to the partition leaders, by node id
+    connections: HashMap<i32, TcpStream>,
+    correlation_id: i32,
+}
+
+impl Producer {
+    fn new(config: KafkaConfig) -> Self {
+        Producer {
+            config,
+            metadata: None,
+            connections: HashMap::new(),
+            correlation_id: 0,
+        }
+    }
+
+    fn send(&mut self, event: &Value) -> Delivery {
+        let uid = event["uid"].as_str().unwrap_or_default();
+        let reader = event["reader"].as_str().unwrap_or_default();
+        let batch = record_batch(uid.as_bytes(), event.to_string().as_bytes(), &[("er302-reader", reader.as_bytes())]);
+        match self.produce(uid.as_bytes(), &batch) {
+            Ok(()) => Delivery::Done,
+            // refusals that another try won't change
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Delivery::Rejected(e.to_string()),
+            Err(e) => {
+                tracing::warn!(error = %e, topic = self.config.topic, "Kafka didn't take the transaction, trying again");
+                self.metadata = None;
+                self.connections.clear();
+                Delivery::Retry
+            }
+        }
+    }
+
+    fn produce(&mut self, key: &[u8], batch: &[u8]) -> io::Result<()> {
+        if self.metadata.is_none() {
+            self.metadata = Some(self.metadata()?);
+        }
+        let metadata = self.metadata.as_ref().expect("metadata was just read");
+        let partition = partition(key, metadata.leaders.len());
+        let leader = metadata.leaders[partition];
+        let address = metadata
+            .brokers
+            .get(&leader)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no leader for partition {}", partition)))?;
+        let mut body = Writer::default();
+        // no transactional id
+        body.i16(-1).i16(self.config.acks).i32(TIMEOUT.as_millis() as i32);
+        body.i32(1).string(&self.config.topic).i32(1).i32(partition as i32).bytes(batch);
+        let mut stream = match self.connections.remove(&leader) {
+            Some(stream) => stream,
+            None => {
+                webhooks::plaintext(&address, &address, self.config.insecure).map_err(io::Error::other)?;
+                connect(&address)?
+            }
+        };
+        let response = self.call(&mut stream, PRODUCE, 3, body.0)?;
+        self.connections.insert(leader, stream);
+        let mut response = Reader(&response);
+        let (_topics, _topic, _partitions) = (response.i32()?, response.string()?, response.i32()?);
+        let (_partition, error) = (response.i32()?, response.i16()?);
+        match error {
+            0 => Ok(()),
+            error if RETRIABLE.contains(&error) => Err(io::Error::other(format!("error code {}", error))),
+            error => Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("error code {}", error))),
+        }
+    }
+
+    // From the first bootstrap broker that answers
+    fn metadata(&mut self) -> io::Result<Metadata> {
+        let mut failure = io::Error::new(io::ErrorKind::NotFound, "no brokers");
+        for broker in self.config.brokers.clone() {
+            let mut body = Writer::default();
+            // the topic, created when the brokers allow it
+            body.i32(1).string(&self.config.topic).i8(1);
+            let answer = connect(&broker).and_then(|mut stream| self.call(&mut stream, METADATA, 4, body.0));
+            match answer.and_then(|answer| self.leaders(&answer)) {
+                Ok(metadata) => return Ok(metadata),
+                Err(e) => failure = e,
+            }
+        }
+        Err(failure)
+    }
+
+    fn leaders(&self, answer: &[u8]) -> io::Result<Metadata> {
+        let mut answer = Reader(answer);
+        let _throttle = answer.i32()?;
+        let mut brokers = HashMap::new();
+        for _ in 0..answer.i32()? {
+            let (node, host, port) = (answer.i32()?, answer.string()?, answer.i32()?);
+            let _rack = answer.string()?;
+            brokers.insert(node, format!("{}:{}", host, port));
+        }
+        let (_cluster, _controller) = (answer.string()?, answer.i32()?);
+        for _ in 0..answer.i32()? {
+            let (error, name, _internal) = (answer.i16()?, answer.string()?, answer.i8()?);
+            let mut leaders = Vec::new();
+            for _ in 0..answer.i32()? {
+                let (_error, index, leader) = (answer.i16()?, answer.i32()?, answer.i32()?);
+                // replica and in-sync nodes
+                for _ in 0..2 {
+                    for _ in 0..answer.i32()? {
+                        answer.i32()?;
+                    }
+                }
+                leaders.push((index, leader));
+            }
+            if name != self.config.topic {
+                continue;
+            }
+            if error != 0 || leaders.is_empty() {
+                return Err(io::Error::other(format!("topic {}: error code {}", name, error)));
+            }
+            leaders.sort();
+            return Ok(Metadata {
+                leaders: leaders.into_iter().map(|(_, leader)| leader).collect(),
+                brokers,
+            });
+        }
+        Err(io::Error::new(io::ErrorKind::NotFound, format!("no topic {}", self.config.topic)))
+    }
+
+    // A request with header v1, the response body after its correlation id
+    fn call(&mut self, stream: &mut TcpStream, api_key: i16, version: i16, body: Vec<u8>) -> io::Result<Vec<u8>> {
+        self.correlation_id = self.correlation_id.wrapping_add(1);
+        let mut request = Writer::default();
+        request.i16(api_key).i16(version).i32(self.correlation_id).string(&self.config.client_id);
+        request.0.extend(body);
+        let mut framed = (request.0.len() as i32).to_be_bytes().to_vec();
+        framed.extend(request.0);
+        stream.write_all(&framed)?;
+        let mut length = [0u8; 4];
+        stream.read_exact(&mut length)?;
+        let mut response = vec![0u8; i32::from_be_bytes(length).max(0) as usize];
+        stream.read_exact(&mut response)?;
+        match response.split_first_chunk::<4>() {
+            Some((id, body)) if i32::from_be_bytes(*id) == self.correlation_id => Ok(body.to_vec()),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "response to another request")),
+        }
+    }
+}
+
+fn connect(address: &str) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    Ok(stream)
+}
+
+// The partition of Kafka's default partitioner, so other producers keyed by UID agree
+fn partition(key: &[u8], partitions: usize) -> usize {
+    (murmur2(key) & 0x7fff_ffff) as usize % partitions
+}
+
+// MurmurHash2 as the Java client has it
+fn murmur2(data: &[u8]) -> u32 {
+    const M: u32 = 0x5bd1_e995;
+    let mut hash = 0x9747_b28c ^ data.len() as u32;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let mut k = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]).wrapping_mul(M);
+        k ^= k >> 24;
+        hash = hash.wrapping_mul(M) ^ k.wrapping_mul(M);
+    }
+    let rest = chunks.remainder();
+    if !rest.is_empty() {
+        for (index, &byte) in rest.iter().enumerate().rev() {
+            hash ^= u32::from(byte) << (8 * index);
+        }
+        hash = hash.wrapping_mul(M);
+    }
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(M);
+    hash ^ (hash >> 15)
+}
+
+// A v2 record batch of one record, uncompressed and without a producer id
+fn record_batch(key: &[u8], value: &[u8], headers: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut record = Writer::default();
+    // attributes, timestamp and offset deltas
+    record.i8(0).varint(0).varint(0);
+    record.varint(key.len() as i64).raw(key);
+    record.varint(value.len() as i64).raw(value);
+    record.varint(headers.len() as i64);
+    for (name, header) in headers {
+        record.varint(name.len() as i64).raw(name.as_bytes());
+        record.varint(header.len() as i64).raw(header);
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as i64;
+    // what the CRC covers: attributes to the end
+    let mut checked = Writer::default();
+    checked.i16(0).i32(0).i64(now).i64(now).i64(-1).i16(-1).i32(-1).i32(1);
+    checked.varint(record.0.len() as i64).raw(&record.0);
+    let mut batch = Writer::default();
+    // base offset, then the length of the rest
+    batch.i64(0).i32((4 + 1 + 4 + checked.0.len()) as i32);
+    // partition leader epoch, magic
+    batch.i32(-1).i8(2).raw(&crc32c(&checked.0).to_be_bytes()).raw(&checked.0);
+    batch.0
+}
+
+// CRC-32C (Castagnoli) of the record batches
+pub fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0x82f6_3b78 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+// Big-endian fields of the requests
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn raw(&mut self, bytes: &[u8]) -> &mut Self {
+        self.0.extend_from_slice(bytes);
+        self
+    }
+
+    fn i8(&mut self, value: i8) -> &mut Self {
+        self.raw(&value.to_be_bytes())
+    }
+
+    fn i16(&mut self, value: i16) -> &mut Self {
+        self.raw(&value.to_be_bytes())
+    }
+
+    fn i32(&mut self, value: i32) -> &mut Self {
+        self.raw(&value.to_be_bytes())
+    }
+
+    fn i64(&mut self, value: i64) -> &mut Self {
+        self.raw(&value.to_be_bytes())
+    }
+
+    fn string(&mut self, text: &str) -> &mut Self {
+        self.i16(text.len() as i16).raw(text.as_bytes())
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.i32(bytes.len() as i32).raw(bytes)
+    }
+
+    // zigzag varint of the records
+    fn varint(&mut self, value: i64) -> &mut Self {
+        let mut value = ((value << 1) ^ (value >> 63)) as u64;
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+        self
+    }
+}
+
+// Fields of the responses
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let (field, rest) = self
+            .0
+            .split_first_chunk::<N>()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated response"))?;
+        self.0 = rest;
+        Ok(*field)
+    }
+
+    fn i8(&mut self) -> io::Result<i8> {
+        self.take().map(i8::from_be_bytes)
+    }
+
+    fn i16(&mut self) -> io::Result<i16> {
+        self.take().map(i16::from_be_bytes)
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        self.take().map(i32::from_be_bytes)
+    }
+
+    // a nullable string is empty
+    fn string(&mut self) -> io::Result<String> {
+        let length = self.i16()?.max(0) as usize;
+        let (text, rest) = self
+            .0
+            .split_at_checked(length)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated response"))?;
+        self.0 = rest;
+        Ok(String::from_utf8_lossy(text).into_owned())
+    }
+}
@@ -0,0 +1,100 @@
+// Card keys in a secrets file instead of the constants compiled into the binary: `keystore`
+// in app.toml or ER302_KEYSTORE names a TOML or JSON file (by its extension) with
+//
+//   app_key = "170597270859"      key A of the application sectors
+//   default_key = "FFFFFFFFFFFF"  key of factory cards and key B, FF..FF when missing
+//   access_bits = "FF078069"      access bits of initialized sectors, FF078069 when missing
+//   master_key = "..."            optional, card.master_key of the key diversification
+//   profiles = [                  optional, keys of cards issued earlier, tried in order
+//     { name = "2024", master_key = "..." },
+//     { name = "legacy", app_key = "170597270859" },
+//   ]
+//
+// It's read once at startup, the server refuses to start when it's missing or invalid, and on
+// Unix also when the group or others may read or write it (chmod 600): the file isn't
+// encrypted, its permissions are all that keeps the keys.
+use er302::{codec, KeyProfile, Keys, ProfileKey};
+use config::{Config, File};
+use rocket::serde::Deserialize;
+use std::path::Path;
+
+pub struct Keystore {
+    pub keys: Keys,
+    pub master_key: Option<Vec<u8>>,
+    pub profiles: Vec<KeyProfile>,
+}
+
+// One of `profiles`, either app_key or master_key
+#[derive(Deserialize)]
+struct Profile {
+    name: String,
+    app_key: Option<String>,
+    master_key: Option<String>,
+}
+
+pub fn load(path: &Path) -> Result<Keystore, String> {
+    let error = |reason: String| format!("keystore {}: {}", path.display(), reason);
+    if !path.is_file() {
+        return Err(error("file not found".to_string()));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(path).map_err(|e| error(e.to_string()))?.permissions().mode();
+        if mode & 0o077 != 0 {
+            return Err(error(format!("mode {:o} lets the group or others at the keys, chmod 600 it", mode & 0o777)));
+        }
+    }
+    let file = Config::builder()
+        .add_source(File::from(path))
+        .build()
+        .map_err(|e| error(e.to_string()))?;
+    let hex = |name: &str, default: Option<&[u8]>| -> Result<Vec<u8>, String> {
+        match file.get_string(name) {
+            Ok(text) => codec::from_hex(&text).map_err(|e| error(format!("{}: {}", name, e))),
+            Err(config::ConfigError::NotFound(_)) => default.map(<[u8]>::to_vec).ok_or_else(|| error(format!("{} is missing", name))),
+            Err(e) => Err(error(format!("{}: {}", name, e))),
+        }
+    };
+    let defaults = Keys::default();
+    let app_key = hex("app_key", None)?;
+    let default_key = hex("default_key", Some(&defaults.default_key))?;
+    let access_bits = hex("access_bits", Some(&defaults.access_bits))?;
+    let keys = Keys {
+        app_key: app_key.try_into().map_err(|_| error("app_key: 12 hex digits".to_string()))?,
+        default_key: default_key.try_into().map_err(|_| error("default_key: 12 hex digits".to_string()))?,
+        access_bits: access_bits.try_into().map_err(|_| error("access_bits: 8 hex digits".to_string()))?,
+    };
+    if !codec::access_bits_valid(&keys.access_bits) {
+        return Err(error("access_bits don't match their inverted copy".to_string()));
+    }
+    let master_key = match file.get_string("master_key") {
+        Ok(key) if key.len() < 16 => return Err(error("master_key: at least 16 bytes".to_string())),
+        Ok(key) => Some(key.into_bytes()),
+        Err(config::ConfigError::NotFound(_)) => None,
+        Err(e) => return Err(error(format!("master_key: {}", e))),
+    };
+    let profiles = match file.get::<Vec<Profile>>("profiles") {
+        Ok(profiles) => profiles.into_iter().map(|profile| key_profile(profile).map_err(error)).collect::<Result<_, _>>()?,
+        Err(config::ConfigError::NotFound(_)) => Vec::new(),
+        Err(e) => return Err(error(format!("profiles: {}", e))),
+    };
+    Ok(Keystore { keys, master_key, profiles })
+}
+
+fn key_profile(profile: Profile) -> Result<KeyProfile, String> {
+    let invalid = |reason: &str| format!("profile {}: {}", profile.name, reason);
+    let key = match (&profile.app_key, &profile.master_key) {
+        (Some(key), None) => {
+            let key = codec::from_hex(key).ok().and_then(|key| key.try_into().ok());
+            ProfileKey::Shared(key.ok_or_else(|| invalid("app_key is 12 hex digits"))?)
+        }
+        (None, Some(master)) if master.len() >= 16 => ProfileKey::Diversified(master.clone().into_bytes()),
+        (None, Some(_)) => return Err(invalid("master_key: at least 16 bytes")),
+        _ => return Err(invalid("either app_key or master_key")),
+    };
+    match profile.name.as_str() {
+        "" | "default" => Err(invalid("the name is empty or taken by the application key")),
+        _ => Ok(KeyProfile { name: profile.name, key }),
+    }
+}
@@ -0,0 +1,21 @@
+// ER302 protocol and driver, without the `serial` feature only the codec is built (no_std + alloc)
+#![cfg_attr(not(any(feature = "serial", test)), no_std)]
+
+extern crate alloc;
+
+pub mod access;
+pub mod args;
+pub mod cardholder;
+pub mod codec;
+pub mod currency;
+pub mod error;
+pub mod hmac;
+pub mod ndef;
+#[cfg(feature = "serial")]
+pub mod reader;
+#[cfg(feature = "serial")]
+pub mod tcp;
+
+pub use error::ReaderError;
+#[cfg(feature = "serial")]
+pub use reader::{ApduResponse, BeepPattern, BeepPatterns, CardCheck, Counters, Exchange, KeyProfile, Keys, Latencies, Latency, ProfileKey, Reader, Retry, ValueMac, APPKEY, DEFAULTKEY, KEYACCESS};
@@ -0,0 +1,449 @@
+// `tracing` subscriber writing one line per event to stdout, plain text or JSON, with a
+// span per HTTP request and per reader command. ER302_LOG_LEVEL (error, warn, info, debug
+// or trace, info by default) and ER302_LOG_FORMAT (plain or json) configure it. Under
+// the `--tui` dashboard the warnings and errors are kept for it instead. The spans of the
+// requests are exported too with `[otlp]`, see otlp.rs.
+use crate::otlp::{self, Finished};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::serde::json::{json, Value};
+use rocket::{Data, Request, Response};
+use std::cell::RefCell;
+use rocket::http::Header;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Metadata, Span, Subscriber};
+use tracing_core::span::Current;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Plain,
+    Json,
+}
+
+// Lines kept for the dashboard
+const CAPTURED: usize = 100;
+
+// Warnings and errors of the log, newest last
+pub type Captured = Arc<Mutex<VecDeque<String>>>;
+
+// Install the logger configured by the environment, only the first call of the process wins
+// `level` is the --log-level flag, above ER302_LOG_LEVEL
+pub fn init(level: Option<Level>) {
+    let (configured, format) = configured();
+    let level = level.unwrap_or(configured);
+    let _ = tracing::subscriber::set_global_default(Logger::new(level, format));
+}
+
+// The same logger, its warnings and errors kept for the dashboard rather than printed
+pub fn init_captured(level: Option<Level>) -> Captured {
+    let level = level.unwrap_or(configured().0);
+    let mut logger = Logger::new(level, Format::Plain);
+    let captured = Captured::default();
+    logger.captured = Some(captured.clone());
+    let _ = tracing::subscriber::set_global_default(logger);
+    captured
+}
+
+fn configured() -> (Level, Format) {
+    let level = std::env::var("ER302_LOG_LEVEL")
+        .ok()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(Level::INFO);
+    let format = match std::env::var("ER302_LOG_FORMAT").as_deref() {
+        Ok("json") => Format::Json,
+        _ => Format::Plain,
+    };
+    (level, format)
+}
+
+struct SpanData {
+    name: &'static str,
+    metadata: &'static Metadata<'static>,
+    fields: Vec<(&'static str, Value)>,
+    parent: Option<Id>,
+    // handles still alive, the span is dropped at 0
+    refs: usize,
+    // W3C trace context, of the parent or the caller's traceparent
+    trace_id: u128,
+    span_id: u64,
+    parent_span_id: Option<u64>,
+    // part of a request's trace, the polling's spans aren't exported
+    traced: bool,
+    start: SystemTime,
+}
+
+pub struct Logger {
+    level: Level,
+    format: Format,
+    next_id: AtomicU64,
+    spans: Mutex<HashMap<u64, SpanData>>,
+    // nothing goes to stdout when set
+    captured: Option<Captured>,
+}
+
+thread_local! {
+    // spans entered on this thread, innermost last
+    static ENTERED: RefCell<Vec<Id>> = const { RefCell::new(Vec::new()) };
+}
+
+#[derive(Default)]
+struct Fields(Vec<(&'static str, Value)>);
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name(), json!(value)));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.push((field.name(), json!(value)));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.push((field.name(), json!(value)));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.push((field.name(), json!(value)));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.push((field.name(), json!(format!("{:?}", value))));
+    }
+}
+
+impl Logger {
+    pub fn new(level: Level, format: Format) -> Self {
+        Logger {
+            level,
+            format,
+            next_id: AtomicU64::new(1),
+            spans: Mutex::new(HashMap::new()),
+            captured: None,
+        }
+    }
+
+    fn current(&self) -> Option<Id> {
+        ENTERED.with(|entered| entered.borrow().last().cloned())
+    }
+
+    // (name, fields) of `span` and its parents, outermost first
+    fn scope(&self, span: Option<Id>) -> Vec<(&'static str, Vec<(&'static str, Value)>)> {
+        let spans = self.spans.lock().unwrap();
+        let mut scope = Vec::new();
+        let mut next = span;
+        while let Some(data) = next.and_then(|id| spans.get(&id.into_u64())) {
+            scope.push((data.name, data.fields.clone()));
+            next = data.parent.clone();
+        }
+        scope.reverse();
+        scope
+    }
+
+    fn line(&self, metadata: &Metadata, scope: &[(&'static str, Vec<(&'static str, Value)>)], fields: Fields) -> String {
+        let mut message = String::new();
+        let mut rest = Vec::new();
+        for (name, value) in fields.0 {
+            match (name, value) {
+                ("message", Value::String(text)) => message = text,
+                field => rest.push(field),
+            }
+        }
+        // strings without their quotes
+        let plain = |value: &Value| match value {
+            Value::String(text) => text.clone(),
+            value => value.to_string(),
+        };
+        match self.format {
+            Format::Plain => {
+                let mut line = format!("{:<5} ", metadata.level());
+                for (name, fields) in scope {
+                    line.push_str(name);
+                    if !fields.is_empty() {
+                        let fields: Vec<String> = fields.iter().map(|(name, value)| format!("{}={}", name, plain(value))).collect();
+                        line.push_str(&format!("{{{}}}", fields.join(" ")));
+                    }
+                    line.push_str(": ");
+                }
+                line.push_str(&message);
+                for (name, value) in rest {
+                    line.push_str(&format!(" {}={}", name, plain(&value)));
+                }
+                line
+            }
+            Format::Json => {
+                let object = |fields: &[(&'static str, Value)]| {
+                    let mut object = json!({});
+                    for (name, value) in fields {
+                        object[*name] = value.clone();
+                    }
+                    object
+                };
+                let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                let mut line = object(&rest);
+                line["timestamp"] = json!(timestamp.as_millis() as f64 / 1000.0);
+                line["level"] = json!(metadata.level().as_str());
+                line["target"] = json!(metadata.target());
+                line["message"] = json!(message);
+                line["spans"] = scope
+                    .iter()
+                    .map(|(name, fields)| {
+                        let mut span = object(fields);
+                        span["span"] = json!(name);
+                        span
+                    })
+                    .collect();
+                line.to_string()
+            }
+        }
+    }
+}
+
+impl Subscriber for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        // only warnings and errors of the dependencies (hyper is chatty at debug)
+        let ours = metadata.target().starts_with("er302") || metadata.target().starts_with("ER302_API");
+        *metadata.level() <= self.level && (ours || *metadata.level() <= Level::WARN)
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        Some(LevelFilter::from_level(self.level))
+    }
+
+    fn new_span(&self, attributes: &Attributes<'_>) -> Id {
+        let mut fields = Fields::default();
+        attributes.record(&mut fields);
+        let parent = match attributes.parent() {
+            Some(parent) => Some(parent.clone()),
+            None if attributes.is_contextual() => self.current(),
+            None => None,
+        };
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut spans = self.spans.lock().unwrap();
+        let name = attributes.metadata().name();
+        let context = parent.as_ref().and_then(|parent| spans.get(&parent.into_u64()));
+        let (trace_id, parent_span_id, traced) = match context {
+            Some(parent) => (parent.trace_id, Some(parent.span_id), parent.traced),
+            // a request continues the caller's trace or starts one
+            None => {
+                let traceparent = fields.0.iter().find(|(field, _)| *field == "traceparent");
+                match traceparent.and_then(|(_, value)| otlp::parse_traceparent(value.as_str()?)) {
+                    Some((trace_id, parent)) => (trace_id, Some(parent), matches!(name, "http" | "grpc")),
+                    None => (u128::from(random()) << 64 | u128::from(random()), None, matches!(name, "http" | "grpc")),
+                }
+            }
+        };
+        let data = SpanData {
+            name,
+            metadata: attributes.metadata(),
+            fields: fields.0,
+            parent: parent.clone(),
+            refs: 1,
+            trace_id,
+            span_id: random(),
+            parent_span_id,
+            traced,
+            start: SystemTime::now(),
+        };
+        // a child keeps its parent alive
+        if let Some(parent) = parent.and_then(|parent| spans.get_mut(&parent.into_u64())) {
+            parent.refs += 1;
+        }
+        spans.insert(id, data);
+        Id::from_u64(id)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        let mut fields = Fields::default();
+        values.record(&mut fields);
+        if let Some(data) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            data.fields.extend(fields.0);
+        }
+    }
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let parent = match event.parent() {
+            Some(parent) => Some(parent.clone()),
+            None if event.is_contextual() => self.current(),
+            None => None,
+        };
+        let scope = self.scope(parent);
+        let line = self.line(event.metadata(), &scope, fields);
+        match &self.captured {
+            Some(captured) if *event.metadata().level() <= Level::WARN => {
+                let mut captured = captured.lock().unwrap();
+                if captured.len() == CAPTURED {
+                    captured.pop_front();
+                }
+                captured.push_back(line);
+            }
+            Some(_) => (),
+            None => println!("{}", line),
+        }
+    }
+
+    fn current_span(&self) -> Current {
+        let Some(id) = self.current() else {
+            return Current::none();
+        };
+        match self.spans.lock().unwrap().get(&id.into_u64()) {
+            Some(data) => Current::new(id, data.metadata),
+            None => Current::none(),
+        }
+    }
+
+    fn enter(&self, span: &Id) {
+        ENTERED.with(|entered| entered.borrow_mut().push(span.clone()));
+    }
+
+    fn exit(&self, span: &Id) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            if let Some(position) = entered.iter().rposition(|id| id == span) {
+                entered.remove(position);
+            }
+        });
+    }
+
+    fn clone_span(&self, span: &Id) -> Id {
+        if let Some(data) = self.spans.lock().unwrap().get_mut(&span.into_u64()) {
+            data.refs += 1;
+        }
+        span.clone()
+    }
+
+    fn try_close(&self, span: Id) -> bool {
+        let mut spans = self.spans.lock().unwrap();
+        let mut next = Some(span);
+        let mut finished = Vec::new();
+        // dropping the last handle releases the parent's reference too
+        while let Some(id) = next.take() {
+            let Some(data) = spans.get_mut(&id.into_u64()) else {
+                break;
+            };
+            data.refs -= 1;
+            if data.refs > 0 {
+                break;
+            }
+            let Some(data) = spans.remove(&id.into_u64()) else {
+                break;
+            };
+            next = data.parent.clone();
+            finished.push(data);
+        }
+        drop(spans);
+        let closed = !finished.is_empty();
+        for data in finished.into_iter().filter(|data| data.traced && otlp::exporting()) {
+            otlp::export(Finished {
+                trace_id: data.trace_id,
+                span_id: data.span_id,
+                parent_id: data.parent_span_id,
+                name: data.name,
+                start: data.start,
+                end: SystemTime::now(),
+                fields: data.fields,
+            });
+        }
+        closed
+    }
+}
+
+// Incoming IDs longer than this (or with other than visible ASCII) are replaced
+const MAX_REQUEST_ID: usize = 128;
+
+// Span of the HTTP request, the reader commands it queues are logged inside it
+pub struct RequestSpan {
+    // from the caller's X-Request-Id or generated, echoed in the response
+    pub id: String,
+    pub span: Span,
+    start: Instant,
+}
+
+// Random bits of the UUIDs and span IDs
+fn random() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
+
+// Random UUID v4, also used for transaction IDs
+pub fn new_uuid() -> String {
+    let bits = (u128::from(random()) << 64 | u128::from(random())) & !(0xf000 << 64) & !(0xc << 60);
+    let bits = bits | 0x4000 << 64 | 0x8 << 60;
+    let hex = format!("{:032x}", bits);
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+fn request_id(request: &Request<'_>) -> String {
+    match request.headers().get_one("X-Request-Id") {
+        Some(id) if !id.is_empty() && id.len() <= MAX_REQUEST_ID && id.bytes().all(|b| b.is_ascii_graphic()) => {
+            id.to_string()
+        }
+        _ => new_uuid(),
+    }
+}
+
+// Opens a span per request and logs its status and duration with the response,
+// which carries the request ID in X-Request-Id
+pub struct RequestLog;
+
+#[rocket::async_trait]
+impl Fairing for RequestLog {
+    fn info(&self) -> Info {
+        Info {
+            name: "request log",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let id = request_id(request);
+        let span = tracing::info_span!(
+            "http",
+            request_id = %id,
+            method = %request.method(),
+            uri = %request.uri(),
+            traceparent = request.headers().get_one("traceparent"),
+            caller = tracing::field::Empty,
+            status = tracing::field::Empty
+        );
+        request.local_cache(|| RequestSpan {
+            id,
+            span,
+            start: Instant::now(),
+        });
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let RequestSpan { id, span, start } = request_span(request);
+        response.set_header(Header::new("X-Request-Id", id.clone()));
+        tracing::info!(
+            parent: span,
+            status = response.status().code,
+            elapsed_ms = start.elapsed().as_millis() as u64,
+            "request done"
+        );
+        // for the exported span, after the line that has it already
+        span.record("status", response.status().code);
+    }
+}
+
+pub fn request_span<'r>(request: &'r Request<'_>) -> &'r RequestSpan {
+    request.local_cache(|| RequestSpan {
+        id: new_uuid(),
+        span: Span::none(),
+        start: Instant::now(),
+    })
+}
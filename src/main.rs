@@ -1,617 +1,2591 @@
-use rocket::serde::{json::Json, Serialize};
-use serialport::SerialPort;
-use std::io::{Read, Write};
+use er302::codec::{BlockAddress, UidFormat, DEFAULT_VALUE_BLOCK};
+use er302::access::{self, AccessBits};
+use er302::cardholder::Cardholder;
+use er302::currency::Currency;
+use er302::ndef::Record;
+use er302::tcp::{self, TcpPort};
+use er302::{codec, Exchange, Latency, Reader, ReaderError};
+use rocket::serde::json::{json, Json, Value};
+use rocket::serde::{Deserialize, Serialize};
+use serialport::{SerialPort, SerialPortInfo, SerialPortType};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
 use std::time::Duration;
-use config::{Config, File, ConfigError};  // Make sure to import Config and File
-use std::thread;
+use config::{Config, Environment, File, ConfigError};  // Make sure to import Config and File
+use rocket::fairing::AdHoc;
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use rocket::response::stream::{Event as SseEvent, EventStream};
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::{Build, Either, Rocket, Route, Shutdown, State};
+use rocket::http::{Header, Status};
+use er302::{BeepPattern, BeepPatterns, Retry, ValueMac};
+use worker::{Options, Polling, ReaderCommand, ReaderSettings, Timeouts, Worker};
+use auth::{ApiKeys, AuthConfig, Caller, Identity, Refusal};
+use audit::{AuditLog, AuditTable, Journal, JsonLines, Operation, Pending, Transaction};
+use logging::RequestLog;
+use cards::{Blacklist, CardFile, Registry};
+use kafka::KafkaConfig;
+use mqtt::MqttConfig;
+use redis::RedisConfig;
+use storage::{Backend, DatabaseConfig};
+use otlp::OtlpConfig;
+use grpc::GrpcConfig;
+use webhooks::Webhook;
+use events::{Event, Events};
+use cors::{Cors, CorsConfig};
+use idempotency::{Idempotency, IdempotencyKey, Refused};
+use tracing::Instrument;
+use readers::{reader_paths, Readers, SelectedReader, DEFAULT_READER};
+use flags::Flags;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::sync::Arc;
 
 
 const PORTNAME: &str = "COM3";
 const BAUDRATE: u32 = 112500;
-const HEADER: &[u8] = &[0xaa, 0xbb];
-// Key A
-const APPKEY: &[u8] = &[0x17, 0x05, 0x97, 0x27, 0x08, 0x59];
-// Default Key
-const DEFAULTKEY: &[u8] = &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
-// Key A all permission | Key B disabled
-const KEYACCESS: &[u8] = &[0xFF, 0x07, 0x80, 0x69];
 
 #[macro_use]
 extern crate rocket;
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 struct ApiResponse {
     status: bool,
-    data: String,
+    // Text for most routes, an object for structured results
+    data: Value,
+    // ReaderError::code() when status is false
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<&'static str>,
+    // card the command talked to, so the POS can check it charged the card it expected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uid: Option<String>,
+    // X-Request-Id of the call, filled in when the reply is sent
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    // of an increase / decrease, the key of its journal entry
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transaction_id: Option<String>,
+    // transaction counter of the card's signed balance, with [card.mac]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    counter: Option<u32>,
+    // key profile the card authenticated with, when the keystore has profiles
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_profile: Option<String>,
+    // serial frames and timings of the command, with ?debug=true
+    #[serde(skip_serializing_if = "Option::is_none")]
+    debug: Option<Value>,
+    // {raw, formatted, currency} of a balance, with [currency]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    balance: Option<Value>,
+    // ReaderCommand::name() of the data, for its type in the /v2 body
+    #[serde(skip)]
+    command: Option<&'static str>,
+}
+
+// JSON body plus the time the command waited for the reader
+struct Reply {
+    body: Json<ApiResponse>,
+    queue_wait: Header<'static>,
+}
+
+impl<'r> Responder<'r, 'static> for Reply {
+    fn respond_to(mut self, request: &'r Request<'_>) -> response::Result<'static> {
+        self.body.request_id = Some(logging::request_span(request).id.clone());
+        self.body.balance = request.rocket().state::<Units>().expect("units are managed").balance(&self.body);
+        if let Some(operation) = request.local_cache(Pending::default).take() {
+            audit_entry(request, operation, &self.body);
+        }
+        // a route answering with its own status (e.g. 408 of /wait) still overrides this one
+        let (body, status) = match v2::is_v2(request.uri().path().as_str()) {
+            true => (Json(v2::body(&self.body, self.body.command)).respond_to(request)?, self.body.code.map(v2::status)),
+            false => (self.body.respond_to(request)?, None),
+        };
+        let mut response = Response::build_from(body);
+        if let Some(status) = status {
+            response.status(status);
+        }
+        response.header(self.queue_wait).ok()
+    }
+}
+
+fn audit_entry(request: &Request<'_>, operation: Operation, body: &ApiResponse) {
+    let Identity(caller) = request.local_cache(|| Identity(None));
+    if let Some(transaction) = &operation.transaction {
+        let events = request.rocket().state::<Events>().expect("events are managed");
+        let journal = request.rocket().state::<Journal>().expect("journal is managed");
+        journal_entry(journal, events, &operation, transaction, body, caller);
+    }
+    let audit = request.rocket().state::<AuditLog>().expect("audit log is managed");
+    audit.record(json!({
+        "request_id": body.request_id,
+        "transaction_id": body.transaction_id,
+        "method": request.method().as_str(),
+        "endpoint": request.uri().path().as_str(),
+        "route": request.route().and_then(|route| route.name.as_deref()),
+        "reader": operation.reader,
+        "command": operation.command,
+        "uid": operation.uid,
+        "amount": operation.amount,
+        "status": body.status,
+        "code": body.code,
+        "caller": caller,
+        "client": request.remote().map(|remote| remote.ip().to_string()),
+    }));
+}
+
+// Journal entry of an increase / decrease, and its `transaction` event when it went through
+fn journal_entry(
+    Journal(journal): &Journal,
+    events: &Events,
+    operation: &Operation,
+    transaction: &Transaction,
+    body: &ApiResponse,
+    caller: &Option<String>,
+) {
+    if body.status {
+        let mut event = Event::new("transaction", operation.uid.clone().unwrap_or_default(), &operation.reader);
+        event.details = json!({
+            "transaction_id": transaction.id,
+            "command": operation.command,
+            "amount": operation.amount,
+            "before": transaction.before,
+            "after": transaction.after,
+            "counter": body.counter,
+        })
+        .as_object()
+        .cloned()
+        .unwrap_or_default();
+        kafka::record(&event);
+        events.publish(event);
+    }
+    let entry = json!({
+        "transaction_id": transaction.id,
+        "request_id": body.request_id,
+        "reader": operation.reader,
+        "uid": operation.uid,
+        "command": operation.command,
+        "amount": operation.amount,
+        "before": transaction.before,
+        "after": transaction.after,
+        "counter": body.counter,
+        "status": body.status,
+        "code": body.code,
+        "caller": caller,
+    });
+    storage::record(&entry);
+    journal.append(entry);
+}
+
+// Opens the connection to the reader, the test-suite swaps it for the simulator
+struct Transport {
+    open: Box<dyn Fn() -> serialport::Result<Box<dyn SerialPort>> + Send + Sync>,
+}
+
+impl Transport {
+    // The [serial] port of the checked configuration, portname "auto" picks the first ER302
+    // found at each connection
+    fn serial(config: &AppConfig) -> Self {
+        Transport::port(config.portname.clone(), config.baudrate, config.reader.timeouts.open)
+    }
+
+    // Simulated reader with the card in its field, nothing is written back to disk
+    fn mock(card: &MockCard, value_block: BlockAddress) -> Self {
+        let mut virtual_card = simulator::Card::with_uid(&card.uid);
+        if let Some(balance) = card.balance {
+            virtual_card.set_key_a(value_block.absolute(), &card.key);
+            virtual_card.set_value(value_block.absolute(), balance);
+        }
+        let simulator = simulator::Simulator::with_card(virtual_card);
+        Transport {
+            open: Box::new(move || Ok(simulator.port())),
+        }
+    }
+
+    // Fixed port of a `[readers.<name>]` section
+    fn port(portname: String, baudrate: u32, open_timeout: Duration) -> Self {
+        Transport {
+            open: Box::new(move || connect(&portname, baudrate, open_timeout)),
+        }
+    }
+}
+
+// Local port name, "auto", or tcp:// / rfc2217:// for a serial-to-ethernet bridge. The
+// worker sets the read timeout of the port it gets.
+fn connect(portname: &str, baudrate: u32, open_timeout: Duration) -> serialport::Result<Box<dyn SerialPort>> {
+    if let Some((address, telnet)) = tcp::parse_url(portname) {
+        return Ok(Box::new(TcpPort::connect(address, baudrate, telnet, open_timeout, Duration::from_secs(2))?));
+    }
+    match portname {
+        "auto" => detect_port(baudrate),
+        name => open_port(name, baudrate),
+    }
+}
+
+fn open_port(name: &str, baudrate: u32) -> serialport::Result<Box<dyn SerialPort>> {
+    serialport::new(name, baudrate).open()
+}
+
+// First port whose device answers the version request
+fn detect_port(baudrate: u32) -> serialport::Result<Box<dyn SerialPort>> {
+    for info in serialport::available_ports()? {
+        let Ok(mut port) = open_port(&info.port_name, baudrate) else {
+            continue;
+        };
+        // don't wait 2 s on every port that isn't a reader
+        port.set_timeout(Duration::from_millis(300))?;
+        let mut reader = Reader::new(port);
+        if let Ok(found) = reader.read_info() {
+            tracing::info!(model = %found.model, firmware = %found.firmware, port = %info.port_name, "reader found");
+            return Ok(reader.into_port());
+        }
+    }
+    Err(serialport::Error::new(serialport::ErrorKind::NoDevice, "no ER302 answered on any serial port"))
+}
+
+struct AppConfig {
+    portname: String,
+    baudrate: u32,
+    host: IpAddr,
+    port: u16,
+    // Where the balance is stored, a data block (never a trailer)
+    value_block: BlockAddress,
+    reader: ReaderSettings,
+    // refuse to start when a reader doesn't answer the startup probe
+    require_reader: bool,
+    // keep the GET /balance/<value>, /increase, /decrease and /initcard routes of old kiosks
+    legacy_get: bool,
+    auth: AuthConfig,
+    cors: CorsConfig,
+    // JSON lines of the card operations, no audit log when None
+    audit_file: Option<PathBuf>,
+    // JSON lines of the increases / decreases
+    journal_file: Option<PathBuf>,
+    // blacklisted UIDs, kept in memory only when None
+    blacklist_file: Option<PathBuf>,
+    // registered UIDs with their label and issue date
+    registry_file: Option<PathBuf>,
+    // only registered cards may be used
+    registry_required: bool,
+    // sector of the cardholder record, no /cardholder when None
+    cardholder_sector: Option<u8>,
+    // `keystore` / ER302_KEYSTORE, the compiled-in keys when None
+    keystore: Option<PathBuf>,
+    // readers besides the `[serial]` one
+    readers: Vec<ReaderConfig>,
+    // [[webhooks]] the card events are POSTed to
+    webhooks: Vec<Webhook>,
+    // [mqtt] broker the card events are published to
+    mqtt: Option<MqttConfig>,
+    // [redis] server the taps and transactions are published to
+    redis: Option<RedisConfig>,
+    // [kafka] brokers the transactions are produced to
+    kafka: Option<KafkaConfig>,
+    // [database] the transactions and card events are recorded in, or DATABASE_URL; None
+    // when it's off
+    database: Option<DatabaseConfig>,
+    // [otlp] collector the request traces are exported to
+    otlp: Option<OtlpConfig>,
+    // [grpc] port of the CardService, off when None
+    grpc: Option<GrpcConfig>,
+    // [mock] card of READER_MODE=mock
+    mock: MockCard,
+    // card.uid_format, `?format=` overrides it per request
+    uid_format: UidFormat,
+    // [wallets.<name>] purses besides the card.sector one
+    wallets: BTreeMap<String, Wallet>,
+    // [currency] of the balances, plain numbers without it
+    currency: Option<Currency>,
+}
+
+// The virtual card every reader holds with READER_MODE=mock
+#[derive(Clone)]
+struct MockCard {
+    uid: Vec<u8>,
+    // None leaves the card as it comes from the factory
+    balance: Option<u32>,
+    // key A of the value block's sector, the compiled-in application key by default
+    key: Vec<u8>,
+}
+
+impl Default for MockCard {
+    fn default() -> Self {
+        MockCard {
+            uid: vec![0xde, 0xad, 0xbe, 0xef],
+            balance: Some(0),
+            key: er302::APPKEY.to_vec(),
+        }
+    }
 }
 
-struct RFID {
-    port: Box<dyn SerialPort>,
+// [wallets.<name>] a purse in a sector of its own, e.g. "canteen" and "printing". It opens
+// with the keystore profile `key_profile` only, the application key and the profiles in turn
+// when missing. The block defaults to card.block.
+#[derive(Deserialize)]
+struct WalletConfig {
+    sector: u8,
+    block: Option<u8>,
+    key_profile: Option<String>,
 }
 
-fn load_config() -> Result<(String, u32, String, u16), ConfigError> {
-    // Use Config::builder() instead of Config::new()
-    let mut config = Config::builder();
+#[derive(Clone, Debug)]
+struct Wallet {
+    block: BlockAddress,
+    key_profile: Option<String>,
+}
+
+// [readers.<name>] portname / baudrate, the baud rate defaults to serial.baudrate
+#[derive(Deserialize)]
+struct ReaderConfig {
+    #[serde(skip)]
+    name: String,
+    portname: String,
+    baudrate: Option<u32>,
+}
 
-    // Add the configuration file (app.conf)
-    config = config.add_source(File::with_name("app"));
+impl Default for AppConfig {
+    fn default() -> Self {
+        AppConfig {
+            portname: PORTNAME.to_string(),
+            baudrate: BAUDRATE,
+            host: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 8000,
+            value_block: DEFAULT_VALUE_BLOCK,
+            reader: ReaderSettings::default(),
+            require_reader: false,
+            legacy_get: true,
+            auth: AuthConfig::default(),
+            cors: CorsConfig::default(),
+            audit_file: None,
+            journal_file: None,
+            blacklist_file: None,
+            registry_file: None,
+            registry_required: false,
+            cardholder_sector: None,
+            keystore: None,
+            readers: Vec::new(),
+            webhooks: Vec::new(),
+            mqtt: None,
+            redis: None,
+            kafka: None,
+            database: None,
+            otlp: None,
+            grpc: None,
+            mock: MockCard::default(),
+            uid_format: UidFormat::Hex,
+            wallets: BTreeMap::new(),
+            currency: None,
+        }
+    }
+}
 
-    // Build the configuration and unwrap values
+// Default value block of the routes, `?sector=&block=` overrides it per request
+struct ValueBlock(BlockAddress);
+
+// card.cardholder_sector
+struct CardholderSector(Option<u8>);
+
+struct Wallets(BTreeMap<String, Wallet>);
+
+// [currency], balances are plain numbers without it
+struct Units(Option<Currency>);
+
+impl Units {
+    // `value` in minor units, or `amount` as a decimal of the currency ("15.50" or 15.5)
+    fn raw(&self, value: Option<u32>, amount: Option<&Value>) -> Result<u32, ReaderError> {
+        let amount = match (value, amount) {
+            (Some(value), None) => return Ok(value),
+            (None, Some(Value::String(amount))) => amount.clone(),
+            (None, Some(Value::Number(amount))) => amount.to_string(),
+            (None, Some(_)) => return Err(ReaderError::InvalidInput("amount is a decimal number".to_string())),
+            _ => return Err(ReaderError::InvalidInput("give either value or amount".to_string())),
+        };
+        match &self.0 {
+            Some(currency) => currency.parse(&amount),
+            None => Err(ReaderError::InvalidInput("amount needs a [currency] in the configuration".to_string())),
+        }
+    }
+
+    // {raw, formatted, currency} of the balance a command left on the card
+    fn balance(&self, response: &ApiResponse) -> Option<Value> {
+        let currency = self.0.as_ref()?;
+        let balance_command = matches!(
+            response.command?.trim_start_matches("dry_run_"),
+            "read_balance" | "init_balance" | "increase" | "decrease"
+        );
+        let raw = match &response.data {
+            Value::String(text) => text.parse().ok()?,
+            data => data["new_balance"].as_u64().and_then(|raw| u32::try_from(raw).ok())?,
+        };
+        (balance_command && response.status).then(|| {
+            json!({ "raw": raw, "formatted": currency.format(raw), "currency": currency.code })
+        })
+    }
+}
+
+impl Wallets {
+    fn get(&self, name: &str) -> Result<&Wallet, ReaderError> {
+        self.0.get(name).ok_or_else(|| ReaderError::InvalidInput(format!("unknown wallet: {}", name)))
+    }
+}
+
+impl ValueBlock {
+    fn resolve(&self, sector: Option<u8>, block: Option<u8>) -> Result<BlockAddress, ReaderError> {
+        BlockAddress::data(sector.unwrap_or(self.0.sector), block.unwrap_or(self.0.block))
+    }
+}
+
+// Defaults, then app.toml (ER302_CONFIG or --config names another file), then the ER302_*
+// environment variables, then the command-line flags, each layer overriding the ones before
+fn load_config() -> Result<AppConfig, ConfigError> {
+    let flags = Flags::get();
+    let file = flags.config.or_else(|| std::env::var("ER302_CONFIG").ok());
+    layered_config(file.as_deref(), &flags.overrides)
+}
+
+// ER302_SERIAL__PORTNAME, ER302_CARD__MAX_BALANCE, ...: a double underscore between the
+// section and the key, so keys with an underscore of their own can be set too
+fn environment() -> Environment {
+    Environment::with_prefix("ER302").prefix_separator("_").separator("__").try_parsing(true)
+}
+
+fn layered_config(file: Option<&str>, overrides: &[(String, String)]) -> Result<AppConfig, ConfigError> {
+    let defaults = AppConfig::default();
+    let mut config = Config::builder()
+        .set_default("serial.portname", defaults.portname)?
+        .set_default("serial.baudrate", defaults.baudrate)?
+        .set_default("api.host", defaults.host.to_string())?
+        .set_default("api.port", defaults.port)?;
+    // app.toml is optional, a file named explicitly isn't
+    config = match file {
+        Some(file) => config.add_source(File::with_name(file)),
+        None => config.add_source(File::with_name("app").required(false)),
+    };
+    config = config.add_source(environment());
+    for (key, value) in overrides {
+        config = config.set_override(key.as_str(), value.as_str())?;
+    }
     let config = config.build()?;
 
     // Extract values
     let portname: String = config.get("serial.portname")?;
     let baudrate: u32 = config.get("serial.baudrate")?;
     let host: String = config.get("api.host")?;
+    let host: IpAddr = host.parse().map_err(|_| ConfigError::Message(format!("api.host: not an IP address: {}", host)))?;
     let port: u16 = config.get("api.port")?;
+    let sector: u8 = get_or(&config, "card.sector", DEFAULT_VALUE_BLOCK.sector)?;
+    let block: u8 = get_or(&config, "card.block", DEFAULT_VALUE_BLOCK.block)?;
+    let value_block = BlockAddress::data(sector, block)
+        .map_err(|e| ConfigError::Message(format!("card.sector / card.block: {}", e)))?;
+    let backup_block: Option<u8> = get_or(&config, "card.backup_block", None)?;
+    if let Some(backup) = backup_block {
+        match BlockAddress::data(sector, backup) {
+            Ok(_) if backup != block => (),
+            _ => return Err(ConfigError::Message("card.backup_block: another data block of card.sector".to_string())),
+        }
+    }
+    let value_mac = value_mac(&config, sector, block, backup_block)?;
+    let cardholder_sector: Option<u8> = get_or(&config, "card.cardholder_sector", None)?;
+    if let Some(cardholder) = cardholder_sector {
+        if cardholder == sector || BlockAddress::data(cardholder, 0).is_err() {
+            return Err(ConfigError::Message("card.cardholder_sector: a sector besides 0 and card.sector".to_string()));
+        }
+    }
+    let require_reader: bool = get_or(&config, "serial.require_reader", false)?;
+    let legacy_get: bool = get_or(&config, "api.legacy_get", true)?;
+    let auth = AuthConfig {
+        keys: get_or(&config, "auth.keys", BTreeMap::new())?,
+        file: get_or(&config, "auth.file", None)?,
+        jwt_secret: get_or(&config, "auth.jwt.secret", None)?,
+        jwt_public_key: get_or(&config, "auth.jwt.public_key_file", None)?,
+        jwt_issuer: get_or(&config, "auth.jwt.issuer", None)?,
+        jwt_audience: get_or(&config, "auth.jwt.audience", None)?,
+        jwt_require_exp: get_or(&config, "auth.jwt.require_exp", false)?,
+        mutations_from: get_or(&config, "auth.mutations_from", Vec::<String>::new())?
+            .iter()
+            .map(|network| network.parse())
+            .collect::<Result<_, String>>()
+            .map_err(|e| ConfigError::Message(format!("auth.mutations_from: {}", e)))?,
+    };
+    let cors = CorsConfig {
+        origins: get_or(&config, "api.cors.origins", Vec::new())?,
+        methods: get_or(&config, "api.cors.methods", CorsConfig::default().methods)?,
+    };
+    let master_key = get_or(&config, "card.master_key", None::<String>)?.map(String::into_bytes);
+    if master_key.as_ref().is_some_and(|key| key.len() < 16) {
+        return Err(ConfigError::Message("card.master_key: at least 16 bytes".to_string()));
+    }
+    let audit_file = get_or(&config, "audit.file", None)?;
+    let journal_file = get_or(&config, "journal.file", None)?;
+    let blacklist_file = get_or(&config, "blacklist.file", None)?;
+    let registry_file = get_or(&config, "registry.file", None)?;
+    let registry_required = get_or(&config, "registry.required", false)?;
+    let webhooks: Vec<Webhook> = get_or(&config, "webhooks", Vec::new())?;
+    webhooks::validate(&webhooks).map_err(|e| ConfigError::Message(format!("webhooks: {}", e)))?;
+    let otlp: Option<OtlpConfig> = get_or(&config, "otlp", None)?;
+    if let Some(otlp) = &otlp {
+        otlp::traces_url(&otlp.endpoint).map_err(|e| ConfigError::Message(format!("otlp.endpoint: {}", e)))?;
+    }
+    let mqtt: Option<MqttConfig> = get_or(&config, "mqtt", None)?;
+    if let Some(mqtt) = &mqtt {
+        mqtt::broker(&mqtt.url)
+            .and_then(|address| webhooks::plaintext(&mqtt.url, &address, mqtt.insecure))
+            .map_err(|e| ConfigError::Message(format!("mqtt.url: {}", e)))?;
+    }
+    let redis: Option<RedisConfig> = get_or(&config, "redis", None)?;
+    if let Some(redis) = &redis {
+        redis::server(&redis.url)
+            .and_then(|server| webhooks::plaintext(&redis.url, &server.address, redis.insecure))
+            .map_err(|e| ConfigError::Message(format!("redis.url: {}", e)))?;
+    }
+    let kafka: Option<KafkaConfig> = get_or(&config, "kafka", None)?;
+    if let Some(kafka) = &kafka {
+        kafka::validate(kafka).map_err(|e| ConfigError::Message(format!("kafka: {}", e)))?;
+    }
+    let mut database: DatabaseConfig = get_or(&config, "database", DatabaseConfig::default())?;
+    if let Ok(url) = std::env::var("DATABASE_URL") {
+        database.url = url;
+    }
+    match storage::backend(&database.url) {
+        Ok(Backend::Postgres(server)) => webhooks::plaintext(&database.url, &server.address, database.insecure),
+        backend => backend.map(|_| ()),
+    }
+    .map_err(|e| ConfigError::Message(format!("database.url: {}", e)))?;
+    let database = Some(database).filter(|database| database.enabled);
+    let mock = mock_card(&config)?;
+    let uid_format = get_or(&config, "card.uid_format", "hex".to_string())?
+        .parse()
+        .map_err(|e: ReaderError| ConfigError::Message(format!("card.uid_format: {}", e)))?;
+    let currency = match get_or(&config, "currency.code", None::<String>)? {
+        Some(code) => Some(
+            Currency::new(&code, get_or(&config, "currency.minor_units", 2)?)
+                .map_err(|e| ConfigError::Message(format!("currency: {}", e)))?,
+        ),
+        None => None,
+    };
+    let polling = Polling {
+        enabled: get_or(&config, "polling.enabled", false)?,
+        interval: Duration::from_millis(get_or(&config, "polling.interval_ms", 200)?),
+        debounce: Duration::from_millis(get_or(&config, "polling.debounce_ms", 500)?),
+        removal_polls: get_or(&config, "polling.removal_polls", 2)?,
+    };
+    if polling.interval < Duration::from_millis(10) {
+        return Err(ConfigError::Message("polling.interval_ms: at least 10".to_string()));
+    }
+    if polling.removal_polls == 0 {
+        return Err(ConfigError::Message("polling.removal_polls: at least 1".to_string()));
+    }
+    let timeouts = timeouts(&config)?;
+    let retry = Retry {
+        attempts: get_or(&config, "retry.attempts", Retry::default().attempts)?,
+        delay: Duration::from_millis(get_or(&config, "retry.delay_ms", Retry::default().delay.as_millis() as u64)?),
+    };
+    if retry.attempts == 0 {
+        return Err(ConfigError::Message("retry.attempts: at least 1".to_string()));
+    }
+    let defaults = BeepPatterns::default();
+    let reader = ReaderSettings {
+        halt: get_or(&config, "card.halt", false)?,
+        max_balance: get_or(&config, "card.max_balance", None)?,
+        backup_block,
+        value_mac,
+        counters: Default::default(),
+        // the card lists are opened by `assemble`
+        card_check: None,
+        master_key,
+        // the keystore's, read by `assemble`
+        keys: Default::default(),
+        key_profiles: Vec::new(),
+        events: Default::default(),
+        polling,
+        timeouts,
+        retry,
+        beeps: BeepPatterns {
+            success: beep_pattern(&config, "success", defaults.success)?,
+            auth_failed: beep_pattern(&config, "auth_failed", defaults.auth_failed)?,
+            no_card: beep_pattern(&config, "no_card", defaults.no_card)?,
+        },
+    };
 
-    Ok((portname, baudrate, host.to_string(), port))
+    let wallets: BTreeMap<String, WalletConfig> = get_or(&config, "wallets", BTreeMap::new())?;
+    let wallets = wallets
+        .into_iter()
+        .map(|(name, wallet)| {
+            let block = BlockAddress::data(wallet.sector, wallet.block.unwrap_or(block))
+                .map_err(|e| ConfigError::Message(format!("wallets.{}: {}", name, e)))?;
+            Ok((name, Wallet { block, key_profile: wallet.key_profile }))
+        })
+        .collect::<Result<_, ConfigError>>()?;
+
+    let readers: BTreeMap<String, ReaderConfig> = get_or(&config, "readers", BTreeMap::new())?;
+    let readers = readers
+        .into_iter()
+        .map(|(name, reader)| ReaderConfig { name, ..reader })
+        .collect();
+
+    Ok(AppConfig {
+        portname,
+        baudrate,
+        host,
+        port,
+        value_block,
+        reader,
+        require_reader,
+        legacy_get,
+        auth,
+        cors,
+        audit_file,
+        journal_file,
+        blacklist_file,
+        registry_file,
+        registry_required,
+        cardholder_sector,
+        keystore: get_or(&config, "keystore", None)?,
+        readers,
+        webhooks,
+        mqtt,
+        redis,
+        kafka,
+        database,
+        otlp,
+        grpc: get_or(&config, "grpc", None)?,
+        mock,
+        uid_format,
+        wallets,
+        currency,
+    })
 }
 
+// [mock] uid and key as hex, balance, `initialized = false` for a factory card
+fn mock_card(config: &Config) -> Result<MockCard, ConfigError> {
+    let default = MockCard::default();
+    let hex = |key: &str, default: &[u8], lengths: &[usize]| -> Result<Vec<u8>, ConfigError> {
+        match get_or(config, key, None::<String>)? {
+            Some(text) => codec::from_hex(&text)
+                .ok()
+                .filter(|bytes| lengths.contains(&bytes.len()))
+                .ok_or_else(|| ConfigError::Message(format!("{}: {:?} bytes as hex", key, lengths))),
+            None => Ok(default.to_vec()),
+        }
+    };
+    let initialized: bool = get_or(config, "mock.initialized", true)?;
+    Ok(MockCard {
+        uid: hex("mock.uid", &default.uid, &[4, 7, 10])?,
+        balance: initialized.then(|| get_or(config, "mock.balance", 0)).transpose()?,
+        key: hex("mock.key", &default.key, &[6])?,
+    })
+}
 
+// [timeouts] open / read / command in milliseconds
+fn timeouts(config: &Config) -> Result<Timeouts, ConfigError> {
+    let default = Timeouts::default();
+    let millis = |name: &str, default: Duration| -> Result<Duration, ConfigError> {
+        let key = format!("timeouts.{}", name);
+        match get_or(config, &key, default.as_millis() as u64)? {
+            0 => Err(ConfigError::Message(format!("{}: at least 1 ms", key))),
+            ms => Ok(Duration::from_millis(ms)),
+        }
+    };
+    let timeouts = Timeouts {
+        open: millis("open", default.open)?,
+        read: millis("read", default.read)?,
+        command: millis("command", default.command)?,
+    };
+    if timeouts.command < timeouts.read {
+        return Err(ConfigError::Message("timeouts.command: at least timeouts.read".to_string()));
+    }
+    Ok(timeouts)
+}
 
-impl RFID {
-    // Constructor to create a new RFID instance
-    fn new(port: Box<dyn SerialPort>) -> Self {
-        RFID { port }
+// [card.mac] block, key_id and keys (key ID -> secret), off without keys
+fn value_mac(config: &Config, sector: u8, value: u8, backup: Option<u8>) -> Result<Option<ValueMac>, ConfigError> {
+    let keys: BTreeMap<String, String> = get_or(config, "card.mac.keys", BTreeMap::new())?;
+    if keys.is_empty() {
+        return Ok(None);
+    }
+    let keys = keys
+        .into_iter()
+        .map(|(id, key)| Ok((id.parse::<u8>().map_err(|_| format!("invalid key ID {}", id))?, key.into_bytes())))
+        .collect::<Result<BTreeMap<_, _>, String>>()
+        .map_err(|e| ConfigError::Message(format!("card.mac.keys: {}", e)))?;
+    let block: u8 = config.get("card.mac.block")?;
+    if BlockAddress::data(sector, block).is_err() || block == value || Some(block) == backup {
+        return Err(ConfigError::Message("card.mac.block: another data block of card.sector".to_string()));
     }
+    let key_id: u8 = config.get("card.mac.key_id")?;
+    if !keys.contains_key(&key_id) {
+        return Err(ConfigError::Message(format!("card.mac.key_id: no key {} in card.mac.keys", key_id)));
+    }
+    Ok(Some(ValueMac { block, key_id, keys }))
+}
 
-    fn calculate_size(data: &[u8]) -> Vec<u8> {
-        // Calculate the length and add 1
-        let length = data.len() + 1;
+// [beep.<outcome>] count / time / pause_ms, each one optional
+fn beep_pattern(config: &Config, outcome: &str, default: BeepPattern) -> Result<BeepPattern, ConfigError> {
+    let key = |name: &str| format!("beep.{}.{}", outcome, name);
+    Ok(BeepPattern {
+        count: get_or(config, &key("count"), default.count)?,
+        time: get_or(config, &key("time"), default.time)?,
+        pause: Duration::from_millis(get_or(config, &key("pause_ms"), default.pause.as_millis() as u64)?),
+    })
+}
 
-        // Convert length to a 2-byte number in little-endian format
-        let low_byte = (length & 0xFF) as u8; // Low byte
-        let high_byte = ((length >> 8) & 0xFF) as u8; // High byte
-        Vec::from([low_byte, high_byte])
+// Optional setting, missing keys fall back to `default`
+fn get_or<'de, T: serde::Deserialize<'de>>(config: &Config, key: &str, default: T) -> Result<T, ConfigError> {
+    match config.get(key) {
+        Err(ConfigError::NotFound(_)) => Ok(default),
+        value => value,
     }
+}
 
-    // Function to calculate XOR over a slice of data
-    fn calculate_xor(data: Vec<u8>) -> Vec<u8> {
-        if data.len() < 4 {
-            panic!("Data must have at least 4 elements to calculate XOR");
+#[launch]
+fn rocket() -> _ {
+    let flags = match Flags::parse(std::env::args().skip(1)) {
+        Ok(flags) if flags.help => {
+            println!("{}", flags::USAGE);
+            std::process::exit(0);
+        }
+        Ok(flags) => flags,
+        Err(e) => {
+            eprintln!("error : {}\n{}", e, flags::USAGE);
+            std::process::exit(2);
         }
+    };
+    let (tui, log_level) = (flags.tui, flags.log_level);
+    flags.install();
+    // --tui: the dashboard on the terminal instead of the log
+    match tui {
+        true => {
+            let captured = logging::init_captured(log_level);
+            tui::attach(build(None), captured)
+        }
+        false => {
+            logging::init(log_level);
+            build(None)
+        }
+    }
+}
 
-        // Calculate XOR from index 3 to the end
-        let xor = data[3..].iter().fold(0, |acc, &x| acc ^ x);
+// The default reader on `transport`, or on the [serial] port when None
+fn build(transport: Option<Transport>) -> Rocket<Build> {
+    configured(load_config(), transport)
+}
 
-        // Append the XOR result to the data and return as a new vector
-        let mut extended_data = Vec::from(data);
-        extended_data.push(xor);
-        extended_data
+fn configured(config: Result<AppConfig, ConfigError>, transport: Option<Transport>) -> Rocket<Build> {
+    let config = match config {
+        Ok(config) => config,
+        // rather than serving with settings nobody asked for
+        Err(e) => {
+            tracing::error!(error = %e, "invalid configuration, not starting");
+            return rocket::build().attach(AdHoc::try_on_ignite("configuration", |rocket| async { Err(rocket) }));
+        }
+    };
+    summary(&config);
+    // READER_MODE=mock / --mock: every reader is simulated, for development without the hardware
+    let mock = Flags::get().mock || match std::env::var("READER_MODE").as_deref() {
+        Ok("mock") => true,
+        Ok("serial") | Err(_) => false,
+        Ok(mode) => {
+            tracing::error!(mode, "unknown READER_MODE, serial or mock, using the serial ports");
+            false
+        }
+    };
+    if mock {
+        tracing::warn!(uid = codec::to_hex(&config.mock.uid), "READER_MODE=mock, no serial port is used");
     }
+    let transport = match mock {
+        true => Transport::mock(&config.mock, config.value_block),
+        false => transport.unwrap_or_else(|| Transport::serial(&config)),
+    };
+    let mut readers = vec![(DEFAULT_READER.to_string(), transport)];
+    for reader in &config.readers {
+        let baudrate = reader.baudrate.unwrap_or(config.baudrate);
+        let transport = match mock {
+            true => Transport::mock(&config.mock, config.value_block),
+            false => Transport::port(reader.portname.clone(), baudrate, config.reader.timeouts.open),
+        };
+        readers.push((reader.name.clone(), transport));
+    }
+    assemble(config, readers)
+}
 
-    // Method to send the request through the serial port
-    fn send_request(&mut self, input: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        // Calculate XOR and prepare final data
+// The effective settings once at startup, without keys and secrets
+fn summary(config: &AppConfig) {
+    let readers: Vec<_> = config.readers.iter().map(|reader| format!("{}={}", reader.name, reader.portname)).collect();
+    let timeouts = config.reader.timeouts;
+    let file = Flags::get().config.or_else(|| std::env::var("ER302_CONFIG").ok());
+    tracing::info!(
+        file = file.as_deref().unwrap_or("app.toml"),
+        portname = config.portname,
+        baudrate = config.baudrate,
+        readers = readers.join(","),
+        address = format!("{}:{}", config.host, config.port),
+        value_block = format!("{}/{}", config.value_block.sector, config.value_block.block),
+        timeouts_ms = format!("{}/{}/{}", timeouts.open.as_millis(), timeouts.read.as_millis(), timeouts.command.as_millis()),
+        retry_attempts = config.reader.retry.attempts,
+        polling = config.reader.polling.enabled,
+        auth = config.auth.enabled(),
+        keystore = config.keystore.as_ref().map(|path| path.display().to_string()),
+        audit = config.audit_file.as_ref().map(|path| path.display().to_string()),
+        journal = config.journal_file.as_ref().map(|path| path.display().to_string()),
+        "configuration"
+    );
+}
 
-        let mut data: Vec<u8> = input.to_vec();
-        let size = Self::calculate_size(input);
+fn assemble(mut config: AppConfig, readers: Vec<(String, Transport)>) -> Rocket<Build> {
+    tracing::info!("Card Reader,Write API for Ehuoyan ER302 by https://sajx.net ⭐️. Under Bartarandishan License");
+    let journal = JsonLines::new(config.journal_file);
+    // a card written back to an older copy is recognised after a restart too
+    audit::seed_counters(&journal, &config.reader.counters);
+    let blacklist = Arc::new(Blacklist(CardFile::load(config.blacklist_file.take())));
+    let registry = Arc::new(Registry {
+        cards: CardFile::load(config.registry_file.take()),
+        required: config.registry_required,
+    });
+    config.reader.card_check = Some(cards::card_check(blacklist.clone(), registry.clone()));
+    // the server doesn't start with a keystore it can't use, rather than with the wrong keys
+    let keystore = config.keystore.take().map(|path| keystore::load(&path)).transpose();
+    match &keystore {
+        Ok(Some(store)) => {
+            config.reader.keys = store.keys.clone();
+            if store.master_key.is_some() {
+                config.reader.master_key = store.master_key.clone();
+            }
+            config.reader.key_profiles = store.profiles.clone();
+        }
+        Ok(None) => (),
+        Err(e) => tracing::error!(error = %e, "can't load the keystore"),
+    }
+    // a wallet whose profile isn't in the keystore would never open
+    let profiles = &config.reader.key_profiles;
+    let unknown = config.wallets.iter().find(|(_, wallet)| {
+        wallet.key_profile.as_ref().is_some_and(|name| name != "default" && !profiles.iter().any(|profile| &profile.name == name))
+    });
+    if let Some((name, wallet)) = unknown {
+        tracing::error!(wallet = name, key_profile = wallet.key_profile, "the wallet's key profile isn't in the keystore");
+    }
+    let keystore_loaded = keystore.is_ok() && unknown.is_none();
+    webhooks::spawn(std::mem::take(&mut config.webhooks), &config.reader.events);
+    if let Some(mqtt) = config.mqtt.take() {
+        mqtt::spawn(mqtt, &config.reader.events);
+    }
+    if let Some(redis) = config.redis.take() {
+        redis::spawn(redis, &config.reader.events);
+    }
+    if let Some(kafka) = config.kafka.take() {
+        kafka::spawn(kafka);
+    }
+    if let Some(database) = config.database.take() {
+        storage::spawn(database, &config.reader.events);
+    }
+    if let Some(otlp) = config.otlp.take() {
+        otlp::spawn(otlp);
+    }
+    let rocket = rocket::build()
+        .configure(rocket::Config {
+            address: config.host,
+            port: config.port,
+            ..Default::default()
+        })
+        .manage(ValueBlock(config.value_block))
+        .manage(config.uid_format)
+        .manage(Wallets(config.wallets))
+        .manage(Units(config.currency))
+        .manage(CardholderSector(config.cardholder_sector))
+        .manage(ApiKeys::new(config.auth))
+        .manage(AuditLog(Arc::new(AuditTable::new(config.audit_file))))
+        .manage(Journal(Arc::new(journal)))
+        .manage(Idempotency::default())
+        // the origins a WebSocket may be opened from, besides the server's own
+        .manage(config.cors.clone())
+        .manage(blacklist)
+        .manage(registry)
+        .manage(config.reader.events.clone())
+        .manage(Readers::new(
+            readers
+                .into_iter()
+                .map(|(name, transport)| (name.clone(), Worker::spawn(name, transport, config.reader.clone())))
+                .collect(),
+        ))
+        .attach(RequestLog)
+        .attach(AdHoc::try_on_ignite("keystore", move |rocket| async move {
+            match keystore_loaded {
+                true => Ok(rocket),
+                false => Err(rocket),
+            }
+        }))
+        .attach(probe(config.require_reader))
+        .attach(drain())
+        .attach(reader_paths())
+        .mount("/", routes![health, ready, metrics::metrics, openapi::openapi, openapi::docs, ui::ui])
+        .register("/", catchers![unauthorized, forbidden, upgrade_required]);
+    let rocket = match config.cors.origins.is_empty() {
+        true => rocket,
+        false => rocket.attach(Cors(config.cors)),
+    };
+    let rocket = match config.grpc {
+        Some(grpc) => rocket.attach(grpc::serve(grpc, config.host)),
+        None => rocket,
+    };
+    // the unversioned paths stay as aliases of v1 for existing kiosks
+    let mut rocket = rocket.mount("/", v1(config.legacy_get));
+    for (base, routes) in api_versions(config.legacy_get) {
+        rocket = rocket.mount(base, routes);
+    }
+    rocket
+}
 
-        data.splice(0..0, size.iter().copied());
-        data.splice(0..0, HEADER.iter().copied());
+// Each API version mounts its own route list, so a /v2 can change paths, bodies and
+// errors while /v1 keeps answering as before
+fn api_versions(legacy_get: bool) -> Vec<(&'static str, Vec<Route>)> {
+    // v2 has no GET mutations and typed bodies
+    vec![("/v1", v1(legacy_get)), (v2::BASE, v1(false))]
+}
 
-        let final_data = Self::calculate_xor(data);
+fn v1(legacy_get: bool) -> Vec<Route> {
+    let mut routes = routes![ports, list_readers, card_events, websocket, present, last_card, wait, audit_log, audit_verify, journal, transactions, blacklist, add_to_blacklist, remove_from_blacklist, registry, registered_card, register_card, unregister_card, id, cardtype, halt, beep, reader_info, reader_status, set_rf, read_balance, post_balance, post_increase, post_decrease, post_initcard, deinit_card, transfer_value, rotate_keys, read_block, read_sector, write_block, write_sector, restore, read_ndef, write_ndef, read_cardholder, write_cardholder, read_page, write_page, raw, apdu, cards_in_field, wallet_balance, post_wallet_balance, post_wallet_increase, post_wallet_decrease];
+    // mutations over GET, replayed by prefetching browsers and proxies
+    if legacy_get {
+        routes.extend(routes![set_balance, increase, decrease, initcard, wallet_increase, wallet_decrease]);
+    }
+    routes
+}
 
-        // Write data to the serial port
-        match self.port.write(&final_data) {
-            Ok(_) => {
-                // println!("{} bytes written: {:X?}", bytes_written, final_data)
+// Ask every reader for its version before serving, so a missing or wrong device shows up
+// in the log right away (and stops the launch with `serial.require_reader`)
+fn probe(require_reader: bool) -> AdHoc {
+    AdHoc::try_on_ignite("ER302 probe", move |rocket| async move {
+        let mut failed = false;
+        for (name, slot) in rocket.state::<Readers>().expect("readers are managed").iter() {
+            let result = slot.worker.send(ReaderCommand::ReaderInfo).await.result;
+            match &result {
+                Ok(info) => tracing::info!(reader = name, model = %info["model"], firmware = %info["firmware"], "reader detected"),
+                Err(e) => tracing::error!(reader = name, error = %e, "no ER302 / YHY523U answered the version request"),
             }
-            Err(e) => eprintln!("Failed to write to serial port: {}", e),
+            failed |= result.is_err();
+            *slot.probe.lock().await = Some(result);
+        }
+        match failed && require_reader {
+            true => Err(rocket),
+            false => Ok(rocket),
         }
-        // thread::sleep(Duration::from_millis(100)); // Add delay only for Windows: (cause Windows is so lazy and can not handle the speed of Rust)
+    })
+}
+
+// On SIGTERM / SIGINT (Rocket stops accepting connections then): every reader finishes
+// its queue and is halted, switched off and closed, then the journal is flushed (the audit
+// table commits each entry as it comes)
+fn drain() -> AdHoc {
+    AdHoc::on_shutdown("drain readers", |rocket| {
+        Box::pin(async move {
+            let readers = rocket.state::<Readers>().expect("readers are managed");
+            rocket::futures::future::join_all(readers.iter().map(|(_, slot)| slot.worker.stop())).await;
+            rocket.state::<Journal>().expect("journal is managed").0.sync();
+            tracing::info!("readers closed, shutting down");
+        })
+    })
+}
+
+// Refused by a request guard, the same JSON shape as the routes
+fn failure(code: &'static str, message: &str) -> Reply {
+    Reply {
+        body: Json(ApiResponse {
+            status: false,
+            data: Value::String(message.to_string()),
+            code: Some(code),
+            request_id: None,
+            transaction_id: None,
+            counter: None,
+            key_profile: None,
+            uid: None,
+            debug: None,
+            balance: None,
+            command: None,
+        }),
+        queue_wait: Header::new("X-Queue-Wait-Ms", "0"),
+    }
+}
+
+#[derive(Responder)]
+#[response(status = 401)]
+struct Unauthorized {
+    body: Reply,
+    challenge: Header<'static>,
+}
+
+#[catch(401)]
+fn unauthorized() -> Unauthorized {
+    Unauthorized {
+        body: failure("UNAUTHORIZED", "missing or invalid API key or token"),
+        challenge: Header::new("WWW-Authenticate", "Bearer"),
+    }
+}
+
+#[derive(Responder)]
+#[response(status = 403)]
+struct Forbidden(Reply);
+
+#[catch(403)]
+fn forbidden(request: &Request) -> Forbidden {
+    let Refusal(reason) = request.local_cache(|| Refusal("forbidden"));
+    Forbidden(failure("FORBIDDEN", reason))
+}
+
+#[derive(Responder)]
+#[response(status = 426)]
+struct UpgradeRequired {
+    body: Reply,
+    version: Header<'static>,
+}
+
+// Not a WebSocket handshake, or one of a version other than 13 (the one RFC 6455 defines)
+#[catch(426)]
+fn upgrade_required() -> UpgradeRequired {
+    UpgradeRequired {
+        body: failure("UPGRADE_REQUIRED", "a WebSocket handshake, version 13, is needed"),
+        version: Header::new("Sec-WebSocket-Version", ws::VERSION),
+    }
+}
+
+fn reply(result: Result<Value, ReaderError>, queue_wait: Duration) -> Reply {
+    let body = match result {
+        Ok(data) => ApiResponse {
+            status: true,
+            data,
+            code: None,
+            request_id: None,
+            transaction_id: None,
+            counter: None,
+            key_profile: None,
+            uid: None,
+            debug: None,
+            balance: None,
+            command: None,
+        },
+        Err(e) => ApiResponse {
+            status: false,
+            data: Value::String(e.to_string()),
+            code: Some(e.code()),
+            request_id: None,
+            transaction_id: None,
+            counter: None,
+            key_profile: None,
+            uid: None,
+            debug: None,
+            balance: None,
+            command: None,
+        },
+    };
+    Reply {
+        body: Json(body),
+        queue_wait: Header::new("X-Queue-Wait-Ms", queue_wait.as_millis().to_string()),
+    }
+}
 
+// Queue one command for the reader worker and wait for its result
+async fn with_reader(reader: &SelectedReader<'_>, command: ReaderCommand) -> Reply {
+    with_profile(reader, command, None).await
+}
 
-        // Buffer to read data
-        let mut buffer: Vec<u8> = vec![0; 1024]; // Allocate a large buffer initially
-        match self.port.read(&mut buffer) {
-            Ok(bytes_read) => {
-                // Trim the buffer to the actual size of the data read
-                buffer.truncate(bytes_read); // Keep only the bytes that were actually read
-                                             // println!("{} bytes read: {:X?}", bytes_read, &buffer);
+// with_reader, opening the card with the key profile `profile` only
+async fn with_profile(reader: &SelectedReader<'_>, command: ReaderCommand, profile: Option<String>) -> Reply {
+    match &reader.slot {
+        Ok(slot) => {
+            let (name, amount) = (command.name(), command.amount());
+            let changes_balance = matches!(command, ReaderCommand::Increase(..) | ReaderCommand::Decrease(..));
+            let options = Options {
+                trace: reader.debug,
+                card: reader.card.clone(),
+                profile,
+            };
+            let response = slot.worker.send_with(command, options).instrument(reader.span.clone()).await;
+            let transaction = changes_balance.then(|| Transaction {
+                id: logging::new_uuid(),
+                before: response.before,
+                after: response.result.as_ref().ok().and_then(|data| data.as_str()?.parse().ok()),
+            });
+            let mut reply = reply(response.result, response.queue_wait);
+            reply.body.transaction_id = transaction.as_ref().map(|transaction| transaction.id.clone());
+            reply.body.counter = response.counter;
+            reply.body.key_profile = response.key_profile;
+            reply.body.uid = response.uid.as_deref().map(|uid| reader.format.render(uid));
+            if reply.body.status {
+                render_uids(&mut reply.body.data, name, reader.format);
             }
-            Err(e) => eprintln!("Failed to read from serial port: {}", e),
+            reply.body.command = Some(name);
+            if reader.debug {
+                reply.body.debug = Some(frames(&response.frames, response.queue_wait, &slot.worker.latencies()));
+            }
+            reader.pending.set(Operation {
+                reader: reader.name.to_string(),
+                command: name,
+                uid: reply.body.uid.clone(),
+                amount,
+                transaction,
+            });
+            reply
         }
+        Err(e) => reply(Err(e.clone()), Duration::ZERO),
+    }
+}
 
-        Ok(buffer) // Return the buffer with the actual size
+// The UIDs in the data of a command in `format`, the worker answers them as hex
+fn render_uids(data: &mut Value, command: &str, format: UidFormat) {
+    let render = |uid: &mut Value| {
+        if let Some(bytes) = uid.as_str().and_then(|hex| codec::from_hex(hex).ok()) {
+            *uid = Value::String(format.render(&bytes));
+        }
+    };
+    match command {
+        "read_id" | "halt" => render(data),
+        "read_uid" | "read_card_type" => render(&mut data["uid"]),
+        "list_cards" => data["cards"].as_array_mut().into_iter().flatten().for_each(|card| render(&mut card["uid"])),
+        _ => (),
     }
+}
 
-    // Beep
-    fn beep(&mut self, time: u8) -> () {
-        let mut beep: Vec<u8> = vec![0x00, 0x00, 0x06, 0x01];
-        beep.extend_from_slice(&time.to_le_bytes());
-        match self.send_request(beep.as_slice()){
-            Ok(_) => (),
-            Err(_) => println!("error to send data")
+// `{"queue_ms": 0, "frames": [{"tx": "AABB...", "rx": "AABB...", "ms": 12.5}], "latencies": {...}}`,
+// rx is null when the reader didn't answer. The latencies are the reader's since startup of
+// the commands in the frames, {count, failures, mean_ms, max_ms} each.
+fn frames(frames: &[Exchange], queue_wait: Duration, latencies: &BTreeMap<&'static str, Latency>) -> Value {
+    let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+    let mut commands = json!({});
+    for exchange in frames {
+        let Some(&[low, high]) = exchange.request.get(6..8) else {
+            continue;
+        };
+        let command = codec::command_name(u16::from_le_bytes([low, high]));
+        if let Some(latency) = latencies.get(command) {
+            commands[command] = json!({
+                "count": latency.count,
+                "failures": latency.failures,
+                "mean_ms": ms(latency.mean()),
+                "max_ms": ms(latency.max),
+            });
         }
     }
+    let frames: Vec<_> = frames
+        .iter()
+        .map(|exchange| {
+            json!({
+                "tx": codec::to_hex(&exchange.request),
+                "rx": exchange.response.as_deref().map(codec::to_hex),
+                "ms": exchange.elapsed.as_secs_f64() * 1000.0,
+            })
+        })
+        .collect();
+    json!({ "queue_ms": queue_wait.as_millis() as u64, "frames": frames, "latencies": commands })
+}
+
+// Same as `with_reader` for commands on the value block
+async fn with_value_block<F>(
+    worker: &SelectedReader<'_>,
+    value_block: &ValueBlock,
+    sector: Option<u8>,
+    block: Option<u8>,
+    command: F,
+) -> Reply
+where
+    F: FnOnce(BlockAddress) -> ReaderCommand,
+{
+    match value_block.resolve(sector, block) {
+        Ok(block) => with_reader(worker, command(block)).await,
+        Err(e) => reply(Err(e), Duration::ZERO),
+    }
+}
 
-    // Request Mifare
-    fn mifare_request(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let mifare_request = &[0x00, 0x00, 0x01, 0x02, 0x52];
-        self.send_request(mifare_request)?;
-        Ok(())
+// A command on the value block of a wallet, with its key profile
+async fn with_wallet<F>(worker: &SelectedReader<'_>, wallets: &Wallets, name: &str, command: F) -> Reply
+where
+    F: FnOnce(BlockAddress) -> ReaderCommand,
+{
+    match wallets.get(name) {
+        Ok(wallet) => with_profile(worker, command(wallet.block), wallet.key_profile.clone()).await,
+        Err(e) => reply(Err(e), Duration::ZERO),
     }
+}
+
+// Successful increases / decreases answer with the receipt instead of the new balance
+fn receipt(worker: &SelectedReader<'_>, mut reply: Reply) -> Reply {
+    if let Some(receipt) = worker.pending.receipt().filter(|_| reply.body.status) {
+        reply.body.data = receipt;
+    }
+    reply
+}
 
-    // Anticollision
-    fn anticollision(&mut self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let anticollision: &[u8] = &[0x00, 0x00, 0x02, 0x02];
-        let cards = self.send_request(anticollision)?;
-        Ok(cards)
+// Increase / decrease once per Idempotency-Key, a retry gets the first answer
+async fn once(
+    idempotency: &Idempotency,
+    key: IdempotencyKey<'_>,
+    worker: &SelectedReader<'_>,
+    request: (&str, u32, Option<u8>, Option<u8>),
+    reply: impl std::future::Future<Output = Reply>,
+) -> Reply {
+    let (command, value, sector, block) = request;
+    let fingerprint = format!("{} {} {:?} {:?} {}", command, value, sector, block, worker.name);
+    match idempotency.run(key.0, fingerprint, reply).await {
+        Ok(reply) => reply,
+        Err(Refused::InvalidKey) => failure("INVALID_INPUT", "Idempotency-Key must be 1 to 255 characters"),
+        Err(Refused::InProgress) => failure("IN_PROGRESS", "a request with this Idempotency-Key is still running"),
+        Err(Refused::Mismatch) => failure("IDEMPOTENCY_MISMATCH", "this Idempotency-Key was used for another request"),
     }
+}
+
+// Serial ports of the host, USB ones with vendor / product id
+#[get("/ports")]
+async fn ports(_caller: Caller) -> Reply {
+    // enumerating walks sysfs / the registry, off the async workers like the reader I/O
+    let result = rocket::tokio::task::spawn_blocking(serialport::available_ports)
+        .await
+        .map_err(|e| ReaderError::PortError(e.to_string()))
+        .and_then(|ports| ports.map_err(|e| ReaderError::PortError(e.to_string())))
+        .map(|ports| Value::Array(ports.iter().map(port_json).collect()));
+    reply(result, Duration::ZERO)
+}
+
+fn port_json(port: &SerialPortInfo) -> Value {
+    match &port.port_type {
+        SerialPortType::UsbPort(usb) => json!({
+            "name": port.port_name,
+            "type": "usb",
+            "vid": format!("{:04X}", usb.vid),
+            "pid": format!("{:04X}", usb.pid),
+            "serial_number": usb.serial_number,
+            "manufacturer": usb.manufacturer,
+            "product": usb.product,
+        }),
+        SerialPortType::PciPort => json!({ "name": port.port_name, "type": "pci" }),
+        SerialPortType::BluetoothPort => json!({ "name": port.port_name, "type": "bluetooth" }),
+        SerialPortType::Unknown => json!({ "name": port.port_name, "type": "unknown" }),
+    }
+}
 
-    // Select Card
-    fn select_card(&mut self, cards: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
-        let selected_card = &[
-            0x00, 0x00, 0x03, 0x02, cards[9], cards[10], cards[11], cards[12],
-        ];
-        self.send_request(selected_card)?;
-        Ok(())
-    }
-
-    // Authenticate on block 53
-    fn authenticate(&mut self, key: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
-        let mut auth: Vec<u8> = vec![0x00, 0x00, 0x07, 0x02, 0x60, 0x35];
-        auth.extend_from_slice(key);
-        self.send_request(auth.as_slice())?;
-        Ok(())
+// UID as hex, `?detailed=true` answers {uid, length} instead
+#[get("/id?<detailed>")]
+async fn id(_caller: Caller, worker: SelectedReader<'_>, detailed: Option<bool>) -> Reply {
+    match detailed {
+        Some(true) => with_reader(&worker, ReaderCommand::ReadUid).await,
+        _ => with_reader(&worker, ReaderCommand::ReadId).await,
     }
+}
+
+// Card family detected from ATQA / SAK, e.g. CLASSIC_1K, ULTRALIGHT, DESFIRE
+#[get("/cardtype")]
+async fn cardtype(_caller: Caller, worker: SelectedReader<'_>) -> Reply {
+    with_reader(&worker, ReaderCommand::ReadCardType).await
+}
+
+// Every card in the field, {cards: [{uid, type, atqa, sak}]}, when two are stacked a UID of
+// it goes to `?card=` of the other routes
+#[get("/cards")]
+async fn cards_in_field(_caller: Caller, worker: SelectedReader<'_>) -> Reply {
+    with_reader(&worker, ReaderCommand::ListCards).await
+}
+
+// HALT the card in the field, returns its UID
+#[post("/halt")]
+async fn halt(_caller: Caller, worker: SelectedReader<'_>) -> Reply {
+    with_reader(&worker, ReaderCommand::Halt).await
+}
 
-    // Read Balance from block 53
-    fn read_balance_request(&mut self) -> Result<u32, Box<dyn std::error::Error>> {
-        let read_balance: &[u8] = &[0x00, 0x00, 0x0B, 0x02, 0x35];
-        let balance = self.send_request(read_balance)?;
-
-        let num: u32 = u32::from_le_bytes([balance[9], balance[10], balance[11], balance[12]]);
-        Ok(num)
-    }
-
-    // Init balance on block 53
-    fn init_balance_request(&mut self, balance: u32) -> Result<(), Box<dyn std::error::Error>> {
-        let mut init_balance: Vec<u8> = vec![0x00, 0x00, 0x0a, 0x02, 0x35];
-        init_balance.extend_from_slice(&(balance.to_le_bytes()));
-        self.send_request(init_balance.as_slice())?;
-        Ok(())
-    }
-
-    // Increase balance on block 53
-    fn increase_balance_request(&mut self, value: u32) -> Result<(), Box<dyn std::error::Error>> {
-        let mut init_balance: Vec<u8> = vec![0x00, 0x00, 0x0D, 0x02, 0x35];
-        init_balance.extend_from_slice(&(value.to_le_bytes()));
-        self.send_request(init_balance.as_slice())?;
-        Ok(())
-    }
-
-    // Decrease balance on block 53
-    fn decrease_balance_request(&mut self, value: u32) -> Result<(), Box<dyn std::error::Error>> {
-        let mut init_balance: Vec<u8> = vec![0x00, 0x00, 0x0c, 0x02, 0x35];
-        init_balance.extend_from_slice(&(value.to_le_bytes()));
-        self.send_request(init_balance.as_slice())?;
-        Ok(())
-    }
-
-    // Init card with keys
-    fn init_card_request(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let mut init_card: Vec<u8> = vec![0x00, 0x00, 0x09, 0x02, 0x37];
-        init_card.extend_from_slice(APPKEY);
-        init_card.extend_from_slice(KEYACCESS);
-        init_card.extend_from_slice(DEFAULTKEY);
-        self.send_request(init_card.as_slice())?;
-        Ok(())
-    }
-
-    //########Functinalities##############################################################################################
-
-    // Read id
-    fn read_id(&mut self) -> Result<String, String> {
-        match self.mifare_request().map_err(|e| e.to_string()) {
-            Ok(_) => match self.anticollision().map_err(|e| e.to_string()) {
-                Ok(cards) => {
-                    if cards.len() > 13 {
-                        self.beep(2);
-                        Ok(cards[9..13]
-                            .iter()
-                            .map(|byte| format!("{:02X}", byte))
-                            .collect::<Vec<String>>()
-                            .join(""))
-                    } else {
-                        Err("Card not found".to_string())
-                    }
-                }
-
-                Err(_) => Err("nothing".to_string()),
-            },
-            Err(_) => Err("Baghali".to_string()),
-        }
-    }
-
-    // Read Balance
-    fn read_balance(&mut self) -> Result<String, String> {
-        match self.mifare_request().map_err(|e| e.to_string()) {
-            Ok(_) => match self.anticollision().map_err(|e| e.to_string()) {
-                Ok(cards) => {
-                    if cards.len() > 13 {
-                        self.select_card(&cards).map_err(|e| e.to_string())?;
-                        match self.authenticate(APPKEY) {
-                            Ok(_) => {
-                        self.beep(2);
-
-                                Ok((self.read_balance_request().map_err(|e| e.to_string())?)
-                                    .to_string())
-                            }
-                            Err(_) => Err("Authentication failed".to_string()),
-                        }
-                    } else {
-                        Err("Card not found".to_string())
-                    }
-                }
-
-                Err(_) => Err("nothing".to_string()),
-            },
-            Err(_) => Err("Baghali".to_string()),
-        }
-    }
-
-    // Init Balance
-    fn init_balance(&mut self, value: u32) -> Result<String, String> {
-        match self.mifare_request().map_err(|e| e.to_string()) {
-            Ok(_) => match self.anticollision().map_err(|e| e.to_string()) {
-                Ok(cards) => {
-                    if cards.len() > 13 {
-                        self.select_card(&cards).map_err(|e| e.to_string())?;
-                        match self.authenticate(APPKEY) {
-                            Ok(_) => {
-                                self.init_balance_request(value).map_err(|e| e.to_string())?;
-                                match self.read_balance() {
-                                    Ok(data) => {
-                        self.beep(2);
-
-                                        Ok(data)
-                                    }
-                                    Err(_) => {
-                                        Err("Balance has wrote to card but can't retrive balance".to_string())
-                                    }
-
-                                }
-                            }
-                            Err(_) => Err("Authentication failed".to_string()),
-                        }
-                    } else {
-                        Err("Card not found".to_string())
-                    }
-                }
-
-                Err(_) => Err("nothing".to_string()),
-            },
-            Err(_) => Err("Baghali".to_string()),
-        }
-    }
-
-    fn increase(&mut self, value: u32) -> Result<String, String> {
-        match self.mifare_request().map_err(|e| e.to_string()) {
-            Ok(_) => match self.anticollision().map_err(|e| e.to_string()) {
-                Ok(cards) => {
-                    if cards.len() > 13 {
-                        self.select_card(&cards).map_err(|e| e.to_string())?;
-                        match self.authenticate(APPKEY) {
-                            Ok(_) => {
-                                self.increase_balance_request(value).map_err(|e| e.to_string())?;
-                                match self.read_balance() {
-                                    Ok(data) => {
-                        self.beep(2);
-
-                                        Ok(data)
-                                    }
-                                    Err(_) => {
-                                        Err("Balance has wrote to card but can't retrive balance".to_string())
-                                    }
-
-                                }
-                            }
-                            Err(_) => Err("Authentication failed".to_string()),
-                        }
-                    } else {
-                        Err("Card not found".to_string())
-                    }
-                }
-
-                Err(_) => Err("nothing".to_string()),
-            },
-            Err(_) => Err("Baghali".to_string()),
-        }
-    }
-    fn decrease(&mut self, value: u32) -> Result<String, String> {
-        match self.mifare_request().map_err(|e| e.to_string()) {
-            Ok(_) => match self.anticollision().map_err(|e| e.to_string()) {
-                Ok(cards) => {
-                    if cards.len() > 13 {
-                        self.select_card(&cards).map_err(|e| e.to_string())?;
-                        match self.authenticate(APPKEY) {
-                            Ok(_) => {
-                                self.decrease_balance_request(value).map_err(|e| e.to_string())?;
-                                match self.read_balance() {
-                                    Ok(data) => {
-                        self.beep(2);
-
-                                        Ok(data)
-                                    }
-                                    Err(_) => {
-                                        Err("Balance has wrote to card but can't retrive balance".to_string())
-                                    }
-
-                                }
-                            }
-                            Err(_) => Err("Authentication failed".to_string()),
-                        }
-                    } else {
-                        Err("Card not found".to_string())
-                    }
-                }
-
-                Err(_) => Err("nothing".to_string()),
-            },
-            Err(_) => Err("Baghali".to_string()),
-        }
-    }
-    fn init_card(&mut self) -> Result<String, String> {
-        match self.mifare_request().map_err(|e| e.to_string()) {
-            Ok(_) => match self.anticollision().map_err(|e| e.to_string()) {
-                Ok(cards) => {
-                    if cards.len() > 13 {
-                        self.select_card(&cards).map_err(|e| e.to_string())?;
-                        match self.authenticate(DEFAULTKEY) {
-                            Ok(_) => {
-                                match self.init_card_request() { 
-                                    Ok(_) => {
-                                        self.beep(2);
-                                        
-                                        Ok("Card configured successfully".to_string()) 
-                                    },
-                                    Err(data) => Err(format!("error: {} \n info : card was configured or there is a problem to config that",data.to_string(),))
-                                }
-                            }
-                            Err(_) => Err("Authentication failed".to_string()),
-                        }
-                    } else {
-                        Err("Card not found".to_string())
-                    }
-                }
-
-                Err(_) => Err("nothing".to_string()),
-            },
-            Err(_) => Err("Baghali".to_string()),
+// Model, firmware revision and serial number of the attached reader
+#[get("/reader/info")]
+async fn reader_info(_caller: Caller, worker: SelectedReader<'_>) -> Reply {
+    with_reader(&worker, ReaderCommand::ReaderInfo).await
+}
+
+// Server-sent events of the cards entering the field, `event: card_detected` with
+// {kind, uid, reader, timestamp}, of one reader with ?reader=<name>
+#[get("/events?<reader>")]
+fn card_events(_caller: Caller, events: &State<Events>, reader: Option<String>, mut shutdown: Shutdown) -> EventStream![] {
+    let mut receiver = events.subscribe();
+    EventStream! {
+        loop {
+            let event = select! {
+                event = receiver.recv() => match event {
+                    Ok(event) => event,
+                    Err(RecvError::Closed) => break,
+                    // a slow client misses the oldest events
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+                _ = &mut shutdown => break,
+            };
+            if reader.as_ref().is_none_or(|reader| *reader == event.reader) {
+                yield SseEvent::json(&event).event(event.kind);
+            }
         }
     }
 }
 
+// The events of /events over a WebSocket, which also takes commands (see ws.rs)
+#[get("/ws?<reader>")]
+fn websocket(
+    _caller: Caller,
+    upgrade: ws::Upgrade,
+    origin: calls::Origin,
+    backend: calls::Backend,
+    reader: Option<String>,
+    shutdown: Shutdown,
+) -> ws::Socket {
+    let session = ws::Session {
+        events: backend.events.subscribe(),
+        reader,
+        backend,
+        origin,
+        shutdown,
+    };
+    ws::Socket { upgrade, session }
+}
 
+// Liveness: the process is up and serving
+#[get("/health")]
+fn health() -> Reply {
+    reply(Ok(json!({ "alive": true })), Duration::ZERO)
+}
 
-#[launch]
-fn rocket() -> _ {
-    // Load configuration
-    let (host, port) = match load_config() {
-        Ok((_,_,host, port)) => {
-            (host,port)
+// Readiness: every reader's port is open and it answers a version request right now.
+// 503 with the failing readers when one doesn't.
+#[get("/ready")]
+async fn ready(readers: &State<Readers>) -> (Status, Reply) {
+    let mut report = json!({});
+    let mut failure = None;
+    for (name, slot) in readers.iter() {
+        let entry = match slot.worker.send(ReaderCommand::ReaderInfo).await.result {
+            Ok(_) => json!({ "ready": true }),
+            Err(e) => {
+                let entry = json!({ "ready": false, "code": e.code(), "message": e.to_string() });
+                failure.get_or_insert(e);
+                entry
+            }
+        };
+        report[name] = entry;
+    }
+    let body = ApiResponse {
+        status: failure.is_none(),
+        data: json!({ "readers": report }),
+        code: failure.as_ref().map(ReaderError::code),
+        request_id: None,
+        transaction_id: None,
+        counter: None,
+        key_profile: None,
+        uid: None,
+        debug: None,
+        balance: None,
+        command: None,
+    };
+    let reply = Reply {
+        body: Json(body),
+        queue_wait: Header::new("X-Queue-Wait-Ms", "0"),
+    };
+    match failure {
+        None => (Status::Ok, reply),
+        Some(_) => (Status::ServiceUnavailable, reply),
+    }
+}
+
+// Names of the configured readers, for `?reader=` and `/readers/<name>/...`
+#[get("/readers")]
+fn list_readers(_caller: Caller, readers: &State<Readers>) -> Reply {
+    let names: Vec<&str> = readers.iter().map(|(name, _)| name).collect();
+    reply(Ok(json!({ "readers": names })), Duration::ZERO)
+}
+
+// Audit log entries, oldest first: ?uid=&route=&caller=&code=&status=&since=&until= (unix
+// seconds) filter them, `limit` (100 by default) keeps the last ones
+#[allow(clippy::too_many_arguments)]
+#[get("/audit?<uid>&<route>&<caller>&<code>&<status>&<since>&<until>&<limit>")]
+fn audit_log(
+    _caller: Caller,
+    log: &State<AuditLog>,
+    uid: Option<&str>,
+    route: Option<&str>,
+    caller: Option<&str>,
+    code: Option<&str>,
+    status: Option<bool>,
+    since: Option<f64>,
+    until: Option<f64>,
+    limit: Option<usize>,
+) -> Reply {
+    let AuditLog(log) = log.inner();
+    if !log.enabled() {
+        return failure("AUDIT_OFF", "the audit log is off, set audit.file");
+    }
+    let filter = audit::Filter { uid, transaction_id: None, route, caller, code, status, since, until };
+    match log.search(&filter, limit.unwrap_or(100).min(audit::MAX_ENTRIES)) {
+        Ok(entries) => reply(Ok(json!({ "entries": entries })), Duration::ZERO),
+        Err(e) => failure("AUDIT_ERROR", &format!("can't read the audit log: {}", e)),
+    }
+}
+
+// Walks the hash chain of the audit log: {entries} when no entry was changed, removed or
+// put in between since it was written
+#[get("/audit/verify")]
+fn audit_verify(_caller: Caller, log: &State<AuditLog>) -> Reply {
+    let AuditLog(log) = log.inner();
+    if !log.enabled() {
+        return failure("AUDIT_OFF", "the audit log is off, set audit.file");
+    }
+    match log.verify() {
+        Ok(Ok(entries)) => reply(Ok(json!({ "entries": entries })), Duration::ZERO),
+        Ok(Err(id)) => failure("AUDIT_TAMPERED", &format!("the audit log's hash chain breaks at entry {}", id)),
+        Err(e) => failure("AUDIT_ERROR", &format!("can't read the audit log: {}", e)),
+    }
+}
+
+// Journal of the increases / decreases with the balances before and after, oldest first:
+// ?transaction_id= finds one, ?uid=&since=&until= the ones of a card
+#[get("/journal?<transaction_id>&<uid>&<since>&<until>&<limit>")]
+fn journal(
+    _caller: Caller,
+    journal: &State<Journal>,
+    transaction_id: Option<&str>,
+    uid: Option<&str>,
+    since: Option<f64>,
+    until: Option<f64>,
+    limit: Option<usize>,
+) -> Reply {
+    let Journal(journal) = journal.inner();
+    if !journal.enabled() {
+        return failure("JOURNAL_OFF", "the journal is off, set journal.file");
+    }
+    let filter = audit::Filter {
+        uid,
+        transaction_id,
+        route: None,
+        caller: None,
+        code: None,
+        status: None,
+        since,
+        until,
+    };
+    match journal.search(&filter, limit.unwrap_or(100).min(audit::MAX_ENTRIES)) {
+        Ok(entries) => reply(Ok(json!({ "entries": entries })), Duration::ZERO),
+        Err(e) => failure("JOURNAL_ERROR", &format!("can't read the journal: {}", e)),
+    }
+}
+
+// The journal for accounting imports, ?format=csv or JSON, in pages of `limit` entries (1000
+// by default) that continue ?after= the last transaction_id of the previous one
+#[allow(clippy::too_many_arguments)]
+#[get("/transactions?<from>&<to>&<uid>&<status>&<format>&<after>&<limit>")]
+fn transactions(
+    _caller: Caller,
+    journal: &State<Journal>,
+    from: Option<f64>,
+    to: Option<f64>,
+    uid: Option<&str>,
+    status: Option<bool>,
+    format: Option<&str>,
+    after: Option<&str>,
+    limit: Option<usize>,
+) -> Either<Reply, export::Export> {
+    let Journal(journal) = journal.inner();
+    if !journal.enabled() {
+        return Either::Left(failure("JOURNAL_OFF", "the journal is off, set journal.file"));
+    }
+    let Some(format) = export::Format::parse(format) else {
+        let error = ReaderError::InvalidInput(format!("unknown format {:?}, csv or json", format.unwrap_or_default()));
+        return Either::Left(reply(Err(error), Duration::ZERO));
+    };
+    match journal.reader() {
+        Ok(file) => Either::Right(export::Export {
+            file,
+            format,
+            uid: uid.map(str::to_string),
+            status,
+            from,
+            to,
+            after: after.map(str::to_string),
+            limit: limit.unwrap_or(export::PAGE).clamp(1, export::MAX_PAGE),
+        }),
+        Err(e) => Either::Left(failure("JOURNAL_ERROR", &format!("can't read the journal: {}", e))),
+    }
+}
+
+// Blacklisted cards: {cards: [{uid, reason, added}]}
+#[get("/blacklist")]
+fn blacklist(_caller: Caller, blacklist: &State<Arc<Blacklist>>) -> Reply {
+    reply(Ok(json!({ "cards": blacklist.0.list() })), Duration::ZERO)
+}
+
+#[derive(Deserialize)]
+struct BlacklistEntry {
+    // lost, cloned, ...
+    reason: Option<String>,
+}
+
+// Refuse every balance operation on the card from now on, {reason?}
+#[post("/blacklist/<uid>", data = "<body>")]
+fn add_to_blacklist(_caller: Caller, blacklist: &State<Arc<Blacklist>>, uid: &str, body: Option<Json<BlacklistEntry>>) -> Reply {
+    let uid = match cards::normalize_uid(uid) {
+        Ok(uid) => uid,
+        Err(e) => return reply(Err(e), Duration::ZERO),
+    };
+    let reason = body.and_then(|body| body.into_inner().reason);
+    match blacklist.0.insert(&uid, json!({ "reason": reason })) {
+        Ok(()) => {
+            tracing::info!(target: "er302::audit", uid, reason, "card blacklisted");
+            reply(Ok(json!({ "uid": uid, "blacklisted": true })), Duration::ZERO)
         }
-        Err(e) => {
-            println!("error : {:?}",e.to_string());
-            ("0.0.0.0".to_string(), 8000)
+        Err(e) => failure("BLACKLIST_ERROR", &format!("can't save the blacklist: {}", e)),
+    }
+}
+
+#[delete("/blacklist/<uid>")]
+fn remove_from_blacklist(_caller: Caller, blacklist: &State<Arc<Blacklist>>, uid: &str) -> Reply {
+    let uid = match cards::normalize_uid(uid) {
+        Ok(uid) => uid,
+        Err(e) => return reply(Err(e), Duration::ZERO),
+    };
+    match blacklist.0.remove(&uid) {
+        Ok(true) => {
+            tracing::info!(target: "er302::audit", uid, "card removed from the blacklist");
+            reply(Ok(json!({ "uid": uid, "blacklisted": false })), Duration::ZERO)
         }
+        Ok(false) => failure("NOT_BLACKLISTED", "the card isn't blacklisted"),
+        Err(e) => failure("BLACKLIST_ERROR", &format!("can't save the blacklist: {}", e)),
+    }
+}
+
+// Registered cards: {cards: [{uid, label, issued_at, added}]}
+#[get("/registry")]
+fn registry(_caller: Caller, registry: &State<Arc<Registry>>) -> Reply {
+    reply(Ok(json!({ "cards": registry.cards.list() })), Duration::ZERO)
+}
+
+#[get("/registry/<uid>")]
+fn registered_card(_caller: Caller, registry: &State<Arc<Registry>>, uid: &str) -> Reply {
+    let uid = match cards::normalize_uid(uid) {
+        Ok(uid) => uid,
+        Err(e) => return reply(Err(e), Duration::ZERO),
     };
-    
-    println!("Card Reader,Write API for Ehuoyan ER302 by https://sajx.net ⭐️. Under Bartarandishan License");
-    rocket::build()
-        .configure(rocket::Config {
-            address: host.parse().unwrap(),
-            port,
-            ..Default::default()
+    match registry.cards.get(&uid) {
+        Some(card) => reply(Ok(card), Duration::ZERO),
+        None => failure("NOT_REGISTERED", "the card isn't registered"),
+    }
+}
+
+#[derive(Deserialize)]
+struct RegistryEntry {
+    label: Option<String>,
+    // unix seconds, now when missing
+    issued_at: Option<f64>,
+}
+
+// Register a card or replace its metadata, {label?, issued_at?}
+#[post("/registry/<uid>", data = "<body>")]
+fn register_card(_caller: Caller, registry: &State<Arc<Registry>>, uid: &str, body: Option<Json<RegistryEntry>>) -> Reply {
+    let uid = match cards::normalize_uid(uid) {
+        Ok(uid) => uid,
+        Err(e) => return reply(Err(e), Duration::ZERO),
+    };
+    let (label, issued_at) = match body.map(Json::into_inner) {
+        Some(entry) => (entry.label, entry.issued_at),
+        None => (None, None),
+    };
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+    let issued_at = issued_at.unwrap_or(now.as_millis() as f64 / 1000.0);
+    match registry.cards.insert(&uid, json!({ "label": label, "issued_at": issued_at })) {
+        Ok(()) => {
+            tracing::info!(target: "er302::audit", uid, label, "card registered");
+            reply(Ok(registry.cards.get(&uid).unwrap_or_default()), Duration::ZERO)
+        }
+        Err(e) => failure("REGISTRY_ERROR", &format!("can't save the registry: {}", e)),
+    }
+}
+
+#[delete("/registry/<uid>")]
+fn unregister_card(_caller: Caller, registry: &State<Arc<Registry>>, uid: &str) -> Reply {
+    let uid = match cards::normalize_uid(uid) {
+        Ok(uid) => uid,
+        Err(e) => return reply(Err(e), Duration::ZERO),
+    };
+    match registry.cards.remove(&uid) {
+        Ok(true) => {
+            tracing::info!(target: "er302::audit", uid, "card unregistered");
+            reply(Ok(json!({ "uid": uid, "registered": false })), Duration::ZERO)
+        }
+        Ok(false) => failure("NOT_REGISTERED", "the card isn't registered"),
+        Err(e) => failure("REGISTRY_ERROR", &format!("can't save the registry: {}", e)),
+    }
+}
+
+// Card in the field from the polling, for UIs that prompt "place your card":
+// {present, uid, present_ms} with how long it has been there
+#[get("/present")]
+fn present(_caller: Caller, reader: SelectedReader<'_>) -> Reply {
+    let presence = reader.slot.and_then(|slot| {
+        let polling = "nothing polls the reader, enable [polling] or listen to /events";
+        slot.worker.presence().ok_or_else(|| ReaderError::InvalidInput(polling.to_string()))
+    });
+    let result = presence.map(|card| match card {
+        Some(card) => json!({
+            "present": true,
+            "uid": reader.format.render(&card.uid),
+            "present_ms": card.since.elapsed().as_millis() as u64,
+        }),
+        None => json!({ "present": false, "uid": null, "present_ms": null }),
+    });
+    reply(result, Duration::ZERO)
+}
+
+// Card detected last by any reader's polling, {uid, reader, timestamp}, for enrollment:
+// "tap the new card, then click register". `data` is null before the first one.
+#[get("/lastcard")]
+fn last_card(_caller: Caller, events: &State<Events>) -> Reply {
+    let card = events.last_card().map(|event| {
+        json!({
+            "uid": event.uid,
+            "reader": event.reader,
+            "timestamp": event.timestamp,
         })
-        .mount("/", routes![id, read_balance, set_balance, increase, decrease, initcard])
-}
-
-
-#[get("/id")]
-fn id() -> Json<ApiResponse> {
-    let portname: String ;
-    let baudrate: u32 ;
-    
-    match load_config() {
-        Ok(conf) => (portname, baudrate,_,_) = conf , 
-        Err(_) => (portname, baudrate) = (PORTNAME.to_string(), BAUDRATE)
-    }
-    match serialport::new(portname, baudrate)
-        .timeout(Duration::from_secs(2))
-        .open()
-    {
-        Ok(port) => {
-            let mut rfid = RFID::new(port);
-
-            match rfid.read_id() {
-                Ok(data) => Json(ApiResponse {
-                    status: true,
-                    data: data,
-                }),
-                Err(data) => Json(ApiResponse {
-                    status: false,
-                    data: data,
-                }),
+    });
+    reply(Ok(card.unwrap_or(Value::Null)), Duration::ZERO)
+}
+
+// Long poll for a tap: the UID of the card presented within ?timeout= seconds (10 by
+// default, at most 60), 408 with NO_CARD when none was
+#[get("/wait?<timeout>")]
+async fn wait(_caller: Caller, reader: SelectedReader<'_>, events: &State<Events>, timeout: Option<u64>) -> (Status, Reply) {
+    let slot = match reader.slot {
+        Ok(slot) => slot,
+        Err(e) => return (Status::Ok, reply(Err(e), Duration::ZERO)),
+    };
+    let timeout = timeout.unwrap_or(10);
+    if timeout == 0 || timeout > 60 {
+        let error = ReaderError::InvalidInput("timeout must be 1-60 seconds".to_string());
+        return (Status::Ok, reply(Err(error), Duration::ZERO));
+    }
+    // the polled state is current only when something polled before this request, the
+    // subscription catches the taps from here on
+    let polled = slot.worker.presence().is_some();
+    let mut receiver = events.subscribe();
+    if let Some(card) = slot.worker.presence().flatten().filter(|_| polled) {
+        return (Status::Ok, tapped(&card.uid, reader.format));
+    }
+    let tap = async {
+        loop {
+            match receiver.recv().await {
+                Ok(event) if event.kind == "card_detected" && event.reader == reader.name => return Some(event.uid),
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
             }
         }
-        Err(_) => Json(ApiResponse {
-            status: false,
-            data: "Error in Connection".to_string(),
-        }),
+    };
+    match rocket::tokio::time::timeout(Duration::from_secs(timeout), tap).await {
+        Ok(Some(uid)) => (Status::Ok, tapped(&codec::from_hex(&uid).unwrap_or_default(), reader.format)),
+        _ => (Status::RequestTimeout, reply(Err(ReaderError::NoCard), Duration::ZERO)),
     }
 }
 
-#[get("/balance")]
-fn read_balance() -> Json<ApiResponse> {
-    let portname: String ;
-    let baudrate: u32 ;
-    
-    match load_config() {
- Ok(conf) => (portname, baudrate,_,_) = conf , 
-        Err(_) => (portname, baudrate) = (PORTNAME.to_string(), BAUDRATE)
-    }
-    match serialport::new(portname, baudrate)
-        .timeout(Duration::from_secs(2))
-        .open()
-    {
-        Ok(port) => {
-            let mut rfid = RFID::new(port);
-
-            match rfid.read_balance() {
-                Ok(data) => Json(ApiResponse {
-                    status: true,
-                    data: data,
-                }),
-                Err(data) => Json(ApiResponse {
-                    status: false,
-                    data: data,
-                }),
-            }
+// The UID of a tap, answered like /id
+fn tapped(uid: &[u8], format: UidFormat) -> Reply {
+    let uid = format.render(uid);
+    let mut reply = reply(Ok(Value::String(uid.clone())), Duration::ZERO);
+    reply.body.uid = Some(uid);
+    reply.body.command = Some("read_id");
+    reply
+}
+
+// Result of the startup probe: {detected, model, firmware, serial} or the probe error
+#[get("/reader/status")]
+async fn reader_status(_caller: Caller, reader: SelectedReader<'_>) -> Reply {
+    let slot = match reader.slot {
+        Ok(slot) => slot,
+        Err(e) => return reply(Err(e), Duration::ZERO),
+    };
+    let result = match &*slot.probe.lock().await {
+        Some(Ok(info)) => {
+            let mut status = info.clone();
+            status["detected"] = Value::Bool(true);
+            Ok(status)
         }
-        Err(_) => Json(ApiResponse {
-            status: false,
-            data: "Error in Connection".to_string(),
-        }),
+        Some(Err(e)) => Err(e.clone()),
+        None => Err(ReaderError::Busy),
+    };
+    reply(result, Duration::ZERO)
+}
+
+#[derive(Deserialize)]
+struct RfSwitch {
+    on: bool,
+}
+
+// RF field on / off, e.g. to save power or to reset a card after a torn write
+#[post("/reader/rf", data = "<body>")]
+async fn set_rf(_caller: Caller, worker: SelectedReader<'_>, body: Json<RfSwitch>) -> Reply {
+    with_reader(&worker, ReaderCommand::SetRf(body.on)).await
+}
+
+// Ad-hoc beeps, ?count=3&time=5&pause_ms=100 (at most 10 beeps, 1 s pause)
+#[post("/beep?<count>&<time>&<pause_ms>")]
+async fn beep(_caller: Caller, worker: SelectedReader<'_>, count: Option<u8>, time: Option<u8>, pause_ms: Option<u64>) -> Reply {
+    match ad_hoc_beep(count, time, pause_ms) {
+        Ok(pattern) => with_reader(&worker, ReaderCommand::Beep(pattern)).await,
+        Err(e) => reply(Err(e), Duration::ZERO),
     }
 }
 
+fn ad_hoc_beep(count: Option<u8>, time: Option<u8>, pause_ms: Option<u64>) -> Result<BeepPattern, ReaderError> {
+    let pattern = BeepPattern {
+        count: count.unwrap_or(1),
+        time: time.unwrap_or(2),
+        pause: Duration::from_millis(pause_ms.unwrap_or(100)),
+    };
+    if pattern.count == 0 || pattern.count > 10 || pattern.pause > Duration::from_secs(1) {
+        return Err(ReaderError::InvalidInput("count must be 1-10 and pause_ms at most 1000".to_string()));
+    }
+    Ok(pattern)
+}
 
-#[get("/balance/<value>")]
-fn set_balance(value: u32) -> Json<ApiResponse> {
-    let portname: String ;
-    let baudrate: u32 ;
-    
-    match load_config() {
- Ok(conf) => (portname, baudrate,_,_) = conf , 
-        Err(_) => (portname, baudrate) = (PORTNAME.to_string(), BAUDRATE)
-    }
-    match serialport::new(portname, baudrate)
-        .timeout(Duration::from_secs(2))
-        .open()
-    {
-        Ok(port) => {
-            let mut rfid = RFID::new(port);
-
-            match rfid.init_balance(value) {
-                Ok(data) => Json(ApiResponse {
-                    status: true,
-                    data: data.to_string(),
-                }),
-                Err(data) => Json(ApiResponse {
-                    status: false,
-                    data: data,
-                }),
-            }
+#[get("/balance?<sector>&<block>")]
+async fn read_balance(
+    _caller: Caller,
+    worker: SelectedReader<'_>,
+    value_block: &State<ValueBlock>,
+    sector: Option<u8>,
+    block: Option<u8>,
+) -> Reply {
+    with_value_block(&worker, value_block, sector, block, ReaderCommand::ReadBalance).await
+}
+
+#[get("/balance/<value>?<sector>&<block>&<dry_run>")]
+async fn set_balance(
+    _caller: Caller,
+    worker: SelectedReader<'_>,
+    value_block: &State<ValueBlock>,
+    value: u32,
+    sector: Option<u8>,
+    block: Option<u8>,
+    dry_run: Option<bool>,
+) -> Reply {
+    with_value_block(&worker, value_block, sector, block, |block| planned(dry_run, ReaderCommand::InitBalance(block, value))).await
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/increase/<value>?<sector>&<block>&<dry_run>")]
+async fn increase(
+    _caller: Caller,
+    worker: SelectedReader<'_>,
+    value_block: &State<ValueBlock>,
+    idempotency: &State<Idempotency>,
+    key: IdempotencyKey<'_>,
+    value: u32,
+    sector: Option<u8>,
+    block: Option<u8>,
+    dry_run: Option<bool>,
+) -> Reply {
+    let increase = with_value_block(&worker, value_block, sector, block, |block| planned(dry_run, ReaderCommand::Increase(block, value)));
+    match dry_run {
+        Some(true) => increase.await,
+        _ => once(idempotency, key, &worker, ("increase", value, sector, block), increase).await,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/decrease/<value>?<sector>&<block>&<dry_run>")]
+async fn decrease(
+    _caller: Caller,
+    worker: SelectedReader<'_>,
+    value_block: &State<ValueBlock>,
+    idempotency: &State<Idempotency>,
+    key: IdempotencyKey<'_>,
+    value: u32,
+    sector: Option<u8>,
+    block: Option<u8>,
+    dry_run: Option<bool>,
+) -> Reply {
+    let decrease = with_value_block(&worker, value_block, sector, block, |block| planned(dry_run, ReaderCommand::Decrease(block, value)));
+    match dry_run {
+        Some(true) => decrease.await,
+        _ => once(idempotency, key, &worker, ("decrease", value, sector, block), decrease).await,
+    }
+}
+
+// With ?dry_run=true the checks of the command (card, key, funds, balance limit) without its
+// write, a dry run isn't journaled and doesn't use up an Idempotency-Key
+fn planned(dry_run: Option<bool>, command: ReaderCommand) -> ReaderCommand {
+    match dry_run {
+        Some(true) => ReaderCommand::DryRun(Box::new(command)),
+        _ => command,
+    }
+}
+
+#[get("/initcard?<sector>")]
+async fn initcard(_caller: Caller, worker: SelectedReader<'_>, value_block: &State<ValueBlock>, sector: Option<u8>) -> Reply {
+    with_value_block(&worker, value_block, sector, None, |block| ReaderCommand::InitCard(block, None)).await
+}
+
+#[derive(Deserialize)]
+struct ValueChange {
+    // minor units, or `amount` as a decimal of [currency]
+    value: Option<u32>,
+    amount: Option<Value>,
+    // value block, card.sector / card.block when missing
+    sector: Option<u8>,
+    block: Option<u8>,
+}
+
+// {value or amount, sector?, block?}
+#[post("/balance?<dry_run>", data = "<body>")]
+async fn post_balance(
+    _caller: Caller,
+    worker: SelectedReader<'_>,
+    value_block: &State<ValueBlock>,
+    units: &State<Units>,
+    dry_run: Option<bool>,
+    body: Json<ValueChange>,
+) -> Reply {
+    let value = match units.raw(body.value, body.amount.as_ref()) {
+        Ok(value) => value,
+        Err(e) => return reply(Err(e), Duration::ZERO),
+    };
+    with_value_block(&worker, value_block, body.sector, body.block, |block| planned(dry_run, ReaderCommand::InitBalance(block, value))).await
+}
+
+// {value or amount, sector?, block?}, answers {uid, previous_balance, amount, new_balance, tx_id}
+#[allow(clippy::too_many_arguments)]
+#[post("/increase?<dry_run>", data = "<body>")]
+async fn post_increase(
+    _caller: Caller,
+    worker: SelectedReader<'_>,
+    value_block: &State<ValueBlock>,
+    units: &State<Units>,
+    idempotency: &State<Idempotency>,
+    key: IdempotencyKey<'_>,
+    dry_run: Option<bool>,
+    body: Json<ValueChange>,
+) -> Reply {
+    let value = match units.raw(body.value, body.amount.as_ref()) {
+        Ok(value) => value,
+        Err(e) => return reply(Err(e), Duration::ZERO),
+    };
+    let (sector, block) = (body.sector, body.block);
+    let increase = async {
+        let reply = with_value_block(&worker, value_block, sector, block, |block| planned(dry_run, ReaderCommand::Increase(block, value))).await;
+        receipt(&worker, reply)
+    };
+    match dry_run {
+        Some(true) => increase.await,
+        _ => once(idempotency, key, &worker, ("increase", value, sector, block), increase).await,
+    }
+}
+
+// Same as POST /increase, INSUFFICIENT_FUNDS above the balance
+#[allow(clippy::too_many_arguments)]
+#[post("/decrease?<dry_run>", data = "<body>")]
+async fn post_decrease(
+    _caller: Caller,
+    worker: SelectedReader<'_>,
+    value_block: &State<ValueBlock>,
+    units: &State<Units>,
+    idempotency: &State<Idempotency>,
+    key: IdempotencyKey<'_>,
+    dry_run: Option<bool>,
+    body: Json<ValueChange>,
+) -> Reply {
+    let value = match units.raw(body.value, body.amount.as_ref()) {
+        Ok(value) => value,
+        Err(e) => return reply(Err(e), Duration::ZERO),
+    };
+    let (sector, block) = (body.sector, body.block);
+    let decrease = async {
+        let reply = with_value_block(&worker, value_block, sector, block, |block| planned(dry_run, ReaderCommand::Decrease(block, value))).await;
+        receipt(&worker, reply)
+    };
+    match dry_run {
+        Some(true) => decrease.await,
+        _ => once(idempotency, key, &worker, ("decrease", value, sector, block), decrease).await,
+    }
+}
+
+// Balance of a [wallets.<name>] purse
+#[get("/wallet/<name>/balance")]
+async fn wallet_balance(_caller: Caller, worker: SelectedReader<'_>, wallets: &State<Wallets>, name: &str) -> Reply {
+    with_wallet(&worker, wallets, name, ReaderCommand::ReadBalance).await
+}
+
+#[derive(Deserialize)]
+struct WalletChange {
+    // minor units, or `amount` as a decimal of [currency]
+    value: Option<u32>,
+    amount: Option<Value>,
+}
+
+// {value or amount}
+#[post("/wallet/<name>/balance?<dry_run>", data = "<body>")]
+async fn post_wallet_balance(
+    _caller: Caller,
+    worker: SelectedReader<'_>,
+    wallets: &State<Wallets>,
+    units: &State<Units>,
+    name: &str,
+    dry_run: Option<bool>,
+    body: Json<WalletChange>,
+) -> Reply {
+    let value = match units.raw(body.value, body.amount.as_ref()) {
+        Ok(value) => value,
+        Err(e) => return reply(Err(e), Duration::ZERO),
+    };
+    with_wallet(&worker, wallets, name, |block| planned(dry_run, ReaderCommand::InitBalance(block, value))).await
+}
+
+// {value or amount}, answers the receipt like POST /increase
+#[allow(clippy::too_many_arguments)]
+#[post("/wallet/<name>/increase?<dry_run>", data = "<body>")]
+async fn post_wallet_increase(
+    _caller: Caller,
+    worker: SelectedReader<'_>,
+    wallets: &State<Wallets>,
+    units: &State<Units>,
+    idempotency: &State<Idempotency>,
+    key: IdempotencyKey<'_>,
+    name: &str,
+    dry_run: Option<bool>,
+    body: Json<WalletChange>,
+) -> Reply {
+    let value = match units.raw(body.value, body.amount.as_ref()) {
+        Ok(value) => value,
+        Err(e) => return reply(Err(e), Duration::ZERO),
+    };
+    wallet_change(&worker, wallets, idempotency, key, name, dry_run, true, value, true).await
+}
+
+// Same as POST /wallet/<name>/increase, INSUFFICIENT_FUNDS above the balance
+#[allow(clippy::too_many_arguments)]
+#[post("/wallet/<name>/decrease?<dry_run>", data = "<body>")]
+async fn post_wallet_decrease(
+    _caller: Caller,
+    worker: SelectedReader<'_>,
+    wallets: &State<Wallets>,
+    idempotency: &State<Idempotency>,
+    units: &State<Units>,
+    key: IdempotencyKey<'_>,
+    name: &str,
+    dry_run: Option<bool>,
+    body: Json<WalletChange>,
+) -> Reply {
+    let value = match units.raw(body.value, body.amount.as_ref()) {
+        Ok(value) => value,
+        Err(e) => return reply(Err(e), Duration::ZERO),
+    };
+    wallet_change(&worker, wallets, idempotency, key, name, dry_run, false, value, true).await
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/wallet/<name>/increase/<value>?<dry_run>")]
+async fn wallet_increase(
+    _caller: Caller,
+    worker: SelectedReader<'_>,
+    wallets: &State<Wallets>,
+    idempotency: &State<Idempotency>,
+    key: IdempotencyKey<'_>,
+    name: &str,
+    value: u32,
+    dry_run: Option<bool>,
+) -> Reply {
+    wallet_change(&worker, wallets, idempotency, key, name, dry_run, true, value, false).await
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/wallet/<name>/decrease/<value>?<dry_run>")]
+async fn wallet_decrease(
+    _caller: Caller,
+    worker: SelectedReader<'_>,
+    wallets: &State<Wallets>,
+    idempotency: &State<Idempotency>,
+    key: IdempotencyKey<'_>,
+    name: &str,
+    value: u32,
+    dry_run: Option<bool>,
+) -> Reply {
+    wallet_change(&worker, wallets, idempotency, key, name, dry_run, false, value, false).await
+}
+
+// An increase / decrease of a wallet, once per Idempotency-Key like the card.sector ones.
+// The POSTs answer the receipt, the GETs the new balance.
+#[allow(clippy::too_many_arguments)]
+async fn wallet_change(
+    worker: &SelectedReader<'_>,
+    wallets: &Wallets,
+    idempotency: &Idempotency,
+    key: IdempotencyKey<'_>,
+    name: &str,
+    dry_run: Option<bool>,
+    increase: bool,
+    value: u32,
+    with_receipt: bool,
+) -> Reply {
+    let block = match wallets.get(name) {
+        Ok(wallet) => wallet.block,
+        Err(e) => return reply(Err(e), Duration::ZERO),
+    };
+    let (change, command): (_, fn(BlockAddress, u32) -> ReaderCommand) = match increase {
+        true => ("increase", ReaderCommand::Increase),
+        false => ("decrease", ReaderCommand::Decrease),
+    };
+    let run = async {
+        let reply = with_wallet(worker, wallets, name, |block| planned(dry_run, command(block, value))).await;
+        match with_receipt {
+            true => receipt(worker, reply),
+            false => reply,
         }
-        Err(_) => Json(ApiResponse {
-            status: false,
-            data: "Error in Connection".to_string(),
-        }),
+    };
+    match dry_run {
+        Some(true) => run.await,
+        _ => once(idempotency, key, worker, (change, value, Some(block.sector), Some(block.block)), run).await,
     }
 }
 
-#[get("/increase/<value>")]
-fn increase(value: u32) -> Json<ApiResponse> {
-    let portname: String ;
-    let baudrate: u32 ;
-    
-    match load_config() {
- Ok(conf) => (portname, baudrate,_,_) = conf , 
-        Err(_) => (portname, baudrate) = (PORTNAME.to_string(), BAUDRATE)
-    }
-    match serialport::new(portname, baudrate)
-        .timeout(Duration::from_secs(2))
-        .open()
-    {
-        Ok(port) => {
-            let mut rfid = RFID::new(port);
-
-            match rfid.increase(value) {
-                Ok(data) => Json(ApiResponse {
-                    status: true,
-                    data: data.to_string(),
-                }),
-                Err(data) => Json(ApiResponse {
-                    status: false,
-                    data: data,
-                }),
+#[derive(Deserialize)]
+struct InitCard {
+    sector: Option<u8>,
+    // the keystore's access bits when missing
+    access: Option<AccessSpec>,
+}
+
+// Access conditions as `keyA: read+write, keyB: none` (see er302::access), `data` for all
+// data blocks unless `block<n>` is given. The conditions of FF078069 fill in the missing ones.
+#[derive(Deserialize)]
+struct AccessSpec {
+    data: Option<String>,
+    block0: Option<String>,
+    block1: Option<String>,
+    block2: Option<String>,
+    trailer: Option<String>,
+    user_byte: Option<u8>,
+}
+
+impl AccessSpec {
+    fn bits(&self) -> Result<Vec<u8>, ReaderError> {
+        let mut bits = AccessBits::TRANSPORT;
+        if let Some(data) = &self.data {
+            bits.conditions[..3].fill(access::data_condition(data)?);
+        }
+        for (block, text) in [&self.block0, &self.block1, &self.block2].into_iter().enumerate() {
+            if let Some(text) = text {
+                bits.conditions[block] = access::data_condition(text)?;
             }
         }
-        Err(_) => Json(ApiResponse {
-            status: false,
-            data: "Error in Connection".to_string(),
-        }),
+        if let Some(trailer) = &self.trailer {
+            bits.conditions[3] = access::trailer_condition(trailer)?;
+        }
+        bits.user_byte = self.user_byte.unwrap_or(bits.user_byte);
+        Ok(bits.encode().to_vec())
     }
 }
 
-#[get("/decrease/<value>")]
-fn decrease(value: u32) -> Json<ApiResponse> {
-    let portname: String ;
-    let baudrate: u32 ;
-    
-    match load_config() {
- Ok(conf) => (portname, baudrate,_,_) = conf , 
-        Err(_) => (portname, baudrate) = (PORTNAME.to_string(), BAUDRATE)
-    }
-    match serialport::new(portname, baudrate)
-        .timeout(Duration::from_secs(2))
-        .open()
-    {
-        Ok(port) => {
-            let mut rfid = RFID::new(port);
-
-            match rfid.decrease(value) {
-                Ok(data) => Json(ApiResponse {
-                    status: true,
-                    data: data.to_string(),
-                }),
-                Err(data) => Json(ApiResponse {
-                    status: false,
-                    data: data,
-                }),
-            }
+// {sector?, access?}, an empty body initializes card.sector
+#[post("/initcard", data = "<body>")]
+async fn post_initcard(_caller: Caller, worker: SelectedReader<'_>, value_block: &State<ValueBlock>, body: Option<Json<InitCard>>) -> Reply {
+    let sector = body.as_ref().and_then(|body| body.sector);
+    match body.as_ref().and_then(|body| body.access.as_ref()).map(AccessSpec::bits).transpose() {
+        Ok(access) => with_value_block(&worker, value_block, sector, None, |block| ReaderCommand::InitCard(block, access)).await,
+        Err(e) => reply(Err(e), Duration::ZERO),
+    }
+}
+
+#[derive(Deserialize)]
+struct Sector {
+    sector: Option<u8>,
+}
+
+#[derive(Deserialize)]
+struct ValueTransfer {
+    // the value block, card.sector / card.block when missing
+    sector: Option<u8>,
+    from: Option<u8>,
+    // another data block of the sector
+    to: u8,
+}
+
+// Copy a value block to another block of its sector with the card's restore and transfer,
+// e.g. a working block into the one a terminal reads: {sector, from, to, balance}
+#[post("/value/transfer", data = "<body>")]
+async fn transfer_value(_caller: Caller, worker: SelectedReader<'_>, value_block: &State<ValueBlock>, body: Json<ValueTransfer>) -> Reply {
+    let command = value_block.resolve(body.sector, body.from).and_then(|from| {
+        let to = BlockAddress::data(from.sector, body.to)?;
+        match to == from {
+            true => Err(ReaderError::InvalidInput("from and to are the same block".to_string())),
+            false => Ok(ReaderCommand::TransferValue(from, to)),
         }
-        Err(_) => Json(ApiResponse {
-            status: false,
-            data: "Error in Connection".to_string(),
-        }),
+    });
+    match command {
+        Ok(command) => with_reader(&worker, command).await,
+        Err(e) => reply(Err(e), Duration::ZERO),
+    }
+}
+
+// Inverse of /initcard for decommissioned cards, {sector?}: the sector's data is zeroed and
+// its keys are FF..FF again
+#[post("/card/deinit", data = "<body>")]
+async fn deinit_card(_caller: Caller, worker: SelectedReader<'_>, value_block: &State<ValueBlock>, body: Option<Json<Sector>>) -> Reply {
+    let sector = body.and_then(|body| body.sector);
+    with_value_block(&worker, value_block, sector, None, ReaderCommand::DeinitCard).await
+}
+
+#[derive(Deserialize)]
+struct KeyRotation {
+    // 12 hex digits each, the card's application key (or its diversified key) / the keystore's
+    // default key when missing
+    new_key: Option<String>,
+    current_key: Option<String>,
+    key_b: Option<String>,
+    // 8 hex digits (3 access bytes and the general purpose byte) or `access`, the ones of
+    // initcard by default
+    access_bits: Option<String>,
+    access: Option<AccessSpec>,
+    // card.sector when missing
+    sector: Option<u8>,
+}
+
+// New key A (and access bits) for a sector whose key leaked, verified by authenticating with it
+#[post("/card/rotate-keys", data = "<body>")]
+async fn rotate_keys(_caller: Caller, worker: SelectedReader<'_>, value_block: &State<ValueBlock>, body: Json<KeyRotation>) -> Reply {
+    let command = value_block.resolve(body.sector, None).and_then(|block| {
+        let access_bits = match (&body.access_bits, &body.access) {
+            (Some(hex), None) => Some(codec::from_hex(hex).and_then(|bits| match bits.len() {
+                4 => Ok(bits),
+                _ => Err(ReaderError::InvalidInput("access bits must be 4 bytes".to_string())),
+            })),
+            (None, Some(access)) => Some(access.bits()),
+            (None, None) => None,
+            (Some(_), Some(_)) => return Err(ReaderError::InvalidInput("give either access_bits or access".to_string())),
+        };
+        Ok(ReaderCommand::RotateKeys {
+            block,
+            current: body.current_key.as_deref().map(parse_key).transpose()?,
+            key_a: body.new_key.as_deref().map(parse_key).transpose()?,
+            access_bits: access_bits.transpose()?,
+            key_b: body.key_b.as_deref().map(parse_key).transpose()?,
+        })
+    });
+    match command {
+        Ok(command) => with_reader(&worker, command).await,
+        Err(e) => reply(Err(e), Duration::ZERO),
     }
 }
 
-#[get("/initcard")]
-fn initcard() -> Json<ApiResponse> {
-    let portname: String ;
-    let baudrate: u32 ;
-    
-    match load_config() {
- Ok(conf) => (portname, baudrate,_,_) = conf , 
-        Err(_) => (portname, baudrate) = (PORTNAME.to_string(), BAUDRATE)
-    }
-    match serialport::new(portname, baudrate)
-        .timeout(Duration::from_secs(2))
-        .open()
-    {
-        Ok(port) => {
-            let mut rfid = RFID::new(port);
-
-            match rfid.init_card() {
-                Ok(data) => Json(ApiResponse {
-                    status: true,
-                    data: data.to_string(),
-                }),
-                Err(data) => Json(ApiResponse {
-                    status: false,
-                    data: data,
-                }),
+// 16 raw bytes of any block (trailers included) as hex and base64
+#[get("/block/<sector>/<block>?<key>")]
+async fn read_block(_caller: Caller, worker: SelectedReader<'_>, sector: u8, block: u8, key: Option<&str>) -> Reply {
+    let command = BlockAddress::new(sector, block).and_then(|block| {
+        Ok(ReaderCommand::ReadBlock(block, key.map(parse_key).transpose()?))
+    });
+    match command {
+        Ok(command) => with_reader(&worker, command).await,
+        Err(e) => reply(Err(e), Duration::ZERO),
+    }
+}
+
+// Every block of a sector (trailer included) as GET /block answers them, read after a single
+// authentication
+#[get("/sector/<sector>?<key>")]
+async fn read_sector(_caller: Caller, worker: SelectedReader<'_>, sector: u8, key: Option<&str>) -> Reply {
+    let command = BlockAddress::new(sector, 0).and_then(|_| {
+        Ok(ReaderCommand::ReadSector(sector, key.map(parse_key).transpose()?))
+    });
+    match command {
+        Ok(command) => with_reader(&worker, command).await,
+        Err(e) => reply(Err(e), Duration::ZERO),
+    }
+}
+
+#[derive(Deserialize)]
+struct SectorWrite {
+    blocks: Vec<SectorBlock>,
+    // 12 hex digits, the card's application key when missing
+    key: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SectorBlock {
+    block: u8,
+    // 16 bytes, either as hex or as base64
+    hex: Option<String>,
+    base64: Option<String>,
+}
+
+// Data blocks of one sector (up to three, 15 in the big sectors of a 4K) written after a
+// single authentication, each read back. A failed block undoes the ones written before it.
+#[post("/sector/<sector>", data = "<body>")]
+async fn write_sector(_caller: Caller, worker: SelectedReader<'_>, sector: u8, body: Json<SectorWrite>) -> Reply {
+    match sector_write(sector, &body) {
+        Ok(command) => with_reader(&worker, command).await,
+        Err(e) => reply(Err(e), Duration::ZERO),
+    }
+}
+
+fn sector_write(sector: u8, body: &SectorWrite) -> Result<ReaderCommand, ReaderError> {
+    BlockAddress::new(sector, 0)?;
+    if body.blocks.is_empty() {
+        return Err(ReaderError::InvalidInput("give at least one block".to_string()));
+    }
+    let mut blocks: Vec<(BlockAddress, Vec<u8>)> = Vec::new();
+    for entry in &body.blocks {
+        // trailers and block 0 go through POST /block with confirm
+        let block = BlockAddress::data(sector, entry.block)?;
+        if blocks.iter().any(|(written, _)| *written == block) {
+            return Err(ReaderError::InvalidInput(format!("block {} given twice", entry.block)));
+        }
+        blocks.push((block, decode_data(&entry.hex, &entry.base64, 16)?));
+    }
+    blocks.sort_by_key(|(block, _)| block.block);
+    let key = body.key.as_deref().map(parse_key).transpose()?;
+    Ok(ReaderCommand::WriteSector(key, blocks))
+}
+
+#[derive(Deserialize)]
+struct BlockWrite {
+    // 16 bytes, either as hex or as base64
+    hex: Option<String>,
+    base64: Option<String>,
+    // 12 hex digits, the card's application key when missing
+    key: Option<String>,
+    // sector trailers and block 0 are only written when this is true
+    #[serde(default, alias = "allow_trailer")]
+    confirm: bool,
+}
+
+impl BlockWrite {
+    fn data(&self) -> Result<Vec<u8>, ReaderError> {
+        decode_data(&self.hex, &self.base64, 16)
+    }
+}
+
+// Raw bytes given either as hex or as base64, `length` bytes long
+fn decode_data(hex: &Option<String>, base64: &Option<String>, length: usize) -> Result<Vec<u8>, ReaderError> {
+    let data = match (hex, base64) {
+        (Some(hex), None) => codec::from_hex(hex)?,
+        (None, Some(text)) => STANDARD
+            .decode(text)
+            .map_err(|e| ReaderError::InvalidInput(format!("invalid base64: {}", e)))?,
+        _ => return Err(ReaderError::InvalidInput("give either hex or base64".to_string())),
+    };
+    match data.len() {
+        len if len == length => Ok(data),
+        _ => Err(ReaderError::InvalidInput(format!("data must be {} bytes", length))),
+    }
+}
+
+// Write one block, the response echoes the written bytes (and the access conditions of a
+// trailer)
+#[post("/block/<sector>/<block>", data = "<body>")]
+async fn write_block(_caller: Caller, worker: SelectedReader<'_>, sector: u8, block: u8, body: Json<BlockWrite>) -> Reply {
+    let command = BlockAddress::new(sector, block).and_then(|block| {
+        let data = body.data()?;
+        let access = interlock(block, &data, body.confirm)?;
+        let key = body.key.as_deref().map(parse_key).transpose()?;
+        Ok((ReaderCommand::WriteBlock(block, key, data), access))
+    });
+    match command {
+        Ok((_, Some(access))) if !body.confirm => confirm_required(json!([access])),
+        Ok((command, access)) => {
+            let mut reply = with_reader(&worker, command).await;
+            if let Some(access) = access.filter(|_| reply.body.status) {
+                reply.body.data["access"] = access["access"].clone();
             }
+            reply
         }
-        Err(_) => Json(ApiResponse {
-            status: false,
-            data: "Error in Connection".to_string(),
-        }),
+        Err(e) => reply(Err(e), Duration::ZERO),
+    }
+}
+
+// Trailers and block 0 can brick a sector or the whole card: None for other blocks, else
+// {sector, block, access} with what a trailer's access bits would allow. Access bits that
+// don't match their inverted copy are refused, the card would lock the sector for good.
+fn interlock(block: BlockAddress, data: &[u8], confirmed: bool) -> Result<Option<Value>, ReaderError> {
+    let access = match (block.is_trailer(), block.absolute()) {
+        (true, _) => worker::access_json(&AccessBits::decode(&data[6..10])?),
+        (false, 0) => Value::Null,
+        (false, _) => return Ok(None),
+    };
+    tracing::warn!(target: "er302::audit", sector = block.sector, block = block.block, access = %access, confirmed, "write to a protected block");
+    Ok(Some(json!({ "sector": block.sector, "block": block.block, "access": access })))
+}
+
+// The protected blocks a write would touch, sent again with `confirm: true` to go ahead
+fn confirm_required(blocks: Value) -> Reply {
+    let mut reply = failure("CONFIRM_REQUIRED", "");
+    reply.body.data = json!({
+        "message": "writes to sector trailers and block 0 need \"confirm\": true",
+        "blocks": blocks,
+    });
+    reply
+}
+
+// Card dump: blocks as returned by GET /block, other fields (e.g. "uid") are ignored
+#[derive(Deserialize)]
+struct Dump {
+    blocks: Vec<DumpBlock>,
+}
+
+#[derive(Deserialize)]
+struct DumpBlock {
+    sector: u8,
+    block: u8,
+    hex: String,
+}
+
+#[derive(Deserialize)]
+struct RestoreRequest {
+    dump: Dump,
+    // 12 hex digits, the card's application key when missing
+    key: Option<String>,
+    // also write block 0 and sector trailers, with `confirm`
+    #[serde(default)]
+    force: bool,
+    #[serde(default)]
+    confirm: bool,
+}
+
+// Write a dump back to the card, block 0 and trailers are skipped unless `force`
+#[post("/restore", data = "<body>")]
+async fn restore(_caller: Caller, worker: SelectedReader<'_>, body: Json<RestoreRequest>) -> Reply {
+    match restore_command(&body) {
+        Ok((_, protected)) if !protected.is_empty() && !body.confirm => confirm_required(json!(protected)),
+        Ok((command, _)) => with_reader(&worker, command).await,
+        Err(e) => reply(Err(e), Duration::ZERO),
+    }
+}
+
+// The command and the interlock answers of the protected blocks it writes
+fn restore_command(body: &RestoreRequest) -> Result<(ReaderCommand, Vec<Value>), ReaderError> {
+    let key = body.key.as_deref().map(parse_key).transpose()?;
+    let mut blocks = Vec::new();
+    let mut skipped = Vec::new();
+    let mut interlocked = Vec::new();
+    for entry in &body.dump.blocks {
+        let block = BlockAddress::new(entry.sector, entry.block)?;
+        let protected = block.is_trailer() || block.absolute() == 0;
+        if protected && !body.force {
+            skipped.push(block);
+            continue;
+        }
+        let data = codec::from_hex(&entry.hex)?;
+        if data.len() != 16 {
+            return Err(ReaderError::InvalidInput(format!(
+                "sector {} block {}: block data must be 16 bytes",
+                entry.sector, entry.block
+            )));
+        }
+        interlocked.extend(interlock(block, &data, body.confirm)?);
+        blocks.push((block, data));
+    }
+    // one authentication per sector
+    blocks.sort_by_key(|(block, _)| block.absolute());
+    Ok((ReaderCommand::Restore { key, blocks, skipped }, interlocked))
+}
+
+// Decoded NDEF records (URI, text, MIME) of an NFC Forum formatted card
+#[get("/ndef")]
+async fn read_ndef(_caller: Caller, worker: SelectedReader<'_>) -> Reply {
+    with_reader(&worker, ReaderCommand::ReadNdef).await
+}
+
+#[derive(Deserialize)]
+struct NdefWrite {
+    // "uri" or "text"
+    #[serde(rename = "type")]
+    kind: String,
+    value: String,
+    // language of text records
+    language: Option<String>,
+}
+
+// Write a single URI or text record, formatting factory cards for NDEF (MAD + NDEF keys)
+#[post("/ndef", data = "<body>")]
+async fn write_ndef(_caller: Caller, worker: SelectedReader<'_>, body: Json<NdefWrite>) -> Reply {
+    let record = match body.kind.as_str() {
+        "uri" => Ok(Record::uri(&body.value)),
+        "text" => Record::text(body.language.as_deref().unwrap_or("en"), &body.value),
+        kind => Err(ReaderError::InvalidInput(format!("unknown record type: {}", kind))),
+    };
+    match record {
+        Ok(record) => with_reader(&worker, ReaderCommand::WriteNdef(vec![record])).await,
+        Err(e) => reply(Err(e), Duration::ZERO),
+    }
+}
+
+// {name, number, expiry} of the cardholder record in card.cardholder_sector
+#[get("/cardholder")]
+async fn read_cardholder(_caller: Caller, worker: SelectedReader<'_>, sector: &State<CardholderSector>) -> Reply {
+    match sector.0 {
+        Some(sector) => with_reader(&worker, ReaderCommand::ReadCardholder(sector)).await,
+        None => failure("CARDHOLDER_OFF", "no cardholder records, set card.cardholder_sector"),
     }
 }
+
+#[derive(Deserialize)]
+struct CardholderWrite {
+    name: String,
+    number: String,
+    // YYYY-MM-DD
+    expiry: String,
+}
+
+#[post("/cardholder", data = "<body>")]
+async fn write_cardholder(
+    _caller: Caller,
+    worker: SelectedReader<'_>,
+    sector: &State<CardholderSector>,
+    body: Json<CardholderWrite>,
+) -> Reply {
+    let Some(sector) = sector.0 else {
+        return failure("CARDHOLDER_OFF", "no cardholder records, set card.cardholder_sector");
+    };
+    let body = body.into_inner();
+    match body.expiry.parse() {
+        Ok(expiry) => {
+            let record = Cardholder { name: body.name, number: body.number, expiry };
+            with_reader(&worker, ReaderCommand::WriteCardholder(sector, record)).await
+        }
+        Err(e) => reply(Err(e), Duration::ZERO),
+    }
+}
+
+// One 4 byte page of an Ultralight / NTAG, no key needed
+#[get("/ul/page/<page>")]
+async fn read_page(_caller: Caller, worker: SelectedReader<'_>, page: u8) -> Reply {
+    with_reader(&worker, ReaderCommand::ReadPage(page)).await
+}
+
+#[derive(Deserialize)]
+struct PageWrite {
+    // 4 bytes, either as hex or as base64
+    hex: Option<String>,
+    base64: Option<String>,
+}
+
+// Pages 0-3 hold the UID, lock bits and OTP, writes there can't be undone so they're refused
+#[post("/ul/page/<page>", data = "<body>")]
+async fn write_page(_caller: Caller, worker: SelectedReader<'_>, page: u8, body: Json<PageWrite>) -> Reply {
+    let command = match page {
+        0..=3 => Err(ReaderError::InvalidInput(format!("page {} is read-only / one-time programmable", page))),
+        _ => decode_data(&body.hex, &body.base64, 4).map(|data| ReaderCommand::WritePage(page, data)),
+    };
+    match command {
+        Ok(command) => with_reader(&worker, command).await,
+        Err(e) => reply(Err(e), Duration::ZERO),
+    }
+}
+
+// Largest payload of a raw command, more than any ER302 command takes
+const RAW_PAYLOAD: usize = 256;
+
+#[derive(Deserialize)]
+struct RawCommand {
+    // command code as 4 hex digits, e.g. "0104" for the version request
+    command: String,
+    // payload as hex, none when missing
+    #[serde(default)]
+    data: String,
+}
+
+impl RawCommand {
+    fn parse(&self) -> Result<ReaderCommand, ReaderError> {
+        let digits = self.command.strip_prefix("0x").unwrap_or(&self.command);
+        let code = Some(digits)
+            .filter(|digits| digits.len() == 4 && digits.bytes().all(|digit| digit.is_ascii_hexdigit()))
+            .and_then(|digits| u16::from_str_radix(digits, 16).ok());
+        let code = code.ok_or_else(|| ReaderError::InvalidInput(format!("command must be 4 hex digits: {}", self.command)))?;
+        let data = codec::from_hex(&self.data)?;
+        if data.len() > RAW_PAYLOAD {
+            return Err(ReaderError::InvalidInput(format!("data must be at most {} bytes", RAW_PAYLOAD)));
+        }
+        Ok(ReaderCommand::Raw(code, data))
+    }
+}
+
+// Any ER302 command the API doesn't wrap (yet): {command, data} in, the reader's answer
+// {node, command, status, data} out. Whatever status the reader gives is passed on, the
+// command may leave the card in a state the other routes don't expect.
+#[post("/raw", data = "<body>")]
+async fn raw(_caller: Caller, worker: SelectedReader<'_>, body: Json<RawCommand>) -> Reply {
+    match body.parse() {
+        Ok(command) => with_reader(&worker, command).await,
+        Err(e) => reply(Err(e), Duration::ZERO),
+    }
+}
+
+// Short APDUs: header, Lc, up to 255 bytes of data and Le
+const APDU_LENGTH: std::ops::RangeInclusive<usize> = 4..=261;
+const APDUS: usize = 16;
+
+#[derive(Deserialize)]
+struct ApduExchange {
+    // command APDUs as hex, sent in order to the same activation of the card
+    apdus: Vec<String>,
+}
+
+impl ApduExchange {
+    fn parse(&self) -> Result<ReaderCommand, ReaderError> {
+        if self.apdus.is_empty() || self.apdus.len() > APDUS {
+            return Err(ReaderError::InvalidInput(format!("give 1 to {} APDUs", APDUS)));
+        }
+        let apdus = self
+            .apdus
+            .iter()
+            .map(|hex| match codec::from_hex(hex)? {
+                apdu if APDU_LENGTH.contains(&apdu.len()) => Ok(apdu),
+                _ => Err(ReaderError::InvalidInput(format!("an APDU is 4 to 261 bytes: {}", hex))),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(ReaderCommand::Apdu(apdus))
+    }
+}
+
+// APDUs to a DESFire or another ISO 14443-4 card, e.g. {"apdus": ["00A4040007D2760000850101"]}:
+// the card is selected and activated with RATS, answers {ats, responses: [{data, sw}]}.
+// UNSUPPORTED_CARD for MIFARE Classic and Ultralight.
+#[post("/apdu", data = "<body>")]
+async fn apdu(_caller: Caller, worker: SelectedReader<'_>, body: Json<ApduExchange>) -> Reply {
+    match body.parse() {
+        Ok(command) => with_reader(&worker, command).await,
+        Err(e) => reply(Err(e), Duration::ZERO),
+    }
+}
+
+// 6 byte MIFARE key as 12 hex digits
+fn parse_key(hex: &str) -> Result<Vec<u8>, ReaderError> {
+    match codec::from_hex(hex)? {
+        key if key.len() == 6 => Ok(key),
+        _ => Err(ReaderError::InvalidInput("key must be 6 bytes".to_string())),
+    }
+}
+
+mod audit;
+mod auth;
+mod calls;
+mod cards;
+mod cors;
+mod events;
+mod export;
+mod flags;
+mod grpc;
+mod idempotency;
+mod jwt;
+mod kafka;
+mod keystore;
+mod logging;
+mod metrics;
+mod mqtt;
+mod openapi;
+mod otlp;
+mod readers;
+mod redis;
+mod spool;
+mod sqlite;
+mod storage;
+mod tui;
+mod ui;
+mod v2;
+mod webhooks;
+mod worker;
+mod ws;
+// the tests use more of it than READER_MODE=mock
+#[cfg_attr(not(test), allow(dead_code))]
+mod simulator;
+#[cfg(test)]
+mod tests;
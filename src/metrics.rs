@@ -0,0 +1,48 @@
+// Prometheus text of GET /metrics: how long each reader took to answer each serial command,
+// as a histogram so the rising latencies of a worn USB cable or a failing reader show up
+// before its commands fail. Open like /health, scrapers rarely carry a key.
+use crate::readers::Readers;
+use er302::reader::LATENCY_BUCKETS_MS;
+use rocket::http::ContentType;
+use rocket::State;
+use std::fmt::Write;
+
+#[get("/metrics")]
+pub fn metrics(readers: &State<Readers>) -> (ContentType, String) {
+    (ContentType::new("text", "plain").with_params(("version", "0.0.4")), render(readers))
+}
+
+fn render(readers: &Readers) -> String {
+    let latencies: Vec<_> = readers
+        .iter()
+        .flat_map(|(reader, slot)| {
+            let reader = reader.replace('\\', "\\\\").replace('"', "\\\"");
+            slot.worker
+                .latencies()
+                .into_iter()
+                .map(move |(command, latency)| (format!("reader=\"{}\",command=\"{}\"", reader, command), latency))
+        })
+        .collect();
+    let mut text = String::new();
+    text.push_str("# HELP er302_command_duration_seconds Time the reader took to answer a serial command\n");
+    text.push_str("# TYPE er302_command_duration_seconds histogram\n");
+    for (labels, latency) in &latencies {
+        for (bound, count) in LATENCY_BUCKETS_MS.iter().zip(latency.buckets) {
+            let _ = writeln!(text, "er302_command_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, *bound as f64 / 1000.0, count);
+        }
+        let _ = writeln!(text, "er302_command_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, latency.count);
+        let _ = writeln!(text, "er302_command_duration_seconds_sum{{{}}} {}", labels, latency.total.as_secs_f64());
+        let _ = writeln!(text, "er302_command_duration_seconds_count{{{}}} {}", labels, latency.count);
+    }
+    text.push_str("# HELP er302_command_failures_total Serial commands the reader didn't answer or answered garbled\n");
+    text.push_str("# TYPE er302_command_failures_total counter\n");
+    for (labels, latency) in &latencies {
+        let _ = writeln!(text, "er302_command_failures_total{{{}}} {}", labels, latency.failures);
+    }
+    text.push_str("# HELP er302_command_last_duration_seconds Answer time of the latest one of the command\n");
+    text.push_str("# TYPE er302_command_last_duration_seconds gauge\n");
+    for (labels, latency) in &latencies {
+        let _ = writeln!(text, "er302_command_last_duration_seconds{{{}}} {}", labels, latency.last.as_secs_f64());
+    }
+    text
+}
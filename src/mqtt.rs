@@ -0,0 +1,163 @@
+// MQTT publishing of the card events for home-automation and IoT stacks: with `[mqtt]` set
+// every event goes to `<topic_prefix>/<reader>/<kind>` (e.g. er302/default/card_detected)
+// with the JSON of the /events data, MQTT 3.1.1 at QoS 0. The connection is opened at the
+// first event and again after it broke.
+use crate::events::{Event, Events};
+use rocket::serde::json;
+use rocket::serde::Deserialize;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+// Pause before connecting again after a failure
+const RECONNECT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct MqttConfig {
+    // mqtt://host[:port], 1883 by default
+    pub url: String,
+    #[serde(default = "default_prefix")]
+    pub topic_prefix: String,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    // plaintext to a broker on another host, off by default
+    #[serde(default)]
+    pub insecure: bool,
+}
+
+fn default_prefix() -> String {
+    "er302".to_string()
+}
+
+fn default_client_id() -> String {
+    "er302-api".to_string()
+}
+
+// host:port of the broker
+pub fn broker(url: &str) -> Result<String, String> {
+    if url.starts_with("mqtts://") {
+        // no TLS client here, a TLS broker goes behind a local bridge
+        return Err(format!("{}: TLS isn't supported, use mqtt://", url));
+    }
+    let authority = url
+        .strip_prefix("mqtt://")
+        .map(|rest| rest.trim_end_matches('/'))
+        .filter(|authority| !authority.is_empty())
+        .ok_or_else(|| format!("{}: not an mqtt://host[:port] URL", url))?;
+    match authority.contains(':') {
+        true => Ok(authority.to_string()),
+        false => Ok(format!("{}:1883", authority)),
+    }
+}
+
+pub fn spawn(config: MqttConfig, events: &Events) {
+    let mut receiver = events.subscribe();
+    thread::Builder::new()
+        .name("er302-mqtt".to_string())
+        .spawn(move || {
+            let mut connection = None;
+            loop {
+                match receiver.blocking_recv() {
+                    Ok(event) => publish(&config, &mut connection, &event),
+                    Err(RecvError::Lagged(missed)) => tracing::warn!(missed, "MQTT fell behind, events dropped"),
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        })
+        .expect("failed to spawn MQTT thread");
+}
+
+// Publishes on the open connection, or on a new one when it's closed or broke
+fn publish(config: &MqttConfig, connection: &mut Option<TcpStream>, event: &Event) {
+    let topic = format!("{}/{}/{}", config.topic_prefix, event.reader, event.kind);
+    let packet = publish_packet(&topic, json::to_string(event).unwrap_or_default().as_bytes());
+    for _ in 0..2 {
+        let stream = match connection.take() {
+            Some(stream) => stream,
+            None => match connect(config) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::error!(url = config.url, error = %e, "can't connect to the MQTT broker");
+                    thread::sleep(RECONNECT);
+                    continue;
+                }
+            },
+        };
+        if (&stream).write_all(&packet).is_ok() {
+            *connection = Some(stream);
+            return;
+        }
+    }
+    tracing::error!(url = config.url, topic, "MQTT event dropped");
+}
+
+fn connect(config: &MqttConfig) -> io::Result<TcpStream> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidInput, e);
+    let mut stream = TcpStream::connect(broker(&config.url).map_err(invalid)?)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    stream.write_all(&connect_packet(config))?;
+    let mut connack = [0u8; 4];
+    stream.read_exact(&mut connack)?;
+    match connack {
+        [0x20, 0x02, _, 0] => Ok(stream),
+        [0x20, 0x02, _, code] => Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("broker refused with code {}", code))),
+        _ => Err(invalid("not a CONNACK".to_string())),
+    }
+}
+
+// Clean session without keep alive, the broker keeps an idle connection
+fn connect_packet(config: &MqttConfig) -> Vec<u8> {
+    let mut flags = 0x02;
+    let mut body = vec![0, 4, b'M', b'Q', b'T', b'T', 4];
+    let mut payload = string(&config.client_id);
+    if let Some(username) = &config.username {
+        flags |= 0x80;
+        payload.extend(string(username));
+    }
+    if let Some(password) = &config.password {
+        flags |= 0x40;
+        payload.extend(string(password));
+    }
+    body.extend_from_slice(&[flags, 0, 0]);
+    body.extend(payload);
+    packet(0x10, body)
+}
+
+fn publish_packet(topic: &str, payload: &[u8]) -> Vec<u8> {
+    let mut body = string(topic);
+    body.extend_from_slice(payload);
+    packet(0x30, body)
+}
+
+// Length-prefixed UTF-8 string
+fn string(text: &str) -> Vec<u8> {
+    let mut bytes = (text.len() as u16).to_be_bytes().to_vec();
+    bytes.extend_from_slice(text.as_bytes());
+    bytes
+}
+
+// Fixed header with the remaining length as a base 128 varint
+fn packet(kind: u8, body: Vec<u8>) -> Vec<u8> {
+    let mut packet = vec![kind];
+    let mut length = body.len();
+    loop {
+        let byte = (length % 128) as u8;
+        length /= 128;
+        match length {
+            0 => {
+                packet.push(byte);
+                break;
+            }
+            _ => packet.push(byte | 0x80),
+        }
+    }
+    packet.extend(body);
+    packet
+}
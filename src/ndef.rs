@@ -0,0 +1,408 @@
+// NDEF on MIFARE Classic: MAD (sector 0) lookup, TLV area and record decoding
+//
+// MAD1     : block 1-2 of sector 0 = crc | info | 15 AIDs (2 bytes, sectors 1..15)
+// TLV area : data blocks of the NDEF sectors, 00 = null, 03 = NDEF message, FE = terminator
+// Record   : MB ME CF SR IL TNF | type length | payload length (1 or 4) | [id length] | type | [id] | payload
+use crate::error::ReaderError;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+// Public key A of the MAD sector and of NDEF sectors (NFC Forum / NXP AN1304)
+pub const MAD_KEY: &[u8] = &[0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5];
+pub const NDEF_KEY: &[u8] = &[0xd3, 0xf7, 0xd3, 0xf7, 0xd3, 0xf7];
+// NFC Forum AID as stored in the MAD
+pub const NDEF_AID: [u8; 2] = [0x03, 0xe1];
+// Trailers of a formatted tag: key A | access bits + GPB | key B
+pub const MAD_TRAILER: [u8; 16] = [
+    0xa0, 0xa1, 0xa2, 0xa3, 0xa4, 0xa5, 0x78, 0x77, 0x88, 0xc1, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+];
+pub const NDEF_TRAILER: [u8; 16] = [
+    0xd3, 0xf7, 0xd3, 0xf7, 0xd3, 0xf7, 0x7f, 0x07, 0x88, 0x40, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+];
+// Data bytes of a 1K sector (3 blocks)
+pub const SECTOR_DATA: usize = 48;
+
+const TLV_NULL: u8 = 0x00;
+const TLV_NDEF: u8 = 0x03;
+const TLV_TERMINATOR: u8 = 0xfe;
+
+// Type Name Format
+pub const TNF_WELL_KNOWN: u8 = 0x01;
+pub const TNF_MIME: u8 = 0x02;
+
+// URI identifier codes 0x00..0x23 of the URI record type definition
+const URI_PREFIXES: [&str; 36] = [
+    "",
+    "http://www.",
+    "https://www.",
+    "http://",
+    "https://",
+    "tel:",
+    "mailto:",
+    "ftp://anonymous:anonymous@",
+    "ftp://ftp.",
+    "ftps://",
+    "sftp://",
+    "smb://",
+    "nfs://",
+    "ftp://",
+    "dav://",
+    "news:",
+    "telnet://",
+    "imap:",
+    "rtsp://",
+    "urn:",
+    "pop:",
+    "sip:",
+    "sips:",
+    "tftp:",
+    "btspp://",
+    "btl2cap://",
+    "btgoep://",
+    "tcpobex://",
+    "irdaobex://",
+    "file://",
+    "urn:epc:id:",
+    "urn:epc:tag:",
+    "urn:epc:pat:",
+    "urn:epc:raw:",
+    "urn:epc:",
+    "urn:nfc:",
+];
+
+// CRC-8 of the MAD: polynomial 0x1D, preset 0xC7, over info byte and AIDs
+pub fn mad_crc(data: &[u8]) -> u8 {
+    data.iter().fold(0xc7, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| match crc & 0x80 {
+            0 => crc << 1,
+            _ => (crc << 1) ^ 0x1d,
+        })
+    })
+}
+
+// MAD assigning `sectors` to NDEF, the 32 bytes of block 1 and 2
+pub fn mad(sectors: &[u8]) -> [u8; 32] {
+    let mut mad = [0u8; 32];
+    // info byte: no card publisher sector
+    mad[1] = 0x01;
+    for &sector in sectors.iter().filter(|sector| (1..16).contains(*sector)) {
+        let offset = sector as usize * 2;
+        mad[offset..offset + 2].copy_from_slice(&NDEF_AID);
+    }
+    mad[0] = mad_crc(&mad[1..]);
+    mad
+}
+
+// Sectors the MAD (32 bytes of block 1 and 2) assigns to NDEF, in card order
+pub fn ndef_sectors(mad: &[u8]) -> Result<Vec<u8>, ReaderError> {
+    if mad.len() != 32 || mad_crc(&mad[1..]) != mad[0] {
+        return Err(ReaderError::NotNdef);
+    }
+    let sectors: Vec<u8> = mad[2..]
+        .chunks(2)
+        .zip(1..)
+        .filter(|(aid, _)| *aid == NDEF_AID)
+        .map(|(_, sector)| sector)
+        .collect();
+    match sectors.is_empty() {
+        true => Err(ReaderError::NotNdef),
+        false => Ok(sectors),
+    }
+}
+
+// NDEF message of the first NDEF TLV, None when the area ends without one
+pub fn find_message(area: &[u8]) -> Result<Option<&[u8]>, ReaderError> {
+    let mut position = 0;
+    while let Some(&tag) = area.get(position) {
+        match tag {
+            TLV_NULL => {
+                position += 1;
+                continue;
+            }
+            TLV_TERMINATOR => return Ok(None),
+            _ => (),
+        }
+        // 1 byte length, or FF and a 2 byte big-endian length
+        let (length, header) = match area.get(position + 1) {
+            Some(0xff) => match area.get(position + 2..position + 4) {
+                Some(length) => (u16::from_be_bytes([length[0], length[1]]) as usize, 4),
+                None => return Err(ReaderError::InvalidNdef("truncated TLV length")),
+            },
+            Some(&length) => (length as usize, 2),
+            None => return Err(ReaderError::InvalidNdef("truncated TLV length")),
+        };
+        let value = area
+            .get(position + header..position + header + length)
+            .ok_or(ReaderError::InvalidNdef("TLV longer than the NDEF sectors"))?;
+        if tag == TLV_NDEF {
+            return Ok(Some(value));
+        }
+        // lock / memory control and proprietary TLVs
+        position += header + length;
+    }
+    Ok(None)
+}
+
+// NDEF TLV with `message` and a terminator, what gets written to the NDEF sectors
+pub fn tlv_area(message: &[u8]) -> Result<Vec<u8>, ReaderError> {
+    let mut area = Vec::from([TLV_NDEF]);
+    match message.len() {
+        length if length < 0xff => area.push(length as u8),
+        length if length < 0xffff => {
+            area.push(0xff);
+            area.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        _ => return Err(ReaderError::InvalidInput(String::from("NDEF message too long"))),
+    }
+    area.extend_from_slice(message);
+    area.push(TLV_TERMINATOR);
+    Ok(area)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub tnf: u8,
+    pub record_type: Vec<u8>,
+    pub id: Vec<u8>,
+    pub payload: Vec<u8>,
+}
+
+// Decoded payload of the record types the API understands
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Content {
+    Uri(String),
+    Text { language: String, text: String },
+    Mime { mime_type: String, payload: Vec<u8> },
+    Other,
+}
+
+impl Record {
+    // URI record, the longest known prefix is replaced by its identifier code
+    pub fn uri(uri: &str) -> Self {
+        let (code, prefix) = URI_PREFIXES
+            .iter()
+            .enumerate()
+            .filter(|(_, prefix)| uri.starts_with(*prefix))
+            .max_by_key(|(_, prefix)| prefix.len())
+            .unwrap_or((0, &""));
+        let mut payload = Vec::from([code as u8]);
+        payload.extend_from_slice(&uri.as_bytes()[prefix.len()..]);
+        Record {
+            tnf: TNF_WELL_KNOWN,
+            record_type: Vec::from(*b"U"),
+            id: Vec::new(),
+            payload,
+        }
+    }
+
+    // UTF-8 text record
+    pub fn text(language: &str, text: &str) -> Result<Self, ReaderError> {
+        if language.len() > 0x3f {
+            return Err(ReaderError::InvalidInput(String::from("language code too long")));
+        }
+        let mut payload = Vec::from([language.len() as u8]);
+        payload.extend_from_slice(language.as_bytes());
+        payload.extend_from_slice(text.as_bytes());
+        Ok(Record {
+            tnf: TNF_WELL_KNOWN,
+            record_type: Vec::from(*b"T"),
+            id: Vec::new(),
+            payload,
+        })
+    }
+
+    // NDEF message of `records`, short records when the payload allows it
+    pub fn encode_message(records: &[Record]) -> Vec<u8> {
+        let mut message = Vec::new();
+        for (index, record) in records.iter().enumerate() {
+            let short = record.payload.len() < 0x100;
+            let mut flags = record.tnf & 0x07;
+            if index == 0 {
+                flags |= 0x80;
+            }
+            if index == records.len() - 1 {
+                flags |= 0x40;
+            }
+            if short {
+                flags |= 0x10;
+            }
+            if !record.id.is_empty() {
+                flags |= 0x08;
+            }
+            message.push(flags);
+            message.push(record.record_type.len() as u8);
+            match short {
+                true => message.push(record.payload.len() as u8),
+                false => message.extend_from_slice(&(record.payload.len() as u32).to_be_bytes()),
+            }
+            if !record.id.is_empty() {
+                message.push(record.id.len() as u8);
+            }
+            message.extend_from_slice(&record.record_type);
+            message.extend_from_slice(&record.id);
+            message.extend_from_slice(&record.payload);
+        }
+        message
+    }
+
+    // Every record of an NDEF message, chunked records aren't supported
+    pub fn parse_message(message: &[u8]) -> Result<Vec<Record>, ReaderError> {
+        let truncated = ReaderError::InvalidNdef("truncated record");
+        let mut records = Vec::new();
+        let mut rest = message;
+        while !rest.is_empty() {
+            let flags = rest[0];
+            if flags & 0x20 != 0 {
+                return Err(ReaderError::InvalidNdef("chunked records aren't supported"));
+            }
+            let short = flags & 0x10 != 0;
+            let has_id = flags & 0x08 != 0;
+            let type_length = *rest.get(1).ok_or(truncated.clone())? as usize;
+            let mut position = 2;
+            let payload_length = match short {
+                true => *rest.get(position).ok_or(truncated.clone())? as usize,
+                false => {
+                    let length = rest.get(position..position + 4).ok_or(truncated.clone())?;
+                    u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize
+                }
+            };
+            position += if short { 1 } else { 4 };
+            let id_length = match has_id {
+                true => {
+                    position += 1;
+                    *rest.get(position - 1).ok_or(truncated.clone())? as usize
+                }
+                false => 0,
+            };
+            let mut take = |length: usize| {
+                let field = rest.get(position..position + length).ok_or(truncated.clone())?;
+                position += length;
+                Ok::<Vec<u8>, ReaderError>(field.to_vec())
+            };
+            let record_type = take(type_length)?;
+            let id = take(id_length)?;
+            let payload = take(payload_length)?;
+            records.push(Record {
+                tnf: flags & 0x07,
+                record_type,
+                id,
+                payload,
+            });
+            rest = &rest[position..];
+            // ME, anything after the last record is padding
+            if flags & 0x40 != 0 {
+                break;
+            }
+        }
+        Ok(records)
+    }
+
+    pub fn content(&self) -> Result<Content, ReaderError> {
+        match (self.tnf, self.record_type.as_slice()) {
+            (TNF_WELL_KNOWN, b"U") => {
+                let (code, rest) = self.payload.split_first().ok_or(ReaderError::InvalidNdef("empty URI record"))?;
+                let prefix = URI_PREFIXES.get(*code as usize).copied().unwrap_or("");
+                Ok(Content::Uri(String::from(prefix) + &utf8(rest)?))
+            }
+            (TNF_WELL_KNOWN, b"T") => {
+                let (status, rest) = self.payload.split_first().ok_or(ReaderError::InvalidNdef("empty text record"))?;
+                let language_length = (status & 0x3f) as usize;
+                if rest.len() < language_length {
+                    return Err(ReaderError::InvalidNdef("truncated text record"));
+                }
+                let (language, text) = rest.split_at(language_length);
+                let text = match status & 0x80 {
+                    0 => utf8(text)?,
+                    _ => utf16(text)?,
+                };
+                Ok(Content::Text {
+                    language: utf8(language)?,
+                    text,
+                })
+            }
+            (TNF_MIME, mime_type) => Ok(Content::Mime {
+                mime_type: utf8(mime_type)?,
+                payload: self.payload.clone(),
+            }),
+            _ => Ok(Content::Other),
+        }
+    }
+}
+
+fn utf8(data: &[u8]) -> Result<String, ReaderError> {
+    core::str::from_utf8(data)
+        .map(String::from)
+        .map_err(|_| ReaderError::InvalidNdef("invalid UTF-8"))
+}
+
+// Big-endian unless the text starts with a byte order mark
+fn utf16(data: &[u8]) -> Result<String, ReaderError> {
+    if !data.len().is_multiple_of(2) {
+        return Err(ReaderError::InvalidNdef("invalid UTF-16"));
+    }
+    let little_endian = data.starts_with(&[0xff, 0xfe]);
+    let units = data.chunks(2).map(|unit| match little_endian {
+        true => u16::from_le_bytes([unit[0], unit[1]]),
+        false => u16::from_be_bytes([unit[0], unit[1]]),
+    });
+    char::decode_utf16(units)
+        .filter(|c| *c != Ok('\u{feff}'))
+        .collect::<Result<String, _>>()
+        .map_err(|_| ReaderError::InvalidNdef("invalid UTF-16"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // MAD of a tag with every sector assigned to NDEF (NXP AN1304)
+    #[test]
+    fn reads_mad() {
+        let mut mad = Vec::from([0x14, 0x01]);
+        for _ in 0..15 {
+            mad.extend_from_slice(&NDEF_AID);
+        }
+        assert_eq!(ndef_sectors(&mad).unwrap(), (1..16).collect::<Vec<u8>>());
+
+        mad[0] = 0x15;
+        assert_eq!(ndef_sectors(&mad), Err(ReaderError::NotNdef));
+    }
+
+    #[test]
+    fn decodes_uri_and_text_records() {
+        // NULL TLV, NDEF TLV with a URI and a text record, terminator
+        let area = [
+            0x00, 0x03, 0x16, 0x91, 0x01, 0x09, b'U', 0x04, b's', b'a', b'j', b'x', b'.', b'n', b'e', b't', 0x51,
+            0x01, 0x05, b'T', 0x02, b'e', b'n', b'h', b'i', 0xfe, 0x00,
+        ];
+        let records = Record::parse_message(find_message(&area).unwrap().unwrap()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].content().unwrap(), Content::Uri("https://sajx.net".into()));
+        assert_eq!(
+            records[1].content().unwrap(),
+            Content::Text {
+                language: "en".into(),
+                text: "hi".into()
+            }
+        );
+    }
+
+    #[test]
+    fn encodes_records() {
+        let records = [Record::uri("https://www.sajx.net"), Record::text("en", "hi").unwrap()];
+        assert_eq!(records[0].payload[0], 0x02);
+        let area = tlv_area(&Record::encode_message(&records)).unwrap();
+        assert_eq!(&area[..3], [0x03, 0x16, 0x91]);
+        assert_eq!(area.last(), Some(&0xfe));
+        let decoded = Record::parse_message(find_message(&area).unwrap().unwrap()).unwrap();
+        assert_eq!(decoded, records);
+        assert_eq!(ndef_sectors(&mad(&[1, 2])).unwrap(), [1, 2]);
+    }
+
+    #[test]
+    fn rejects_broken_tlvs() {
+        assert_eq!(find_message(&[0x00, 0xfe, 0x03]), Ok(None));
+        assert!(find_message(&[0x03, 0x10, 0xd1]).is_err());
+        assert!(Record::parse_message(&[0xd1, 0x01, 0x08, b'U']).is_err());
+    }
+}
@@ -0,0 +1,629 @@
+// OpenAPI 3 description of the routes, served at /openapi.json with a Swagger UI at /docs.
+// Written by hand: `spec_covers_every_route` fails when a mounted route is missing here.
+use rocket::response::content::RawHtml;
+use rocket::serde::json::{json, Value};
+
+// Swagger UI from the CDN, pointed at our spec
+const SWAGGER_UI: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+  <title>ER302 API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+  <script>SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });</script>
+</body>
+</html>"##;
+
+#[get("/openapi.json")]
+pub fn openapi() -> Value {
+    spec()
+}
+
+#[get("/docs")]
+pub fn docs() -> RawHtml<&'static str> {
+    RawHtml(SWAGGER_UI)
+}
+
+fn query(name: &str, kind: &str, description: &str) -> Value {
+    json!({ "name": name, "in": "query", "required": false, "schema": { "type": kind }, "description": description })
+}
+
+fn path(name: &str, kind: &str, description: &str) -> Value {
+    json!({ "name": name, "in": "path", "required": true, "schema": { "type": kind }, "description": description })
+}
+
+// Value block parameters of the balance routes
+fn value_block() -> Vec<Value> {
+    vec![
+        query("sector", "integer", "sector of the value block, card.sector by default"),
+        query("block", "integer", "block in the sector, card.block by default"),
+    ]
+}
+
+fn wallet() -> Vec<Value> {
+    vec![path("name", "string", "a [wallets.<name>] of the configuration")]
+}
+
+// Checks without the write, answers {dry_run, previous_balance, amount?, new_balance}
+fn dry_run() -> Value {
+    query("dry_run", "boolean", "detect, authenticate and check the funds and the balance limit, then stop before writing")
+}
+
+// Retries with the same key get the first answer instead of charging the card again
+fn idempotency_key() -> Value {
+    json!({
+        "name": "Idempotency-Key",
+        "in": "header",
+        "required": false,
+        "schema": { "type": "string", "maxLength": 255 },
+        "description": "unique per payment, a retry with the same key (and request) answers like the first one for 24 h",
+    })
+}
+
+struct Operation {
+    method: &'static str,
+    path: &'static str,
+    summary: &'static str,
+    parameters: Vec<Value>,
+    // component schema of the JSON body
+    body: Option<&'static str>,
+    // talks to a reader, `?reader=` picks which one
+    reader: bool,
+    // under /v1 (with an unversioned alias), health checks and the docs aren't
+    versioned: bool,
+    // behind API keys, the versioned routes and the journal export
+    secured: bool,
+    // a GET mutation of /v1 only, /v2 doesn't have them
+    legacy: bool,
+    // answers text/plain instead of an ApiResponse
+    text: bool,
+    // also answers text/csv, with ?format=csv
+    csv: bool,
+}
+
+fn operation(method: &'static str, path: &'static str, summary: &'static str) -> Operation {
+    Operation {
+        method,
+        path,
+        summary,
+        parameters: Vec::new(),
+        body: None,
+        reader: true,
+        versioned: true,
+        secured: true,
+        legacy: false,
+        text: false,
+        csv: false,
+    }
+}
+
+impl Operation {
+    fn parameters(mut self, parameters: Vec<Value>) -> Self {
+        self.parameters.extend(parameters);
+        self
+    }
+
+    fn body(mut self, schema: &'static str) -> Self {
+        self.body = Some(schema);
+        self
+    }
+
+    fn no_reader(mut self) -> Self {
+        self.reader = false;
+        self
+    }
+
+    fn unversioned(mut self) -> Self {
+        self.versioned = false;
+        self.secured = false;
+        self
+    }
+
+    fn legacy(mut self) -> Self {
+        self.legacy = true;
+        self
+    }
+
+    fn text(mut self) -> Self {
+        self.text = true;
+        self
+    }
+
+    fn csv(mut self) -> Self {
+        self.csv = true;
+        self
+    }
+}
+
+fn operations() -> Vec<Operation> {
+    vec![
+        operation("get", "/health", "Liveness, the process is up").no_reader().unversioned(),
+        operation("get", "/ready", "Readiness, every reader answers a version request (503 otherwise)").no_reader().unversioned(),
+        operation("get", "/metrics", "Answer times of the serial commands per reader and command, in the Prometheus text format")
+            .no_reader()
+            .unversioned()
+            .text(),
+        operation("get", "/transactions", "The journal for accounting imports, oldest first: a page of `limit` entries as {entries, next}, `next` the transaction_id to continue ?after= (null on the last page)")
+            .no_reader()
+            .csv()
+            .parameters(vec![
+                query("from", "number", "unix seconds"),
+                query("to", "number", "unix seconds"),
+                query("uid", "string", "card UID as hex"),
+                query("status", "boolean", "only the increases / decreases that went through, or only the failed ones"),
+                query("format", "string", "json (the default) or csv, one line per entry with the timestamp in RFC 3339"),
+                query("after", "string", "transaction_id of the last entry of the previous page"),
+                query("limit", "integer", "entries of the page, 1000 by default and at most 10000"),
+            ]),
+        operation("get", "/ports", "Serial ports of the host, USB ones with vendor / product id").no_reader(),
+        operation("get", "/readers", "Names of the configured readers").no_reader(),
+        operation("get", "/events", "Server-sent events `card_detected` {kind, uid, reader, timestamp} of the cards entering the field, `card_removed` of the ones leaving it and `transaction` of the completed increases / decreases")
+            .no_reader()
+            .parameters(vec![query("reader", "string", "only the events of this reader")]),
+        operation("get", "/present", "Whether a card is in the field with its UID and for how long (present_ms), from the polling"),
+        operation("get", "/lastcard", "UID, reader and timestamp of the card the polling detected last, null before the first").no_reader(),
+        operation("get", "/wait", "Long poll for a tap: the UID of the card presented in time, 408 with NO_CARD after the timeout")
+            .parameters(vec![query("timeout", "integer", "seconds, 10 by default and at most 60")]),
+        operation("get", "/ws", "WebSocket of the /events events, takes {command: id | read_balance | beep, id, reader, ...} messages")
+            .no_reader()
+            .parameters(vec![query("reader", "string", "only the events of this reader")]),
+        operation("get", "/audit", "Audit log of the card operations, oldest first").no_reader().parameters(vec![
+            query("uid", "string", "card UID as hex"),
+            query("route", "string", "route name, e.g. post_increase"),
+            query("caller", "string", "API key name or JWT subject"),
+            query("code", "string", "error code, e.g. NO_CARD"),
+            query("status", "boolean", "only successful (true) or failed (false) operations"),
+            query("since", "number", "unix seconds"),
+            query("until", "number", "unix seconds"),
+            query("limit", "integer", "keep the last ones, 100 by default and at most 1000"),
+        ]),
+        operation("get", "/audit/verify", "Walks the hash chain of the audit log, AUDIT_TAMPERED with the ID of the entry where one was changed, removed or put in between").no_reader(),
+        operation("get", "/journal", "Journal of the increases / decreases with the balances before and after")
+            .no_reader()
+            .parameters(vec![
+                query("transaction_id", "string", "transaction_id of an increase / decrease response"),
+                query("uid", "string", "card UID as hex"),
+                query("since", "number", "unix seconds"),
+                query("until", "number", "unix seconds"),
+                query("limit", "integer", "keep the last ones, 100 by default and at most 1000"),
+            ]),
+        operation("get", "/blacklist", "Blacklisted cards with the reason and when they were added").no_reader(),
+        operation("post", "/blacklist/{uid}", "Blacklist a lost or cloned card, its balance operations fail with CARD_BLOCKED")
+            .no_reader()
+            .parameters(vec![path("uid", "string", "card UID as hex")])
+            .body("BlacklistEntry"),
+        operation("delete", "/blacklist/{uid}", "Take a card off the blacklist")
+            .no_reader()
+            .parameters(vec![path("uid", "string", "card UID as hex")]),
+        operation("get", "/registry", "Registered cards with their label and issue date").no_reader(),
+        operation("get", "/registry/{uid}", "One registered card")
+            .no_reader()
+            .parameters(vec![path("uid", "string", "card UID as hex")]),
+        operation("post", "/registry/{uid}", "Register a card or replace its label / issue date")
+            .no_reader()
+            .parameters(vec![path("uid", "string", "card UID as hex")])
+            .body("RegistryEntry"),
+        operation("delete", "/registry/{uid}", "Unregister a card, with registry.required it can't be used anymore")
+            .no_reader()
+            .parameters(vec![path("uid", "string", "card UID as hex")]),
+        operation("get", "/id", "UID of the card in the field as hex")
+            .parameters(vec![query("detailed", "boolean", "answer {uid, length} instead")]),
+        operation("get", "/cardtype", "Card family from ATQA / SAK: {type, uid, atqa, sak}"),
+        operation("get", "/cards", "Every card in the field: {cards: [{type, uid, atqa, sak}]}, each one is halted while looking"),
+        operation("post", "/halt", "HALT the card in the field, returns its UID"),
+        operation("get", "/reader/info", "Model, firmware and serial number of the reader"),
+        operation("get", "/reader/status", "Result of the startup probe"),
+        operation("post", "/reader/rf", "Switch the RF field on or off").body("RfSwitch"),
+        operation("post", "/beep", "Play beeps").parameters(vec![
+            query("count", "integer", "1 to 10 beeps, 1 by default"),
+            query("time", "integer", "length of a beep in 10 ms units"),
+            query("pause_ms", "integer", "pause between beeps, at most 1000"),
+        ]),
+        operation("get", "/balance", "Balance of the value block").parameters(value_block()),
+        operation("post", "/balance", "Set the balance").parameters(vec![dry_run()]).body("ValueChange"),
+        operation("post", "/increase", "Add to the balance, data is a Receipt")
+            .parameters(vec![idempotency_key(), dry_run()])
+            .body("ValueChange"),
+        operation("post", "/decrease", "Take from the balance, data is a Receipt")
+            .parameters(vec![idempotency_key(), dry_run()])
+            .body("ValueChange"),
+        operation("post", "/initcard", "Set the application key on the value sector").body("InitCard"),
+        operation("post", "/card/deinit", "Zero the data blocks of the value sector and put the factory keys back")
+            .body("Sector"),
+        operation("post", "/value/transfer", "Copy a value block to another block of its sector with restore and transfer")
+            .body("ValueTransfer"),
+        operation("post", "/card/rotate-keys", "Write a new key A / access bits and verify them by authenticating with the new key")
+            .body("KeyRotation"),
+        operation("get", "/balance/{value}", "Set the balance (legacy, prefer POST)")
+            .legacy()
+            .parameters(vec![path("value", "integer", "new balance"), dry_run()])
+            .parameters(value_block()),
+        operation("get", "/increase/{value}", "Add to the balance (legacy, prefer POST)")
+            .legacy()
+            .parameters(vec![path("value", "integer", "amount"), idempotency_key(), dry_run()])
+            .parameters(value_block()),
+        operation("get", "/decrease/{value}", "Take from the balance (legacy, prefer POST)")
+            .legacy()
+            .parameters(vec![path("value", "integer", "amount"), idempotency_key(), dry_run()])
+            .parameters(value_block()),
+        operation("get", "/initcard", "Set the application key on the value sector (legacy, prefer POST)")
+            .legacy()
+            .parameters(vec![query("sector", "integer", "sector to initialize, card.sector by default")]),
+        operation("get", "/wallet/{name}/balance", "Balance of a [wallets.<name>] purse").parameters(wallet()),
+        operation("post", "/wallet/{name}/balance", "Set the balance of a wallet")
+            .parameters(wallet())
+            .parameters(vec![dry_run()])
+            .body("WalletChange"),
+        operation("post", "/wallet/{name}/increase", "Add to a wallet, data is a Receipt")
+            .parameters(wallet())
+            .parameters(vec![idempotency_key(), dry_run()])
+            .body("WalletChange"),
+        operation("post", "/wallet/{name}/decrease", "Take from a wallet, data is a Receipt")
+            .parameters(wallet())
+            .parameters(vec![idempotency_key(), dry_run()])
+            .body("WalletChange"),
+        operation("get", "/wallet/{name}/increase/{value}", "Add to a wallet (legacy, prefer POST)")
+            .legacy()
+            .parameters(wallet())
+            .parameters(vec![path("value", "integer", "amount"), idempotency_key(), dry_run()]),
+        operation("get", "/wallet/{name}/decrease/{value}", "Take from a wallet (legacy, prefer POST)")
+            .legacy()
+            .parameters(wallet())
+            .parameters(vec![path("value", "integer", "amount"), idempotency_key(), dry_run()]),
+        operation("get", "/block/{sector}/{block}", "16 raw bytes of a block as hex and base64").parameters(vec![
+            path("sector", "integer", "sector number"),
+            path("block", "integer", "block in the sector"),
+            query("key", "string", "key A as 12 hex digits, the application key by default"),
+        ]),
+        operation("get", "/sector/{sector}", "Every block of a sector after one authentication: {sector, blocks: [{block, hex, base64}]}")
+            .parameters(vec![
+                path("sector", "integer", "sector number"),
+                query("key", "string", "key A as 12 hex digits, the application key by default"),
+            ]),
+        operation("post", "/sector/{sector}", "Write data blocks of a sector in one session, each read back")
+            .parameters(vec![path("sector", "integer", "sector number")])
+            .body("SectorWrite"),
+        operation("post", "/block/{sector}/{block}", "Write one block")
+            .parameters(vec![
+                path("sector", "integer", "sector number"),
+                path("block", "integer", "block in the sector"),
+            ])
+            .body("BlockWrite"),
+        operation("post", "/restore", "Write a dump back to the card").body("RestoreRequest"),
+        operation("get", "/ndef", "Decoded NDEF records of an NFC Forum formatted card"),
+        operation("post", "/ndef", "Write a URI or text record, formatting the card if needed").body("NdefWrite"),
+        operation("get", "/cardholder", "Cardholder record of card.cardholder_sector: {name, number, expiry}"),
+        operation("post", "/cardholder", "Write the cardholder record, with a CRC-16 checked on every read").body("Cardholder"),
+        operation("get", "/ul/page/{page}", "One 4 byte page of an Ultralight / NTAG")
+            .parameters(vec![path("page", "integer", "page number")]),
+        operation("post", "/ul/page/{page}", "Write one page (4 and up)")
+            .parameters(vec![path("page", "integer", "page number")])
+            .body("PageWrite"),
+        operation("post", "/apdu", "Select and activate (RATS) an ISO 14443-4 card, then send it APDUs; data is {ats, responses: [{data, sw}]}")
+            .body("ApduExchange"),
+        operation("post", "/raw", "Any ER302 command framed and sent as is, data is {node, command, status, data} of the answer (admin)")
+            .body("RawCommand"),
+    ]
+}
+
+fn schemas() -> Value {
+    let string = json!({ "type": "string" });
+    json!({
+        "ApiResponse": {
+            "type": "object",
+            "required": ["status", "data"],
+            "properties": {
+                "status": { "type": "boolean" },
+                "data": { "description": "text for most routes, an object for structured results, the error message on failure" },
+                "code": { "type": "string", "description": "error code when status is false, e.g. NO_CARD, AUTH_FAILED" },
+                "request_id": { "type": "string", "description": "X-Request-Id of the call" },
+                "uid": { "type": "string", "description": "UID (hex, or ?format=) of the card the command talked to, also when it failed on that card" },
+                "transaction_id": { "type": "string", "description": "of an increase / decrease that reached the card, see GET /journal" },
+                "counter": { "type": "integer", "description": "transaction counter of the card's signed balance, with [card.mac]" },
+                "key_profile": { "type": "string", "description": "key profile the card authenticated with, \"default\" for the application key, when the keystore has profiles" },
+                "debug": { "type": "object", "description": "with ?debug=true: queue_ms and the frames, each {tx, rx, ms} with rx null when the reader didn't answer" },
+                "balance": { "$ref": "#/components/schemas/Money" },
+            },
+        },
+        "ApiResponseV2": {
+            "type": "object",
+            "description": "exactly one of data and error",
+            "properties": {
+                "data": { "description": "{uid, balance} of the balance routes, {uid} of /id, /halt and /wait, {message, uid?} of the commands without a result, the /v1 object of the others" },
+                "error": {
+                    "type": "object",
+                    "required": ["code", "message"],
+                    "properties": {
+                        "code": { "type": "string", "description": "e.g. NO_CARD, AUTH_FAILED" },
+                        "message": { "type": "string" },
+                        "uid": { "type": "string", "description": "card the command failed on" },
+                    },
+                },
+                "request_id": { "type": "string", "description": "X-Request-Id of the call" },
+                "transaction_id": { "type": "string", "description": "of an increase / decrease that reached the card, see GET /journal" },
+                "counter": { "type": "integer", "description": "transaction counter of the card's signed balance, with [card.mac]" },
+                "key_profile": { "type": "string", "description": "key profile the card authenticated with, when the keystore has profiles" },
+                "debug": { "type": "object", "description": "with ?debug=true: queue_ms and the frames, each {tx, rx, ms} with rx null when the reader didn't answer" },
+                "balance": { "$ref": "#/components/schemas/Money" },
+            },
+        },
+        "Money": {
+            "type": "object",
+            "description": "with [currency], the balance a balance read or change left on the card",
+            "properties": {
+                "raw": { "type": "integer", "description": "in minor units, as stored on the card" },
+                "formatted": { "type": "string", "description": "e.g. \"15.50\"" },
+                "currency": { "type": "string", "description": "ISO 4217 code" },
+            },
+        },
+        "ValueChange": {
+            "type": "object",
+            "description": "either value or amount",
+            "properties": {
+                "value": { "type": "integer", "minimum": 0, "description": "in minor units" },
+                "amount": { "oneOf": [{ "type": "string" }, { "type": "number" }], "description": "decimal of [currency], e.g. \"15.50\"" },
+                "sector": { "type": "integer", "description": "card.sector by default" },
+                "block": { "type": "integer", "description": "card.block by default" },
+            },
+        },
+        "WalletChange": {
+            "type": "object",
+            "description": "either value or amount",
+            "properties": {
+                "value": { "type": "integer", "minimum": 0, "description": "in minor units" },
+                "amount": { "oneOf": [{ "type": "string" }, { "type": "number" }], "description": "decimal of [currency], e.g. \"15.50\"" },
+            },
+        },
+        "Receipt": {
+            "type": "object",
+            "description": "data of a successful POST /increase or /decrease",
+            "properties": {
+                "uid": string,
+                "previous_balance": { "type": "integer" },
+                "amount": { "type": "integer" },
+                "new_balance": { "type": "integer" },
+                "tx_id": { "type": "string", "description": "same as transaction_id" },
+            },
+        },
+        "Sector": {
+            "type": "object",
+            "properties": { "sector": { "type": "integer", "description": "card.sector by default" } },
+        },
+        "ValueTransfer": {
+            "type": "object",
+            "required": ["to"],
+            "properties": {
+                "sector": { "type": "integer", "description": "card.sector by default" },
+                "from": { "type": "integer", "description": "value block, card.block by default" },
+                "to": { "type": "integer", "description": "another data block of the sector" },
+            },
+        },
+        "InitCard": {
+            "type": "object",
+            "properties": {
+                "sector": { "type": "integer", "description": "card.sector by default" },
+                "access": { "$ref": "#/components/schemas/AccessSpec" },
+            },
+        },
+        "AccessSpec": {
+            "type": "object",
+            "description": "access conditions as `keyA: read+write, keyB: none`, the ones of FF078069 where missing",
+            "properties": {
+                "data": { "type": "string", "description": "all data blocks: read, write, increment, decrement" },
+                "block0": { "type": "string" },
+                "block1": { "type": "string" },
+                "block2": { "type": "string" },
+                "trailer": { "type": "string", "description": "read_access, write_access, write_keys, read_key_b" },
+                "user_byte": { "type": "integer" },
+            },
+        },
+        "KeyRotation": {
+            "type": "object",
+            "properties": {
+                "new_key": { "type": "string", "description": "key A, 12 hex digits, the application key by default" },
+                "current_key": { "type": "string", "description": "key A the sector has now, the application key by default" },
+                "key_b": { "type": "string", "description": "FFFFFFFFFFFF by default" },
+                "access_bits": { "type": "string", "description": "8 hex digits, FF078069 by default" },
+                "access": { "$ref": "#/components/schemas/AccessSpec" },
+                "sector": { "type": "integer", "description": "card.sector by default" },
+            },
+        },
+        "BlacklistEntry": {
+            "type": "object",
+            "properties": { "reason": { "type": "string", "description": "e.g. lost or cloned" } },
+        },
+        "RegistryEntry": {
+            "type": "object",
+            "properties": {
+                "label": { "type": "string", "description": "e.g. the cardholder or the card number printed on it" },
+                "issued_at": { "type": "number", "description": "unix seconds, the time of the registration by default" },
+            },
+        },
+        "RfSwitch": {
+            "type": "object",
+            "required": ["on"],
+            "properties": { "on": { "type": "boolean" } },
+        },
+        "BlockWrite": {
+            "type": "object",
+            "description": "16 bytes given either as hex or as base64",
+            "properties": {
+                "hex": string,
+                "base64": string,
+                "key": { "type": "string", "description": "key A as 12 hex digits" },
+                "confirm": { "type": "boolean", "default": false, "description": "needed to write a sector trailer or block 0, allow_trailer is an alias" },
+            },
+        },
+        "SectorWrite": {
+            "type": "object",
+            "required": ["blocks"],
+            "properties": {
+                "blocks": {
+                    "type": "array",
+                    "description": "data blocks of the sector, no trailer and no block 0",
+                    "items": {
+                        "type": "object",
+                        "required": ["block"],
+                        "description": "16 bytes given either as hex or as base64",
+                        "properties": { "block": { "type": "integer" }, "hex": string, "base64": string },
+                    },
+                },
+                "key": { "type": "string", "description": "key A as 12 hex digits" },
+            },
+        },
+        "RestoreRequest": {
+            "type": "object",
+            "required": ["dump"],
+            "properties": {
+                "dump": {
+                    "type": "object",
+                    "properties": {
+                        "blocks": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "required": ["sector", "block", "hex"],
+                                "properties": {
+                                    "sector": { "type": "integer" },
+                                    "block": { "type": "integer" },
+                                    "hex": string,
+                                },
+                            },
+                        },
+                    },
+                },
+                "key": { "type": "string", "description": "key A as 12 hex digits" },
+                "force": { "type": "boolean", "default": false, "description": "also write block 0 and sector trailers" },
+                "confirm": { "type": "boolean", "default": false, "description": "needed when force writes block 0 or a trailer" },
+            },
+        },
+        "NdefWrite": {
+            "type": "object",
+            "required": ["type", "value"],
+            "properties": {
+                "type": { "type": "string", "enum": ["uri", "text"] },
+                "value": string,
+                "language": { "type": "string", "default": "en" },
+            },
+        },
+        "Cardholder": {
+            "type": "object",
+            "required": ["name", "number", "expiry"],
+            "properties": {
+                "name": { "type": "string", "description": "owner name, at most 24 bytes of UTF-8" },
+                "number": { "type": "string", "description": "card number, at most 16 ASCII characters" },
+                "expiry": { "type": "string", "format": "date", "description": "YYYY-MM-DD" },
+            },
+        },
+        "PageWrite": {
+            "type": "object",
+            "description": "4 bytes given either as hex or as base64",
+            "properties": { "hex": string, "base64": string },
+        },
+        "ApduExchange": {
+            "type": "object",
+            "required": ["apdus"],
+            "properties": {
+                "apdus": { "type": "array", "items": string, "minItems": 1, "maxItems": 16, "description": "command APDUs as hex, 4 to 261 bytes each" },
+            },
+        },
+        "RawCommand": {
+            "type": "object",
+            "required": ["command"],
+            "properties": {
+                "command": { "type": "string", "description": "command code as 4 hex digits, e.g. 0104 for the version" },
+                "data": { "type": "string", "description": "payload as hex, at most 256 bytes" },
+            },
+        },
+    })
+}
+
+pub fn spec() -> Value {
+    let reader = query("reader", "string", "configured reader name, \"default\" when missing");
+    let card = query("card", "string", "UID (hex) of the card to talk to when several are in the field, NO_CARD when it isn't");
+    let format = query("format", "string", "UIDs as hex, reversed, decimal, wiegand26 or wiegand34, card.uid_format by default");
+    let debug = query("debug", "boolean", "admin only: the serial frames (hex) and timings of the command in `debug`");
+    let request_id = json!({
+        "name": "X-Request-Id",
+        "in": "header",
+        "required": false,
+        "schema": { "type": "string" },
+        "description": "echoed in the response, generated when missing",
+    });
+    let mut paths = json!({});
+    for operation in operations() {
+        let mut parameters = operation.parameters;
+        if operation.reader {
+            parameters.push(reader.clone());
+            parameters.push(card.clone());
+            parameters.push(format.clone());
+            parameters.push(debug.clone());
+        }
+        parameters.push(request_id.clone());
+        let mut entry = json!({
+            "summary": operation.summary,
+            "parameters": parameters,
+            "responses": {
+                "200": {
+                    "description": "status false with an error code when the operation failed",
+                    "content": { "application/json": { "schema": { "$ref": "#/components/schemas/ApiResponse" } } },
+                },
+            },
+        });
+        if operation.text {
+            entry["responses"] = json!({
+                "200": { "description": "text", "content": { "text/plain": { "schema": { "type": "string" } } } },
+            });
+        }
+        if operation.csv {
+            entry["responses"]["200"]["content"]["text/csv"] = json!({ "schema": { "type": "string" } });
+        }
+        if let Some(schema) = operation.body {
+            entry["requestBody"] = json!({
+                "required": true,
+                "content": { "application/json": { "schema": { "$ref": format!("#/components/schemas/{}", schema) } } },
+            });
+        }
+        let path = match operation.versioned {
+            true => format!("/v1{}", operation.path),
+            false => operation.path.to_string(),
+        };
+        // only enforced once keys are configured
+        if operation.secured {
+            entry["security"] = json!([{ "bearer": [] }, {}]);
+        }
+        if operation.versioned && !operation.legacy {
+            let mut typed = entry.clone();
+            let schema = json!({ "application/json": { "schema": { "$ref": "#/components/schemas/ApiResponseV2" } } });
+            typed["responses"] = json!({
+                "200": { "description": "typed data", "content": schema },
+                "default": {
+                    "description": "error with its code: 400 bad input, 403 blocked card, 404 no card, 409 insufficient funds \
+                        or balance limit, 422 card refused (e.g. AUTH_FAILED), 502 garbled reader answer, 503 reader unavailable, \
+                        504 reader timeout",
+                    "content": schema,
+                },
+            });
+            paths[format!("/v2{}", operation.path)][operation.method] = typed;
+        }
+        paths[path][operation.method] = entry;
+    }
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "ER302 API",
+            "description": "Mifare / NTAG card reading and writing through an Ehuoyan ER302 reader. \
+                The /v1 routes also answer without the prefix, /v2 has the same routes (without the GET mutations) with typed bodies, \
+                and /readers/<name>/... selects a reader like ?reader=<name>.",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas(),
+            "securitySchemes": { "bearer": { "type": "http", "scheme": "bearer", "description": "API key of the kiosk or a JWT with a read, cashier or admin role" } },
+        },
+    })
+}
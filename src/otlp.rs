@@ -0,0 +1,163 @@
+// OpenTelemetry trace export: with `[otlp]` set the spans of each HTTP request, the reader
+// command it queued (the card session) and every serial frame of it are sent as OTLP/HTTP
+// JSON to `<endpoint>/v1/traces`, e.g. of a Jaeger or Tempo collector. A `traceparent`
+// header (W3C trace context) of the caller makes the request a child of the kiosk's span,
+// so a transaction can be followed across services. Spans go out in batches once a second,
+// ones the collector doesn't take are dropped rather than queued up.
+use crate::webhooks;
+use rocket::serde::json::{json, Value};
+use rocket::serde::Deserialize;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Spans waiting for the exporter, more are dropped
+const QUEUE: usize = 4096;
+const BATCH: usize = 512;
+const FLUSH: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct OtlpConfig {
+    // http://host[:port] of the collector's OTLP/HTTP receiver, /v1/traces is added
+    pub endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+}
+
+fn default_service_name() -> String {
+    "er302-api".to_string()
+}
+
+// A closed span of a traced request
+pub struct Finished {
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub parent_id: Option<u64>,
+    pub name: &'static str,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub fields: Vec<(&'static str, Value)>,
+}
+
+static EXPORTER: OnceLock<SyncSender<Finished>> = OnceLock::new();
+
+// The traces URL of the endpoint
+pub fn traces_url(endpoint: &str) -> Result<String, String> {
+    let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+    webhooks::target(&url)?;
+    Ok(url)
+}
+
+// Whether closed spans are exported
+pub fn exporting() -> bool {
+    EXPORTER.get().is_some()
+}
+
+pub fn export(span: Finished) {
+    if let Some(exporter) = EXPORTER.get() {
+        if let Err(TrySendError::Full(_)) = exporter.try_send(span) {
+            tracing::warn!("OTLP exporter fell behind, span dropped");
+        }
+    }
+}
+
+// Start the exporter, only the first call of the process does
+pub fn spawn(config: OtlpConfig) {
+    let (sender, spans) = mpsc::sync_channel(QUEUE);
+    if EXPORTER.set(sender).is_err() {
+        return;
+    }
+    thread::Builder::new()
+        .name("er302-otlp".to_string())
+        .spawn(move || run(config, spans))
+        .expect("failed to spawn OTLP exporter");
+}
+
+fn run(config: OtlpConfig, spans: Receiver<Finished>) {
+    let Ok(url) = traces_url(&config.endpoint) else {
+        return;
+    };
+    let mut batch = Vec::new();
+    let mut flushed = Instant::now();
+    loop {
+        match spans.recv_timeout(FLUSH) {
+            Ok(span) => batch.push(span),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if !batch.is_empty() && (batch.len() >= BATCH || flushed.elapsed() >= FLUSH) {
+            let body = body(&config.service_name, &batch).to_string();
+            match webhooks::post_json(&url, &[], &body) {
+                Ok(status) if (200..300).contains(&status) => (),
+                Ok(status) => tracing::warn!(url, status, spans = batch.len(), "OTLP collector refused the spans"),
+                Err(e) => tracing::warn!(url, error = %e, spans = batch.len(), "OTLP collector unreachable"),
+            }
+            batch.clear();
+            flushed = Instant::now();
+        }
+    }
+}
+
+// (trace ID, parent span ID) of a `traceparent` header, 00-<trace id>-<span id>-<flags>
+pub fn parse_traceparent(header: &str) -> Option<(u128, u64)> {
+    let mut parts = header.trim().split('-');
+    let (version, trace, span, flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    if version.len() != 2 || trace.len() != 32 || span.len() != 16 || flags.len() != 2 {
+        return None;
+    }
+    let trace = u128::from_str_radix(trace, 16).ok().filter(|&id| id != 0)?;
+    let span = u64::from_str_radix(span, 16).ok().filter(|&id| id != 0)?;
+    Some((trace, span))
+}
+
+// ExportTraceServiceRequest of the OTLP JSON encoding
+pub fn body(service_name: &str, spans: &[Finished]) -> Value {
+    let nanos = |time: SystemTime| time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos().to_string();
+    let spans: Vec<Value> = spans
+        .iter()
+        .map(|span| {
+            let fields = |name: &str| span.fields.iter().rev().find(|(field, _)| *field == name).map(|(_, value)| value);
+            // a command's error code, a request answered 5xx
+            let status = match (fields("code"), fields("status").and_then(Value::as_u64)) {
+                (Some(code), _) => json!({ "code": 2, "message": code.as_str().unwrap_or_default() }),
+                (None, Some(status)) if status >= 500 => json!({ "code": 2 }),
+                _ => json!({ "code": 0 }),
+            };
+            let mut entry = json!({
+                "traceId": format!("{:032x}", span.trace_id),
+                "spanId": format!("{:016x}", span.span_id),
+                "name": span.name,
+                // the request is the server side of the caller's span
+                "kind": if matches!(span.name, "http" | "grpc") { 2 } else { 1 },
+                "startTimeUnixNano": nanos(span.start),
+                "endTimeUnixNano": nanos(span.end),
+                "attributes": span.fields.iter().map(|(name, value)| attribute(name, value)).collect::<Vec<_>>(),
+                "status": status,
+            });
+            if let Some(parent) = span.parent_id {
+                entry["parentSpanId"] = json!(format!("{:016x}", parent));
+            }
+            entry
+        })
+        .collect();
+    json!({
+        "resourceSpans": [{
+            "resource": { "attributes": [attribute("service.name", &json!(service_name))] },
+            "scopeSpans": [{ "scope": { "name": "er302", "version": env!("CARGO_PKG_VERSION") }, "spans": spans }],
+        }],
+    })
+}
+
+fn attribute(name: &str, value: &Value) -> Value {
+    let value = match value {
+        Value::Bool(value) => json!({ "boolValue": value }),
+        // 64 bit integers are strings in OTLP JSON
+        Value::Number(number) if number.is_u64() || number.is_i64() => json!({ "intValue": number.to_string() }),
+        Value::Number(number) => json!({ "doubleValue": number.as_f64() }),
+        Value::String(text) => json!({ "stringValue": text }),
+        value => json!({ "stringValue": value.to_string() }),
+    };
+    json!({ "key": name, "value": value })
+}
@@ -0,0 +1,1481 @@
+// ER302 driver: frames requests, talks to the serial port and runs the card operations
+use crate::access::AccessBits;
+use crate::cardholder::{Cardholder, RECORD_LENGTH};
+use crate::codec::{self, BlockAddress, CardInfo, CardType, Frame, ReaderInfo};
+use crate::error::ReaderError;
+use crate::hmac::{constant_time_eq, hmac_sha256};
+use crate::ndef;
+use serialport::SerialPort;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::io::{ErrorKind, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Key A
+pub const APPKEY: &[u8] = &[0x17, 0x05, 0x97, 0x27, 0x08, 0x59];
+// Default Key
+pub const DEFAULTKEY: &[u8] = &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+// Key A all permission | Key B disabled
+pub const KEYACCESS: &[u8] = &[0xFF, 0x07, 0x80, 0x69];
+// Cards list_cards() looks for, more don't fit the antenna's field anyway
+pub const MAX_CARDS: usize = 8;
+
+// Keys the reader writes and authenticates with, APPKEY / DEFAULTKEY / KEYACCESS unless a
+// keystore replaces them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Keys {
+    // key A of the application sectors
+    pub app_key: [u8; 6],
+    // key of factory cards, and key B of initialized sectors
+    pub default_key: [u8; 6],
+    // access bits of initialized sectors
+    pub access_bits: [u8; 4],
+}
+
+impl Default for Keys {
+    fn default() -> Self {
+        Keys {
+            app_key: [0x17, 0x05, 0x97, 0x27, 0x08, 0x59],
+            default_key: [0xff; 6],
+            access_bits: [0xFF, 0x07, 0x80, 0x69],
+        }
+    }
+}
+
+// Another application key cards were issued with, e.g. before a key change
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyProfile {
+    pub name: String,
+    pub key: ProfileKey,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProfileKey {
+    // the same key A on every card
+    Shared([u8; 6]),
+    // key A derived from this master key and the UID, like card.master_key
+    Diversified(Vec<u8>),
+}
+
+impl ProfileKey {
+    pub fn for_card(&self, uid: &[u8]) -> Vec<u8> {
+        match self {
+            ProfileKey::Shared(key) => key.to_vec(),
+            ProfileKey::Diversified(master) => diversify(master, uid),
+        }
+    }
+}
+
+// First 6 bytes of HMAC-SHA256(master key, "ER302 key A" | UID)
+fn diversify(master: &[u8], uid: &[u8]) -> Vec<u8> {
+    let mut message = b"ER302 key A".to_vec();
+    message.extend_from_slice(uid);
+    hmac_sha256(master, &message)[..6].to_vec()
+}
+
+// `count` beeps of `time` (reader units of 10 ms) with `pause` in between
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BeepPattern {
+    pub count: u8,
+    pub time: u8,
+    pub pause: Duration,
+}
+
+impl BeepPattern {
+    pub const SILENT: BeepPattern = BeepPattern {
+        count: 0,
+        time: 0,
+        pause: Duration::ZERO,
+    };
+}
+
+// What the reader plays for each outcome of an operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BeepPatterns {
+    pub success: BeepPattern,
+    pub auth_failed: BeepPattern,
+    pub no_card: BeepPattern,
+}
+
+impl Default for BeepPatterns {
+    // One short beep on success, like before patterns existed
+    fn default() -> Self {
+        BeepPatterns {
+            success: BeepPattern {
+                count: 1,
+                time: 2,
+                pause: Duration::ZERO,
+            },
+            auth_failed: BeepPattern::SILENT,
+            no_card: BeepPattern::SILENT,
+        }
+    }
+}
+
+// HMAC of the balance, kept in another data block of the value block's sector as
+// key ID (1 byte), counter (4 bytes LE) and the first 11 bytes of the HMAC-SHA256 over
+// UID, block, balance and counter. Balances written by other tools don't match it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ValueMac {
+    pub block: u8,
+    // key new MACs are written with
+    pub key_id: u8,
+    // key ID -> key, the older ones still verify cards written before a rotation
+    pub keys: BTreeMap<u8, Vec<u8>>,
+}
+
+impl ValueMac {
+    const LENGTH: usize = 11;
+
+    fn sign(&self, key_id: u8, uid: &[u8], block: BlockAddress, balance: u32, counter: u32) -> Option<[u8; 11]> {
+        let key = self.keys.get(&key_id)?;
+        let mut message = b"ER302 value".to_vec();
+        message.extend_from_slice(uid);
+        message.extend_from_slice(&[block.sector, block.block, key_id]);
+        message.extend_from_slice(&balance.to_le_bytes());
+        message.extend_from_slice(&counter.to_le_bytes());
+        let mut mac = [0; Self::LENGTH];
+        mac.copy_from_slice(&hmac_sha256(key, &message)[..Self::LENGTH]);
+        Some(mac)
+    }
+}
+
+// Highest transaction counter seen per card UID, shared by all readers: a card answering
+// with a lower one is an older dump written back
+#[derive(Debug, Clone, Default)]
+pub struct Counters(Arc<Mutex<HashMap<Vec<u8>, u32>>>);
+
+impl Counters {
+    pub fn seen(&self, uid: &[u8]) -> Option<u32> {
+        self.0.lock().unwrap().get(uid).copied()
+    }
+
+    pub fn record(&self, uid: &[u8], counter: u32) {
+        let mut counters = self.0.lock().unwrap();
+        let seen = counters.entry(uid.to_vec()).or_insert(counter);
+        *seen = counter.max(*seen);
+    }
+}
+
+// Upper bounds of the latency histogram's buckets in milliseconds
+pub const LATENCY_BUCKETS_MS: [u64; 9] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500];
+
+// Answer times of one serial command
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Latency {
+    pub count: u64,
+    // the reader didn't answer or answered garbage, their time counts too
+    pub failures: u64,
+    pub total: Duration,
+    pub max: Duration,
+    pub last: Duration,
+    // exchanges within each of LATENCY_BUCKETS_MS, cumulative like Prometheus' buckets
+    pub buckets: [u64; LATENCY_BUCKETS_MS.len()],
+}
+
+impl Latency {
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            count => Duration::from_secs_f64(self.total.as_secs_f64() / count as f64),
+        }
+    }
+}
+
+// Answer times per command (codec::command_name) of one reader since startup, clones share
+// them
+#[derive(Debug, Clone, Default)]
+pub struct Latencies(Arc<Mutex<BTreeMap<&'static str, Latency>>>);
+
+impl Latencies {
+    pub fn record(&self, command: &'static str, elapsed: Duration, answered: bool) {
+        let mut latencies = self.0.lock().unwrap();
+        let latency = latencies.entry(command).or_default();
+        latency.count += 1;
+        latency.failures += u64::from(!answered);
+        latency.total += elapsed;
+        latency.max = latency.max.max(elapsed);
+        latency.last = elapsed;
+        for (bucket, &bound) in latency.buckets.iter_mut().zip(LATENCY_BUCKETS_MS.iter()) {
+            *bucket += u64::from(elapsed <= Duration::from_millis(bound));
+        }
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<&'static str, Latency> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+// Whether balance operations may run on a card, by UID, e.g. Err(CardBlocked) for a lost one
+pub type CardCheck = Arc<dyn Fn(&[u8]) -> Result<(), ReaderError> + Send + Sync>;
+
+// Response APDU of an ISO 14443-4 card: data and the status word SW1 SW2
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ApduResponse {
+    pub data: Vec<u8>,
+    pub sw: [u8; 2],
+}
+
+// One frame sent and the reader's answer, None when it didn't answer in time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exchange {
+    pub request: Vec<u8>,
+    pub response: Option<Vec<u8>>,
+    pub elapsed: Duration,
+}
+
+// Retries of card activation and authentication after a transient error, see set_retry()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retry {
+    // tries in all, 1 never retries
+    pub attempts: u32,
+    // before the first retry, doubled for each one after it
+    pub delay: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            attempts: 1,
+            delay: Duration::from_millis(20),
+        }
+    }
+}
+
+// A frame damaged on the serial line or by a card at the edge of the field, the same
+// request may well get through
+fn garbled(error: &ReaderError) -> bool {
+    matches!(error, ReaderError::ChecksumMismatch | ReaderError::InvalidFrame(_))
+}
+
+pub struct Reader {
+    port: Box<dyn SerialPort>,
+    // codec::REQUEST_ALL, or REQUEST_IDLE so halted cards stay quiet
+    request_mode: u8,
+    beeps: BeepPatterns,
+    // UID of the card activated last, for the audit log
+    last_uid: Option<Vec<u8>>,
+    // balances above this are refused
+    max_balance: Option<u32>,
+    // block (in the value block's sector) holding a copy of the balance against torn writes
+    backup_block: Option<u8>,
+    value_mac: Option<ValueMac>,
+    counters: Counters,
+    // transaction counter of the card the last command talked to
+    last_counter: Option<u32>,
+    card_check: Option<CardCheck>,
+    keys: Keys,
+    // key A of each card is derived from its UID with this secret, keys.app_key while None
+    master_key: Option<Vec<u8>>,
+    // tried in order when the application key fails
+    profiles: Vec<KeyProfile>,
+    // profile the last session authenticated with, while there are profiles
+    last_profile: Option<String>,
+    // frames exchanged since trace_frames(true), for diagnosing the protocol in the field
+    trace: Option<Vec<Exchange>>,
+    // UID of the card to talk to when several are in the field, see target_card()
+    target: Option<Vec<u8>>,
+    // the only key profile sessions open with, see pin_profile()
+    pinned: Option<String>,
+    retry: Retry,
+    latencies: Latencies,
+}
+
+impl Reader {
+    // Constructor to create a new Reader instance
+    pub fn new(port: Box<dyn SerialPort>) -> Self {
+        Reader {
+            port,
+            request_mode: codec::REQUEST_ALL,
+            beeps: BeepPatterns::default(),
+            last_uid: None,
+            max_balance: None,
+            backup_block: None,
+            value_mac: None,
+            counters: Counters::default(),
+            last_counter: None,
+            card_check: None,
+            keys: Keys::default(),
+            master_key: None,
+            profiles: Vec::new(),
+            last_profile: None,
+            trace: None,
+            target: None,
+            pinned: None,
+            retry: Retry::default(),
+            latencies: Latencies::default(),
+        }
+    }
+
+    // Give the port back, e.g. after probing it
+    pub fn into_port(self) -> Box<dyn SerialPort> {
+        self.port
+    }
+
+    // Retry the request / anticollision / select of a card that answered and then dropped
+    // out, and authentications whose answer came back garbled. A card that isn't there, a
+    // wrong key or a reader that doesn't answer fail at once.
+    pub fn set_retry(&mut self, retry: Retry) {
+        self.retry = retry;
+    }
+
+    // Wait before retry number `retry` + 1
+    fn back_off(&self, retry: u32, error: &ReaderError) {
+        let delay = self.retry.delay.saturating_mul(1 << retry.min(16));
+        tracing::debug!(error = %error, retry = retry + 1, delay_ms = delay.as_millis() as u64, "transient card error, retrying");
+        thread::sleep(delay);
+    }
+
+    // Where the answer time of every frame is recorded
+    pub fn set_latencies(&mut self, latencies: Latencies) {
+        self.latencies = latencies;
+    }
+
+    pub fn set_beep_patterns(&mut self, beeps: BeepPatterns) {
+        self.beeps = beeps;
+    }
+
+    // Refuse to set or increase a balance above `max`
+    pub fn set_max_balance(&mut self, max: Option<u32>) {
+        self.max_balance = max;
+    }
+
+    // Keep a copy of every balance in `block` of the value block's sector: it's written and
+    // verified first, and a value block left corrupt by a card pulled mid-write is restored
+    // from it on the next read
+    pub fn set_backup_block(&mut self, block: Option<u8>) {
+        self.backup_block = block;
+    }
+
+    fn backup_of(&self, block: BlockAddress) -> Result<Option<BlockAddress>, ReaderError> {
+        let Some(backup) = self.backup_block else {
+            return Ok(None);
+        };
+        let backup = BlockAddress::data(block.sector, backup)?;
+        match backup == block {
+            true => Err(ReaderError::InvalidBlock { sector: block.sector, block: block.block }),
+            false => Ok(Some(backup)),
+        }
+    }
+
+    // Sign every balance, and refuse balances whose MAC doesn't match
+    pub fn set_value_mac(&mut self, mac: Option<ValueMac>) {
+        self.value_mac = mac;
+    }
+
+    // Refuse cards whose counter is below the highest one seen in `counters`
+    pub fn set_counters(&mut self, counters: Counters) {
+        self.counters = counters;
+    }
+
+    // Counter of the signed balance the last command read or wrote, None without [card.mac]
+    pub fn take_last_counter(&mut self) -> Option<u32> {
+        self.last_counter.take()
+    }
+
+    // Asked before every balance operation, with the UID of the card
+    pub fn set_card_check(&mut self, check: Option<CardCheck>) {
+        self.card_check = check;
+    }
+
+    pub fn set_keys(&mut self, keys: Keys) {
+        self.keys = keys;
+    }
+
+    pub fn keys(&self) -> &Keys {
+        &self.keys
+    }
+
+    // Diversify the application key: every card gets its own key A, derived from `master` and
+    // its UID, so the key read out of one card doesn't open the others
+    pub fn set_master_key(&mut self, master: Option<Vec<u8>>) {
+        self.master_key = master;
+    }
+
+    // Key A of the application sectors of the card with `uid`: the first 6 bytes of
+    // HMAC-SHA256(master key, "ER302 key A" | UID), or keys.app_key without a master key
+    pub fn app_key(&self, uid: &[u8]) -> Vec<u8> {
+        match &self.master_key {
+            Some(master) => diversify(master, uid),
+            None => self.keys.app_key.to_vec(),
+        }
+    }
+
+    // Keys of cards issued earlier, tried in order after the application key ("default")
+    pub fn set_key_profiles(&mut self, profiles: Vec<KeyProfile>) {
+        self.profiles = profiles;
+    }
+
+    // Name of the key profile the last command authenticated with, None without profiles
+    pub fn take_last_profile(&mut self) -> Option<String> {
+        self.last_profile.take()
+    }
+
+    // Authenticate the sector of `block` with the application key, then with each profile
+    pub fn authenticate_app(&mut self, block: BlockAddress, uid: &[u8]) -> Result<(), ReaderError> {
+        if let Some(name) = self.pinned.clone() {
+            let key = match self.profiles.iter().find(|profile| profile.name == name) {
+                Some(profile) => profile.key.for_card(uid),
+                None if name == "default" => self.app_key(uid),
+                None => return Err(ReaderError::AuthFailed),
+            };
+            self.authenticate(block, &key)?;
+            self.last_profile = Some(name);
+            return Ok(());
+        }
+        let mut result = self.authenticate(block, &self.app_key(uid));
+        let mut used = "default".to_string();
+        for profile in self.profiles.clone() {
+            if result != Err(ReaderError::AuthFailed) {
+                break;
+            }
+            // the failed authentication halted the card
+            self.wake_up();
+            result = self.authenticate(block, &profile.key.for_card(uid));
+            used = profile.name;
+        }
+        if result.is_ok() && !self.profiles.is_empty() {
+            self.last_profile = Some(used);
+        }
+        result
+    }
+
+    fn mac_block_of(&self, block: BlockAddress) -> Result<Option<BlockAddress>, ReaderError> {
+        let Some(mac) = &self.value_mac else {
+            return Ok(None);
+        };
+        let address = BlockAddress::data(block.sector, mac.block)?;
+        match address == block || Some(mac.block) == self.backup_block {
+            true => Err(ReaderError::InvalidBlock { sector: block.sector, block: mac.block }),
+            false => Ok(Some(address)),
+        }
+    }
+
+    // Check the MAC of `balance` read from the authenticated value block, returns its counter
+    fn verify_value(&mut self, block: BlockAddress, balance: u32) -> Result<u32, ReaderError> {
+        let Some(address) = self.mac_block_of(block)? else {
+            return Ok(0);
+        };
+        let data = self.read_block_request(address)?;
+        let counter = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+        let uid = self.last_uid.clone().unwrap_or_default();
+        let mac = self.value_mac.as_ref().and_then(|mac| mac.sign(data[0], &uid, block, balance, counter));
+        match mac {
+            Some(mac) if constant_time_eq(&mac, &data[5..]) => match self.counters.seen(&uid) {
+                Some(seen) if counter < seen => {
+                    tracing::warn!(uid = %codec::to_hex(&uid), counter, seen, "card counter went back");
+                    Err(ReaderError::CardRollback { counter, seen })
+                }
+                _ => {
+                    self.counters.record(&uid, counter);
+                    self.last_counter = Some(counter);
+                    Ok(counter)
+                }
+            },
+            _ => {
+                tracing::warn!(uid = %codec::to_hex(&uid), key_id = data[0], balance, "balance MAC doesn't match");
+                Err(ReaderError::ValueTampered)
+            }
+        }
+    }
+
+    // Write the MAC of the new `balance` of the authenticated value block with the current key
+    fn sign_value(&mut self, block: BlockAddress, balance: u32, counter: u32) -> Result<(), ReaderError> {
+        let Some(address) = self.mac_block_of(block)? else {
+            return Ok(());
+        };
+        let uid = self.last_uid.clone().unwrap_or_default();
+        let value_mac = self.value_mac.as_ref().expect("checked by mac_block_of");
+        let mac = value_mac
+            .sign(value_mac.key_id, &uid, block, balance, counter)
+            .ok_or_else(|| ReaderError::InvalidInput(format!("no key for MAC key ID {}", value_mac.key_id)))?;
+        let mut data = vec![value_mac.key_id];
+        data.extend_from_slice(&counter.to_le_bytes());
+        data.extend_from_slice(&mac);
+        self.send_checked(&codec::write_block(address.absolute(), &data))?;
+        self.counters.record(&uid, counter);
+        self.last_counter = Some(counter);
+        Ok(())
+    }
+
+    // Counter of the MAC block without checking the MAC, for a balance that's set anew
+    fn mac_counter(&mut self, block: BlockAddress) -> Result<u32, ReaderError> {
+        match self.mac_block_of(block)? {
+            Some(address) => {
+                let data = self.read_block_request(address)?;
+                Ok(u32::from_le_bytes([data[1], data[2], data[3], data[4]]))
+            }
+            None => Ok(0),
+        }
+    }
+
+    // Balance of the authenticated value block, repaired from the backup if it's corrupt
+    fn read_value(&mut self, block: BlockAddress) -> Result<u32, ReaderError> {
+        let Some(backup) = self.backup_of(block)? else {
+            return self.read_balance_request(block);
+        };
+        match self.read_balance_request(block) {
+            // not a valid value block anymore
+            Err(ReaderError::ProtocolError { .. }) => {
+                // the refused read may have halted the card
+                self.open_session(block, None)?;
+                let balance = self.read_balance_request(backup)?;
+                self.init_balance_request(block, balance)?;
+                tracing::warn!(sector = block.sector, block = block.block, balance, "value block restored from its backup");
+                Ok(balance)
+            }
+            result => result,
+        }
+    }
+
+    // Balance and MAC counter of the authenticated value block. One that doesn't match the MAC
+    // (a write torn between the MAC and the value block) or has a counter older than the card's
+    // is restored from the backup when the backup passes both checks.
+    fn read_signed(&mut self, block: BlockAddress) -> Result<(u32, u32), ReaderError> {
+        let balance = self.read_value(block)?;
+        let error = match self.verify_value(block, balance) {
+            Ok(counter) => return Ok((balance, counter)),
+            Err(e @ (ReaderError::ValueTampered | ReaderError::CardRollback { .. })) => e,
+            Err(e) => return Err(e),
+        };
+        let Some(backup) = self.backup_of(block)? else {
+            return Err(error);
+        };
+        let balance = self.read_balance_request(backup)?;
+        let counter = self.verify_value(block, balance).map_err(|_| error)?;
+        self.init_balance_request(block, balance)?;
+        tracing::warn!(sector = block.sector, block = block.block, balance, "value block failed its MAC check, restored from its backup");
+        Ok((balance, counter))
+    }
+
+    // Backup first (verified), then the MAC of the new balance, then the value block, so a
+    // write torn at any point leaves one copy that matches the MAC
+    fn write_value(&mut self, block: BlockAddress, balance: u32, counter: u32) -> Result<(), ReaderError> {
+        let Some(backup) = self.backup_of(block)? else {
+            self.init_balance_request(block, balance)?;
+            return self.sign_value(block, balance, counter);
+        };
+        self.init_balance_request(backup, balance)?;
+        if self.read_balance_request(backup)? != balance {
+            return Err(ReaderError::ReadBackFailed);
+        }
+        self.sign_value(block, balance, counter)?;
+        self.init_balance_request(block, balance)
+    }
+
+    fn check_balance(&self, balance: u64) -> Result<(), ReaderError> {
+        let max = self.max_balance.unwrap_or(u32::MAX);
+        match balance > u64::from(max) {
+            true => Err(ReaderError::BalanceLimit { max }),
+            false => Ok(()),
+        }
+    }
+
+    // Only detect idle cards, a card halted by `halt` is ignored until it's presented again
+    pub fn skip_halted_cards(&mut self, skip: bool) {
+        self.request_mode = match skip {
+            true => codec::REQUEST_IDLE,
+            false => codec::REQUEST_ALL,
+        };
+    }
+
+    // Activate the card with this UID from now on, stacked cards in front of it are halted
+    // until it wins the anticollision. Any card in the field again with None.
+    pub fn target_card(&mut self, uid: Option<Vec<u8>>) {
+        self.target = uid;
+    }
+
+    // Open sessions with this key profile only ("default" is the application key) instead of
+    // trying each, e.g. for a wallet sector with keys of its own. All of them again with None.
+    pub fn pin_profile(&mut self, profile: Option<String>) {
+        self.pinned = profile;
+    }
+
+    // Record the frames from now on (or not), take_trace() hands them out and stops
+    pub fn trace_frames(&mut self, on: bool) {
+        self.trace = on.then(Vec::new);
+    }
+
+    pub fn take_trace(&mut self) -> Vec<Exchange> {
+        self.trace.take().unwrap_or_default()
+    }
+
+    // UID of the card the last command talked to, None when no card answered since the last call
+    pub fn take_last_uid(&mut self) -> Option<Vec<u8>> {
+        self.last_uid.take()
+    }
+
+    // Method to send the request through the serial port, returns the validated response frame
+    pub fn send_request(&mut self, input: &[u8]) -> Result<Frame, ReaderError> {
+        let command = match input.get(2..4) {
+            Some(&[low, high]) => codec::command_name(u16::from_le_bytes([low, high])),
+            _ => "other",
+        };
+        let _span = tracing::info_span!("frame", command).entered();
+        // Calculate XOR and prepare final data
+        let final_data = codec::encode_frame(input);
+        tracing::debug!(tx = %codec::to_hex(&final_data), "frame sent");
+
+        // Write data to the serial port
+        let started = Instant::now();
+        let buffer = self
+            .port
+            .write_all(&final_data)
+            .map_err(|e| ReaderError::PortError(e.to_string()))
+            .and_then(|_| self.read_frame());
+        // thread::sleep(Duration::from_millis(100)); // Add delay only for Windows: (cause Windows is so lazy and can not handle the speed of Rust)
+        let elapsed = started.elapsed();
+        if let Some(trace) = self.trace.as_mut() {
+            trace.push(Exchange {
+                request: final_data,
+                response: buffer.as_ref().ok().cloned(),
+                elapsed,
+            });
+        }
+
+        let frame = buffer.and_then(|buffer| {
+            tracing::debug!(rx = %codec::to_hex(&buffer), "frame received");
+            Frame::parse(&buffer)
+        });
+        self.latencies.record(command, elapsed, frame.is_ok());
+        let frame = frame?;
+        // the reader echoes the command code of the request
+        if input.get(2..4) != Some(&frame.command.to_le_bytes()[..]) {
+            return Err(ReaderError::InvalidFrame("response to another command"));
+        }
+        Ok(frame)
+    }
+
+    // Read until one whole frame arrived, frames may come in several chunks.
+    // The port timeout is the deadline for the whole frame.
+    fn read_frame(&mut self) -> Result<Vec<u8>, ReaderError> {
+        let deadline = Instant::now() + self.port.timeout();
+        let mut buffer: Vec<u8> = Vec::new();
+        let mut chunk = [0u8; 256];
+        loop {
+            codec::skip_to_header(&mut buffer);
+            if let Some(length) = codec::frame_length(&buffer) {
+                if buffer.len() >= length {
+                    buffer.truncate(length);
+                    return Ok(buffer);
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(ReaderError::Timeout);
+            }
+            match self.port.read(&mut chunk) {
+                Ok(bytes_read) => buffer.extend_from_slice(&chunk[..bytes_read]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) if e.kind() == ErrorKind::TimedOut => return Err(ReaderError::Timeout),
+                Err(e) => return Err(ReaderError::PortError(e.to_string())),
+            }
+        }
+    }
+
+    // Send the request and make sure the reader answered with status 0x00
+    pub fn send_checked(&mut self, input: &[u8]) -> Result<Frame, ReaderError> {
+        self.send_request(input)?.check_status()
+    }
+
+    // Beep
+    pub fn beep(&mut self, time: u8) {
+        match self.send_request(&codec::beep(time)){
+            Ok(_) => (),
+            Err(e) => tracing::warn!(error = %e, "beep failed")
+        }
+    }
+
+    // Play `pattern`, blocks until the last beep started
+    pub fn beep_pattern(&mut self, pattern: BeepPattern) {
+        for i in 0..pattern.count {
+            if i > 0 {
+                thread::sleep(Duration::from_millis(pattern.time as u64 * 10) + pattern.pause);
+            }
+            self.beep(pattern.time);
+        }
+    }
+
+    fn signal_success(&mut self) {
+        self.beep_pattern(self.beeps.success);
+    }
+
+    // Pattern of a failed operation, errors without a pattern stay silent
+    pub fn signal_error(&mut self, error: &ReaderError) {
+        match error {
+            ReaderError::AuthFailed => self.beep_pattern(self.beeps.auth_failed),
+            ReaderError::NoCard => self.beep_pattern(self.beeps.no_card),
+            _ => (),
+        }
+    }
+
+    // Request Mifare, returns the ATQA of the card
+    pub fn mifare_request(&mut self) -> Result<u16, ReaderError> {
+        self.request_card(self.request_mode)
+    }
+
+    fn request_card(&mut self, mode: u8) -> Result<u16, ReaderError> {
+        match self.send_checked(&codec::mifare_request_mode(mode)) {
+            Ok(frame) if frame.data.len() >= 2 => Ok(u16::from_le_bytes([frame.data[0], frame.data[1]])),
+            Ok(_) => Err(ReaderError::InvalidFrame("ATQA missing from response")),
+            Err(ReaderError::ProtocolError { .. }) => Err(ReaderError::NoCard),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Anticollision, returns the 4 UID bytes of cascade level 1
+    pub fn anticollision(&mut self) -> Result<Vec<u8>, ReaderError> {
+        self.anticollision_level(codec::CASCADE_LEVELS[0])
+    }
+
+    // Anticollision on one cascade level, may start with the cascade tag
+    pub fn anticollision_level(&mut self, level: u8) -> Result<Vec<u8>, ReaderError> {
+        match self.send_checked(&codec::anticollision_level(level)) {
+            Ok(frame) if frame.data.len() >= 4 => Ok(frame.data[..4].to_vec()),
+            Ok(_) | Err(ReaderError::ProtocolError { .. }) => Err(ReaderError::NoCard),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Select Card (cascade level 1), returns the SAK
+    pub fn select_card(&mut self, uid: &[u8]) -> Result<u8, ReaderError> {
+        self.select_level(codec::CASCADE_LEVELS[0], uid)
+    }
+
+    // Select on one cascade level, returns the SAK
+    pub fn select_level(&mut self, level: u8, part: &[u8]) -> Result<u8, ReaderError> {
+        match self.send_checked(&codec::select_level(level, part)) {
+            Ok(frame) => frame.data.first().copied().ok_or(ReaderError::InvalidFrame("SAK missing from response")),
+            Err(ReaderError::ProtocolError { .. }) => Err(ReaderError::NoCard),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Any command of the ER302 protocol, framed like the others and answered with whatever
+    // status the reader returns
+    pub fn raw_command(&mut self, code: u16, data: &[u8]) -> Result<Frame, ReaderError> {
+        self.send_request(&codec::command(code, data))
+    }
+
+    // Model, firmware and serial number of the reader itself
+    pub fn read_info(&mut self) -> Result<ReaderInfo, ReaderError> {
+        let version = self.send_checked(&codec::read_version())?;
+        let serial = self.send_checked(&codec::read_serial())?;
+        Ok(ReaderInfo::parse(&version.data, &serial.data))
+    }
+
+    // RF field on / off, switching it off and on again resets every card in the field
+    pub fn set_rf(&mut self, on: bool) -> Result<(), ReaderError> {
+        self.send_checked(&codec::antenna(on))?;
+        Ok(())
+    }
+
+    // HALT the selected card
+    pub fn halt(&mut self) -> Result<(), ReaderError> {
+        self.send_checked(&codec::halt())?;
+        Ok(())
+    }
+
+    // Authenticate on the sector of `block`
+    pub fn authenticate(&mut self, block: BlockAddress, key: &[u8]) -> Result<(), ReaderError> {
+        let mut retry = 0;
+        loop {
+            match self.send_checked(&codec::authenticate(block.absolute(), key)) {
+                Ok(_) => return Ok(()),
+                // the card refused the key, trying it again won't help
+                Err(ReaderError::ProtocolError { .. }) => return Err(ReaderError::AuthFailed),
+                Err(e) if garbled(&e) && retry + 1 < self.retry.attempts => {
+                    self.back_off(retry, &e);
+                    // whatever the card made of it, it starts over once selected again
+                    self.wake_up();
+                    retry += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Read the 16 bytes of a block of the authenticated sector
+    pub fn read_block_request(&mut self, block: BlockAddress) -> Result<Vec<u8>, ReaderError> {
+        self.read_request(block.absolute())
+    }
+
+    // READ of a block number (Classic) or page number (Ultralight), always 16 bytes
+    fn read_request(&mut self, address: u8) -> Result<Vec<u8>, ReaderError> {
+        let frame = self.send_checked(&codec::read_block(address))?;
+        if frame.data.len() != 16 {
+            return Err(ReaderError::InvalidFrame("block data isn't 16 bytes"));
+        }
+        Ok(frame.data)
+    }
+
+    // Write one 4 byte page of the selected Ultralight
+    pub fn write_page_request(&mut self, page: u8, data: &[u8]) -> Result<(), ReaderError> {
+        self.send_checked(&codec::ultralight_write(page, data))?;
+        Ok(())
+    }
+
+    // Read Balance from the value block
+    pub fn read_balance_request(&mut self, block: BlockAddress) -> Result<u32, ReaderError> {
+        let balance = self.send_checked(&codec::read_value(block.absolute()))?;
+        match balance.data.get(..4) {
+            Some(value) => Ok(u32::from_le_bytes([value[0], value[1], value[2], value[3]])),
+            None => Err(ReaderError::InvalidFrame("value missing from response")),
+        }
+    }
+
+    // Init balance on the value block
+    pub fn init_balance_request(&mut self, block: BlockAddress, balance: u32) -> Result<(), ReaderError> {
+        self.send_checked(&codec::value_operation(codec::INIT_VALUE, block.absolute(), balance))?;
+        Ok(())
+    }
+
+    // Increase balance on the value block, then transfer the result to it like a decrease
+    pub fn increase_balance_request(&mut self, block: BlockAddress, value: u32) -> Result<(), ReaderError> {
+        self.send_checked(&codec::value_operation(codec::INCREMENT, block.absolute(), value))?;
+        self.transfer_request(block)
+    }
+
+    // Decrease balance on the value block, then transfer the result to it: cards that only
+    // keep a decrement in their transfer buffer commit it, for the others it's a no-op
+    pub fn decrease_balance_request(&mut self, block: BlockAddress, value: u32) -> Result<(), ReaderError> {
+        self.send_checked(&codec::value_operation(codec::DECREMENT, block.absolute(), value))?;
+        self.transfer_request(block)
+    }
+
+    // Load the value of `block` into the card's transfer buffer
+    pub fn restore_request(&mut self, block: BlockAddress) -> Result<(), ReaderError> {
+        self.send_checked(&codec::restore(block.absolute()))?;
+        Ok(())
+    }
+
+    // Write the transfer buffer to `block`, of the sector the last decrement / increment /
+    // restore was on
+    pub fn transfer_request(&mut self, block: BlockAddress) -> Result<(), ReaderError> {
+        self.send_checked(&codec::transfer(block.absolute()))?;
+        Ok(())
+    }
+
+    // Init the sector of `block` with keys, `key` A (the application key or the card's
+    // diversified key) and `access` bits
+    pub fn init_card_request(&mut self, block: BlockAddress, key: &[u8], access: &[u8]) -> Result<(), ReaderError> {
+        let mut trailer: Vec<u8> = Vec::new();
+        trailer.extend_from_slice(key);
+        trailer.extend_from_slice(access);
+        trailer.extend_from_slice(&self.keys.default_key);
+        self.send_checked(&codec::write_block(block.trailer(), &trailer))?;
+        Ok(())
+    }
+
+    //########Functinalities##############################################################################################
+
+    // Request + anticollision / select on every cascade level until the UID is complete.
+    // The card is selected afterwards, the SAK is the one of the last level. With a target
+    // card, NO_CARD unless that one is in the field.
+    pub fn activate(&mut self) -> Result<CardInfo, ReaderError> {
+        let Some(target) = self.target.clone() else {
+            return self.activate_with(self.request_mode);
+        };
+        // wake them all, then halt the ones winning the anticollision before the target does
+        let mut mode = codec::REQUEST_ALL;
+        for _ in 0..MAX_CARDS {
+            let card = self.activate_with(mode)?;
+            if card.uid == target {
+                return Ok(card);
+            }
+            self.halt()?;
+            mode = codec::REQUEST_IDLE;
+        }
+        self.last_uid = None;
+        Err(ReaderError::NoCard)
+    }
+
+    // Every card in the field (up to MAX_CARDS), each one found is halted so the next request
+    // finds another
+    pub fn list_cards(&mut self) -> Result<Vec<CardInfo>, ReaderError> {
+        let mut cards: Vec<CardInfo> = Vec::new();
+        let mut mode = codec::REQUEST_ALL;
+        while cards.len() < MAX_CARDS {
+            let card = match self.activate_with(mode) {
+                Ok(card) => card,
+                Err(ReaderError::NoCard) => break,
+                Err(e) => return Err(e),
+            };
+            // a card that didn't halt would be found forever
+            if cards.iter().any(|found| found.uid == card.uid) {
+                break;
+            }
+            self.halt()?;
+            cards.push(card);
+            mode = codec::REQUEST_IDLE;
+        }
+        self.last_uid = None;
+        if !cards.is_empty() {
+            self.signal_success();
+        }
+        Ok(cards)
+    }
+
+    fn activate_with(&mut self, mode: u8) -> Result<CardInfo, ReaderError> {
+        let mut retry = 0;
+        loop {
+            // a card that answered the request and then not the anticollision / select moved
+            let mut answered = false;
+            match self.activate_once(mode, &mut answered) {
+                Err(e) if (garbled(&e) || answered && e == ReaderError::NoCard) && retry + 1 < self.retry.attempts => {
+                    self.back_off(retry, &e);
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn activate_once(&mut self, mode: u8, answered: &mut bool) -> Result<CardInfo, ReaderError> {
+        let atqa = self.request_card(mode)?;
+        *answered = true;
+        let mut uid = Vec::new();
+        for level in codec::CASCADE_LEVELS {
+            let part = self.anticollision_level(level)?;
+            let sak = self.select_level(level, &part)?;
+            match part[0] {
+                codec::CASCADE_TAG => uid.extend_from_slice(&part[1..]),
+                _ => uid.extend_from_slice(&part),
+            }
+            // SAK 0x04: UID not complete, go on with the next level
+            if sak & 0x04 == 0 {
+                self.last_uid = Some(uid.clone());
+                return Ok(CardInfo { atqa, sak, uid });
+            }
+        }
+        Err(ReaderError::InvalidFrame("UID longer than 10 bytes"))
+    }
+
+    // Select the Ultralight / NTAG in the field, returns its 7 byte UID
+    fn ultralight_session(&mut self) -> Result<Vec<u8>, ReaderError> {
+        let card = self.activate()?;
+        if card.card_type() != CardType::Ultralight {
+            return Err(ReaderError::UnsupportedCard);
+        }
+        Ok(card.uid)
+    }
+
+    // Select the card and activate ISO 14443-4 (T=CL) on it, returns the card and its ATS
+    pub fn activate_iso_dep(&mut self) -> Result<(CardInfo, Vec<u8>), ReaderError> {
+        let card = self.activate()?;
+        // SAK bit 6: the card speaks ISO 14443-4
+        if card.sak & 0x20 == 0 {
+            return Err(ReaderError::UnsupportedCard);
+        }
+        let ats = self.send_checked(&codec::rats())?.data;
+        Ok((card, ats))
+    }
+
+    // One APDU to the card activate_iso_dep() activated. A status word other than 9000 is
+    // the card's answer, not an error here.
+    pub fn transmit_apdu(&mut self, apdu: &[u8]) -> Result<ApduResponse, ReaderError> {
+        let mut data = self.send_checked(&codec::apdu(apdu))?.data;
+        if data.len() < 2 {
+            return Err(ReaderError::InvalidFrame("response APDU without a status word"));
+        }
+        let sw = data.split_off(data.len() - 2);
+        Ok(ApduResponse { data, sw: [sw[0], sw[1]] })
+    }
+
+    // Activate the card and send it `apdus` in order, returns its ATS and the responses
+    pub fn apdu_session(&mut self, apdus: &[Vec<u8>]) -> Result<(Vec<u8>, Vec<ApduResponse>), ReaderError> {
+        let (_, ats) = self.activate_iso_dep()?;
+        let responses = apdus.iter().map(|apdu| self.transmit_apdu(apdu)).collect::<Result<_, _>>()?;
+        self.signal_success();
+        Ok((ats, responses))
+    }
+
+    // Select the card again after it halted (failed authentication or write)
+    fn wake_up(&mut self) {
+        let _ = self.activate();
+    }
+
+    // Activate and authenticate the sector of `block` with `key`, the card's application key
+    // when None
+    fn open_session(&mut self, block: BlockAddress, key: Option<&[u8]>) -> Result<Vec<u8>, ReaderError> {
+        let card = self.activate()?;
+        match key {
+            Some(key) => self.authenticate(block, key)?,
+            None => self.authenticate_app(block, &card.uid)?,
+        }
+        Ok(card.uid)
+    }
+
+    // Session on the value block of a card the card check allows
+    fn value_session(&mut self, block: BlockAddress) -> Result<(), ReaderError> {
+        let uid = self.open_session(block, None)?;
+        match &self.card_check {
+            Some(check) => check(&uid),
+            None => Ok(()),
+        }
+    }
+
+    // Read the balance back after a value operation
+    fn read_back(&mut self, block: BlockAddress) -> Result<String, ReaderError> {
+        match self.read_balance(block) {
+            // read_balance already played the success pattern
+            Ok(data) => Ok(data),
+            Err(_) => Err(ReaderError::ReadBackFailed),
+        }
+    }
+
+    // Copy the value of `from` to `to` of the same sector with the card's restore and
+    // transfer, `to` ends up a value block with the same balance
+    pub fn transfer_value(&mut self, from: BlockAddress, to: BlockAddress) -> Result<u32, ReaderError> {
+        if from.sector != to.sector {
+            return Err(ReaderError::InvalidInput("a value is only transferred within its sector".to_string()));
+        }
+        self.value_session(from)?;
+        self.restore_request(from)?;
+        self.transfer_request(to)?;
+        let balance = self.read_balance_request(to).map_err(|_| ReaderError::ReadBackFailed)?;
+        self.signal_success();
+        Ok(balance)
+    }
+
+    // Read id, the whole 4, 7 or 10 byte UID
+    pub fn read_id(&mut self) -> Result<String, ReaderError> {
+        Ok(codec::to_hex(&self.read_card()?.uid))
+    }
+
+    // Select and halt the card in the field, returns its UID
+    pub fn halt_card(&mut self) -> Result<String, ReaderError> {
+        let card = self.activate()?;
+        self.halt()?;
+        self.signal_success();
+        Ok(codec::to_hex(&card.uid))
+    }
+
+    // UID of the card in the field, None without one. Quiet (no beeps) for background polling.
+    pub fn poll_card(&mut self) -> Result<Option<Vec<u8>>, ReaderError> {
+        let result = self.activate();
+        self.last_uid = None;
+        match result {
+            Ok(card) => Ok(Some(card.uid)),
+            Err(ReaderError::NoCard) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // UID, ATQA and SAK of the card in the field
+    pub fn read_card(&mut self) -> Result<CardInfo, ReaderError> {
+        let card = self.activate()?;
+        self.signal_success();
+        Ok(card)
+    }
+
+    // Read the 16 bytes of any block, authenticating its sector with `key` (the card's
+    // application key when None)
+    pub fn read_block(&mut self, block: BlockAddress, key: Option<&[u8]>) -> Result<Vec<u8>, ReaderError> {
+        self.open_session(block, key)?;
+        let data = self.read_block_request(block)?;
+        self.signal_success();
+        Ok(data)
+    }
+
+    // Every block of `sector` (trailer included) after one authentication with `key` (the
+    // card's application key when None)
+    pub fn read_sector_blocks(&mut self, sector: u8, key: Option<&[u8]>) -> Result<Vec<Vec<u8>>, ReaderError> {
+        self.open_session(BlockAddress::new(sector, 0)?, key)?;
+        let blocks = (0..BlockAddress::blocks_in_sector(sector))
+            .map(|block| self.read_block_request(BlockAddress::new(sector, block)?))
+            .collect::<Result<_, _>>()?;
+        self.signal_success();
+        Ok(blocks)
+    }
+
+    // Write data blocks of one sector in a single session, reading each back. When a block
+    // fails, the ones before it get their previous bytes back so a layout isn't left half
+    // written, unless the card left the field.
+    pub fn write_sector_blocks(&mut self, key: Option<&[u8]>, blocks: &[(BlockAddress, Vec<u8>)]) -> Result<(), ReaderError> {
+        let Some(&(first, _)) = blocks.first() else {
+            return Err(ReaderError::InvalidInput("no blocks to write".to_string()));
+        };
+        self.open_session(first, key)?;
+        let previous = blocks
+            .iter()
+            .map(|(block, _)| self.read_block_request(*block))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (written, (block, data)) in blocks.iter().enumerate() {
+            let Err(e) = self.write_verified(*block, data) else {
+                continue;
+            };
+            // the card drops the authentication on a refused write
+            let restored = self.open_session(first, key).is_ok()
+                && blocks[..=written]
+                    .iter()
+                    .zip(&previous)
+                    .all(|((block, _), data)| self.write_verified(*block, data).is_ok());
+            tracing::warn!(sector = first.sector, block = block.block, error = %e, restored, "sector write failed");
+            return Err(e);
+        }
+        self.signal_success();
+        Ok(())
+    }
+
+    fn write_verified(&mut self, block: BlockAddress, data: &[u8]) -> Result<(), ReaderError> {
+        self.send_checked(&codec::write_block(block.absolute(), data))?;
+        match self.read_block_request(block)? == data {
+            true => Ok(()),
+            false => Err(ReaderError::ReadBackFailed),
+        }
+    }
+
+    // One 4 byte page of an Ultralight / NTAG
+    pub fn read_page(&mut self, page: u8) -> Result<Vec<u8>, ReaderError> {
+        self.ultralight_session()?;
+        // READ answers with 4 pages starting at `page`
+        let mut data = self.read_request(page)?;
+        data.truncate(4);
+        self.signal_success();
+        Ok(data)
+    }
+
+    pub fn write_page(&mut self, page: u8, data: &[u8]) -> Result<(), ReaderError> {
+        if data.len() != 4 {
+            return Err(ReaderError::InvalidInput("page data must be 4 bytes".to_string()));
+        }
+        self.ultralight_session()?;
+        self.write_page_request(page, data)?;
+        self.signal_success();
+        Ok(())
+    }
+
+    // Data blocks of `sector`, trailer excluded
+    fn read_sector(&mut self, sector: u8, key: &[u8]) -> Result<Vec<u8>, ReaderError> {
+        self.authenticate(BlockAddress::new(sector, 0)?, key)?;
+        let mut data = Vec::new();
+        for block in 0..BlockAddress::blocks_in_sector(sector) - 1 {
+            data.extend(self.read_block_request(BlockAddress::new(sector, block)?)?);
+        }
+        Ok(data)
+    }
+
+    // NDEF records of an NFC Forum formatted MIFARE Classic 1K (MAD1 in sector 0)
+    pub fn read_ndef(&mut self) -> Result<Vec<ndef::Record>, ReaderError> {
+        self.activate()?;
+        let mad = match self.read_sector(0, ndef::MAD_KEY) {
+            Ok(sector) => sector[16..].to_vec(),
+            Err(ReaderError::AuthFailed) => return Err(ReaderError::NotNdef),
+            Err(e) => return Err(e),
+        };
+        let mut area = Vec::new();
+        for sector in ndef::ndef_sectors(&mad)? {
+            area.extend(self.read_sector(sector, ndef::NDEF_KEY)?);
+            // no need to read further once the TLVs are complete
+            if ndef::find_message(&area).is_ok() {
+                break;
+            }
+        }
+        let records = match ndef::find_message(&area)? {
+            Some(message) => ndef::Record::parse_message(message)?,
+            None => Vec::new(),
+        };
+        self.signal_success();
+        Ok(records)
+    }
+
+    // Write the data blocks of `sector` (zero padded), and its trailer when given
+    fn write_sector(&mut self, sector: u8, key: &[u8], data: &[u8], trailer: Option<&[u8]>) -> Result<(), ReaderError> {
+        self.authenticate(BlockAddress::new(sector, 0)?, key)?;
+        for block in 0..BlockAddress::blocks_in_sector(sector) - 1 {
+            let mut bytes = [0u8; 16];
+            let start = block as usize * 16;
+            if let Some(chunk) = data.get(start..data.len().min(start + 16)) {
+                bytes[..chunk.len()].copy_from_slice(chunk);
+            }
+            self.send_checked(&codec::write_block(BlockAddress::new(sector, block)?.absolute(), &bytes))?;
+        }
+        if let Some(trailer) = trailer {
+            let block = BlockAddress::new(sector, 0)?;
+            self.send_checked(&codec::write_block(block.trailer(), trailer))?;
+        }
+        Ok(())
+    }
+
+    // Write an NDEF message. Formatted cards keep their MAD, factory cards get a MAD
+    // and NDEF trailers on sectors 1.. as far as the message needs.
+    pub fn write_ndef(&mut self, records: &[ndef::Record]) -> Result<(), ReaderError> {
+        let area = ndef::tlv_area(&ndef::Record::encode_message(records))?;
+        let too_long = || ReaderError::InvalidInput("NDEF message doesn't fit on the card".to_string());
+        self.activate()?;
+        let formatted = match self.read_sector(0, ndef::MAD_KEY) {
+            Ok(sector) => ndef::ndef_sectors(&sector[16..]).ok(),
+            Err(ReaderError::AuthFailed) => {
+                self.wake_up();
+                None
+            }
+            Err(e) => return Err(e),
+        };
+        match formatted {
+            Some(sectors) => {
+                if area.len() > sectors.len() * ndef::SECTOR_DATA {
+                    return Err(too_long());
+                }
+                for (sector, data) in sectors.iter().zip(area.chunks(ndef::SECTOR_DATA)) {
+                    self.write_sector(*sector, ndef::NDEF_KEY, data, None)?;
+                }
+            }
+            None => {
+                let sectors: Vec<u8> = (1..).take(area.len().div_ceil(ndef::SECTOR_DATA)).collect();
+                if sectors.len() > 15 {
+                    return Err(too_long());
+                }
+                // block 0 is read-only, the MAD is block 1 and 2
+                let mad = ndef::mad(&sectors);
+                let factory = self.keys.default_key;
+                self.authenticate(BlockAddress::new(0, 0)?, &factory)?;
+                self.send_checked(&codec::write_block(1, &mad[..16]))?;
+                self.send_checked(&codec::write_block(2, &mad[16..]))?;
+                self.send_checked(&codec::write_block(3, &ndef::MAD_TRAILER))?;
+                for (sector, data) in sectors.iter().zip(area.chunks(ndef::SECTOR_DATA)) {
+                    self.write_sector(*sector, &factory, data, Some(&ndef::NDEF_TRAILER))?;
+                }
+            }
+        }
+        self.signal_success();
+        Ok(())
+    }
+
+    // Write the 16 bytes of any block, authenticating its sector with `key` (the card's
+    // application key when None)
+    pub fn write_block(&mut self, block: BlockAddress, key: Option<&[u8]>, data: &[u8]) -> Result<(), ReaderError> {
+        if data.len() != 16 {
+            return Err(ReaderError::InvalidInput("block data must be 16 bytes".to_string()));
+        }
+        self.open_session(block, key)?;
+        self.send_checked(&codec::write_block(block.absolute(), data))?;
+        self.signal_success();
+        Ok(())
+    }
+
+    // Write many blocks in one card session, authenticating each sector once.
+    // Returns one result per block, in order.
+    pub fn write_blocks(
+        &mut self,
+        key: Option<&[u8]>,
+        blocks: &[(BlockAddress, Vec<u8>)],
+    ) -> Result<Vec<Result<(), ReaderError>>, ReaderError> {
+        let card = self.activate()?;
+        // sector authenticated last and how that went
+        let mut session: Option<(u8, Result<(), ReaderError>)> = None;
+        let mut results = Vec::new();
+        for (block, data) in blocks {
+            if session.as_ref().map(|(sector, _)| *sector) != Some(block.sector) {
+                let result = match key {
+                    Some(key) => self.authenticate(*block, key),
+                    None => self.authenticate_app(*block, &card.uid),
+                };
+                if result.is_err() {
+                    self.wake_up();
+                }
+                session = Some((block.sector, result));
+            }
+            let result = match &session {
+                Some((_, Err(e))) => Err(e.clone()),
+                _ if data.len() != 16 => Err(ReaderError::InvalidInput("block data must be 16 bytes".to_string())),
+                _ => self.send_checked(&codec::write_block(block.absolute(), data)).map(|_| ()),
+            };
+            if let Err(ReaderError::ProtocolError { .. }) = result {
+                // the card drops the authentication on a refused write
+                self.wake_up();
+                session = None;
+            }
+            results.push(result);
+        }
+        self.signal_success();
+        Ok(results)
+    }
+
+    // Cardholder record of blocks 0-2 of `sector`, keyed with the application key like the value sector
+    pub fn read_cardholder(&mut self, sector: u8) -> Result<Cardholder, ReaderError> {
+        self.open_session(BlockAddress::data(sector, 0)?, None)?;
+        let cardholder = Cardholder::decode(&self.read_record(sector)?)?;
+        self.signal_success();
+        Ok(cardholder)
+    }
+
+    pub fn write_cardholder(&mut self, sector: u8, cardholder: &Cardholder) -> Result<(), ReaderError> {
+        let record = cardholder.encode()?;
+        self.open_session(BlockAddress::data(sector, 0)?, None)?;
+        for (block, data) in record.chunks(16).enumerate() {
+            self.send_checked(&codec::write_block(BlockAddress::data(sector, block as u8)?.absolute(), data))?;
+        }
+        if self.read_record(sector)? != record {
+            return Err(ReaderError::ReadBackFailed);
+        }
+        self.signal_success();
+        Ok(())
+    }
+
+    fn read_record(&mut self, sector: u8) -> Result<Vec<u8>, ReaderError> {
+        let mut record = Vec::new();
+        for block in 0..(RECORD_LENGTH / 16) as u8 {
+            record.extend(self.read_block_request(BlockAddress::data(sector, block)?)?);
+        }
+        Ok(record)
+    }
+
+    // Read Balance
+    pub fn read_balance(&mut self, block: BlockAddress) -> Result<String, ReaderError> {
+        self.value_session(block)?;
+        let (balance, _) = self.read_signed(block)?;
+        self.signal_success();
+        Ok(balance.to_string())
+    }
+
+    // Init Balance
+    pub fn init_balance(&mut self, block: BlockAddress, value: u32) -> Result<String, ReaderError> {
+        self.check_balance(u64::from(value))?;
+        self.value_session(block)?;
+        let counter = self.mac_counter(block)?;
+        self.write_value(block, value, counter.wrapping_add(1))?;
+        self.read_back(block)
+    }
+
+    pub fn increase(&mut self, block: BlockAddress, value: u32) -> Result<String, ReaderError> {
+        self.change_balance(block, value, true, &mut None).map(|after| after.to_string())
+    }
+
+    pub fn decrease(&mut self, block: BlockAddress, value: u32) -> Result<String, ReaderError> {
+        self.change_balance(block, value, false, &mut None).map(|after| after.to_string())
+    }
+
+    // Increase (or decrease) the balance by `value`, returns the new one. `before` gets the
+    // balance read before the change, also when the change itself fails.
+    pub fn change_balance(
+        &mut self,
+        block: BlockAddress,
+        value: u32,
+        increase: bool,
+        before: &mut Option<u32>,
+    ) -> Result<u32, ReaderError> {
+        let (counter, after) = self.prepare_change(block, value, increase, before)?;
+        let counter = counter.wrapping_add(1);
+        match (increase, self.backup_of(block)?) {
+            (true, None) => {
+                self.increase_balance_request(block, value)?;
+                self.sign_value(block, after, counter)?;
+            }
+            (false, None) => {
+                self.decrease_balance_request(block, value)?;
+                self.sign_value(block, after, counter)?;
+            }
+            // the new balance is written to both blocks instead of incremented in place
+            (_, Some(_)) => self.write_value(block, after, counter)?,
+        }
+        self.read_back(block)?.parse().map_err(|_| ReaderError::ReadBackFailed)
+    }
+
+    // Everything change_balance checks before its write: the card, its key and signed balance,
+    // the funds and the balance limit. Returns (MAC counter, balance after the change).
+    fn prepare_change(
+        &mut self,
+        block: BlockAddress,
+        value: u32,
+        increase: bool,
+        before: &mut Option<u32>,
+    ) -> Result<(u32, u32), ReaderError> {
+        self.value_session(block)?;
+        let (balance, counter) = self.read_signed(block)?;
+        *before = Some(balance);
+        // refused here rather than relying on the card (or a reader) to catch the underflow
+        if !increase && value > balance {
+            return Err(ReaderError::InsufficientFunds { balance, amount: value });
+        }
+        if increase {
+            self.check_balance(u64::from(balance) + u64::from(value))?;
+        }
+        let after = match increase {
+            true => balance + value,
+            false => balance - value,
+        };
+        Ok((counter, after))
+    }
+
+    // Dry run of change_balance: the balance it would leave, or the error it would fail with,
+    // without writing to the card
+    pub fn plan_change(
+        &mut self,
+        block: BlockAddress,
+        value: u32,
+        increase: bool,
+        before: &mut Option<u32>,
+    ) -> Result<u32, ReaderError> {
+        self.prepare_change(block, value, increase, before).map(|(_, after)| after)
+    }
+
+    // Dry run of init_balance, `before` gets the balance it would replace if the block holds one
+    pub fn plan_init_balance(&mut self, block: BlockAddress, value: u32, before: &mut Option<u32>) -> Result<u32, ReaderError> {
+        self.check_balance(u64::from(value))?;
+        self.value_session(block)?;
+        self.mac_counter(block)?;
+        *before = self.read_value(block).ok();
+        Ok(value)
+    }
+
+    // Write a new trailer (key A | access bits | key B) to the sector of `block`, checked by
+    // authenticating with the new key A. `current` and `key_a` are the card's application key
+    // when None, `rest` the access bits and key B.
+    pub fn change_keys(
+        &mut self,
+        block: BlockAddress,
+        current: Option<&[u8]>,
+        key_a: Option<&[u8]>,
+        rest: &[u8],
+    ) -> Result<(), ReaderError> {
+        if rest.len() != 10 {
+            return Err(ReaderError::InvalidInput("access bits and key B are 10 bytes".to_string()));
+        }
+        AccessBits::decode(&rest[..4])?;
+        let uid = self.open_session(block, current)?;
+        let mut trailer = key_a.map_or_else(|| self.app_key(&uid), <[u8]>::to_vec);
+        trailer.extend_from_slice(rest);
+        self.send_checked(&codec::write_block(block.trailer(), &trailer))?;
+        // a new session, the card still has the old key's one
+        match self.open_session(block, Some(&trailer[..6])) {
+            Ok(_) => (),
+            Err(ReaderError::AuthFailed) => return Err(ReaderError::KeyNotVerified),
+            Err(e) => return Err(e),
+        }
+        self.signal_success();
+        Ok(())
+    }
+
+    // Undo init_card: zero the data blocks of the sector holding `block` (value, backup and
+    // MAC blocks) and put the factory keys and transport access bits back
+    pub fn deinit_card(&mut self, block: BlockAddress) -> Result<String, ReaderError> {
+        self.open_session(block, None)?;
+        for data in 0..BlockAddress::blocks_in_sector(block.sector) - 1 {
+            let data = BlockAddress::new(block.sector, data)?;
+            if block.sector != 0 || data.block != 0 {
+                self.send_checked(&codec::write_block(data.absolute(), &[0; 16]))?;
+            }
+        }
+        // KEYACCESS are the transport access bits, key A may do everything
+        let factory = self.keys.default_key;
+        let mut rest = KEYACCESS.to_vec();
+        rest.extend_from_slice(&factory);
+        self.change_keys(block, None, Some(&factory), &rest)?;
+        Ok("Card reset to the factory keys".to_string())
+    }
+
+    // Init the sector holding `block`, with the keystore's access bits when `access` is None
+    pub fn init_card(&mut self, block: BlockAddress, access: Option<&[u8]>) -> Result<String, ReaderError> {
+        let access = match access {
+            Some(access) => AccessBits::decode(access)?.encode(),
+            None => self.keys.access_bits,
+        };
+        let factory = self.keys.default_key;
+        let uid = self.open_session(block, Some(&factory))?;
+        let key = self.app_key(&uid);
+        self.init_card_request(block, &key, &access)?;
+        self.signal_success();
+        Ok("Card configured successfully".to_string())
+    }
+}
@@ -0,0 +1,141 @@
+// Several readers behind one API, each with its own worker, picked per request
+// with `?reader=<name>` or a `/readers/<name>/...` path
+use crate::audit::Pending;
+use crate::auth::{self, Refusal};
+use crate::logging;
+use crate::worker::Worker;
+use er302::codec::UidFormat;
+use er302::{codec, ReaderError};
+use rocket::fairing::AdHoc;
+use rocket::http::uri::Origin;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::json::Value;
+use rocket::tokio::sync::Mutex;
+use tracing::Span;
+
+// The reader of the `[serial]` section, used when a request names none
+pub const DEFAULT_READER: &str = "default";
+
+pub struct Slot {
+    pub worker: Worker,
+    // Outcome of the startup probe, reader info or why it failed
+    pub probe: Mutex<Option<Result<Value, ReaderError>>>,
+}
+
+// In configuration order, the default reader first
+pub struct Readers(Vec<(String, Slot)>);
+
+impl Readers {
+    pub fn new(workers: Vec<(String, Worker)>) -> Self {
+        let slots = workers
+            .into_iter()
+            .map(|(name, worker)| {
+                let probe = Mutex::new(None);
+                (name, Slot { worker, probe })
+            })
+            .collect();
+        Readers(slots)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Slot> {
+        self.0.iter().find(|(slot, _)| slot == name).map(|(_, slot)| slot)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Slot)> {
+        self.0.iter().map(|(name, slot)| (name.as_str(), slot))
+    }
+}
+
+// Reader named by the request (or why there is none), the request's log span and where
+// its operation is recorded for the audit log. `?debug=true` (admin only) asks for the serial
+// frames of the command in the reply, `?card=<UID>` picks one of several cards in the field
+// and `?format=` is how UIDs are shown (card.uid_format by default).
+pub struct SelectedReader<'r> {
+    pub name: &'r str,
+    pub slot: Result<&'r Slot, ReaderError>,
+    pub span: Span,
+    pub pending: &'r Pending,
+    pub debug: bool,
+    pub card: Option<Vec<u8>>,
+    pub format: UidFormat,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SelectedReader<'r> {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let readers = request.rocket().state::<Readers>().expect("readers are managed");
+        let name = match request.query_value::<&str>("reader") {
+            Some(Ok(name)) => name,
+            _ => DEFAULT_READER,
+        };
+        let card = match request.query_value::<&str>("card") {
+            Some(Ok(uid)) => Some(codec::from_hex(uid)),
+            _ => None,
+        };
+        let slot = readers
+            .get(name)
+            .ok_or_else(|| ReaderError::InvalidInput(format!("unknown reader: {}", name)));
+        let default = *request.rocket().state::<UidFormat>().expect("UID format is managed");
+        let format = match request.query_value::<&str>("format") {
+            Some(Ok(name)) => name.parse(),
+            _ => Ok(default),
+        };
+        // an invalid UID or format fails the command like an unknown reader
+        let (slot, card, format) = match (card.transpose(), format) {
+            (Ok(card), Ok(format)) => (slot, card, format),
+            (Err(e), _) | (_, Err(e)) => (Err(e), None, default),
+        };
+        let debug = matches!(request.query_value::<bool>("debug"), Some(Ok(true)));
+        // the frames show keys, the Caller guard before this one has authenticated the caller
+        if debug && !auth::is_admin(request) {
+            request.local_cache(|| Refusal("debug=true needs the admin role"));
+            return Outcome::Error((Status::Forbidden, "debug not allowed"));
+        }
+        let span = logging::request_span(request).span.clone();
+        let pending = request.local_cache(Pending::default);
+        Outcome::Success(SelectedReader { name, slot, span, pending, debug, card, format })
+    }
+}
+
+// `[/v1]/readers/<name>/<route>?query` is served as `[/v1]/<route>?query&reader=<name>`, a
+// `reader` of the query is dropped so the path's wins
+pub fn reader_paths() -> AdHoc {
+    AdHoc::on_request("reader paths", |request, _| {
+        Box::pin(async move {
+            let uri = request.uri();
+            let path = uri.path().as_str();
+            let (version, path) = split_version(path);
+            let Some((name, rest)) = path.strip_prefix("/readers/").and_then(|path| path.split_once('/')) else {
+                return;
+            };
+            let query: String = uri
+                .query()
+                .map(|query| query.as_str())
+                .unwrap_or_default()
+                .split('&')
+                .filter(|field| !field.is_empty() && field.split('=').next() != Some("reader"))
+                .map(|field| format!("{}&", field))
+                .collect();
+            let rewritten = format!("{}/{}?{}reader={}", version, rest, query, name);
+            if let Ok(origin) = Origin::parse_owned(rewritten) {
+                request.set_uri(origin);
+            }
+        })
+    })
+}
+
+// ("/v1", "/rest") for versioned paths, ("", path) for the unversioned aliases
+fn split_version(path: &str) -> (&str, &str) {
+    let digits = path
+        .strip_prefix("/v")
+        .map(|rest| rest.bytes().take_while(u8::is_ascii_digit).count())
+        .unwrap_or(0);
+    match digits {
+        0 => ("", path),
+        digits if path[digits + 2..].starts_with('/') => path.split_at(digits + 2),
+        _ => ("", path),
+    }
+}
@@ -0,0 +1,191 @@
+// Redis publishing of the taps and transactions for Redis-centric backends: with `[redis]`
+// set the card_detected and transaction events are PUBLISHed to `channel` with the JSON of
+// the /events data, and every card_detected is also SET as `last_card_key` for
+// `last_card_ttl` seconds, so a backend can ask for the card just tapped. RESP over plain
+// TCP, the connection is opened at the first event and again after it broke.
+use crate::events::{Event, Events};
+use rocket::serde::json;
+use rocket::serde::Deserialize;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+// Pause before connecting again after a failure
+const RECONNECT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct RedisConfig {
+    // redis://[[username]:password@]host[:port][/database], 6379 by default
+    pub url: String,
+    #[serde(default = "default_channel")]
+    pub channel: String,
+    #[serde(default = "default_last_card_key")]
+    pub last_card_key: String,
+    // seconds
+    #[serde(default = "default_ttl")]
+    pub last_card_ttl: u64,
+    // plaintext to a server on another host, off by default
+    #[serde(default)]
+    pub insecure: bool,
+}
+
+fn default_channel() -> String {
+    "er302:events".to_string()
+}
+
+fn default_last_card_key() -> String {
+    "lastcard".to_string()
+}
+
+fn default_ttl() -> u64 {
+    30
+}
+
+// Where and as whom to connect, from the URL
+#[derive(Debug, PartialEq)]
+pub struct Server {
+    pub address: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub database: Option<u32>,
+}
+
+pub fn server(url: &str) -> Result<Server, String> {
+    if url.starts_with("rediss://") {
+        // no TLS client here, a TLS server goes behind a local tunnel
+        return Err(format!("{}: TLS isn't supported, use redis://", url));
+    }
+    let invalid = || format!("{}: not a redis://host[:port][/database] URL", url);
+    let rest = url.strip_prefix("redis://").ok_or_else(invalid)?;
+    let (authority, database) = match rest.split_once('/') {
+        Some((authority, "")) => (authority, None),
+        Some((authority, database)) => (authority, Some(database.parse().map_err(|_| invalid())?)),
+        None => (rest, None),
+    };
+    let (credentials, host) = match authority.rsplit_once('@') {
+        Some((credentials, host)) => (Some(credentials), host),
+        None => (None, authority),
+    };
+    // `:password` for the default user, `username:password` with ACLs
+    let (username, password) = match credentials.map(|credentials| credentials.split_once(':')) {
+        Some(Some((username, password))) => (Some(username).filter(|username| !username.is_empty()), Some(password)),
+        Some(None) => return Err(invalid()),
+        None => (None, None),
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok(Server {
+        address: match host.contains(':') {
+            true => host.to_string(),
+            false => format!("{}:6379", host),
+        },
+        username: username.map(str::to_string),
+        password: password.map(str::to_string),
+        database,
+    })
+}
+
+pub fn spawn(config: RedisConfig, events: &Events) {
+    let mut receiver = events.subscribe();
+    thread::Builder::new()
+        .name("er302-redis".to_string())
+        .spawn(move || {
+            let mut connection = None;
+            loop {
+                match receiver.blocking_recv() {
+                    Ok(event) if matches!(event.kind, "card_detected" | "transaction") => publish(&config, &mut connection, &event),
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(missed)) => tracing::warn!(missed, "Redis fell behind, events dropped"),
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        })
+        .expect("failed to spawn Redis thread");
+}
+
+// Sends on the open connection, or on a new one when it's closed or broke
+fn publish(config: &RedisConfig, connection: &mut Option<TcpStream>, event: &Event) {
+    let payload = json::to_string(event).unwrap_or_default();
+    let mut commands = vec![command(&["PUBLISH", &config.channel, &payload])];
+    if event.kind == "card_detected" {
+        commands.push(command(&["SET", &config.last_card_key, &payload, "EX", &config.last_card_ttl.to_string()]));
+    }
+    for _ in 0..2 {
+        let mut stream = match connection.take() {
+            Some(stream) => stream,
+            None => match connect(config) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    tracing::error!(error = %e, "can't connect to Redis");
+                    thread::sleep(RECONNECT);
+                    continue;
+                }
+            },
+        };
+        let sent = commands.iter().try_for_each(|command| {
+            // the server refused the command, the connection is fine
+            if let Err(e) = call(&mut stream, command)? {
+                tracing::error!(error = e, kind = event.kind, "Redis refused the event");
+            }
+            Ok::<_, io::Error>(())
+        });
+        if sent.is_ok() {
+            *connection = Some(stream);
+            return;
+        }
+    }
+    tracing::error!(channel = config.channel, kind = event.kind, "Redis event dropped");
+}
+
+fn connect(config: &RedisConfig) -> io::Result<TcpStream> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidInput, e);
+    let server = server(&config.url).map_err(invalid)?;
+    let mut stream = TcpStream::connect(&server.address)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let refused = |e: String| io::Error::new(io::ErrorKind::PermissionDenied, e);
+    if let Some(password) = &server.password {
+        let auth = match &server.username {
+            Some(username) => command(&["AUTH", username, password]),
+            None => command(&["AUTH", password]),
+        };
+        call(&mut stream, &auth)?.map_err(refused)?;
+    }
+    if let Some(database) = server.database {
+        call(&mut stream, &command(&["SELECT", &database.to_string()]))?.map_err(refused)?;
+    }
+    Ok(stream)
+}
+
+// An array of bulk strings
+fn command(arguments: &[&str]) -> Vec<u8> {
+    let mut command = format!("*{}\r\n", arguments.len()).into_bytes();
+    for argument in arguments {
+        command.extend(format!("${}\r\n", argument.len()).into_bytes());
+        command.extend_from_slice(argument.as_bytes());
+        command.extend_from_slice(b"\r\n");
+    }
+    command
+}
+
+// The reply line to a command, `+OK` or `:1`, an `-ERR ...` is the server's error
+fn call(stream: &mut TcpStream, command: &[u8]) -> io::Result<Result<String, String>> {
+    stream.write_all(command)?;
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    while !line.ends_with(b"\r\n") {
+        stream.read_exact(&mut byte)?;
+        line.push(byte[0]);
+    }
+    let line = String::from_utf8_lossy(&line[..line.len() - 2]).into_owned();
+    match line.as_bytes().first() {
+        Some(b'+' | b':') => Ok(Ok(line[1..].to_string())),
+        Some(b'-') => Ok(Err(line[1..].to_string())),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unexpected reply {:?}", line))),
+    }
+}
@@ -0,0 +1,578 @@
+// In-memory ER302 with a virtual MIFARE Classic 1K, Ultralight or DESFire card, speaks the same frames as the real reader
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const STATUS_OK: u8 = 0x00;
+const STATUS_FAIL: u8 = 0x01;
+
+pub struct Card {
+    pub uid: Vec<u8>,
+    pub atqa: [u8; 2],
+    pub sak: u8,
+    pub blocks: [[u8; 16]; 64],
+    // Ultralight / NTAG memory, empty on Classic cards
+    pub pages: Vec<[u8; 4]>,
+    // HALT received, only a REQUEST_ALL wakes it up
+    pub halted: bool,
+    // blocks whose access bits refuse writes
+    pub read_only: BTreeSet<u8>,
+    // a decrement or increment only reaches the transfer buffer, the block changes with the
+    // TRANSFER after it (the firmware of the simulated reader writes it back itself)
+    pub buffered: bool,
+}
+
+impl Card {
+    // Factory Classic 1K: FF keys and transport access bits in every trailer
+    pub fn new(uid: [u8; 4]) -> Self {
+        let mut blocks = [[0u8; 16]; 64];
+        for trailer in blocks.iter_mut().skip(3).step_by(4) {
+            trailer.copy_from_slice(&[
+                0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x07, 0x80, 0x69, 0xff, 0xff, 0xff, 0xff,
+                0xff, 0xff,
+            ]);
+        }
+        Card {
+            uid: uid.to_vec(),
+            atqa: [0x04, 0x00],
+            sak: 0x08,
+            blocks,
+            pages: Vec::new(),
+            halted: false,
+            read_only: BTreeSet::new(),
+            buffered: false,
+        }
+    }
+
+    // Classic 1K with a double (7) or triple (10) size UID
+    pub fn with_uid(uid: &[u8]) -> Self {
+        let mut card = Card::new([0; 4]);
+        card.uid = uid.to_vec();
+        card
+    }
+
+    // NTAG213 sized Ultralight (45 pages), UID in pages 0-1
+    pub fn ultralight(uid: [u8; 7]) -> Self {
+        let mut pages = vec![[0u8; 4]; 45];
+        pages[0][..3].copy_from_slice(&uid[..3]);
+        pages[1].copy_from_slice(&uid[3..]);
+        Card {
+            uid: uid.to_vec(),
+            atqa: [0x44, 0x00],
+            sak: 0x00,
+            blocks: [[0u8; 16]; 64],
+            pages,
+            halted: false,
+            read_only: BTreeSet::new(),
+            buffered: false,
+        }
+    }
+
+    // DESFire EV1: ISO 14443-4, no MIFARE Classic memory
+    pub fn desfire(uid: [u8; 7]) -> Self {
+        Card {
+            uid: uid.to_vec(),
+            atqa: [0x44, 0x03],
+            sak: 0x20,
+            blocks: [[0u8; 16]; 64],
+            pages: Vec::new(),
+            halted: false,
+            read_only: BTreeSet::new(),
+            buffered: false,
+        }
+    }
+
+    pub fn is_ultralight(&self) -> bool {
+        !self.pages.is_empty()
+    }
+
+    pub fn key_a(&self, block: u8) -> &[u8] {
+        &self.blocks[(block | 0x03) as usize][..6]
+    }
+
+    pub fn set_key_a(&mut self, block: u8, key: &[u8]) {
+        self.blocks[(block | 0x03) as usize][..6].copy_from_slice(key);
+    }
+
+    // Value block layout: value, !value, value, addr, !addr, addr, !addr
+    pub fn set_value(&mut self, block: u8, value: u32) {
+        let data = &mut self.blocks[block as usize];
+        data[0..4].copy_from_slice(&value.to_le_bytes());
+        data[4..8].copy_from_slice(&(!value).to_le_bytes());
+        data[8..12].copy_from_slice(&value.to_le_bytes());
+        data[12..16].copy_from_slice(&[block, !block, block, !block]);
+    }
+
+    pub fn value(&self, block: u8) -> Option<u32> {
+        let data = &self.blocks[block as usize];
+        let value = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        let inverted = u32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+        let copy = u32::from_le_bytes([data[8], data[9], data[10], data[11]]);
+        (value == !inverted && value == copy).then_some(value)
+    }
+}
+
+#[derive(Default)]
+pub struct State {
+    // the card that answered the last request
+    pub card: Option<Card>,
+    // more cards stacked in the field, one of them takes `card`'s place on a request
+    pub stacked: Vec<Card>,
+    authenticated: Option<u8>,
+    // transfer buffer: the value of the last decrement, increment or restore
+    transfer: Option<u32>,
+    // the whole UID went through select
+    selected: bool,
+    // RATS answered, the card takes APDUs
+    iso_dep: bool,
+    // time of every beep so far
+    pub beeps: Vec<u8>,
+    pub rf_off: bool,
+    // USB cable pulled: I/O on open ports fails
+    pub unplugged: bool,
+    // every command code a well-formed frame carried
+    pub commands: BTreeSet<u16>,
+    // a card at the edge of the field: this many of the next anticollisions go unanswered
+    pub glitches: u32,
+    // command codes whose next answer comes back with a broken checksum, once per entry
+    pub garbled: Vec<u16>,
+    // a torn write: the card leaves the field right after the next write to this block, into
+    // `pulled`
+    pub tear: Option<u8>,
+    pub pulled: Option<Card>,
+    // how long the reader takes to answer each frame
+    pub latency: Duration,
+}
+
+// Shared between every port the transport opens, so the card outlives a request
+#[derive(Clone, Default)]
+pub struct Simulator {
+    pub state: Arc<Mutex<State>>,
+    // Max bytes returned by one read, to emulate fragmented frames
+    chunk: Option<usize>,
+}
+
+impl Simulator {
+    pub fn with_card(card: Card) -> Self {
+        let simulator = Simulator::default();
+        simulator.state.lock().unwrap().card = Some(card);
+        simulator
+    }
+
+    pub fn fragmented(mut self, chunk: usize) -> Self {
+        self.chunk = Some(chunk);
+        self
+    }
+
+    pub fn port(&self) -> Box<dyn SerialPort> {
+        Box::new(SimulatedPort {
+            state: self.state.clone(),
+            pending: VecDeque::new(),
+            timeout: Duration::from_secs(2),
+            chunk: self.chunk,
+        })
+    }
+}
+
+impl State {
+    fn handle(&mut self, command: u16, data: &[u8]) -> (u8, Vec<u8>) {
+        // no field, no card
+        if self.rf_off && command >= 0x0200 {
+            return (STATUS_FAIL, vec![]);
+        }
+        match command {
+            // Version and serial number
+            0x0104 => (STATUS_OK, b"ER302 V2.1\0".to_vec()),
+            0x0105 => (STATUS_OK, vec![0x30, 0x02, 0x00, 0x42]),
+            // Antenna, a card that lost power forgets halt and authentication
+            0x010C => match data {
+                [on] => {
+                    self.rf_off = *on == 0;
+                    self.authenticated = None;
+                    self.selected = false;
+                    self.iso_dep = false;
+                    if let Some(card) = &mut self.card {
+                        card.halted = false;
+                    }
+                    (STATUS_OK, vec![])
+                }
+                _ => (STATUS_FAIL, vec![]),
+            },
+            // Beep
+            0x0106 => {
+                self.beeps.extend_from_slice(data);
+                (STATUS_OK, vec![])
+            }
+            // Request: 0x52 all cards, 0x26 idle cards only
+            0x0201 => {
+                self.request(data == [0x52]);
+                match &mut self.card {
+                    Some(card) if data == [0x52] || !card.halted => {
+                        card.halted = false;
+                        let atqa = card.atqa.to_vec();
+                        self.authenticated = None;
+                        self.selected = false;
+                        self.iso_dep = false;
+                        (STATUS_OK, atqa)
+                    }
+                    _ => (STATUS_FAIL, vec![]),
+                }
+            }
+            // Halt, only the selected card listens
+            0x0204 => match &mut self.card {
+                Some(card) if self.selected => {
+                    card.halted = true;
+                    self.authenticated = None;
+                    self.selected = false;
+                    self.iso_dep = false;
+                    (STATUS_OK, vec![])
+                }
+                _ => (STATUS_FAIL, vec![]),
+            },
+            // Anticollision, no data for cascade level 1, the SEL code for level 2 and 3
+            0x0202 if self.glitches > 0 => {
+                self.glitches -= 1;
+                (STATUS_FAIL, vec![])
+            }
+            0x0202 => match (&self.card, cascade_level(data)) {
+                (Some(card), Some(level)) => match cascade_part(&card.uid, level) {
+                    Some(part) => (STATUS_OK, part),
+                    None => (STATUS_FAIL, vec![]),
+                },
+                _ => (STATUS_FAIL, vec![]),
+            },
+            // Select, SAK 0x04 means the UID isn't complete
+            0x0203 => {
+                let (level, part) = match data.len() {
+                    4 => (Some(0), data),
+                    _ => (data.first().and_then(|level| cascade_level(&[*level])), data.get(1..).unwrap_or(&[])),
+                };
+                match (&self.card, level) {
+                    (Some(card), Some(level)) if cascade_part(&card.uid, level).as_deref() == Some(part) => {
+                        match cascade_part(&card.uid, level + 1) {
+                            Some(_) => (STATUS_OK, vec![0x04]),
+                            None => {
+                                self.selected = true;
+                                (STATUS_OK, vec![card.sak])
+                            }
+                        }
+                    }
+                    _ => (STATUS_FAIL, vec![]),
+                }
+            }
+            // Ultralight write: page + 4 bytes
+            0x0213 => match &mut self.card {
+                Some(card) if data.len() == 5 && (data[0] as usize) < card.pages.len() => {
+                    card.pages[data[0] as usize].copy_from_slice(&data[1..]);
+                    (STATUS_OK, vec![])
+                }
+                _ => (STATUS_FAIL, vec![]),
+            },
+            // Read on an Ultralight: 4 pages from data[0], no authentication
+            0x0208 if self.card.as_ref().is_some_and(Card::is_ultralight) => {
+                let pages = &self.card.as_ref().unwrap().pages;
+                match data {
+                    [page] if (*page as usize) < pages.len() => {
+                        let data = (0..4).flat_map(|i| pages[(*page as usize + i) % pages.len()]).collect();
+                        (STATUS_OK, data)
+                    }
+                    _ => (STATUS_FAIL, vec![]),
+                }
+            }
+            // RATS of the selected card, answered with its ATS
+            0x0216 => match &self.card {
+                Some(card) if self.selected && card.sak & 0x20 != 0 => {
+                    self.iso_dep = true;
+                    (STATUS_OK, vec![0x06, 0x75, 0x77, 0x81, 0x02, 0x80])
+                }
+                _ => (STATUS_FAIL, vec![]),
+            },
+            // APDU: SELECT answers 9000, DESFire GetVersion its first part, anything else
+            // "instruction not supported"
+            0x0217 if self.iso_dep => match data {
+                [0x00, 0xa4, ..] => (STATUS_OK, vec![0x90, 0x00]),
+                [0x90, 0x60, ..] => (STATUS_OK, vec![0x04, 0x01, 0x01, 0x01, 0x00, 0x18, 0x05, 0x91, 0xaf]),
+                _ => (STATUS_OK, vec![0x6d, 0x00]),
+            },
+            // Authenticate with key A
+            0x0207 => match &self.card {
+                Some(card) if data.len() == 8 && data[0] == 0x60 && card.key_a(data[1]) == &data[2..] => {
+                    self.authenticated = Some(data[1] >> 2);
+                    self.transfer = None;
+                    (STATUS_OK, vec![])
+                }
+                _ => {
+                    self.authenticated = None;
+                    (STATUS_FAIL, vec![])
+                }
+            },
+            _ => {
+                let answer = self.handle_block(command, data);
+                let write = !matches!(command, 0x0208 | 0x020B | 0x020E);
+                if write && answer.0 == STATUS_OK && data.first() == self.tear.as_ref() {
+                    self.tear = None;
+                    self.pulled = self.card.take();
+                }
+                answer
+            }
+        }
+    }
+
+    // With stacked cards the lowest UID of those answering the request wins the anticollision
+    // and becomes `card`. REQUEST_ALL wakes every card.
+    fn request(&mut self, all: bool) {
+        if !self.stacked.is_empty() {
+            let mut cards: Vec<Card> = self.card.take().into_iter().chain(self.stacked.drain(..)).collect();
+            cards.sort_by(|a, b| a.uid.cmp(&b.uid));
+            if all {
+                cards.iter_mut().for_each(|card| card.halted = false);
+            }
+            self.card = cards.iter().position(|card| !card.halted).map(|first| cards.remove(first));
+            self.stacked = cards;
+        }
+    }
+
+    // Commands that need the sector of data[0] to be authenticated
+    fn handle_block(&mut self, command: u16, data: &[u8]) -> (u8, Vec<u8>) {
+        let authenticated = self.authenticated;
+        let transfer = &mut self.transfer;
+        let card = match &mut self.card {
+            Some(card) if !data.is_empty() && authenticated == Some(data[0] >> 2) => card,
+            _ => return (STATUS_FAIL, vec![]),
+        };
+        let block = data[0];
+        let amount = |data: &[u8]| u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+        match (command, data.len()) {
+            // Read block
+            (0x0208, 1) => (STATUS_OK, card.blocks[block as usize].to_vec()),
+            // Write block
+            (0x0209, 17) if card.read_only.contains(&block) => (STATUS_FAIL, vec![]),
+            (0x0209, 17) => {
+                card.blocks[block as usize].copy_from_slice(&data[1..]);
+                (STATUS_OK, vec![])
+            }
+            // Init value
+            (0x020A, 5) => {
+                card.set_value(block, amount(data));
+                (STATUS_OK, vec![])
+            }
+            // Read value
+            (0x020B, 1) => match card.value(block) {
+                Some(value) => (STATUS_OK, value.to_le_bytes().to_vec()),
+                None => (STATUS_FAIL, vec![]),
+            },
+            // Decrement, the reader refuses to go below zero. The firmware transfers the result
+            // back to the block (unless the card is `buffered`), it stays in the transfer
+            // buffer as well.
+            (0x020C, 5) => match card.value(block).and_then(|value| value.checked_sub(amount(data))) {
+                Some(value) => {
+                    if !card.buffered {
+                        card.set_value(block, value);
+                    }
+                    *transfer = Some(value);
+                    (STATUS_OK, vec![])
+                }
+                None => (STATUS_FAIL, vec![]),
+            },
+            // Increment
+            (0x020D, 5) => match card.value(block).and_then(|value| value.checked_add(amount(data))) {
+                Some(value) => {
+                    if !card.buffered {
+                        card.set_value(block, value);
+                    }
+                    *transfer = Some(value);
+                    (STATUS_OK, vec![])
+                }
+                None => (STATUS_FAIL, vec![]),
+            },
+            // Restore: a value block into the transfer buffer
+            (0x020E, 1) => match card.value(block) {
+                Some(value) => {
+                    *transfer = Some(value);
+                    (STATUS_OK, vec![])
+                }
+                None => (STATUS_FAIL, vec![]),
+            },
+            // Transfer: the buffer into a value block of the authenticated sector
+            (0x020F, 1) if card.read_only.contains(&block) => (STATUS_FAIL, vec![]),
+            (0x020F, 1) => match *transfer {
+                Some(value) => {
+                    card.set_value(block, value);
+                    (STATUS_OK, vec![])
+                }
+                None => (STATUS_FAIL, vec![]),
+            },
+            _ => (STATUS_FAIL, vec![]),
+        }
+    }
+}
+
+// 0, 1 or 2 from the SEL code of a request, no code is level 1
+fn cascade_level(data: &[u8]) -> Option<usize> {
+    match data {
+        [] | [0x93] => Some(0),
+        [0x95] => Some(1),
+        [0x97] => Some(2),
+        _ => None,
+    }
+}
+
+// UID bytes of one cascade level, the cascade tag 0x88 + 3 bytes unless it's the last level
+fn cascade_part(uid: &[u8], level: usize) -> Option<Vec<u8>> {
+    let levels = match uid.len() {
+        4 => 1,
+        7 => 2,
+        _ => 3,
+    };
+    match level {
+        level if level + 1 < levels => Some([&[0x88], &uid[level * 3..level * 3 + 3]].concat()),
+        level if level + 1 == levels => Some(uid[level * 3..].to_vec()),
+        _ => None,
+    }
+}
+
+struct SimulatedPort {
+    state: Arc<Mutex<State>>,
+    pending: VecDeque<u8>,
+    timeout: Duration,
+    chunk: Option<usize>,
+}
+
+impl Write for SimulatedPort {
+    // Frame: AA BB | len (LE) | node (2) | command (LE) | data | xor
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() < 9 || buf[..2] != [0xaa, 0xbb] {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "bad frame"));
+        }
+        let command = u16::from_le_bytes([buf[6], buf[7]]);
+        let mut state = self.state.lock().unwrap();
+        if state.unplugged {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "device disconnected"));
+        }
+        // the size counts node, command, data and xor, the xor covers size (high byte) to data
+        let size = u16::from_le_bytes([buf[2], buf[3]]) as usize;
+        let xor = buf[3..buf.len() - 1].iter().fold(0, |acc, &x| acc ^ x);
+        if size != buf.len() - 4 || xor != buf[buf.len() - 1] {
+            // the reader drops a corrupt frame without an answer
+            return Ok(buf.len());
+        }
+        state.commands.insert(command);
+        let (status, data) = state.handle(command, &buf[8..buf.len() - 1]);
+        let garbled = state.garbled.iter().position(|&code| code == command).map(|i| state.garbled.remove(i)).is_some();
+        let latency = state.latency;
+        drop(state);
+        std::thread::sleep(latency);
+
+        let mut response = vec![0xaa, 0xbb];
+        response.extend_from_slice(&((data.len() + 6) as u16).to_le_bytes());
+        response.extend_from_slice(&[buf[4], buf[5], buf[6], buf[7], status]);
+        response.extend_from_slice(&data);
+        let xor = response[4..].iter().fold(0, |acc, &x| acc ^ x);
+        response.push(if garbled { !xor } else { xor });
+        self.pending.extend(response);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for SimulatedPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Operation timed out"));
+        }
+        let count = buf.len().min(self.pending.len()).min(self.chunk.unwrap_or(usize::MAX));
+        for (slot, byte) in buf.iter_mut().zip(self.pending.drain(..count)) {
+            *slot = byte;
+        }
+        Ok(count)
+    }
+}
+
+impl SerialPort for SimulatedPort {
+    fn name(&self) -> Option<String> {
+        Some("simulator".to_string())
+    }
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(115200)
+    }
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+    fn set_baud_rate(&mut self, _: u32) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn set_data_bits(&mut self, _: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn set_flow_control(&mut self, _: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn set_parity(&mut self, _: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn set_stop_bits(&mut self, _: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+    fn write_request_to_send(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn write_data_terminal_ready(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(self.pending.len() as u32)
+    }
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+    fn clear(&self, _: ClearBuffer) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(SimulatedPort {
+            state: self.state.clone(),
+            pending: VecDeque::new(),
+            timeout: self.timeout,
+            chunk: self.chunk,
+        }))
+    }
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+// Durable queue of the records a sink still has to deliver, for the ones that mustn't be
+// lost (the Kafka settlement records, the database rows): a record is appended to the spool
+// file before the request answers, and a thread of the sink delivers the file in order,
+// trying a record again until it's taken. A record the other end refuses for good is moved
+// to `<spool>.rejected` with the reason, for someone to look at. How far the file was
+// delivered is kept in `<spool>.offset`, so a restart carries on where it stopped; the file
+// is emptied once all of it went through.
+use rocket::serde::json::{json, serde_json, Value};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Before the second try of a record, doubled for each one after it up to MAX_BACKOFF
+const BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+// How long the thread sleeps without a new record before it looks at the file again
+const IDLE: Duration = Duration::from_secs(1);
+
+// What became of a record
+pub enum Delivery {
+    Done,
+    // not now, the same record is tried again after the backoff
+    Retry,
+    // never, with the reason
+    Rejected(String),
+}
+
+pub struct Spool {
+    path: PathBuf,
+    // appends, and the emptying once it's delivered
+    file: Mutex<File>,
+    pushed: Condvar,
+}
+
+impl Spool {
+    pub fn open(path: &Path) -> io::Result<Arc<Self>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Arc::new(Spool {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+            pushed: Condvar::new(),
+        }))
+    }
+
+    // Appends the record, once this returns it's on the disk and delivered eventually
+    pub fn push(&self, record: &Value) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        file.write_all(format!("{}\n", record).as_bytes())?;
+        file.sync_data()?;
+        self.pushed.notify_one();
+        Ok(())
+    }
+
+    // Delivers the records in order, forever, on the calling thread
+    pub fn deliver(&self, mut send: impl FnMut(&Value) -> Delivery) {
+        let mut offset = self.offset();
+        loop {
+            let records = match self.pending(offset) {
+                Ok(records) => records,
+                Err(e) => {
+                    tracing::error!(file = %self.path.display(), error = %e, "can't read the spool");
+                    thread::sleep(MAX_BACKOFF);
+                    continue;
+                }
+            };
+            if records.is_empty() {
+                offset = self.empty(offset);
+                continue;
+            }
+            for (line, length) in records {
+                match serde_json::from_str::<Value>(&line) {
+                    Ok(record) => self.send(&record, &mut send),
+                    // a line cut short by a crash
+                    Err(e) => self.reject(&line, &e.to_string()),
+                }
+                offset += length;
+                self.save_offset(offset);
+            }
+        }
+    }
+
+    fn send(&self, record: &Value, send: &mut impl FnMut(&Value) -> Delivery) {
+        let mut backoff = BACKOFF;
+        loop {
+            match send(record) {
+                Delivery::Done => return,
+                Delivery::Rejected(reason) => return self.reject(&record.to_string(), &reason),
+                Delivery::Retry => {
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+
+    // The complete lines after `offset` with their length, newline included
+    fn pending(&self, offset: u64) -> io::Result<Vec<(String, u64)>> {
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut reader = BufReader::new(file);
+        let mut records = Vec::new();
+        loop {
+            let mut line = String::new();
+            let length = reader.read_line(&mut line)?;
+            // the last one may still be written
+            if length == 0 || !line.ends_with('\n') {
+                return Ok(records);
+            }
+            records.push((line.trim_end().to_string(), length as u64));
+        }
+    }
+
+    // Empties the file when nothing was pushed since `offset`, then waits for the next push
+    fn empty(&self, offset: u64) -> u64 {
+        let file = self.file.lock().unwrap();
+        let mut offset = offset;
+        if offset > 0 && file.metadata().is_ok_and(|metadata| metadata.len() == offset) {
+            match file.set_len(0) {
+                Ok(()) => {
+                    offset = 0;
+                    self.save_offset(0);
+                }
+                Err(e) => tracing::error!(file = %self.path.display(), error = %e, "can't empty the spool"),
+            }
+        }
+        let _woken = self.pushed.wait_timeout(file, IDLE).unwrap();
+        offset
+    }
+
+    fn offset_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".offset");
+        path.into()
+    }
+
+    fn offset(&self) -> u64 {
+        let offset = std::fs::read_to_string(self.offset_path()).ok().and_then(|text| text.trim().parse().ok()).unwrap_or(0);
+        // a spool emptied after the offset was written
+        let length = std::fs::metadata(&self.path).map(|metadata| metadata.len()).unwrap_or(0);
+        if offset > length {
+            0
+        } else {
+            offset
+        }
+    }
+
+    fn save_offset(&self, offset: u64) {
+        if let Err(e) = std::fs::write(self.offset_path(), offset.to_string()) {
+            tracing::error!(file = %self.path.display(), error = %e, "can't write the spool offset");
+        }
+    }
+
+    fn reject(&self, record: &str, reason: &str) {
+        tracing::error!(file = %self.path.display(), reason, record, "record refused, moved to the rejected ones");
+        let mut path = self.path.clone().into_os_string();
+        path.push(".rejected");
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let entry = json!({ "record": record, "reason": reason, "timestamp": timestamp.as_millis() as f64 / 1000.0 });
+        let written = OpenOptions::new().create(true).append(true).open(&path).and_then(|mut file| file.write_all(format!("{}\n", entry).as_bytes()));
+        if let Err(e) = written {
+            tracing::error!(file = %PathBuf::from(path).display(), error = %e, record, "can't keep the rejected record");
+        }
+    }
+}
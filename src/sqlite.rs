@@ -0,0 +1,300 @@
+// SQLite through the C library of the system (libsqlite3, the `libsqlite3-dev` package to
+// build): a connection to a database file, statements run with their parameters bound and
+// the rows read back as values. Only what the audit table and the database records need.
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::ptr;
+use std::time::Duration;
+
+// How long a statement waits for another connection's lock before it's SQLITE_BUSY
+const BUSY: Duration = Duration::from_secs(5);
+
+const SQLITE_OK: c_int = 0;
+const SQLITE_BUSY: c_int = 5;
+const SQLITE_LOCKED: c_int = 6;
+const SQLITE_IOERR: c_int = 10;
+const SQLITE_FULL: c_int = 13;
+const SQLITE_CANTOPEN: c_int = 14;
+const SQLITE_ROW: c_int = 100;
+const SQLITE_DONE: c_int = 101;
+const SQLITE_INTEGER: c_int = 1;
+const SQLITE_FLOAT: c_int = 2;
+const SQLITE_TEXT: c_int = 3;
+const SQLITE_OPEN_READONLY: c_int = 0x01;
+const SQLITE_OPEN_READWRITE: c_int = 0x02;
+const SQLITE_OPEN_CREATE: c_int = 0x04;
+const SQLITE_OPEN_FULLMUTEX: c_int = 0x10000;
+// the text is copied before bind returns
+const SQLITE_TRANSIENT: isize = -1;
+
+#[repr(C)]
+struct Sqlite3 {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct Stmt {
+    _private: [u8; 0],
+}
+
+#[link(name = "sqlite3")]
+extern "C" {
+    fn sqlite3_open_v2(filename: *const c_char, db: *mut *mut Sqlite3, flags: c_int, vfs: *const c_char) -> c_int;
+    fn sqlite3_close_v2(db: *mut Sqlite3) -> c_int;
+    fn sqlite3_errmsg(db: *mut Sqlite3) -> *const c_char;
+    fn sqlite3_errstr(code: c_int) -> *const c_char;
+    fn sqlite3_busy_timeout(db: *mut Sqlite3, ms: c_int) -> c_int;
+    fn sqlite3_exec(
+        db: *mut Sqlite3,
+        sql: *const c_char,
+        callback: *const c_void,
+        argument: *mut c_void,
+        error: *mut *mut c_char,
+    ) -> c_int;
+    fn sqlite3_free(pointer: *mut c_void);
+    fn sqlite3_prepare_v2(
+        db: *mut Sqlite3,
+        sql: *const c_char,
+        length: c_int,
+        statement: *mut *mut Stmt,
+        tail: *mut *const c_char,
+    ) -> c_int;
+    fn sqlite3_bind_null(statement: *mut Stmt, index: c_int) -> c_int;
+    fn sqlite3_bind_int64(statement: *mut Stmt, index: c_int, value: i64) -> c_int;
+    fn sqlite3_bind_double(statement: *mut Stmt, index: c_int, value: f64) -> c_int;
+    fn sqlite3_bind_text(statement: *mut Stmt, index: c_int, text: *const c_char, length: c_int, destructor: isize) -> c_int;
+    fn sqlite3_step(statement: *mut Stmt) -> c_int;
+    fn sqlite3_column_count(statement: *mut Stmt) -> c_int;
+    fn sqlite3_column_type(statement: *mut Stmt, column: c_int) -> c_int;
+    fn sqlite3_column_int64(statement: *mut Stmt, column: c_int) -> i64;
+    fn sqlite3_column_double(statement: *mut Stmt, column: c_int) -> f64;
+    fn sqlite3_column_text(statement: *mut Stmt, column: c_int) -> *const u8;
+    fn sqlite3_column_bytes(statement: *mut Stmt, column: c_int) -> c_int;
+    fn sqlite3_finalize(statement: *mut Stmt) -> c_int;
+}
+
+// A parameter or a column, blobs aren't used
+#[derive(Clone, Debug, PartialEq)]
+pub enum Sql {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+}
+
+impl Sql {
+    pub fn text(&self) -> Option<&str> {
+        match self {
+            Sql::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn integer(&self) -> Option<i64> {
+        match self {
+            Sql::Integer(value) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+impl From<Option<&str>> for Sql {
+    fn from(text: Option<&str>) -> Self {
+        text.map_or(Sql::Null, |text| Sql::Text(text.to_string()))
+    }
+}
+
+// The result code of the library and its message
+#[derive(Debug)]
+pub struct Error {
+    pub code: c_int,
+    pub message: String,
+}
+
+impl Error {
+    // The database can't take it now (locked, disk full or unreachable), unlike a statement
+    // it refuses
+    pub fn retryable(&self) -> bool {
+        matches!(self.code, SQLITE_BUSY | SQLITE_LOCKED | SQLITE_IOERR | SQLITE_FULL | SQLITE_CANTOPEN)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (SQLite error {})", self.message, self.code)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e.code {
+            SQLITE_CANTOPEN => io::Error::new(io::ErrorKind::NotFound, e.to_string()),
+            _ => io::Error::other(e.to_string()),
+        }
+    }
+}
+
+pub struct Connection(*mut Sqlite3);
+
+// opened serialized (SQLITE_OPEN_FULLMUTEX), the library locks each call itself
+unsafe impl Send for Connection {}
+
+impl Connection {
+    // Read and write, the file is created when there's none
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        Self::open_with(path, SQLITE_OPEN_READWRITE | SQLITE_OPEN_CREATE)
+    }
+
+    // Read only, SQLITE_CANTOPEN when there's no file
+    pub fn open_read_only(path: &Path) -> Result<Self, Error> {
+        Self::open_with(path, SQLITE_OPEN_READONLY)
+    }
+
+    fn open_with(path: &Path, flags: c_int) -> Result<Self, Error> {
+        let filename = CString::new(path.to_string_lossy().as_bytes()).map_err(|_| Error {
+            code: SQLITE_CANTOPEN,
+            message: format!("{}: a NUL in the path", path.display()),
+        })?;
+        let mut db = ptr::null_mut();
+        // SAFETY: a NUL-terminated filename and an out pointer, the handle is closed on an
+        // error as well as by drop
+        let code = unsafe { sqlite3_open_v2(filename.as_ptr(), &mut db, flags | SQLITE_OPEN_FULLMUTEX, ptr::null()) };
+        let connection = Connection(db);
+        if code != SQLITE_OK {
+            return Err(connection.error(code));
+        }
+        // SAFETY: an open handle
+        unsafe { sqlite3_busy_timeout(db, BUSY.as_millis() as c_int) };
+        Ok(connection)
+    }
+
+    // Statements separated by `;`, without parameters or rows
+    pub fn execute(&self, sql: &str) -> Result<(), Error> {
+        let sql = statement_text(sql)?;
+        let mut message = ptr::null_mut();
+        // SAFETY: an open handle, a NUL-terminated statement and no callback; the message is
+        // allocated by the library and freed here
+        let code = unsafe { sqlite3_exec(self.0, sql.as_ptr(), ptr::null(), ptr::null_mut(), &mut message) };
+        if code == SQLITE_OK {
+            return Ok(());
+        }
+        match message.is_null() {
+            true => Err(self.error(code)),
+            false => {
+                // SAFETY: set by sqlite3_exec, NUL-terminated
+                let text = unsafe { CStr::from_ptr(message) }.to_string_lossy().into_owned();
+                unsafe { sqlite3_free(message.cast()) };
+                Err(Error { code, message: text })
+            }
+        }
+    }
+
+    // One statement with `?` parameters, its rows with a value per column
+    pub fn query(&self, sql: &str, parameters: &[Sql]) -> Result<Vec<Vec<Sql>>, Error> {
+        let mut rows = Vec::new();
+        self.each(sql, parameters, |row| {
+            rows.push(row);
+            true
+        })?;
+        Ok(rows)
+    }
+
+    // One statement with `?` parameters, `row` is called with each row until it says to stop
+    pub fn each(&self, sql: &str, parameters: &[Sql], mut row: impl FnMut(Vec<Sql>) -> bool) -> Result<(), Error> {
+        let sql = statement_text(sql)?;
+        let mut statement = ptr::null_mut();
+        // SAFETY: an open handle and a NUL-terminated statement, the statement is finalized
+        // below on every path
+        let code = unsafe { sqlite3_prepare_v2(self.0, sql.as_ptr(), -1, &mut statement, ptr::null_mut()) };
+        if code != SQLITE_OK {
+            return Err(self.error(code));
+        }
+        let result = self.run(statement, parameters, &mut row);
+        // SAFETY: prepared above, not used after this
+        unsafe { sqlite3_finalize(statement) };
+        result
+    }
+
+    fn run(&self, statement: *mut Stmt, parameters: &[Sql], row: &mut impl FnMut(Vec<Sql>) -> bool) -> Result<(), Error> {
+        for (index, parameter) in parameters.iter().enumerate() {
+            let index = index as c_int + 1;
+            // SAFETY: a prepared statement; the text is copied by the library (SQLITE_TRANSIENT)
+            let code = unsafe {
+                match parameter {
+                    Sql::Null => sqlite3_bind_null(statement, index),
+                    Sql::Integer(value) => sqlite3_bind_int64(statement, index, *value),
+                    Sql::Real(value) => sqlite3_bind_double(statement, index, *value),
+                    Sql::Text(text) => {
+                        sqlite3_bind_text(statement, index, text.as_ptr().cast(), text.len() as c_int, SQLITE_TRANSIENT)
+                    }
+                }
+            };
+            if code != SQLITE_OK {
+                return Err(self.error(code));
+            }
+        }
+        loop {
+            // SAFETY: a prepared statement with its parameters bound
+            match unsafe { sqlite3_step(statement) } {
+                SQLITE_DONE => return Ok(()),
+                SQLITE_ROW => {
+                    if !row(columns(statement)) {
+                        return Ok(());
+                    }
+                }
+                code => return Err(self.error(code)),
+            }
+        }
+    }
+
+    fn error(&self, code: c_int) -> Error {
+        // SAFETY: the message of the handle or, without one (out of memory at open), of the
+        // code, both NUL-terminated and owned by the library
+        let message = unsafe {
+            match self.0.is_null() {
+                true => CStr::from_ptr(sqlite3_errstr(code)),
+                false => CStr::from_ptr(sqlite3_errmsg(self.0)),
+            }
+        };
+        Error {
+            code,
+            message: message.to_string_lossy().into_owned(),
+        }
+    }
+}
+
+fn statement_text(sql: &str) -> Result<CString, Error> {
+    CString::new(sql).map_err(|_| Error {
+        code: 1,
+        message: "a NUL in the statement".to_string(),
+    })
+}
+
+// The columns of the row a statement is on
+fn columns(statement: *mut Stmt) -> Vec<Sql> {
+    // SAFETY: a statement on a row (SQLITE_ROW), the text is copied before the next step
+    unsafe {
+        (0..sqlite3_column_count(statement))
+            .map(|column| match sqlite3_column_type(statement, column) {
+                SQLITE_INTEGER => Sql::Integer(sqlite3_column_int64(statement, column)),
+                SQLITE_FLOAT => Sql::Real(sqlite3_column_double(statement, column)),
+                SQLITE_TEXT => {
+                    let text = sqlite3_column_text(statement, column);
+                    let length = sqlite3_column_bytes(statement, column) as usize;
+                    Sql::Text(String::from_utf8_lossy(std::slice::from_raw_parts(text, length)).into_owned())
+                }
+                _ => Sql::Null,
+            })
+            .collect()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // SAFETY: every statement was finalized, the handle isn't used after this; closing a
+        // null handle is a no-op
+        unsafe { sqlite3_close_v2(self.0) };
+    }
+}
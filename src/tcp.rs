@@ -0,0 +1,312 @@
+// Reader behind a serial-to-ethernet bridge: `tcp://host:port` is a raw socket,
+// `rfc2217://host:port` speaks telnet with the COM-PORT-OPTION to set the baud rate
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+// Connection to the bridge when no `serial.open_timeout_ms` is given
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Telnet (RFC 854) and COM-PORT-OPTION (RFC 2217) codes
+const IAC: u8 = 0xff;
+const DONT: u8 = 0xfe;
+const DO: u8 = 0xfd;
+const WONT: u8 = 0xfc;
+const WILL: u8 = 0xfb;
+const SB: u8 = 0xfa;
+const SE: u8 = 0xf0;
+const BINARY: u8 = 0x00;
+const COM_PORT_OPTION: u8 = 0x2c;
+const SET_BAUDRATE: u8 = 0x01;
+
+// `tcp://` / `rfc2217://` port name to (address, telnet), None for local ports
+pub fn parse_url(portname: &str) -> Option<(&str, bool)> {
+    if let Some(address) = portname.strip_prefix("tcp://") {
+        return Some((address, false));
+    }
+    portname.strip_prefix("rfc2217://").map(|address| (address, true))
+}
+
+pub struct TcpPort {
+    stream: TcpStream,
+    address: String,
+    baud_rate: u32,
+    timeout: Duration,
+    // None on raw sockets
+    telnet: Option<Telnet>,
+}
+
+impl TcpPort {
+    pub fn connect(address: &str, baud_rate: u32, telnet: bool, connect_timeout: Duration, timeout: Duration) -> io::Result<Self> {
+        let mut last_error = io::Error::new(ErrorKind::NotFound, format!("{} doesn't resolve", address));
+        for socket in address.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&socket, connect_timeout) {
+                Ok(stream) => {
+                    stream.set_nodelay(true)?;
+                    let mut port = TcpPort {
+                        stream,
+                        address: address.to_string(),
+                        baud_rate,
+                        timeout: Duration::ZERO,
+                        telnet: telnet.then(Telnet::default),
+                    };
+                    port.set_timeout(timeout)?;
+                    if telnet {
+                        port.negotiate()?;
+                    }
+                    return Ok(port);
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    // 8-bit data both ways, then the baud rate of the remote port
+    fn negotiate(&mut self) -> io::Result<()> {
+        let mut request = vec![IAC, WILL, BINARY, IAC, DO, BINARY, IAC, WILL, COM_PORT_OPTION];
+        request.extend_from_slice(&set_baudrate(self.baud_rate));
+        self.stream.write_all(&request)
+    }
+}
+
+fn set_baudrate(baud_rate: u32) -> Vec<u8> {
+    let mut request = vec![IAC, SB, COM_PORT_OPTION, SET_BAUDRATE];
+    request.extend_from_slice(&escape(&baud_rate.to_be_bytes()));
+    request.extend_from_slice(&[IAC, SE]);
+    request
+}
+
+// IAC in the data is sent twice
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for &byte in data {
+        escaped.push(byte);
+        if byte == IAC {
+            escaped.push(IAC);
+        }
+    }
+    escaped
+}
+
+// Where the decoder is in a telnet command, commands may be split over reads
+#[derive(Default, Clone, Copy, PartialEq, Debug)]
+enum Telnet {
+    #[default]
+    Data,
+    Iac,
+    // after WILL / WONT / DO / DONT
+    Option(u8),
+    Sub,
+    SubIac,
+}
+
+impl Telnet {
+    // Append the data bytes of `input` to `data`, answers to the server's requests go to `replies`
+    fn decode(&mut self, input: &[u8], data: &mut Vec<u8>, replies: &mut Vec<u8>) {
+        for &byte in input {
+            *self = match (*self, byte) {
+                (Telnet::Data, IAC) => Telnet::Iac,
+                (Telnet::Data, _) => {
+                    data.push(byte);
+                    Telnet::Data
+                }
+                (Telnet::Iac, IAC) => {
+                    data.push(IAC);
+                    Telnet::Data
+                }
+                (Telnet::Iac, WILL | WONT | DO | DONT) => Telnet::Option(byte),
+                (Telnet::Iac, SB) => Telnet::Sub,
+                (Telnet::Iac, _) => Telnet::Data,
+                (Telnet::Option(verb), option) => {
+                    // refuse what we didn't ask for, the rest acknowledges our requests
+                    match (verb, option) {
+                        (DO, BINARY | COM_PORT_OPTION) | (WILL, BINARY) | (WONT | DONT, _) => (),
+                        (DO, _) => replies.extend_from_slice(&[IAC, WONT, option]),
+                        _ => replies.extend_from_slice(&[IAC, DONT, option]),
+                    }
+                    Telnet::Data
+                }
+                // the server's COM-PORT-OPTION notifications aren't needed
+                (Telnet::Sub, IAC) => Telnet::SubIac,
+                (Telnet::Sub, _) => Telnet::Sub,
+                (Telnet::SubIac, SE) => Telnet::Data,
+                (Telnet::SubIac, _) => Telnet::Sub,
+            };
+        }
+    }
+}
+
+impl Read for TcpPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let count = match self.stream.read(buf) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::ConnectionAborted, "connection closed by the bridge")),
+                Ok(count) => count,
+                // unix reports an expired read timeout as WouldBlock
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Err(ErrorKind::TimedOut.into()),
+                Err(e) => return Err(e),
+            };
+            let Some(telnet) = self.telnet.as_mut() else {
+                return Ok(count);
+            };
+            let mut data = Vec::with_capacity(count);
+            let mut replies = Vec::new();
+            telnet.decode(&buf[..count], &mut data, &mut replies);
+            if !replies.is_empty() {
+                self.stream.write_all(&replies)?;
+            }
+            // only telnet commands came in, wait for data
+            if !data.is_empty() {
+                buf[..data.len()].copy_from_slice(&data);
+                return Ok(data.len());
+            }
+        }
+    }
+}
+
+impl Write for TcpPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.telnet {
+            Some(_) => self.stream.write_all(&escape(buf)).map(|_| buf.len()),
+            None => self.stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl SerialPort for TcpPort {
+    fn name(&self) -> Option<String> {
+        Some(self.address.clone())
+    }
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(self.baud_rate)
+    }
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+    // raw bridges are configured on the bridge itself
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        if self.telnet.is_some() {
+            self.stream.write_all(&set_baudrate(baud_rate))?;
+        }
+        self.baud_rate = baud_rate;
+        Ok(())
+    }
+    fn set_data_bits(&mut self, _: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn set_flow_control(&mut self, _: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn set_parity(&mut self, _: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn set_stop_bits(&mut self, _: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        // a zero socket timeout is refused, use the shortest one instead
+        let socket_timeout = Some(timeout.max(Duration::from_millis(1)));
+        self.stream.set_read_timeout(socket_timeout)?;
+        self.stream.set_write_timeout(socket_timeout)?;
+        self.timeout = timeout;
+        Ok(())
+    }
+    fn write_request_to_send(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn write_data_terminal_ready(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+    fn clear(&self, _: ClearBuffer) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(TcpPort {
+            stream: self.stream.try_clone()?,
+            address: self.address.clone(),
+            baud_rate: self.baud_rate,
+            timeout: self.timeout,
+            telnet: self.telnet,
+        }))
+    }
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_urls() {
+        assert_eq!(parse_url("tcp://10.0.0.5:4001"), Some(("10.0.0.5:4001", false)));
+        assert_eq!(parse_url("rfc2217://bridge:2217"), Some(("bridge:2217", true)));
+        assert_eq!(parse_url("/dev/ttyUSB0"), None);
+    }
+
+    #[test]
+    fn escapes_telnet_data() {
+        assert_eq!(escape(&[0xaa, 0xff, 0x01]), vec![0xaa, 0xff, 0xff, 0x01]);
+        assert_eq!(
+            set_baudrate(115200),
+            vec![IAC, SB, COM_PORT_OPTION, SET_BAUDRATE, 0x00, 0x01, 0xc2, 0x00, IAC, SE]
+        );
+    }
+
+    #[test]
+    fn decodes_telnet_stream() {
+        let mut telnet = Telnet::default();
+        let (mut data, mut replies) = (Vec::new(), Vec::new());
+        // acknowledgement, a notification, an unknown request and an escaped 0xFF
+        let input = [
+            IAC, DO, COM_PORT_OPTION, 0xaa, IAC, SB, COM_PORT_OPTION, 101, 0, IAC, SE, 0xbb, IAC, DO, 0x18, IAC,
+        ];
+        telnet.decode(&input, &mut data, &mut replies);
+        assert_eq!(data, vec![0xaa, 0xbb]);
+        assert_eq!(replies, vec![IAC, WONT, 0x18]);
+        // the IAC at the end of the last read is completed by the next one
+        telnet.decode(&[IAC, 0x01], &mut data, &mut replies);
+        assert_eq!(data, vec![0xaa, 0xbb, 0xff, 0x01]);
+        assert_eq!(telnet, Telnet::Data);
+    }
+}
@@ -0,0 +1,235 @@
+// Terminal dashboard of `--tui`, for commissioning readers on site: per reader the serial
+// link and the card in the field with its balance, the latest taps and the warnings and
+// errors of the log, redrawn in place with ANSI escapes. The server runs as usual behind it.
+use crate::events::{Event, Events};
+use crate::logging::Captured;
+use crate::readers::Readers;
+use crate::worker::{ReaderCommand, Worker};
+use crate::ValueBlock;
+use er302::codec::BlockAddress;
+use er302::ReaderError;
+use rocket::fairing::AdHoc;
+use rocket::serde::json::Value;
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::{self, error::RecvError};
+use rocket::tokio::time::sleep;
+use rocket::{Build, Rocket, Shutdown};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Redraws while nothing happens, for the durations and the log
+const REFRESH: Duration = Duration::from_millis(500);
+const TAPS: usize = 8;
+const ERRORS: usize = 8;
+// Version requests to a reader that doesn't answer, to see it come back
+const REPROBE: Duration = Duration::from_secs(5);
+
+// Alternate screen, cursor hidden / back to the shell's screen
+const ENTER: &str = "\x1b[?1049h\x1b[?25l";
+const LEAVE: &str = "\x1b[?25h\x1b[?1049l";
+
+// While the dashboard holds the alternate screen
+static SHOWN: AtomicBool = AtomicBool::new(false);
+
+struct ReaderView {
+    name: String,
+    worker: Worker,
+    // model and firmware, or why the reader doesn't answer
+    link: Result<String, String>,
+    // UID and balance (or the error reading it) of the card in the field
+    card: Option<(String, Result<String, String>)>,
+}
+
+struct Dashboard {
+    readers: Vec<ReaderView>,
+    value_block: BlockAddress,
+    // (time, reader, UID), newest first
+    taps: VecDeque<(String, String, String)>,
+    captured: Captured,
+    started: SystemTime,
+}
+
+// The server with the dashboard instead of the log on stdout
+pub fn attach(rocket: Rocket<Build>, captured: Captured) -> Rocket<Build> {
+    // Rocket's own launch messages would scribble over the screen
+    let figment = rocket.figment().clone().merge(("log_level", "off"));
+    rocket.configure(figment).attach(AdHoc::on_liftoff("dashboard", move |rocket| {
+        Box::pin(async move {
+            let readers = rocket.state::<Readers>().expect("readers are managed");
+            let mut views = Vec::new();
+            for (name, slot) in readers.iter() {
+                let link = match &*slot.probe.lock().await {
+                    Some(result) => link(result),
+                    None => Err("not probed yet".to_string()),
+                };
+                views.push(ReaderView {
+                    name: name.to_string(),
+                    worker: slot.worker.clone(),
+                    link,
+                    card: None,
+                });
+            }
+            let dashboard = Dashboard {
+                readers: views,
+                value_block: rocket.state::<ValueBlock>().expect("value block is managed").0,
+                taps: VecDeque::new(),
+                captured,
+                started: SystemTime::now(),
+            };
+            let events = rocket.state::<Events>().expect("events are managed").subscribe();
+            rocket::tokio::spawn(run(dashboard, events, rocket.shutdown()));
+        })
+    }))
+}
+
+async fn run(mut dashboard: Dashboard, mut events: broadcast::Receiver<Event>, mut shutdown: Shutdown) {
+    // subscribed, so the workers poll for cards
+    print!("{}", ENTER);
+    SHOWN.store(true, Ordering::SeqCst);
+    // a panic anywhere gives the shell its screen back before the message is printed, and
+    // the dashboard stops, the server runs on with the log on stderr
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        leave();
+        previous(info);
+    }));
+    let mut probed = Instant::now();
+    while SHOWN.load(Ordering::SeqCst) {
+        if probed.elapsed() >= REPROBE {
+            dashboard.reprobe().await;
+            probed = Instant::now();
+        }
+        dashboard.draw();
+        select! {
+            event = events.recv() => match event {
+                Ok(event) => dashboard.update(event).await,
+                Err(RecvError::Lagged(_)) => (),
+                Err(RecvError::Closed) => break,
+            },
+            _ = sleep(REFRESH) => (),
+            _ = &mut shutdown => break,
+        }
+    }
+    leave();
+}
+
+// Back to the shell's screen, once
+fn leave() {
+    let mut stdout = std::io::stdout().lock();
+    if SHOWN.swap(false, Ordering::SeqCst) {
+        let _ = stdout.write_all(LEAVE.as_bytes()).and_then(|()| stdout.flush());
+    }
+}
+
+impl Dashboard {
+    async fn reprobe(&mut self) {
+        for view in self.readers.iter_mut().filter(|view| view.link.is_err()) {
+            view.link = link(&view.worker.send(ReaderCommand::ReaderInfo).await.result);
+        }
+    }
+
+    async fn update(&mut self, event: Event) {
+        let Some(view) = self.readers.iter_mut().find(|view| view.name == event.reader) else {
+            return;
+        };
+        match event.kind {
+            "card_detected" => {
+                self.taps.push_front((clock(SystemTime::now()), event.reader.clone(), event.uid.clone()));
+                self.taps.truncate(TAPS);
+                let result = view.worker.send(ReaderCommand::ReadBalance(self.value_block)).await.result;
+                // an answer, even a card error, shows the link is up
+                match &result {
+                    Err(e @ (ReaderError::PortError(_) | ReaderError::OpenTimeout { .. } | ReaderError::Timeout)) => view.link = Err(e.to_string()),
+                    _ => {
+                        if view.link.is_err() {
+                            view.link = Ok("answering".to_string());
+                        }
+                    }
+                }
+                let balance = result.map(|balance| plain(&balance)).map_err(|e| e.to_string());
+                view.card = Some((event.uid, balance));
+            }
+            "card_removed" => view.card = None,
+            _ => (),
+        }
+    }
+
+    fn draw(&self) {
+        let mut screen = String::from("\x1b[H");
+        let mut line = |text: String| {
+            screen.push_str(&text);
+            // rest of the previous, longer line
+            screen.push_str("\x1b[K\r\n");
+        };
+        let uptime = self.started.elapsed().unwrap_or_default().as_secs();
+        line(format!(
+            "\x1b[1mER302 dashboard\x1b[0m   up {}:{:02}:{:02}   value block {}/{}   Ctrl-C stops the server",
+            uptime / 3600,
+            uptime / 60 % 60,
+            uptime % 60,
+            self.value_block.sector,
+            self.value_block.block
+        ));
+        line(String::new());
+        line(format!("\x1b[1m{:<16} {:<34} {:<16} {}\x1b[0m", "READER", "SERIAL LINK", "CARD", "BALANCE"));
+        for view in &self.readers {
+            let link = match &view.link {
+                Ok(info) => format!("\x1b[32m{:<34}\x1b[0m", format!("up, {}", info)),
+                Err(e) => format!("\x1b[31m{:<34}\x1b[0m", format!("down, {}", e)),
+            };
+            let (uid, balance) = match &view.card {
+                Some((uid, Ok(balance))) => (uid.as_str(), balance.clone()),
+                Some((uid, Err(e))) => (uid.as_str(), format!("\x1b[33m{}\x1b[0m", e)),
+                None => ("-", String::new()),
+            };
+            line(format!("{:<16} {} {:<16} {}", view.name, link, uid, balance));
+        }
+        line(String::new());
+        line("\x1b[1mLAST CARDS\x1b[0m".to_string());
+        for (time, reader, uid) in &self.taps {
+            line(format!("{}  {:<16} {}", time, reader, uid));
+        }
+        for _ in self.taps.len()..TAPS {
+            line(String::new());
+        }
+        line(String::new());
+        line("\x1b[1mRECENT ERRORS\x1b[0m".to_string());
+        let captured = self.captured.lock().unwrap();
+        for error in captured.iter().rev().take(ERRORS) {
+            line(error.clone());
+        }
+        drop(captured);
+        // whatever is left below from a taller screen
+        screen.push_str("\x1b[J");
+        // under the lock of stdout, a panic on another thread can't leave the screen between
+        // the check and the write
+        let mut stdout = std::io::stdout().lock();
+        if SHOWN.load(Ordering::SeqCst) {
+            let _ = stdout.write_all(screen.as_bytes()).and_then(|()| stdout.flush());
+        }
+    }
+}
+
+// Link status of a version request
+fn link(result: &Result<Value, ReaderError>) -> Result<String, String> {
+    match result {
+        Ok(info) => Ok(format!("{} {}", plain(&info["model"]), plain(&info["firmware"]))),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Strings without their quotes
+fn plain(value: &Value) -> String {
+    match value {
+        Value::String(text) => text.clone(),
+        value => value.to_string(),
+    }
+}
+
+// HH:MM:SS UTC
+fn clock(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() % 86400;
+    format!("{:02}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
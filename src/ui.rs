@@ -0,0 +1,147 @@
+// Operator page at /ui: buttons for the everyday card operations and the live card events,
+// so the reader can be used from a browser without writing a client. Plain HTML and script
+// served from the binary, it calls the /v1 routes like any other frontend (with the API key
+// typed in, when auth is on).
+use rocket::response::content::RawHtml;
+
+const PAGE: &str = r##"<!DOCTYPE html>
+<html>
+<head>
+  <meta charset="utf-8">
+  <meta name="viewport" content="width=device-width, initial-scale=1">
+  <title>ER302 operator</title>
+  <style>
+    body { font-family: sans-serif; margin: 2em; max-width: 60em; }
+    fieldset { margin-bottom: 1em; }
+    button { margin: 0.2em; padding: 0.5em 1em; }
+    input[type=number] { width: 8em; }
+    #answer { font-size: 1.4em; padding: 0.5em; min-height: 1.5em; }
+    .ok { background: #e3f6e3; }
+    .failed { background: #fbe3e3; }
+    #events { font-family: monospace; height: 20em; overflow-y: auto; border: 1px solid #ccc; padding: 0.5em; }
+  </style>
+</head>
+<body>
+  <h1>ER302 operator</h1>
+  <fieldset>
+    <legend>Connection</legend>
+    <label>Reader <select id="reader"></select></label>
+    <label>API key <input id="key" type="password" placeholder="only with auth on"></label>
+  </fieldset>
+  <fieldset>
+    <legend>Card</legend>
+    <button id="read-id">Read ID</button>
+    <button id="read-balance">Read balance</button>
+    <label>Balance <input id="value" type="number" min="0" value="0"></label>
+    <button id="set-balance">Set balance</button>
+    <button id="init-card">Init card</button>
+  </fieldset>
+  <div id="answer"></div>
+  <h2>Card events</h2>
+  <div id="events"></div>
+  <script>
+    const $ = (id) => document.getElementById(id);
+    $("key").value = localStorage.getItem("er302-key") || "";
+
+    function headers() {
+      const key = $("key").value.trim();
+      return key ? { "Authorization": "Bearer " + key } : {};
+    }
+
+    function url(path) {
+      const reader = $("reader").value;
+      return "/v1" + path + (reader ? "?reader=" + encodeURIComponent(reader) : "");
+    }
+
+    async function call(method, path, body) {
+      const init = { method, headers: headers() };
+      if (body !== undefined) {
+        init.headers["Content-Type"] = "application/json";
+        init.body = JSON.stringify(body);
+      }
+      try {
+        const answer = await (await fetch(url(path), init)).json();
+        const data = typeof answer.data === "string" ? answer.data : JSON.stringify(answer.data);
+        show(answer.status, answer.status ? data : (answer.code || "") + " " + data);
+      } catch (e) {
+        show(false, e.toString());
+      }
+    }
+
+    function show(ok, text) {
+      $("answer").className = ok ? "ok" : "failed";
+      $("answer").textContent = text;
+    }
+
+    $("read-id").onclick = () => call("GET", "/id");
+    $("read-balance").onclick = () => call("GET", "/balance");
+    $("set-balance").onclick = () => call("POST", "/balance", { value: Number($("value").value) });
+    $("init-card").onclick = () => {
+      if (confirm("Give the card in the field the application key?")) call("POST", "/initcard", {});
+    };
+
+    // fetch rather than EventSource, which can't send the API key
+    let feed = null;
+    async function listen() {
+      if (feed) feed.abort();
+      feed = new AbortController();
+      try {
+        const response = await fetch(url("/events"), { headers: headers(), signal: feed.signal });
+        const reader = response.body.getReader();
+        const decoder = new TextDecoder();
+        let buffer = "";
+        for (;;) {
+          const { value, done } = await reader.read();
+          if (done) break;
+          buffer += decoder.decode(value, { stream: true });
+          let end;
+          while ((end = buffer.indexOf("\n\n")) >= 0) {
+            const message = buffer.slice(0, end);
+            buffer = buffer.slice(end + 2);
+            const data = message.split("\n").filter((line) => line.startsWith("data:"));
+            if (data.length) event(JSON.parse(data.map((line) => line.slice(5)).join("\n")));
+          }
+        }
+      } catch (e) {
+        if (e.name === "AbortError") return;
+      }
+      setTimeout(listen, 2000);
+    }
+
+    function event(event) {
+      const line = document.createElement("div");
+      const time = new Date(event.timestamp * 1000).toLocaleTimeString();
+      const amount = event.amount !== undefined ? " " + event.command + " " + event.amount + " -> " + event.after : "";
+      line.textContent = time + "  " + event.reader + "  " + event.kind + "  " + event.uid + amount;
+      $("events").prepend(line);
+    }
+
+    async function readers() {
+      try {
+        const answer = await (await fetch("/v1/readers", { headers: headers() })).json();
+        const select = $("reader");
+        const current = select.value;
+        select.innerHTML = "";
+        for (const name of (answer.data && answer.data.readers) || []) {
+          select.add(new Option(name, name));
+        }
+        if (current) select.value = current;
+      } catch (e) {
+        show(false, e.toString());
+      }
+    }
+
+    $("key").onchange = () => {
+      localStorage.setItem("er302-key", $("key").value.trim());
+      readers().then(listen);
+    };
+    $("reader").onchange = listen;
+    readers().then(listen);
+  </script>
+</body>
+</html>"##;
+
+#[get("/ui")]
+pub fn ui() -> RawHtml<&'static str> {
+    RawHtml(PAGE)
+}
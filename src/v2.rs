@@ -0,0 +1,135 @@
+// Bodies of the /v2 routes: the routes of /v1 without the legacy GETs, answering typed data
+// instead of the text kiosks parse, `{"data": {"uid": "DEADBEEF", "balance": 1500}}` on success
+// and `{"error": {"code": "AUTH_FAILED", "message": "..."}}` on failure. The routes don't know
+// their version, the reply is reshaped on its way out. Failures get the HTTP status of
+// their code instead of a 200.
+use crate::ApiResponse;
+use rocket::http::Status;
+use rocket::serde::json::Value;
+use rocket::serde::Serialize;
+
+pub const BASE: &str = "/v2";
+
+#[derive(Serialize)]
+pub struct Body {
+    #[serde(flatten)]
+    outcome: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    transaction_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    counter: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_profile: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    debug: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    balance: Option<Value>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Outcome {
+    Data(Data),
+    Error(Error),
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Data {
+    // balance reads and writes
+    Balance {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        uid: Option<String>,
+        balance: u32,
+    },
+    // id, halt and wait
+    Card { uid: String },
+    // what a command without a result did, e.g. a beep or an initcard
+    Done {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        uid: Option<String>,
+        message: String,
+    },
+    // the routes that answer an object already
+    Object(Value),
+}
+
+#[derive(Serialize)]
+struct Error {
+    code: &'static str,
+    message: String,
+    // card the command failed on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uid: Option<String>,
+}
+
+// Whether a request path is one of the /v2 routes
+pub fn is_v2(path: &str) -> bool {
+    path.strip_prefix(BASE).is_some_and(|rest| rest.starts_with('/'))
+}
+
+// The /v2 form of a reply, `command` is the ReaderCommand::name() behind the data
+pub fn body(response: &ApiResponse, command: Option<&str>) -> Body {
+    let uid = response.uid.clone();
+    let outcome = match response.code {
+        Some(code) => Outcome::Error(Error {
+            code,
+            message: text(&response.data),
+            uid,
+        }),
+        None => Outcome::Data(data(&response.data, command, uid)),
+    };
+    Body {
+        outcome,
+        request_id: response.request_id.clone(),
+        transaction_id: response.transaction_id.clone(),
+        counter: response.counter,
+        key_profile: response.key_profile.clone(),
+        debug: response.debug.clone(),
+        balance: response.balance.clone(),
+    }
+}
+
+// HTTP status of an error code, the JSON body says which one it was
+pub fn status(code: &str) -> Status {
+    match code {
+        "INVALID_INPUT" | "INVALID_BLOCK" => Status::BadRequest,
+        "UNAUTHORIZED" => Status::Unauthorized,
+        // the caller's role or the blacklist / registry refuse it
+        "FORBIDDEN" | "CARD_BLOCKED" | "CARD_NOT_REGISTERED" => Status::Forbidden,
+        "NO_CARD" | "NOT_BLACKLISTED" | "NOT_REGISTERED" | "AUDIT_OFF" | "JOURNAL_OFF" | "CARDHOLDER_OFF" => Status::NotFound,
+        "INSUFFICIENT_FUNDS" | "BALANCE_LIMIT" | "IN_PROGRESS" => Status::Conflict,
+        "CONFIRM_REQUIRED" => Status::PreconditionRequired,
+        // the card answered, but not with what the request needs
+        "AUTH_FAILED" | "NOT_NDEF" | "INVALID_NDEF" | "UNSUPPORTED_CARD" | "VALUE_TAMPERED" | "CARD_ROLLBACK"
+        | "INVALID_CARDHOLDER" | "KEY_NOT_VERIFIED" | "IDEMPOTENCY_MISMATCH" => Status::UnprocessableEntity,
+        // the reader answered garbage
+        "INVALID_FRAME" | "CHECKSUM_MISMATCH" | "PROTOCOL_ERROR" | "READ_BACK_FAILED" => Status::BadGateway,
+        "PORT_ERROR" | "OPEN_TIMEOUT" | "BUSY" => Status::ServiceUnavailable,
+        "TIMEOUT" | "DEADLINE_EXCEEDED" => Status::GatewayTimeout,
+        _ => Status::InternalServerError,
+    }
+}
+
+fn data(data: &Value, command: Option<&str>, uid: Option<String>) -> Data {
+    let Value::String(text) = data else {
+        return Data::Object(data.clone());
+    };
+    match command {
+        Some("read_balance" | "init_balance" | "increase" | "decrease") => match text.parse() {
+            Ok(balance) => Data::Balance { uid, balance },
+            Err(_) => Data::Done { uid, message: text.clone() },
+        },
+        Some("read_id" | "halt") => Data::Card { uid: text.clone() },
+        _ => Data::Done { uid, message: text.clone() },
+    }
+}
+
+fn text(data: &Value) -> String {
+    match data {
+        Value::String(text) => text.clone(),
+        data => data.to_string(),
+    }
+}
@@ -0,0 +1,156 @@
+// Outbound webhooks: every `[[webhooks]]` entry gets the card events (taps and completed
+// transactions) POSTed as JSON, so access-control backends react without polling. The body
+// is signed with `X-ER302-Signature: sha256=<HMAC-SHA256 of the body with the secret, hex>`;
+// a delivery that fails or isn't answered 2xx is tried again with a growing pause.
+use crate::events::{Event, Events};
+use er302::codec;
+use er302::hmac::hmac_sha256;
+use rocket::serde::json;
+use rocket::serde::Deserialize;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+// Pause before the second attempt, doubled after every failure up to MAX_BACKOFF
+const BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Webhook {
+    // http://host[:port]/path
+    pub url: String,
+    pub secret: String,
+    // event kinds sent, all when empty
+    #[serde(default)]
+    pub events: Vec<String>,
+    // deliveries of an event, the first one included
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
+    // plaintext to a host other than this one, off by default
+    #[serde(default)]
+    pub insecure: bool,
+}
+
+fn default_attempts() -> u32 {
+    5
+}
+
+// host:port and path of an http:// URL
+pub fn target(url: &str) -> Result<(String, String), String> {
+    // no TLS client here, an https endpoint goes behind a local proxy
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("{}: only http:// webhooks are supported", url))?;
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    match authority.is_empty() {
+        true => Err(format!("{}: no host", url)),
+        false if authority.contains(':') => Ok((authority.to_string(), path.to_string())),
+        false => Ok((format!("{}:80", authority), path.to_string())),
+    }
+}
+
+// Whether host[:port] is this machine, the only place plaintext is sent without `insecure`
+pub fn loopback(address: &str) -> bool {
+    let host = match address.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.bytes().all(|byte| byte.is_ascii_digit()) => host,
+        _ => address,
+    };
+    let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+    host.eq_ignore_ascii_case("localhost") || host.parse::<std::net::IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+// The error of a plaintext endpoint on another host without `insecure = true`
+pub fn plaintext(url: &str, address: &str, insecure: bool) -> Result<(), String> {
+    match insecure || loopback(address) {
+        true => Ok(()),
+        false => Err(format!("{}: plaintext to another host, go through a local TLS proxy or set insecure = true", url)),
+    }
+}
+
+pub fn validate(webhooks: &[Webhook]) -> Result<(), String> {
+    for webhook in webhooks {
+        let (address, _) = target(&webhook.url)?;
+        plaintext(&webhook.url, &address, webhook.insecure)?;
+        if webhook.secret.is_empty() {
+            return Err(format!("{}: no secret", webhook.url));
+        }
+    }
+    Ok(())
+}
+
+// One thread per webhook, so a slow endpoint holds up only its own deliveries
+pub fn spawn(webhooks: Vec<Webhook>, events: &Events) {
+    for webhook in webhooks {
+        let mut receiver = events.subscribe();
+        thread::Builder::new()
+            .name("er302-webhook".to_string())
+            .spawn(move || loop {
+                match receiver.blocking_recv() {
+                    Ok(event) if webhook.events.is_empty() || webhook.events.iter().any(|kind| kind == event.kind) => {
+                        deliver(&webhook, &event)
+                    }
+                    Ok(_) => (),
+                    Err(RecvError::Lagged(missed)) => {
+                        tracing::warn!(url = webhook.url, missed, "webhook fell behind, events dropped")
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            })
+            .expect("failed to spawn webhook thread");
+    }
+}
+
+fn deliver(webhook: &Webhook, event: &Event) {
+    let body = json::to_string(event).unwrap_or_default();
+    // lowercase hex, as receivers usually compare it
+    let signature = codec::to_hex(&hmac_sha256(webhook.secret.as_bytes(), body.as_bytes())).to_lowercase();
+    let signature = format!("sha256={}", signature);
+    let headers = [("X-ER302-Event", event.kind), ("X-ER302-Signature", signature.as_str())];
+    let mut backoff = BACKOFF;
+    for attempt in 1..=webhook.attempts.max(1) {
+        match post_json(&webhook.url, &headers, &body) {
+            Ok(status) if (200..300).contains(&status) => return,
+            Ok(status) => tracing::warn!(url = webhook.url, attempt, status, "webhook refused the event"),
+            Err(e) => tracing::warn!(url = webhook.url, attempt, error = %e, "webhook unreachable"),
+        }
+        if attempt < webhook.attempts {
+            thread::sleep(backoff);
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+    tracing::error!(url = webhook.url, kind = event.kind, uid = event.uid, "webhook delivery given up");
+}
+
+// POST a JSON body to an http:// URL, the status code of the answer
+pub fn post_json(url: &str, headers: &[(&str, &str)], body: &str) -> std::io::Result<u16> {
+    let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+    let (authority, path) = target(url).map_err(invalid)?;
+    let mut stream = TcpStream::connect(authority.as_str())?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let host = authority.strip_suffix(":80").unwrap_or(&authority);
+    let headers: String = headers.iter().map(|(name, value)| format!("{}: {}\r\n", name, value)).collect();
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{}Connection: close\r\n\r\n{}",
+        path,
+        host,
+        body.len(),
+        headers,
+        body
+    )?;
+    let mut head = [0u8; 12];
+    stream.read_exact(&mut head)?;
+    // HTTP/1.1 204
+    std::str::from_utf8(&head[9..12])
+        .ok()
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| invalid("not an HTTP answer".to_string()))
+}
@@ -0,0 +1,835 @@
+// Background thread that owns the serial port, routes queue `ReaderCommand`s to it. One
+// thread per reader with blocking port I/O until the driver is ported to tokio-serial (see
+// Not done in the README): a stuck reader ties up its own thread only, the async routes
+// await the reply with a deadline and never block on a port.
+use crate::events::{Event, Events};
+use crate::Transport;
+use er302::access::AccessBits;
+use er302::cardholder::Cardholder;
+use er302::codec::{BlockAddress, CardInfo};
+use er302::ndef::{Content, Record};
+use er302::{codec, BeepPattern, BeepPatterns, CardCheck, Counters, Exchange, KeyProfile, Keys, Latencies, Latency, Reader, ReaderError, Retry, ValueMac};
+use rocket::serde::json::{json, Value};
+use rocket::tokio::sync::oneshot;
+use rocket::tokio::time::{sleep, timeout};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::collections::BTreeMap;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::io::ErrorKind;
+use std::thread;
+use tracing::Span;
+use std::time::{Duration, Instant};
+
+// Jobs waiting for the reader before new ones are refused
+pub const QUEUE_DEPTH: usize = 16;
+
+pub enum ReaderCommand {
+    ReadId,
+    // {uid, length}
+    ReadUid,
+    // {type, uid, atqa, sak}
+    ReadCardType,
+    Halt,
+    Beep(BeepPattern),
+    // {model, firmware, serial}
+    ReaderInfo,
+    SetRf(bool),
+    ReadBalance(BlockAddress),
+    InitBalance(BlockAddress, u32),
+    Increase(BlockAddress, u32),
+    Decrease(BlockAddress, u32),
+    // block, access bits (the keystore's when None)
+    InitCard(BlockAddress, Option<Vec<u8>>),
+    DeinitCard(BlockAddress),
+    // new trailer (key A | access bits | key B) of the block's sector, written after
+    // authenticating with `current`. Keys A are the card's application key when None, the
+    // access bits and key B those of the keystore.
+    RotateKeys {
+        block: BlockAddress,
+        current: Option<Vec<u8>>,
+        key_a: Option<Vec<u8>>,
+        access_bits: Option<Vec<u8>>,
+        key_b: Option<Vec<u8>>,
+    },
+    // block, key (the card's application key when None)
+    ReadBlock(BlockAddress, Option<Vec<u8>>),
+    // sector, key: {sector, blocks: [{block, hex, base64}]}
+    ReadSector(u8, Option<Vec<u8>>),
+    // key, data blocks of one sector: written in one session and each read back
+    WriteSector(Option<Vec<u8>>, Vec<(BlockAddress, Vec<u8>)>),
+    // from, to: the value of one block copied to another of its sector
+    TransferValue(BlockAddress, BlockAddress),
+    // block, key, data
+    WriteBlock(BlockAddress, Option<Vec<u8>>, Vec<u8>),
+    // Write dump blocks, `skipped` is only echoed in the report
+    Restore {
+        key: Option<Vec<u8>>,
+        blocks: Vec<(BlockAddress, Vec<u8>)>,
+        skipped: Vec<BlockAddress>,
+    },
+    ReadNdef,
+    WriteNdef(Vec<Record>),
+    // sector, {name, number, expiry}
+    ReadCardholder(u8),
+    WriteCardholder(u8, Cardholder),
+    // Ultralight / NTAG page
+    ReadPage(u8),
+    WritePage(u8, Vec<u8>),
+    // command code, payload: {node, command, status, data} of the answer, any status
+    Raw(u16, Vec<u8>),
+    // every card in the field: {cards: [{uid, type, atqa, sak}]}
+    ListCards,
+    // command APDUs for an ISO 14443-4 card, in one activation: {uid, ats, responses}
+    Apdu(Vec<Vec<u8>>),
+    // Checks of an increase, decrease or init_balance without their write,
+    // {dry_run, previous_balance, amount?, new_balance}
+    DryRun(Box<ReaderCommand>),
+}
+
+impl ReaderCommand {
+    // For the logs, without the data (keys) of the command
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReaderCommand::ReadId => "read_id",
+            ReaderCommand::ReadUid => "read_uid",
+            ReaderCommand::ReadCardType => "read_card_type",
+            ReaderCommand::Halt => "halt",
+            ReaderCommand::Beep(_) => "beep",
+            ReaderCommand::ReaderInfo => "reader_info",
+            ReaderCommand::SetRf(_) => "set_rf",
+            ReaderCommand::ReadBalance(_) => "read_balance",
+            ReaderCommand::InitBalance(..) => "init_balance",
+            ReaderCommand::Increase(..) => "increase",
+            ReaderCommand::Decrease(..) => "decrease",
+            ReaderCommand::InitCard(..) => "init_card",
+            ReaderCommand::DeinitCard(_) => "deinit_card",
+            ReaderCommand::RotateKeys { .. } => "rotate_keys",
+            ReaderCommand::ReadBlock(..) => "read_block",
+            ReaderCommand::ReadSector(..) => "read_sector",
+            ReaderCommand::WriteSector(..) => "write_sector",
+            ReaderCommand::TransferValue(..) => "transfer_value",
+            ReaderCommand::WriteBlock(..) => "write_block",
+            ReaderCommand::Restore { .. } => "restore",
+            ReaderCommand::ReadNdef => "read_ndef",
+            ReaderCommand::WriteNdef(_) => "write_ndef",
+            ReaderCommand::ReadCardholder(_) => "read_cardholder",
+            ReaderCommand::WriteCardholder(..) => "write_cardholder",
+            ReaderCommand::ReadPage(_) => "read_page",
+            ReaderCommand::WritePage(..) => "write_page",
+            ReaderCommand::Raw(..) => "raw",
+            ReaderCommand::Apdu(_) => "apdu",
+            ReaderCommand::ListCards => "list_cards",
+            ReaderCommand::DryRun(command) => match **command {
+                ReaderCommand::Increase(..) => "dry_run_increase",
+                ReaderCommand::Decrease(..) => "dry_run_decrease",
+                ReaderCommand::InitBalance(..) => "dry_run_init_balance",
+                _ => "dry_run",
+            },
+        }
+    }
+
+    // Value the balance is set to, increased or decreased by
+    pub fn amount(&self) -> Option<u32> {
+        match self {
+            ReaderCommand::InitBalance(_, value) | ReaderCommand::Increase(_, value) | ReaderCommand::Decrease(_, value) => {
+                Some(*value)
+            }
+            ReaderCommand::DryRun(command) => command.amount(),
+            _ => None,
+        }
+    }
+}
+
+pub struct Reply {
+    pub result: Result<Value, ReaderError>,
+    // Time the command spent in the queue before the reader picked it up
+    pub queue_wait: Duration,
+    // card the command talked to, if one answered
+    pub uid: Option<Vec<u8>>,
+    // balance before an increase / decrease, if it could be read
+    pub before: Option<u32>,
+    // transaction counter of the signed balance, with [card.mac]
+    pub counter: Option<u32>,
+    // key profile the card authenticated with, while there are profiles
+    pub key_profile: Option<String>,
+    // serial frames of the command, when it was sent traced
+    pub frames: Vec<Exchange>,
+}
+
+// How one command is run, `send` uses the defaults
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    // record the serial frames for the reply
+    pub trace: bool,
+    // UID of the card to talk to when several are in the field
+    pub card: Option<Vec<u8>>,
+    // the key profile of a wallet, the only one tried
+    pub profile: Option<String>,
+}
+
+struct Job {
+    // None stops the worker
+    command: Option<ReaderCommand>,
+    // span of the route that queued it
+    span: Span,
+    enqueued: Instant,
+    options: Options,
+    reply: oneshot::Sender<Reply>,
+    // QUEUED, then STARTED by the worker or EXPIRED by the route, whichever comes first
+    stage: Arc<AtomicU8>,
+}
+
+const QUEUED: u8 = 0;
+// the command may reach the card, the route waits for its outcome
+const STARTED: u8 = 1;
+// the route answered DEADLINE_EXCEEDED, the command mustn't run
+const EXPIRED: u8 = 2;
+
+// Clones queue their commands on the same reader
+#[derive(Clone)]
+pub struct Worker {
+    queue: SyncSender<Job>,
+    presence: Presence,
+    polling: Polling,
+    events: Events,
+    // serial.command_timeout_ms
+    deadline: Duration,
+    // answer times of the reader's frames, shared with its Reader
+    latencies: Latencies,
+}
+
+// Card in the field as the polling last saw it
+#[derive(Clone, Default)]
+pub struct Presence(Arc<Mutex<PresenceState>>);
+
+#[derive(Default)]
+struct PresenceState {
+    card: Option<Present>,
+    // polls in a row that didn't see the card
+    missed: u32,
+    // the card that left the field last and when, within the debounce window it's the same tap
+    left: Option<(Present, Instant)>,
+}
+
+#[derive(Clone)]
+pub struct Present {
+    pub uid: Vec<u8>,
+    pub since: Instant,
+}
+
+// How the worker drives the reader
+#[derive(Clone, Default)]
+pub struct ReaderSettings {
+    // every command ends with a HALT of the card
+    pub halt: bool,
+    pub beeps: BeepPatterns,
+    // card.max_balance, no limit when None
+    pub max_balance: Option<u32>,
+    // card.backup_block, a copy of the balance in the value sector
+    pub backup_block: Option<u8>,
+    // [card.mac], balances aren't signed when None
+    pub value_mac: Option<ValueMac>,
+    // card counters seen by all the readers, clones share them
+    pub counters: Counters,
+    // blacklist and registry, asked before balance operations
+    pub card_check: Option<CardCheck>,
+    // card.master_key, every card gets its own key A derived from it
+    pub master_key: Option<Vec<u8>>,
+    // the keystore's, the compiled-in ones without a keystore
+    pub keys: Keys,
+    // the keystore's profiles, tried after the application key
+    pub key_profiles: Vec<KeyProfile>,
+    // where polled cards are published, clones share it
+    pub events: Events,
+    pub polling: Polling,
+    pub timeouts: Timeouts,
+    // [retry] of transient card errors
+    pub retry: Retry,
+}
+
+// [serial] open_timeout_ms / read_timeout_ms / command_timeout_ms
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    // connecting to a serial-to-ethernet bridge, local ports open at once
+    pub open: Duration,
+    // the reader's answer to one frame, TIMEOUT after it
+    pub read: Duration,
+    // a route's wait for its command to start, queue wait and port open included,
+    // DEADLINE_EXCEEDED after it. A started command is waited for.
+    pub command: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            open: Duration::from_secs(2),
+            read: Duration::from_secs(2),
+            command: Duration::from_secs(10),
+        }
+    }
+}
+
+// [polling]: look for cards between commands even when nobody listens to the events, so
+// the present / absent state is always current
+#[derive(Clone, Copy, Debug)]
+pub struct Polling {
+    pub enabled: bool,
+    pub interval: Duration,
+    // a card back in the field this soon after it left isn't a new tap
+    pub debounce: Duration,
+    // polls in a row that miss the card before it left the field
+    pub removal_polls: u32,
+}
+
+impl Default for Polling {
+    // while GET /events has subscribers only
+    fn default() -> Self {
+        Polling {
+            enabled: false,
+            interval: Duration::from_millis(200),
+            debounce: Duration::from_millis(500),
+            removal_polls: 2,
+        }
+    }
+}
+
+impl Worker {
+    // Spawn the worker of reader `name`, the port is opened right away
+    pub fn spawn(name: String, transport: Transport, settings: ReaderSettings) -> Self {
+        let (queue, jobs) = mpsc::sync_channel(QUEUE_DEPTH);
+        let presence = Presence::default();
+        let (polling, events) = (settings.polling, settings.events.clone());
+        let deadline = settings.timeouts.command;
+        let state = presence.clone();
+        let latencies = Latencies::default();
+        let recorded = latencies.clone();
+        thread::Builder::new()
+            .name("er302-worker".to_string())
+            .spawn(move || run(name, transport, jobs, settings, state, recorded))
+            .expect("failed to spawn reader worker");
+        Worker { queue, presence, polling, events, deadline, latencies }
+    }
+
+    // Answer times per serial command since startup
+    pub fn latencies(&self) -> BTreeMap<&'static str, Latency> {
+        self.latencies.snapshot()
+    }
+
+    // Card in the field, None while nothing polls the reader
+    pub fn presence(&self) -> Option<Option<Present>> {
+        let polled = self.polling.enabled || self.events.listening();
+        polled.then(|| self.presence.0.lock().unwrap().card.clone())
+    }
+
+    pub async fn send(&self, command: ReaderCommand) -> Reply {
+        self.send_with(command, Options::default()).await
+    }
+
+    pub async fn send_with(&self, command: ReaderCommand, options: Options) -> Reply {
+        let (reply, mut response) = oneshot::channel();
+        let stage = Arc::new(AtomicU8::new(QUEUED));
+        let job = Job {
+            command: Some(command),
+            span: Span::current(),
+            enqueued: Instant::now(),
+            options,
+            reply,
+            stage: stage.clone(),
+        };
+        let error = |error: ReaderError| Reply {
+            result: Err(error),
+            queue_wait: Duration::ZERO,
+            uid: None,
+            before: None,
+            counter: None,
+            key_profile: None,
+            frames: Vec::new(),
+        };
+        let stopped = || ReaderError::PortError("reader worker stopped".to_string());
+        match self.queue.try_send(job) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => return error(ReaderError::Busy),
+            Err(TrySendError::Disconnected(_)) => return error(stopped()),
+        }
+        match timeout(self.deadline, &mut response).await {
+            Ok(Ok(reply)) => reply,
+            Ok(Err(_)) => error(stopped()),
+            Err(_) if stage.compare_exchange(QUEUED, EXPIRED, Ordering::SeqCst, Ordering::SeqCst).is_ok() => {
+                error(ReaderError::DeadlineExceeded { ms: self.deadline.as_millis() as u64 })
+            }
+            // running already: a mutation may change the card, so its outcome is the answer
+            Err(_) => response.await.unwrap_or_else(|_| error(stopped())),
+        }
+    }
+
+    // Runs the commands queued so far, then halts the card, switches the field off and
+    // closes the port. Commands sent afterwards fail like on a worker that died.
+    pub async fn stop(&self) {
+        let (reply, response) = oneshot::channel();
+        let mut job = Job {
+            command: None,
+            span: Span::current(),
+            enqueued: Instant::now(),
+            options: Options::default(),
+            reply,
+            stage: Arc::new(AtomicU8::new(QUEUED)),
+        };
+        let stopped = async {
+            // a full queue drains, the stop waits for room rather than being refused
+            loop {
+                match self.queue.try_send(job) {
+                    Ok(()) => break,
+                    Err(TrySendError::Full(back)) => job = back,
+                    Err(TrySendError::Disconnected(_)) => return,
+                }
+                sleep(Duration::from_millis(20)).await;
+            }
+            let _ = response.await;
+        };
+        // the queue takes at most QUEUE_DEPTH commands of the deadline each
+        if timeout(self.deadline * (QUEUE_DEPTH as u32 + 1), stopped).await.is_err() {
+            tracing::warn!("reader didn't stop in time, closing it anyway");
+        }
+    }
+}
+
+// First and longest wait before reopening a port that failed to open
+const RECONNECT_MIN: Duration = Duration::from_millis(250);
+const RECONNECT_MAX: Duration = Duration::from_secs(30);
+
+// Owns the port: drops it after an I/O error and reopens it on the next command,
+// backing off exponentially while opening keeps failing (cable unplugged).
+struct Connection {
+    transport: Transport,
+    settings: ReaderSettings,
+    latencies: Latencies,
+    reader: Option<Reader>,
+    backoff: Duration,
+    retry_at: Instant,
+    // why the port is closed
+    error: ReaderError,
+}
+
+impl Connection {
+    fn new(transport: Transport, settings: ReaderSettings, latencies: Latencies) -> Self {
+        let mut connection = Connection {
+            transport,
+            settings,
+            latencies,
+            reader: None,
+            backoff: Duration::ZERO,
+            retry_at: Instant::now(),
+            error: ReaderError::PortError(String::new()),
+        };
+        // open right away so a missing port shows up in the log at startup
+        let _ = connection.reader();
+        connection
+    }
+
+    fn reader(&mut self) -> Result<&mut Reader, ReaderError> {
+        if self.reader.is_none() {
+            if Instant::now() < self.retry_at {
+                return Err(match &self.error {
+                    ReaderError::PortError(e) => ReaderError::PortError(format!("{} (reconnecting)", e)),
+                    e => e.clone(),
+                });
+            }
+            match (self.transport.open)() {
+                Ok(mut port) => {
+                    if let Err(e) = port.set_timeout(self.settings.timeouts.read) {
+                        return Err(ReaderError::PortError(e.to_string()));
+                    }
+                    let mut reader = Reader::new(port);
+                    reader.skip_halted_cards(self.settings.halt);
+                    reader.set_beep_patterns(self.settings.beeps);
+                    reader.set_max_balance(self.settings.max_balance);
+                    reader.set_backup_block(self.settings.backup_block);
+                    reader.set_retry(self.settings.retry);
+                    reader.set_latencies(self.latencies.clone());
+                    reader.set_value_mac(self.settings.value_mac.clone());
+                    reader.set_counters(self.settings.counters.clone());
+                    reader.set_card_check(self.settings.card_check.clone());
+                    reader.set_master_key(self.settings.master_key.clone());
+                    reader.set_keys(self.settings.keys.clone());
+                    reader.set_key_profiles(self.settings.key_profiles.clone());
+                    self.reader = Some(reader);
+                    self.backoff = Duration::ZERO;
+                }
+                Err(e) => {
+                    self.backoff = (self.backoff * 2).clamp(RECONNECT_MIN, RECONNECT_MAX);
+                    tracing::error!(error = %e, retry_ms = self.backoff.as_millis() as u64, "can't open serial port");
+                    self.retry_at = Instant::now() + self.backoff;
+                    self.error = match e.kind() {
+                        serialport::ErrorKind::Io(ErrorKind::TimedOut) => ReaderError::OpenTimeout {
+                            ms: self.settings.timeouts.open.as_millis() as u64,
+                        },
+                        _ => ReaderError::PortError(e.to_string()),
+                    };
+                    return Err(self.error.clone());
+                }
+            }
+        }
+        Ok(self.reader.as_mut().expect("reader was just opened"))
+    }
+
+    // Close the port after an I/O error of a command, the next one reopens it right away.
+    // Commands aren't retried: a write may have reached the card before the port failed.
+    fn check(&mut self, result: &Result<Value, ReaderError>) {
+        if self.reader.is_none() {
+            return;
+        }
+        if let Err(ReaderError::PortError(e)) = result {
+            tracing::warn!(error = %e, "serial port failed, reopening it");
+            self.reader = None;
+            self.error = ReaderError::PortError(e.clone());
+            self.retry_at = Instant::now();
+        }
+    }
+}
+
+fn run(name: String, transport: Transport, jobs: Receiver<Job>, settings: ReaderSettings, presence: Presence, latencies: Latencies) {
+    let halt = settings.halt;
+    let events = settings.events.clone();
+    let polling = settings.polling;
+    let deadline = settings.timeouts.command;
+    let mut connection = Connection::new(transport, settings, latencies);
+    loop {
+        let job = match jobs.recv_timeout(polling.interval) {
+            Ok(job) => job,
+            Err(RecvTimeoutError::Timeout) => {
+                if polling.enabled || events.listening() {
+                    poll(&mut connection, &presence, &polling, &events, &name);
+                }
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let queue_wait = job.enqueued.elapsed();
+        let Some(command) = job.command else {
+            // the port is closed between frames rather than dying with the process
+            if let Some(reader) = connection.reader.as_mut() {
+                let _ = reader.halt();
+                let _ = reader.set_rf(false);
+            }
+            connection.reader = None;
+            tracing::info!(reader = name, "reader stopped");
+            let _ = job.reply.send(Reply {
+                result: Ok(Value::Null),
+                queue_wait,
+                uid: None,
+                before: None,
+                counter: None,
+                key_profile: None,
+                frames: Vec::new(),
+            });
+            break;
+        };
+        let span = tracing::info_span!(parent: &job.span, "command", name = command.name(), code = tracing::field::Empty).entered();
+        // the route answered DEADLINE_EXCEEDED already, the command mustn't reach the card later
+        if queue_wait >= deadline {
+            tracing::warn!(queue_ms = queue_wait.as_millis() as u64, "command expired in the queue");
+            continue;
+        }
+        let started = Instant::now();
+        let mut before = None;
+        let result = connection.reader().and_then(|reader| {
+            // the route gave up while the port opened, from here on it waits for the outcome
+            if job.stage.compare_exchange(QUEUED, STARTED, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+                return Err(ReaderError::DeadlineExceeded { ms: deadline.as_millis() as u64 });
+            }
+            reader.trace_frames(job.options.trace);
+            reader.target_card(job.options.card);
+            reader.pin_profile(job.options.profile);
+            let result = execute(reader, command, &mut before);
+            if let Err(e) = &result {
+                reader.signal_error(e);
+            }
+            // the card may be gone or halted already
+            if halt {
+                let _ = reader.halt();
+            }
+            reader.target_card(None);
+            reader.pin_profile(None);
+            result
+        });
+        let elapsed_ms = started.elapsed().as_millis() as u64;
+        match &result {
+            Ok(_) => tracing::info!(elapsed_ms, queue_ms = queue_wait.as_millis() as u64, "command done"),
+            Err(e) => {
+                tracing::warn!(elapsed_ms, code = e.code(), error = %e, "command failed");
+                // for the exported span, after the line that has it already
+                span.record("code", e.code());
+            }
+        }
+        let uid = connection.reader.as_mut().and_then(Reader::take_last_uid);
+        let counter = connection.reader.as_mut().and_then(Reader::take_last_counter);
+        let key_profile = connection.reader.as_mut().and_then(Reader::take_last_profile);
+        let frames = connection.reader.as_mut().map(Reader::take_trace).unwrap_or_default();
+        connection.check(&result);
+        // the route may have timed out and dropped its receiver
+        let _ = job.reply.send(Reply { result, queue_wait, uid, before, counter, key_profile, frames });
+    }
+}
+
+// Look for a card: a new one in the field is published as card_detected, one that's gone
+// (missed removal_polls times, then not back within the debounce window) as card_removed
+fn poll(connection: &mut Connection, presence: &Presence, polling: &Polling, events: &Events, name: &str) {
+    let Ok(reader) = connection.reader() else {
+        return;
+    };
+    let uid = match reader.poll_card() {
+        Ok(uid) => uid,
+        Err(e) => {
+            connection.check(&Err(e));
+            return;
+        }
+    };
+    let mut state = presence.0.lock().unwrap();
+    if state.card.as_ref().is_some_and(|card| uid.as_ref() == Some(&card.uid)) {
+        state.missed = 0;
+        return;
+    }
+    // gone after removal_polls misses, or right away when another card is there
+    if let Some(card) = state.card.take() {
+        state.missed += 1;
+        if uid.is_none() && state.missed < polling.removal_polls {
+            state.card = Some(card);
+            return;
+        }
+        tracing::debug!(reader = name, uid = codec::to_hex(&card.uid), "card absent");
+        state.missed = 0;
+        state.left = Some((card, Instant::now()));
+    }
+    let gone = state.left.take_if(|(card, left)| {
+        left.elapsed() >= polling.debounce || uid.as_ref().is_some_and(|uid| *uid != card.uid)
+    });
+    if let Some((card, _)) = gone {
+        events.publish(Event::new("card_removed", codec::to_hex(&card.uid), name));
+    }
+    let Some(uid) = uid else {
+        return;
+    };
+    // one tap that flickered at the edge of the field
+    let bounced = state.left.take().map(|(card, _)| card);
+    if bounced.is_none() {
+        events.publish(Event::new("card_detected", codec::to_hex(&uid), name));
+    }
+    state.card = Some(bounced.unwrap_or(Present { uid, since: Instant::now() }));
+}
+
+// `before` gets the balance an increase / decrease started from
+fn execute(reader: &mut Reader, command: ReaderCommand, before: &mut Option<u32>) -> Result<Value, ReaderError> {
+    let text = match command {
+        ReaderCommand::ReadId => reader.read_id(),
+        ReaderCommand::Halt => reader.halt_card(),
+        ReaderCommand::ReaderInfo => {
+            let info = reader.read_info()?;
+            return Ok(json!({ "model": info.model, "firmware": info.firmware, "serial": info.serial }));
+        }
+        ReaderCommand::SetRf(on) => {
+            reader.set_rf(on)?;
+            return Ok(json!({ "rf": on }));
+        }
+        ReaderCommand::Beep(pattern) => {
+            reader.beep_pattern(pattern);
+            Ok("Beep played".to_string())
+        }
+        ReaderCommand::ReadUid => {
+            let card = reader.read_card()?;
+            return Ok(json!({ "uid": codec::to_hex(&card.uid), "length": card.uid.len() }));
+        }
+        ReaderCommand::ReadCardType => return Ok(card_json(&reader.read_card()?)),
+        ReaderCommand::ListCards => {
+            let cards: Vec<_> = reader.list_cards()?.iter().map(card_json).collect();
+            return Ok(json!({ "cards": cards }));
+        }
+        ReaderCommand::ReadBalance(block) => reader.read_balance(block),
+        ReaderCommand::InitBalance(block, value) => reader.init_balance(block, value),
+        ReaderCommand::Increase(block, value) => reader.change_balance(block, value, true, before).map(|after| after.to_string()),
+        ReaderCommand::Decrease(block, value) => reader.change_balance(block, value, false, before).map(|after| after.to_string()),
+        ReaderCommand::InitCard(block, access) => reader.init_card(block, access.as_deref()),
+        ReaderCommand::DeinitCard(block) => reader.deinit_card(block),
+        ReaderCommand::RotateKeys { block, current, key_a, access_bits, key_b } => {
+            let keys = reader.keys();
+            let mut rest = access_bits.unwrap_or(keys.access_bits.to_vec());
+            rest.extend(key_b.unwrap_or(keys.default_key.to_vec()));
+            reader.change_keys(block, current.as_deref(), key_a.as_deref(), &rest)?;
+            let access = AccessBits::decode(&rest[..4])?;
+            return Ok(json!({ "sector": block.sector, "verified": true, "access": access_json(&access) }));
+        }
+        ReaderCommand::ReadBlock(block, key) => return reader.read_block(block, key.as_deref()).map(block_json),
+        ReaderCommand::ReadSector(sector, key) => {
+            let blocks = reader.read_sector_blocks(sector, key.as_deref())?;
+            return Ok(sector_json(sector, blocks.into_iter().enumerate().map(|(block, data)| (block as u8, data))));
+        }
+        ReaderCommand::TransferValue(from, to) => {
+            let balance = reader.transfer_value(from, to)?;
+            return Ok(json!({ "sector": from.sector, "from": from.block, "to": to.block, "balance": balance }));
+        }
+        ReaderCommand::WriteSector(key, blocks) => {
+            reader.write_sector_blocks(key.as_deref(), &blocks)?;
+            let sector = blocks.first().map(|(block, _)| block.sector).unwrap_or_default();
+            return Ok(sector_json(sector, blocks.into_iter().map(|(block, data)| (block.block, data))));
+        }
+        ReaderCommand::WriteBlock(block, key, data) => {
+            return reader.write_block(block, key.as_deref(), &data).map(|_| block_json(data))
+        }
+        ReaderCommand::Restore { key, blocks, skipped } => {
+            let results = reader.write_blocks(key.as_deref(), &blocks)?;
+            return Ok(restore_json(&blocks, results, &skipped));
+        }
+        ReaderCommand::ReadNdef => return ndef_json(reader.read_ndef()?),
+        ReaderCommand::ReadPage(page) => return reader.read_page(page).map(block_json),
+        ReaderCommand::WritePage(page, data) => return reader.write_page(page, &data).map(|_| block_json(data)),
+        ReaderCommand::WriteNdef(records) => {
+            reader.write_ndef(&records)?;
+            return ndef_json(records);
+        }
+        ReaderCommand::ReadCardholder(sector) => return reader.read_cardholder(sector).map(|record| cardholder_json(&record)),
+        ReaderCommand::WriteCardholder(sector, record) => {
+            reader.write_cardholder(sector, &record)?;
+            return Ok(cardholder_json(&record));
+        }
+        ReaderCommand::Raw(code, data) => {
+            let frame = reader.raw_command(code, &data)?;
+            return Ok(json!({
+                "node": format!("{:04X}", frame.node),
+                "command": format!("{:04X}", frame.command),
+                "status": frame.status,
+                "data": codec::to_hex(&frame.data),
+            }));
+        }
+        ReaderCommand::Apdu(apdus) => {
+            let (ats, responses) = reader.apdu_session(&apdus)?;
+            let responses: Vec<_> = responses
+                .iter()
+                .map(|response| json!({ "data": codec::to_hex(&response.data), "sw": codec::to_hex(&response.sw) }))
+                .collect();
+            return Ok(json!({ "ats": codec::to_hex(&ats), "responses": responses }));
+        }
+        ReaderCommand::DryRun(command) => return dry_run(reader, *command, before),
+    };
+    text.map(Value::String)
+}
+
+// What the command would leave on the card, refused with the error the command itself would get
+fn dry_run(reader: &mut Reader, command: ReaderCommand, before: &mut Option<u32>) -> Result<Value, ReaderError> {
+    let (after, amount) = match command {
+        ReaderCommand::Increase(block, value) => (reader.plan_change(block, value, true, before)?, Some(value)),
+        ReaderCommand::Decrease(block, value) => (reader.plan_change(block, value, false, before)?, Some(value)),
+        ReaderCommand::InitBalance(block, value) => (reader.plan_init_balance(block, value, before)?, None),
+        command => return Err(ReaderError::InvalidInput(format!("{} has no dry run", command.name()))),
+    };
+    let mut plan = json!({ "dry_run": true, "previous_balance": *before, "new_balance": after });
+    if let Some(amount) = amount {
+        plan["amount"] = json!(amount);
+    }
+    Ok(plan)
+}
+
+fn card_json(card: &CardInfo) -> Value {
+    json!({
+        "type": card.card_type().name(),
+        "uid": codec::to_hex(&card.uid),
+        "atqa": format!("{:04X}", card.atqa),
+        "sak": format!("{:02X}", card.sak),
+    })
+}
+
+fn cardholder_json(record: &Cardholder) -> Value {
+    json!({ "name": record.name, "number": record.number, "expiry": record.expiry.to_string() })
+}
+
+fn address_json(block: &BlockAddress) -> Value {
+    json!({ "sector": block.sector, "block": block.block })
+}
+
+// `complete` is false when at least one block couldn't be written
+fn restore_json(
+    blocks: &[(BlockAddress, Vec<u8>)],
+    results: Vec<Result<(), ReaderError>>,
+    skipped: &[BlockAddress],
+) -> Value {
+    let mut written = Vec::new();
+    let mut failed = Vec::new();
+    for ((block, data), result) in blocks.iter().zip(results) {
+        match result {
+            Ok(()) => {
+                let mut entry = address_json(block);
+                if let Some(access) = block.is_trailer().then(|| AccessBits::decode(&data[6..10]).ok()).flatten() {
+                    entry["access"] = access_json(&access);
+                }
+                written.push(entry)
+            }
+            Err(e) => failed.push(json!({
+                "sector": block.sector,
+                "block": block.block,
+                "code": e.code(),
+                "message": e.to_string(),
+            })),
+        }
+    }
+    json!({
+        "complete": failed.is_empty(),
+        "written": written,
+        "skipped": skipped.iter().map(address_json).collect::<Vec<_>>(),
+        "failed": failed,
+    })
+}
+
+// {records: [...]}, records of unknown types keep their raw TNF / type / payload
+fn ndef_json(records: Vec<Record>) -> Result<Value, ReaderError> {
+    let records = records
+        .iter()
+        .map(|record| {
+            Ok(match record.content()? {
+                Content::Uri(uri) => json!({ "type": "uri", "value": uri }),
+                Content::Text { language, text } => json!({ "type": "text", "value": text, "language": language }),
+                Content::Mime { mime_type, payload } => json!({
+                    "type": "mime",
+                    "mime_type": mime_type,
+                    "base64": STANDARD.encode(payload),
+                }),
+                Content::Other => json!({
+                    "type": "other",
+                    "tnf": record.tnf,
+                    "record_type": codec::to_hex(&record.record_type),
+                    "payload": codec::to_hex(&record.payload),
+                }),
+            })
+        })
+        .collect::<Result<Vec<_>, ReaderError>>()?;
+    Ok(json!({ "records": records }))
+}
+
+// What each block of a sector allows, see er302::access
+pub fn access_json(access: &AccessBits) -> Value {
+    json!({
+        "block0": access.describe(0),
+        "block1": access.describe(1),
+        "block2": access.describe(2),
+        "trailer": access.describe(3),
+        "user_byte": access.user_byte,
+    })
+}
+
+fn block_json(data: Vec<u8>) -> Value {
+    json!({
+        "hex": codec::to_hex(&data),
+        "base64": STANDARD.encode(&data),
+    })
+}
+
+fn sector_json(sector: u8, blocks: impl Iterator<Item = (u8, Vec<u8>)>) -> Value {
+    let blocks: Vec<Value> = blocks
+        .map(|(block, data)| {
+            let mut json = block_json(data);
+            json["block"] = json!(block);
+            json
+        })
+        .collect();
+    json!({ "sector": sector, "blocks": blocks })
+}
@@ -0,0 +1,302 @@
+// WebSocket (RFC 6455) on GET /ws for kiosk UIs that already keep a socket open: the card
+// events of GET /events come as text messages, and a text message with a command gets the
+// answer of the matching route with `reply` (and the `id` it was sent with) added. Each
+// command is journaled and audited like the route's, as `ws.<command>`.
+//
+//   {"command": "read_balance", "id": 7}  ->  {"reply": "read_balance", "id": 7, "status": true, "data": "1200"}
+//   {"command": "beep", "count": 2, "reader": "front-door"}
+//   {"command": "id"}
+use crate::auth::{refuse, Identity};
+use crate::calls::{Backend, Origin};
+use crate::cors::CorsConfig;
+use crate::events::Event;
+use crate::worker::{Options, ReaderCommand};
+use crate::{ad_hoc_beep, logging, reply};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use er302::hmac::sha1;
+use er302::ReaderError;
+use rocket::data::{IoHandler, IoStream};
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::{self, json, Value};
+use rocket::serde::Deserialize;
+use rocket::tokio::io::{split, AsyncRead, AsyncReadExt, AsyncWriteExt};
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::{self, error::RecvError};
+use rocket::Shutdown;
+use std::io;
+use std::pin::Pin;
+use std::time::Duration;
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// Sec-WebSocket-Version of RFC 6455, the only one taken
+pub const VERSION: &str = "13";
+// Longest message taken, commands are a few dozen bytes
+const MAX_MESSAGE: usize = 64 * 1024;
+
+// Status codes of the close frames the server fails a connection with
+const PROTOCOL_ERROR: u16 = 1002;
+const TOO_BIG: u16 = 1009;
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+// The handshake of the request, Sec-WebSocket-Accept of its key
+pub struct Upgrade {
+    accept: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Upgrade {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let headers = request.headers();
+        // comma separated, e.g. `Connection: keep-alive, Upgrade`
+        let has = |name: &str, token: &str| {
+            headers
+                .get(name)
+                .flat_map(|value| value.split(','))
+                .any(|value| value.trim().eq_ignore_ascii_case(token))
+        };
+        let key = headers.get_one("Sec-WebSocket-Key");
+        let (Some(key), true, true) = (key, has("Upgrade", "websocket"), has("Connection", "upgrade")) else {
+            return Outcome::Error((Status::UpgradeRequired, "not a WebSocket handshake"));
+        };
+        if headers.get_one("Sec-WebSocket-Version").map(str::trim) != Some(VERSION) {
+            return Outcome::Error((Status::UpgradeRequired, "not WebSocket version 13"));
+        }
+        // a browser sends the page's, so another site's page can't use the kiosk's socket;
+        // other clients send none
+        if let Some(origin) = headers.get_one("Origin") {
+            let cors = request.rocket().state::<CorsConfig>().expect("CORS origins are managed");
+            if !same_origin(origin, headers.get_one("Host")) && !cors.allows(origin) {
+                let Identity(caller) = request.local_cache(|| Identity(None));
+                tracing::warn!(origin, "WebSocket origin not allowed");
+                refuse(request, caller.clone(), "the page's origin may not open a WebSocket");
+                return Outcome::Error((Status::Forbidden, "origin not allowed"));
+            }
+        }
+        Outcome::Success(Upgrade { accept: accept(key) })
+    }
+}
+
+// `http://kiosk.local:8000` of a page served by the API itself, Host `kiosk.local:8000`
+fn same_origin(origin: &str, host: Option<&str>) -> bool {
+    let authority = origin.split_once("://").map(|(_, authority)| authority);
+    matches!((authority, host), (Some(authority), Some(host)) if authority.eq_ignore_ascii_case(host.trim()))
+}
+
+pub fn accept(key: &str) -> String {
+    STANDARD.encode(sha1(format!("{}{}", key.trim(), GUID).as_bytes()))
+}
+
+// Answer to the handshake, the connection is handed to the session after it
+pub struct Socket {
+    pub upgrade: Upgrade,
+    pub session: Session,
+}
+
+impl<'r, 'o: 'r> Responder<'r, 'o> for Socket {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'o> {
+        Response::build()
+            .raw_header("Sec-WebSocket-Accept", self.upgrade.accept)
+            .upgrade("websocket", self.session)
+            .ok()
+    }
+}
+
+pub struct Session {
+    pub events: broadcast::Receiver<Event>,
+    // only the events of this reader, all when None
+    pub reader: Option<String>,
+    pub backend: Backend,
+    // of the handshake, each command gets its own request ID
+    pub origin: Origin,
+    pub shutdown: Shutdown,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Command {
+    command: String,
+    // echoed in the reply, to tell the replies apart
+    id: Option<Value>,
+    reader: Option<String>,
+    // read_balance
+    sector: Option<u8>,
+    block: Option<u8>,
+    // beep
+    count: Option<u8>,
+    time: Option<u8>,
+    pause_ms: Option<u64>,
+}
+
+impl Session {
+    async fn answer(&self, message: &[u8]) -> Value {
+        let command: Command = match json::from_slice(message) {
+            Ok(command) => command,
+            Err(e) => {
+                let error = ReaderError::InvalidInput(format!("not a command: {}", e));
+                return json!({ "reply": null, "status": false, "data": error.to_string(), "code": error.code() });
+            }
+        };
+        let reader_command = match command.command.as_str() {
+            "id" => Ok(ReaderCommand::ReadId),
+            "read_balance" => self.backend.value_block(command.sector, command.block).map(ReaderCommand::ReadBalance),
+            "beep" => ad_hoc_beep(command.count, command.time, command.pause_ms).map(ReaderCommand::Beep),
+            other => Err(ReaderError::InvalidInput(format!("unknown command {}, one of id, read_balance, beep", other))),
+        };
+        let body = match reader_command {
+            Ok(reader_command) => {
+                let origin = Origin {
+                    request_id: logging::new_uuid(),
+                    route: format!("ws.{}", command.command),
+                    ..self.origin.clone()
+                };
+                let reader = command.reader.as_deref().unwrap_or_default();
+                self.backend.send(&origin, reader, reader_command, Options::default()).await
+            }
+            Err(e) => reply(Err(e), Duration::ZERO).body.into_inner(),
+        };
+        let mut answer = json::to_value(&body).unwrap_or_default();
+        answer["reply"] = Value::String(command.command);
+        if let Some(id) = command.id {
+            answer["id"] = id;
+        }
+        answer
+    }
+}
+
+#[rocket::async_trait]
+impl IoHandler for Session {
+    async fn io(self: Pin<Box<Self>>, io: IoStream) -> io::Result<()> {
+        let mut session = Pin::into_inner(self);
+        let (reader, mut writer) = split(io);
+        // reading a frame isn't cancel safe, so the same read goes on across the events
+        let mut next = Box::pin(read_frame(reader));
+        // the message so far and its opcode, while its fragments come in
+        let (mut message, mut fragmented) = (Vec::new(), None);
+        loop {
+            select! {
+                (reader, frame) = &mut next => {
+                    let frame = match frame {
+                        Ok(Ok(frame)) => frame,
+                        Ok(Err(code)) => return writer.write_all(&encode(CLOSE, &code.to_be_bytes())).await,
+                        // the client went away without a close frame
+                        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                        Err(e) => return Err(e),
+                    };
+                    next = Box::pin(read_frame(reader));
+                    let opcode = match (frame.opcode, fragmented) {
+                        // control frames may come between the fragments
+                        (CLOSE | PING | PONG, _) => frame.opcode,
+                        (TEXT | BINARY, None) => frame.opcode,
+                        (CONTINUATION, Some(opcode)) => opcode,
+                        // a new message before the last one ended, a continuation of none
+                        _ => return writer.write_all(&encode(CLOSE, &PROTOCOL_ERROR.to_be_bytes())).await,
+                    };
+                    match opcode {
+                        // echo the status code
+                        CLOSE => return writer.write_all(&encode(CLOSE, frame.payload.get(..2).unwrap_or_default())).await,
+                        PING => writer.write_all(&encode(PONG, &frame.payload)).await?,
+                        TEXT | BINARY => {
+                            if message.len() + frame.payload.len() > MAX_MESSAGE {
+                                return writer.write_all(&encode(CLOSE, &TOO_BIG.to_be_bytes())).await;
+                            }
+                            message.extend_from_slice(&frame.payload);
+                            fragmented = (!frame.fin).then_some(opcode);
+                            // binary messages are read to their end and dropped
+                            if frame.fin && opcode == TEXT {
+                                let answer = session.answer(&message).await;
+                                writer.write_all(&encode(TEXT, answer.to_string().as_bytes())).await?;
+                            }
+                            if frame.fin {
+                                message.clear();
+                            }
+                        }
+                        // pongs
+                        _ => (),
+                    }
+                }
+                event = session.events.recv() => match event {
+                    Ok(event) if session.reader.as_ref().is_none_or(|reader| *reader == event.reader) => {
+                        let text = json::to_string(&event).unwrap_or_default();
+                        writer.write_all(&encode(TEXT, text.as_bytes())).await?;
+                    }
+                    // a slow client misses the oldest events
+                    Ok(_) | Err(RecvError::Lagged(_)) => (),
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                _ = &mut session.shutdown => {
+                    // 1001 going away
+                    return writer.write_all(&encode(CLOSE, &1001u16.to_be_bytes())).await;
+                }
+            }
+        }
+    }
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// The reader comes back with the frame, for the next read
+async fn read_frame<R: AsyncRead + Unpin>(mut reader: R) -> (R, io::Result<Result<Frame, u16>>) {
+    let frame = next_frame(&mut reader).await;
+    (reader, frame)
+}
+
+// The next frame, or the status code to close with when it breaks the protocol
+async fn next_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Result<Frame, u16>> {
+    let mut head = [0u8; 2];
+    reader.read_exact(&mut head).await?;
+    let length = match head[1] & 0x7f {
+        126 => u64::from(reader.read_u16().await?),
+        127 => reader.read_u64().await?,
+        length => u64::from(length),
+    };
+    let (fin, opcode) = (head[0] & 0x80 != 0, head[0] & 0x0f);
+    // clients mask every frame, no extension is negotiated (RSV bits) and control frames are
+    // whole and at most 125 bytes
+    if head[1] & 0x80 == 0 || head[0] & 0x70 != 0 || (opcode >= CLOSE && (!fin || length > 125)) {
+        return Ok(Err(PROTOCOL_ERROR));
+    }
+    if length > MAX_MESSAGE as u64 {
+        return Ok(Err(TOO_BIG));
+    }
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask).await?;
+    let mut payload = vec![0; length as usize];
+    reader.read_exact(&mut payload).await?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Ok(Frame { fin, opcode, payload }))
+}
+
+// Unmasked and unfragmented, as a server sends them
+fn encode(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        length @ 0..=125 => frame.push(length as u8),
+        length @ 126..=0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}